@@ -1,4 +1,7 @@
 fn main() {
-	println!("cargo:rustc-link-lib=framework=Hypervisor");
-	println!("link-arg=-mmacosx-version-min=11.0");
+	// the bindings of the other architecture may be built on any host for documentation
+	if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("macos") {
+		println!("cargo:rustc-link-lib=framework=Hypervisor");
+		println!("link-arg=-mmacosx-version-min=11.0");
+	}
 }
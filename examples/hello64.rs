@@ -0,0 +1,100 @@
+//! Boots a 64-bit guest that prints a greeting to the serial port and halts
+//!
+//! The guest RAM is identity mapped by page tables at 0x1000, the code starts at
+//! 0x10000. Run it with
+//!
+//! ```shell
+//! $ cargo run --example hello64
+//! ```
+//!
+//! If Hypervisor.framework denies the VM, sign the binary with the entitlements of
+//! the repository and start it directly:
+//!
+//! ```shell
+//! $ codesign --entitlements app.entitlements -s - target/debug/examples/hello64
+//! $ target/debug/examples/hello64
+//! ```
+
+#[cfg(target_arch = "x86_64")]
+use std::io;
+#[cfg(target_arch = "x86_64")]
+use xhypervisor::consts::vmx_exit::VMX_REASON_IRQ;
+#[cfg(target_arch = "x86_64")]
+use xhypervisor::{
+	build_identity_page_tables, Error, ExitDetails, Register, SerialConsole, Vm, COM1,
+};
+
+/// Size of the guest RAM, covered by a single 2 MiB page
+#[cfg(target_arch = "x86_64")]
+const RAM_SIZE: usize = 0x200000;
+
+/// Guest physical address of the PML4
+#[cfg(target_arch = "x86_64")]
+const PML4_ADDRESS: u64 = 0x1000;
+
+/// Guest physical address of the code
+#[cfg(target_arch = "x86_64")]
+const CODE_ADDRESS: u64 = 0x10000;
+
+/// Returns the guest code, which writes `message` byte by byte to COM1
+#[cfg(target_arch = "x86_64")]
+fn payload(message: &str) -> Vec<u8> {
+	let mut code = vec![
+		0x66, 0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+		0x48, 0x8d, 0x35, 0x09, 0x00, 0x00, 0x00, /* lea message(%rip), %rsi */
+		/* loop: */
+		0xac, /* lods (%rsi), %al */
+		0x84, 0xc0, /* test %al, %al */
+		0x74, 0x03, /* jz done */
+		0xee, /* out %al, (%dx) */
+		0xeb, 0xf8, /* jmp loop */
+		/* done: */
+		0xf4, /* hlt */
+	];
+	/* message: */
+	code.extend_from_slice(message.as_bytes());
+	code.push(0);
+
+	code
+}
+
+#[cfg(target_arch = "x86_64")]
+fn main() -> Result<(), Error> {
+	let (vm, ram) = Vm::with_guest_ram(RAM_SIZE)?;
+
+	let mut tables = vec![0; 3 * 0x1000];
+	build_identity_page_tables(&mut tables, PML4_ADDRESS, RAM_SIZE as u64)?;
+	ram.write(PML4_ADDRESS as usize, &tables)?;
+	ram.write(
+		CODE_ADDRESS as usize,
+		&payload("Hello from a 64-bit guest!\n"),
+	)?;
+
+	let vcpu = vm.create_vcpu()?;
+	vcpu.setup_long_mode(PML4_ADDRESS)?;
+	vcpu.set_entry_point(CODE_ADDRESS)?;
+	vcpu.write_register(&Register::RSP, RAM_SIZE as u64)?;
+
+	let mut console = SerialConsole::new(COM1);
+	let mut stdout = io::stdout();
+	loop {
+		vcpu.run()?;
+		match vcpu.exit_details()? {
+			ExitDetails::Io(io) if console.handle_io(&vcpu, &io, &mut stdout)? => {}
+			ExitDetails::Hlt => break,
+			/* host interrupts and the first accesses to the RAM */
+			ExitDetails::Other { reason, .. } if reason.0 == VMX_REASON_IRQ => {}
+			ExitDetails::EptViolation(ept) if ept.gpa < RAM_SIZE as u64 => {}
+			details => {
+				return Err(Error::Error.context(format!("unexpected exit: {:?}", details)));
+			}
+		}
+	}
+
+	vcpu.destroy()
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn main() {
+	eprintln!("hello64 boots an x86_64 guest, it needs a Mac with an Intel processor");
+}
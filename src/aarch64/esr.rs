@@ -0,0 +1,214 @@
+//! Decoding of the exception syndrome register.
+
+use crate::aarch64::ffi::hv_vcpu_exit_exception_t;
+use core::fmt;
+
+/// Exception class of a guest exception, taken from bits 31:26 of the syndrome.
+///
+/// The guest runs below EL2, so the classes are those of exceptions taken from a lower
+/// exception level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ExceptionClass {
+	/// Unknown reason.
+	Unknown,
+
+	/// Trapped `WFI` or `WFE` instruction.
+	WfiWfe,
+
+	/// Access to SIMD or floating-point registers trapped by CPTR_EL2.
+	SimdFpAccess,
+
+	/// Illegal execution state.
+	IllegalState,
+
+	/// `SVC` instruction in AArch64 state.
+	Svc,
+
+	/// `HVC` instruction in AArch64 state.
+	Hvc,
+
+	/// `SMC` instruction in AArch64 state.
+	Smc,
+
+	/// Trapped `MSR`, `MRS` or system instruction in AArch64 state.
+	SysReg,
+
+	/// Instruction abort.
+	InstructionAbort,
+
+	/// PC alignment fault.
+	PcAlignment,
+
+	/// Data abort.
+	DataAbort,
+
+	/// SP alignment fault.
+	SpAlignment,
+
+	/// Trapped floating-point exception in AArch64 state.
+	FpException,
+
+	/// SError interrupt.
+	SError,
+
+	/// Hardware breakpoint.
+	Breakpoint,
+
+	/// Software step.
+	SoftwareStep,
+
+	/// Watchpoint.
+	Watchpoint,
+
+	/// `BRK` instruction in AArch64 state.
+	Brk,
+
+	/// Any other exception class.
+	Other(u8),
+}
+
+impl From<u8> for ExceptionClass {
+	fn from(value: u8) -> ExceptionClass {
+		match value {
+			0x00 => ExceptionClass::Unknown,
+			0x01 => ExceptionClass::WfiWfe,
+			0x07 => ExceptionClass::SimdFpAccess,
+			0x0e => ExceptionClass::IllegalState,
+			0x15 => ExceptionClass::Svc,
+			0x16 => ExceptionClass::Hvc,
+			0x17 => ExceptionClass::Smc,
+			0x18 => ExceptionClass::SysReg,
+			0x20 => ExceptionClass::InstructionAbort,
+			0x22 => ExceptionClass::PcAlignment,
+			0x24 => ExceptionClass::DataAbort,
+			0x26 => ExceptionClass::SpAlignment,
+			0x2c => ExceptionClass::FpException,
+			0x2f => ExceptionClass::SError,
+			0x30 => ExceptionClass::Breakpoint,
+			0x32 => ExceptionClass::SoftwareStep,
+			0x34 => ExceptionClass::Watchpoint,
+			0x3c => ExceptionClass::Brk,
+			value => ExceptionClass::Other(value),
+		}
+	}
+}
+
+impl From<ExceptionClass> for u8 {
+	fn from(value: ExceptionClass) -> u8 {
+		match value {
+			ExceptionClass::Unknown => 0x00,
+			ExceptionClass::WfiWfe => 0x01,
+			ExceptionClass::SimdFpAccess => 0x07,
+			ExceptionClass::IllegalState => 0x0e,
+			ExceptionClass::Svc => 0x15,
+			ExceptionClass::Hvc => 0x16,
+			ExceptionClass::Smc => 0x17,
+			ExceptionClass::SysReg => 0x18,
+			ExceptionClass::InstructionAbort => 0x20,
+			ExceptionClass::PcAlignment => 0x22,
+			ExceptionClass::DataAbort => 0x24,
+			ExceptionClass::SpAlignment => 0x26,
+			ExceptionClass::FpException => 0x2c,
+			ExceptionClass::SError => 0x2f,
+			ExceptionClass::Breakpoint => 0x30,
+			ExceptionClass::SoftwareStep => 0x32,
+			ExceptionClass::Watchpoint => 0x34,
+			ExceptionClass::Brk => 0x3c,
+			ExceptionClass::Other(value) => value,
+		}
+	}
+}
+
+/// Exception syndrome of a guest exception (ESR_EL2).
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Esr(pub u64);
+
+impl Esr {
+	/// Returns the exception class.
+	pub fn exception_class(&self) -> ExceptionClass {
+		ExceptionClass::from(((self.0 >> 26) & 0x3f) as u8)
+	}
+
+	/// Returns the length of the trapped instruction in bytes (2 or 4).
+	pub fn instruction_length(&self) -> u64 {
+		if self.0 & (1 << 25) != 0 {
+			4
+		} else {
+			2
+		}
+	}
+
+	/// Returns the instruction specific syndrome.
+	pub fn iss(&self) -> u32 {
+		(self.0 & 0x1ff_ffff) as u32
+	}
+
+	/// Returns the fault status code of an instruction or data abort.
+	pub fn fault_status(&self) -> Option<u8> {
+		match self.exception_class() {
+			ExceptionClass::InstructionAbort | ExceptionClass::DataAbort => {
+				Some((self.iss() & 0x3f) as u8)
+			}
+			_ => None,
+		}
+	}
+
+	/// Returns whether a data abort was caused by a write.
+	pub fn is_write(&self) -> Option<bool> {
+		match self.exception_class() {
+			ExceptionClass::DataAbort => Some(self.iss() & (1 << 6) != 0),
+			_ => None,
+		}
+	}
+}
+
+impl From<u64> for Esr {
+	fn from(value: u64) -> Esr {
+		Esr(value)
+	}
+}
+
+impl fmt::Debug for Esr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_struct("Esr")
+			.field("exception_class", &self.exception_class())
+			.field("iss", &format_args!("{:#x}", self.iss()))
+			.finish()
+	}
+}
+
+/// Guest exception that caused an exit.
+#[derive(Copy, Clone, Debug)]
+pub struct GuestException(hv_vcpu_exit_exception_t);
+
+impl GuestException {
+	/// Returns the exception syndrome.
+	pub fn syndrome(&self) -> Esr {
+		Esr(self.0.syndrome)
+	}
+
+	/// Returns the faulting virtual address (FAR_EL2).
+	///
+	/// The value is only defined for aborts and watchpoints.
+	pub fn fault_va(&self) -> u64 {
+		self.0.virtual_address
+	}
+
+	/// Returns the faulting intermediate physical address.
+	///
+	/// The value is only defined for aborts on stage 2 translations.
+	pub fn fault_ipa(&self) -> u64 {
+		self.0.physical_address
+	}
+
+	/// Returns the exception as reported by the framework.
+	pub fn raw(&self) -> hv_vcpu_exit_exception_t {
+		self.0
+	}
+}
+
+impl From<hv_vcpu_exit_exception_t> for GuestException {
+	fn from(exception: hv_vcpu_exit_exception_t) -> GuestException {
+		GuestException(exception)
+	}
+}
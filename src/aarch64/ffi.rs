@@ -38,7 +38,10 @@ pub type hv_interrupt_type_t = u32;
 /// Cache type.
 pub type hv_cache_type_t = u32;
 
-//pub type hv_simd_fp_uchar16_t = core::arch::aarch64::uint8x16_t;
+/// Value of an ARM SIMD & FP register (layout of `uint8x16_t`).
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct hv_simd_fp_uchar16_t(pub [u8; 16]);
 
 /// Memory region permissions.
 pub type hv_memory_flags_t = u64;
@@ -49,6 +52,11 @@ pub type hv_return_t = u32;
 /// Type of ARM feature register.
 pub type hv_feature_reg_t = u32;
 
+/// Signature of `hv_vm_get_max_vcpu_count`, which returns the maximum number of
+/// vCPUs that the hypervisor supports. The function is only available on macOS 15
+/// and later, so it is looked up at runtime instead of being linked.
+pub type hv_vm_get_max_vcpu_count_t = unsafe extern "C" fn(max_vcpu_count: *mut u32) -> hv_return_t;
+
 /// Contains details of a vCPU exception.
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
@@ -713,6 +721,7 @@ pub const HV_FEATURE_REG_CLIDR_EL1: hv_feature_reg_t = 10;
 /// The value that identifies feature register DCZID_EL0.
 pub const HV_FEATURE_REG_DCZID_EL0: hv_feature_reg_t = 11;
 
+#[cfg(target_arch = "aarch64")]
 extern "C" {
 
 	// VM APIs
@@ -774,8 +783,14 @@ extern "C" {
 	/// Sets the value of a vCPU register.
 	pub fn hv_vcpu_set_reg(vcpu: hv_vcpu_t, reg: hv_reg_t, value: u64) -> hv_return_t;
 
-	// TODO: SIMD APIs
-	//pub fn hv_vcpu_get_simd_fp_reg(vcpu: hv_vcpu_t, reg: hv_simd_fp_reg_t, value: *mut hv_simd_fp_uchar16_t) -> hv_return_t;
+	/// Gets the current value of a vCPU SIMD & FP register.
+	pub fn hv_vcpu_get_simd_fp_reg(
+		vcpu: hv_vcpu_t,
+		reg: hv_simd_fp_reg_t,
+		value: *mut hv_simd_fp_uchar16_t,
+	) -> hv_return_t;
+
+	// TODO: passing a SIMD type by value requires the unstable `simd_ffi` feature
 	//pub fn hv_vcpu_set_simd_fp_reg(vcpu: hv_vcpu_t, reg: hv_simd_fp_reg_t, value: hv_simd_fp_uchar16_t) -> hv_return_t;
 
 	/// Gets the current value of a vCPU system register.
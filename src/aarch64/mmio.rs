@@ -0,0 +1,152 @@
+//! Decoding and completion of MMIO accesses of the guest.
+
+use crate::aarch64::{ExceptionClass, Register, VirtualCpu, VirtualCpuExitReason};
+use crate::Error;
+
+/// Guest access to an IPA that isn't mapped, decoded from a data abort.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MmioExit {
+	/// Intermediate physical address of the access.
+	pub ipa: u64,
+
+	/// `true` for a store, `false` for a load.
+	pub is_write: bool,
+
+	/// Access size in bytes (1, 2, 4 or 8).
+	pub size: u8,
+
+	/// Index of the general purpose register of the access, 31 stands for XZR.
+	pub reg: u8,
+
+	/// Value of a store, truncated to `size`.
+	pub data: Option<u64>,
+
+	/// A load sign-extends the value.
+	pub sign_extend: bool,
+
+	/// A load targets the 64-bit register instead of its lower 32 bits.
+	pub sixty_four: bool,
+}
+
+// Returns the general purpose register with the index, `None` for XZR
+fn gpr(index: u8) -> Option<Register> {
+	let reg = match index {
+		0 => Register::X0,
+		1 => Register::X1,
+		2 => Register::X2,
+		3 => Register::X3,
+		4 => Register::X4,
+		5 => Register::X5,
+		6 => Register::X6,
+		7 => Register::X7,
+		8 => Register::X8,
+		9 => Register::X9,
+		10 => Register::X10,
+		11 => Register::X11,
+		12 => Register::X12,
+		13 => Register::X13,
+		14 => Register::X14,
+		15 => Register::X15,
+		16 => Register::X16,
+		17 => Register::X17,
+		18 => Register::X18,
+		19 => Register::X19,
+		20 => Register::X20,
+		21 => Register::X21,
+		22 => Register::X22,
+		23 => Register::X23,
+		24 => Register::X24,
+		25 => Register::X25,
+		26 => Register::X26,
+		27 => Register::X27,
+		28 => Register::X28,
+		29 => Register::X29,
+		30 => Register::X30,
+		_ => return None,
+	};
+
+	Some(reg)
+}
+
+// Returns a mask covering `size` bytes
+fn size_mask(size: u8) -> u64 {
+	u64::MAX >> (64 - 8 * u32::from(size))
+}
+
+impl VirtualCpu<'_> {
+	/// Decodes the last exit of the VirtualCpu as an MMIO access.
+	///
+	/// Returns `None` if the exit isn't a data abort or if the syndrome doesn't describe
+	/// the access, e.g. for load/store pair instructions.
+	pub fn mmio_exit(&self) -> Result<Option<MmioExit>, Error> {
+		let exception = match self.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => exception,
+			_ => return Ok(None),
+		};
+		let esr = exception.syndrome();
+		let iss = esr.iss();
+		if esr.exception_class() != ExceptionClass::DataAbort || iss & (1 << 24) == 0 {
+			return Ok(None);
+		}
+
+		let size = 1 << ((iss >> 22) & 0x3);
+		let reg = ((iss >> 16) & 0x1f) as u8;
+		let is_write = iss & (1 << 6) != 0;
+		let data = if is_write {
+			let value = match gpr(reg) {
+				Some(reg) => self.read_register(reg)?,
+				None => 0,
+			};
+			Some(value & size_mask(size))
+		} else {
+			None
+		};
+
+		Ok(Some(MmioExit {
+			ipa: exception.fault_ipa(),
+			is_write,
+			size,
+			reg,
+			data,
+			sign_extend: iss & (1 << 21) != 0,
+			sixty_four: iss & (1 << 15) != 0,
+		}))
+	}
+
+	/// Completes an MMIO load of the last exit.
+	///
+	/// Writes `value`, truncated to the access size and sign- or zero-extended like by
+	/// the load instruction, into the target register and advances PC past the
+	/// instruction. Returns `Error::BadArg` if the last exit isn't an MMIO load.
+	pub fn complete_mmio_read(&self, value: u64) -> Result<(), Error> {
+		let mmio = match self.mmio_exit()? {
+			Some(mmio) if !mmio.is_write => mmio,
+			_ => return Err(Error::BadArg),
+		};
+
+		let mut value = value & size_mask(mmio.size);
+		if mmio.sign_extend && mmio.size < 8 {
+			let shift = 64 - 8 * u32::from(mmio.size);
+			value = (((value << shift) as i64) >> shift) as u64;
+		}
+		if !mmio.sixty_four {
+			value &= 0xffff_ffff;
+		}
+
+		if let Some(reg) = gpr(mmio.reg) {
+			self.write_register(reg, value)?;
+		}
+
+		self.skip_instruction()
+	}
+
+	/// Completes an MMIO store of the last exit by advancing PC past the instruction.
+	///
+	/// Returns `Error::BadArg` if the last exit isn't an MMIO store.
+	pub fn complete_mmio_write(&self) -> Result<(), Error> {
+		match self.mmio_exit()? {
+			Some(mmio) if mmio.is_write => self.skip_instruction(),
+			_ => Err(Error::BadArg),
+		}
+	}
+}
@@ -1,45 +1,198 @@
+mod esr;
 pub mod ffi;
-
+mod mmio;
+mod pstate;
+mod regs;
+mod signal;
+mod state;
+mod vtimer;
+
+pub use self::esr::{Esr, ExceptionClass, GuestException};
 use self::ffi::*;
-use crate::{match_MemPerm, match_error_code, Error, MemPerm};
+pub use self::mmio::MmioExit;
+pub use self::pstate::Pstate;
+pub use self::regs::GpRegs;
+pub(crate) use self::signal::unblocked;
+pub use self::signal::{
+	cancel_signal, set_cancel_signal, CancelHandle, SignalGuard, DEFAULT_CANCEL_SIGNAL,
+};
+pub use self::state::VcpuState;
+pub use self::vtimer::VTimer;
+use crate::vm::{Vcpus, Vm};
+use crate::{
+	create_claimed_vm, dedup_vcpu_ids, match_MemPerm, match_error_code, match_flags_error,
+	memory_flags, Error, MemFlags, MemPerm, ModelRegisters,
+};
+use core::fmt;
 use libc::*;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem;
 use std::ptr::null_mut;
+use std::sync::Weak;
+use std::time::Duration;
 
 /// Creates a VM instance for the current Mach task
+///
+/// Only one VM instance may exist per task. If it already exists, `Error::BadArg` is
+/// returned with a context that says so, without calling into the framework.
+/// `Error::NoDev` is returned with a hint to check `sysctl kern.hv_support`.
 pub fn create_vm() -> Result<(), Error> {
-	match_error_code(unsafe { hv_vm_create(null_mut()) })
+	create_claimed_vm(|| {
+		traced!(
+			debug,
+			match_error_code(unsafe { hv_vm_create(null_mut()) }),
+			"create_vm"
+		)
+	})
 }
 
 /// Maps a region in the virtual address space of the current task into the guest physical
 /// address space of the virutal machine
-pub fn map_mem(mem: &[u8], ipa: u64, mem_perm: MemPerm) -> Result<(), Error> {
-	match_error_code(unsafe {
-		hv_vm_map(
-			mem.as_ptr() as *mut c_void,
+///
+/// The guest may write to the region, so it is borrowed mutably.
+pub fn map_mem(mem: &mut [u8], ipa: u64, mem_perm: MemPerm) -> Result<(), Error> {
+	map_mem_with_flags(mem, ipa, mem_perm, MemFlags::NONE)
+}
+
+/// Maps a region like `map_mem` with additional memory flags
+///
+/// Returns `Error::Unsupp` if the framework rejects the flags.
+pub fn map_mem_with_flags(
+	mem: &mut [u8],
+	ipa: u64,
+	mem_perm: MemPerm,
+	flags: MemFlags,
+) -> Result<(), Error> {
+	let _span =
+		debug_span!("map_mem", ipa, size = mem.len(), perm = ?mem_perm, flags = flags.bits());
+
+	traced!(
+		debug,
+		match_flags_error(
+			match_error_code(unsafe {
+				hv_vm_map(
+					mem.as_mut_ptr() as *mut c_void,
+					ipa as hv_ipa_t,
+					mem.len() as size_t,
+					memory_flags(mem_perm, flags),
+				)
+			}),
+			flags
+		),
+		"map_mem"
+	)
+}
+
+/// Maps a region of externally managed host memory into the guest physical address space
+/// of the virtual machine
+///
+/// # Safety
+///
+/// `ptr` must be page-aligned and valid for reads and, if `mem_perm` allows writes,
+/// for writes of `len` bytes until the region is unmapped or the VM is destroyed.
+pub unsafe fn map_mem_raw(
+	ptr: *mut u8,
+	len: usize,
+	ipa: u64,
+	mem_perm: MemPerm,
+) -> Result<(), Error> {
+	let _span = debug_span!("map_mem_raw", ?ptr, ipa, size = len, perm = ?mem_perm);
+
+	traced!(
+		debug,
+		match_error_code(hv_vm_map(
+			ptr as *mut c_void,
 			ipa as hv_ipa_t,
-			mem.len() as size_t,
+			len as size_t,
 			match_MemPerm(mem_perm),
-		)
-	})
+		)),
+		"map_mem_raw"
+	)
 }
 
 /// Unmaps a region in the guest physical address space of the virutal machine
 pub fn unmap_mem(ipa: u64, size: usize) -> Result<(), Error> {
-	match_error_code(unsafe { hv_vm_unmap(ipa as hv_ipa_t, size as size_t) })
+	let _span = debug_span!("unmap_mem", ipa, size);
+
+	traced!(
+		debug,
+		match_error_code(unsafe { hv_vm_unmap(ipa as hv_ipa_t, size as size_t) }),
+		"unmap_mem"
+	)
 }
 
 /// Modifies the permissions of a region in the guest physical address space of the virtual
 /// machine
 pub fn protect_mem(ipa: u64, size: usize, mem_perm: MemPerm) -> Result<(), Error> {
-	match_error_code(unsafe {
-		hv_vm_protect(ipa as hv_ipa_t, size as size_t, match_MemPerm(mem_perm))
-	})
+	protect_mem_with_flags(ipa, size, mem_perm, MemFlags::NONE)
+}
+
+/// Modifies the permissions of a region like `protect_mem` with additional memory flags
+///
+/// Returns `Error::Unsupp` if the framework rejects the flags.
+pub fn protect_mem_with_flags(
+	ipa: u64,
+	size: usize,
+	mem_perm: MemPerm,
+	flags: MemFlags,
+) -> Result<(), Error> {
+	let _span = debug_span!("protect_mem", ipa, size, perm = ?mem_perm, flags = flags.bits());
+
+	traced!(
+		debug,
+		match_flags_error(
+			match_error_code(unsafe {
+				hv_vm_protect(
+					ipa as hv_ipa_t,
+					size as size_t,
+					memory_flags(mem_perm, flags),
+				)
+			}),
+			flags
+		),
+		"protect_mem"
+	)
+}
+
+/// Forces an immediate exit of a set of VirtualCpus
+///
+/// * `vcpu_ids` Array of VirtualCpu IDs
+///
+/// Duplicate IDs are removed. Returns `Error::BadArg` if the array is empty or holds
+/// more than `MAX_INTERRUPT_VCPUS` IDs.
+pub fn interrupt_vcpus(vcpu_ids: &[hv_vcpu_t]) -> Result<(), Error> {
+	let ids = dedup_vcpu_ids(vcpu_ids)?;
+	match_error_code(unsafe { hv_vcpus_exit(ids.as_ptr(), ids.len() as u32) })
+}
+
+/// Returns the maximum number of VirtualCpus that the host supports per VM
+///
+/// The framework reports the limit on macOS 15 and later. Older hosts get
+/// `Error::Unsupp`.
+pub fn max_vcpu_count() -> Result<u32, Error> {
+	let symbol = unsafe {
+		dlsym(
+			RTLD_DEFAULT,
+			b"hv_vm_get_max_vcpu_count\0".as_ptr() as *const c_char,
+		)
+	};
+	if symbol.is_null() {
+		return Err(Error::Unsupp.context("hv_vm_get_max_vcpu_count requires macOS 15 or later"));
+	}
+
+	let get_max_vcpu_count: hv_vm_get_max_vcpu_count_t = unsafe { mem::transmute(symbol) };
+	let mut count: u32 = 0;
+	match_error_code(unsafe { get_max_vcpu_count(&mut count) })?;
+
+	Ok(count)
 }
 
 #[derive(Copy, Clone, Debug)]
 /// Exit reason of a virtual CPU
 /// Enum is derived from
 /// https://github.com/Thog/ahv
+#[non_exhaustive]
 pub enum VirtualCpuExitReason {
 	/// Asynchronous exit.
 	Cancelled,
@@ -47,14 +200,17 @@ pub enum VirtualCpuExitReason {
 	/// Guest exception.
 	Exception {
 		/// The informations about the guest exception.
-		exception: hv_vcpu_exit_exception_t,
+		exception: GuestException,
 	},
 
 	/// Virtual Timer enters the pending state.
 	VTimerActivated,
 
-	/// Unexpected exit.
-	Unknown,
+	/// Unexpected exit, with the raw `hv_exit_reason_t` value.
+	///
+	/// This covers `HV_EXIT_REASON_UNKNOWN` as well as reasons that are newer than
+	/// these bindings.
+	Unknown(hv_exit_reason_t),
 }
 
 impl From<hv_vcpu_exit_t> for VirtualCpuExitReason {
@@ -62,27 +218,82 @@ impl From<hv_vcpu_exit_t> for VirtualCpuExitReason {
 		match value.reason {
 			HV_EXIT_REASON_CANCELED => VirtualCpuExitReason::Cancelled,
 			HV_EXIT_REASON_EXCEPTION => VirtualCpuExitReason::Exception {
-				exception: value.exception,
+				exception: GuestException::from(value.exception),
 			},
 			HV_EXIT_REASON_VTIMER_ACTIVATED => VirtualCpuExitReason::VTimerActivated,
-			HV_EXIT_REASON_UNKNOWN => VirtualCpuExitReason::Unknown,
+			reason => VirtualCpuExitReason::Unknown(reason),
+		}
+	}
+}
 
-			// Unexpected unknown
-			_ => VirtualCpuExitReason::Unknown,
+/// Interrupt line of a VirtualCpu
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InterruptType {
+	/// ARM IRQ
+	IRQ,
+	/// ARM FIQ
+	FIQ,
+}
+
+impl From<InterruptType> for hv_interrupt_type_t {
+	fn from(value: InterruptType) -> hv_interrupt_type_t {
+		match value {
+			InterruptType::IRQ => HV_INTERRUPT_TYPE_IRQ,
+			InterruptType::FIQ => HV_INTERRUPT_TYPE_FIQ,
 		}
 	}
 }
 
+/// Returns the size of the floating point and SIMD state in bytes
+///
+/// The state consists of the 32 128-bit registers Q0-Q31. FPCR and FPSR are
+/// accessible as regular registers.
+pub fn fpstate_size() -> usize {
+	32 * 16
+}
+
+/// Options for the creation of a VirtualCpu.
+///
+/// The default leaves the VirtualCpu as the framework creates it.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VcpuConfig {
+	/// Masks the virtual timer before the first run.
+	///
+	/// The framework creates VirtualCpus with the virtual timer unmasked, so a guest
+	/// that enables the timer causes `VTimerActivated` exits right away. A masked
+	/// timer doesn't cause exits until it is unmasked by `set_vtimer_mask`, e.g.
+	/// through `VTimer`.
+	pub vtimer_masked: bool,
+}
+
 /// Virtual CPU
-pub struct VirtualCpu {
+///
+/// VirtualCpus created by `Vm::create_vcpu` borrow the `Vm` for `'vm`, so the VM
+/// can't be destroyed while they are alive. A VirtualCpu is bound to the thread
+/// that created it. Unlike on x86_64, this needs no runtime check, since the
+/// VirtualCpu is neither `Send` nor `Sync` and can't reach another thread.
+pub struct VirtualCpu<'vm> {
 	/// Virtual CPU handle
 	id: hv_vcpu_t,
 
 	/// VirtualCPU exit informations.
 	vcpu_exit: *const hv_vcpu_exit_t,
+
+	/// Whether a run has completed, i.e. `vcpu_exit` holds an exit.
+	pub(crate) exited: Cell<bool>,
+
+	/// PC read by `instruction_pointer` since the last run or write of PC.
+	cached_ip: Cell<Option<u64>>,
+
+	/// Registry of the Vm that created the VirtualCpu.
+	pub(crate) registry: Weak<Vcpus>,
+
+	/// VM instance the VirtualCpu belongs to.
+	vm: PhantomData<&'vm Vm>,
 }
 
 /// aarch64 architectural register
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Register {
 	/// X0 register.
 	X0,
@@ -196,6 +407,57 @@ pub enum Register {
 	CPSR,
 }
 
+impl Register {
+	// Returns the canonical lowercase name of the register
+	fn name(&self) -> &'static str {
+		match self {
+			Register::X0 => "x0",
+			Register::X1 => "x1",
+			Register::X2 => "x2",
+			Register::X3 => "x3",
+			Register::X4 => "x4",
+			Register::X5 => "x5",
+			Register::X6 => "x6",
+			Register::X7 => "x7",
+			Register::X8 => "x8",
+			Register::X9 => "x9",
+			Register::X10 => "x10",
+			Register::X11 => "x11",
+			Register::X12 => "x12",
+			Register::X13 => "x13",
+			Register::X14 => "x14",
+			Register::X15 => "x15",
+			Register::X16 => "x16",
+			Register::X17 => "x17",
+			Register::X18 => "x18",
+			Register::X19 => "x19",
+			Register::X20 => "x20",
+			Register::X21 => "x21",
+			Register::X22 => "x22",
+			Register::X23 => "x23",
+			Register::X24 => "x24",
+			Register::X25 => "x25",
+			Register::X26 => "x26",
+			Register::X27 => "x27",
+			Register::X28 => "x28",
+			Register::X29 => "x29",
+			Register::FP => "fp",
+			Register::X30 => "x30",
+			Register::LR => "lr",
+			Register::PC => "pc",
+			Register::FPCR => "fpcr",
+			Register::FPSR => "fpsr",
+			Register::CPSR => "cpsr",
+		}
+	}
+}
+
+impl fmt::Display for Register {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
 impl From<Register> for hv_reg_t {
 	fn from(value: Register) -> hv_reg_t {
 		match value {
@@ -580,6 +842,13 @@ pub enum SystemRegister {
 	SP_EL1,
 }
 
+impl fmt::Display for SystemRegister {
+	/// Prints the architectural name of the register, e.g. `SCTLR_EL1`.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		fmt::Debug::fmt(self, f)
+	}
+}
+
 impl From<SystemRegister> for hv_sys_reg_t {
 	fn from(value: SystemRegister) -> hv_sys_reg_t {
 		match value {
@@ -699,26 +968,150 @@ impl From<SystemRegister> for hv_sys_reg_t {
 	}
 }
 
-impl VirtualCpu {
-	pub fn new() -> Result<VirtualCpu, Error> {
+impl VirtualCpu<'static> {
+	/// Creates a VirtualCpu instance for the current thread without binding it to a `Vm`.
+	///
+	/// This is the escape hatch for VMs created by `create_vm`, `Vm::create_vcpu` is
+	/// the safe way to create a VirtualCpu.
+	///
+	/// # Safety
+	///
+	/// The VM instance must exist until the VirtualCpu is destroyed, i.e. the caller
+	/// has to call `destroy` before `destroy_vm` and must not use the VirtualCpu
+	/// after the VM instance is gone.
+	pub unsafe fn new_unbound() -> Result<VirtualCpu<'static>, Error> {
+		unsafe { VirtualCpu::new_unbound_with(&VcpuConfig::default()) }
+	}
+
+	/// Creates a VirtualCpu instance with the options of `config` without binding it
+	/// to a `Vm`.
+	///
+	/// # Safety
+	///
+	/// See `new_unbound`.
+	pub unsafe fn new_unbound_with(config: &VcpuConfig) -> Result<VirtualCpu<'static>, Error> {
 		let handle: hv_vcpu_config_t = core::ptr::null_mut();
 		let mut vcpu_handle: hv_vcpu_t = 0;
 		let mut vcpu_exit: *const hv_vcpu_exit_t = core::ptr::null_mut();
 
-		match_error_code(unsafe { hv_vcpu_create(&mut vcpu_handle, &mut vcpu_exit, &handle) })?;
+		traced!(
+			debug,
+			match_error_code(unsafe { hv_vcpu_create(&mut vcpu_handle, &mut vcpu_exit, &handle) }),
+			vcpu = vcpu_handle,
+			"create_vcpu"
+		)?;
 
-		Ok(VirtualCpu {
+		let vcpu = VirtualCpu {
 			id: vcpu_handle,
 			vcpu_exit: vcpu_exit,
-		})
+			exited: Cell::new(false),
+			cached_ip: Cell::new(None),
+			registry: Weak::new(),
+			vm: PhantomData,
+		};
+		if config.vtimer_masked {
+			if let Err(err) = vcpu.set_vtimer_mask(true) {
+				let _ = vcpu.destroy();
+				return Err(err);
+			}
+		}
+
+		Ok(vcpu)
 	}
+}
 
+impl VirtualCpu<'_> {
 	pub fn get_id(&self) -> hv_vcpu_t {
 		self.id
 	}
 
-	pub fn exit_reason(&self) -> VirtualCpuExitReason {
-		VirtualCpuExitReason::from(unsafe { *self.vcpu_exit })
+	/// Returns the cumulative execution time of the VirtualCpu in nanoseconds.
+	pub fn exec_time(&self) -> Result<u64, Error> {
+		let mut exec_time: u64 = 0;
+
+		match_error_code(unsafe { hv_vcpu_get_exec_time(self.id, &mut exec_time) })?;
+
+		Ok(exec_time)
+	}
+
+	/// Returns the reason of the last exit of the VirtualCpu.
+	///
+	/// Returns `None` before the first run has completed, since the framework fills in
+	/// the exit information only when `hv_vcpu_run` returns.
+	pub fn exit_reason(&self) -> Option<VirtualCpuExitReason> {
+		if !self.exited.get() {
+			return None;
+		}
+
+		Some(VirtualCpuExitReason::from(unsafe { *self.vcpu_exit }))
+	}
+
+	/// Executes the VirtualCpu and returns the exit reason with the time spent in the guest.
+	///
+	/// The time is the difference of `exec_time` around the run.
+	pub fn run_and_time(&self) -> Result<(VirtualCpuExitReason, Duration), Error> {
+		let start = self.exec_time()?;
+		self.run()?;
+		let end = self.exec_time()?;
+
+		let reason = self.exit_reason().ok_or(Error::Error)?;
+
+		Ok((reason, Duration::from_nanos(end - start)))
+	}
+
+	/// Advances PC past the instruction that caused the current exception.
+	///
+	/// The instruction length is taken from the IL bit of the syndrome. For `HVC` and
+	/// `SVC`, PC already points behind the instruction and is left unchanged. Returns
+	/// `Error::BadArg` if the last exit wasn't caused by an exception.
+	pub fn skip_instruction(&self) -> Result<(), Error> {
+		let esr = match self.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => exception.syndrome(),
+			_ => return Err(Error::BadArg),
+		};
+
+		match esr.exception_class() {
+			ExceptionClass::Hvc | ExceptionClass::Svc => Ok(()),
+			_ => {
+				let pc = self.read_register(Register::PC)?;
+				self.write_register(Register::PC, pc + esr.instruction_length())
+			}
+		}
+	}
+
+	/// Returns the instruction pointer (PC) of the VirtualCpu.
+	///
+	/// The value is cached until the next run or write of PC, so repeated calls
+	/// while handling one exit read the register only once.
+	pub fn instruction_pointer(&self) -> Result<u64, Error> {
+		if let Some(ip) = self.cached_ip.get() {
+			return Ok(ip);
+		}
+
+		let ip = self.read_register(Register::PC)?;
+		self.cached_ip.set(Some(ip));
+
+		Ok(ip)
+	}
+
+	// Drops the PC cached by `instruction_pointer`.
+	pub(crate) fn forget_instruction_pointer(&self) {
+		self.cached_ip.set(None);
+	}
+
+	/// Sets the instruction pointer (PC) of the VirtualCpu.
+	pub fn set_instruction_pointer(&self, value: u64) -> Result<(), Error> {
+		self.write_register(Register::PC, value)
+	}
+
+	/// Lets the VirtualCpu start executing at `addr` in EL1h.
+	///
+	/// CPSR is set to EL1 with SP_EL1 as stack pointer and the D, A, I and F
+	/// exceptions masked, and PC to `addr`. The MMU state in SCTLR_EL1 is left as it
+	/// is, so `addr` is a guest physical address unless the guest enabled translation.
+	pub fn set_entry_point(&self, addr: u64) -> Result<(), Error> {
+		self.write_pstate(Pstate::el1h())?;
+		self.write_register(Register::PC, addr)
 	}
 
 	/// Returns the current value of an architectural aarch64 register
@@ -726,31 +1119,158 @@ impl VirtualCpu {
 	pub fn read_register(&self, reg: Register) -> Result<u64, Error> {
 		let mut value: u64 = 0;
 
-		match_error_code(unsafe {
-			hv_vcpu_get_reg(self.id, hv_reg_t::from(reg), &mut value as *mut u64)
-		})?;
-
-		Ok(value)
+		traced!(
+			trace,
+			match_error_code(unsafe {
+				hv_vcpu_get_reg(self.id, hv_reg_t::from(reg), &mut value as *mut u64)
+			})
+			.map(|()| value),
+			vcpu = self.id,
+			%reg,
+			"read_register"
+		)
 	}
 
 	/// Sets the value of an architectural x86 register of the VirtualCpu
 	pub fn write_register(&self, reg: Register, value: u64) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_set_reg(self.id, hv_reg_t::from(reg), value) })
+		if let Register::PC = reg {
+			self.forget_instruction_pointer();
+		}
+
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_set_reg(self.id, hv_reg_t::from(reg), value) }),
+			vcpu = self.id,
+			%reg,
+			value,
+			"write_register"
+		)
 	}
 
 	/// Gets a system register value.
 	pub fn read_system_register(&self, reg: SystemRegister) -> Result<u64, Error> {
 		let mut value: u64 = 0;
 
+		traced!(
+			trace,
+			match_error_code(unsafe {
+				hv_vcpu_get_sys_reg(self.id, hv_sys_reg_t::from(reg), &mut value as *mut u64)
+			})
+			.map(|()| value),
+			vcpu = self.id,
+			%reg,
+			"read_system_register"
+		)
+	}
+
+	/// Gets a system register value.
+	pub fn write_system_register(&self, reg: SystemRegister, value: u64) -> Result<(), Error> {
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_set_sys_reg(self.id, hv_sys_reg_t::from(reg), value) }),
+			vcpu = self.id,
+			%reg,
+			value,
+			"write_system_register"
+		)
+	}
+
+	/// Returns whether an interrupt line of the VirtualCpu is asserted
+	pub fn pending_interrupt(&self, irq: InterruptType) -> Result<bool, Error> {
+		let mut pending = false;
+
 		match_error_code(unsafe {
-			hv_vcpu_get_sys_reg(self.id, hv_sys_reg_t::from(reg), &mut value as *mut u64)
+			hv_vcpu_get_pending_interrupt(self.id, hv_interrupt_type_t::from(irq), &mut pending)
 		})?;
 
-		Ok(value)
+		Ok(pending)
 	}
 
-	/// Gets a system register value.
-	pub fn write_system_register(&self, reg: SystemRegister, value: u64) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_set_sys_reg(self.id, hv_sys_reg_t::from(reg), value) })
+	/// Asserts or deasserts an interrupt line of the VirtualCpu
+	///
+	/// The framework clears the pending interrupt when `run` returns.
+	pub fn set_pending_interrupt(&self, irq: InterruptType, pending: bool) -> Result<(), Error> {
+		match_error_code(unsafe {
+			hv_vcpu_set_pending_interrupt(self.id, hv_interrupt_type_t::from(irq), pending)
+		})
+	}
+
+	/// Returns whether the virtual timer of the VirtualCpu is masked
+	pub fn vtimer_mask(&self) -> Result<bool, Error> {
+		let mut masked = false;
+
+		match_error_code(unsafe { hv_vcpu_get_vtimer_mask(self.id, &mut masked) })?;
+
+		Ok(masked)
+	}
+
+	/// Masks or unmasks the virtual timer of the VirtualCpu
+	///
+	/// See `VTimer` for the handshake that is required after a `VTimerActivated` exit.
+	pub fn set_vtimer_mask(&self, masked: bool) -> Result<(), Error> {
+		match_error_code(unsafe { hv_vcpu_set_vtimer_mask(self.id, masked) })
+	}
+
+	/// Returns the virtual timer offset of the VirtualCpu
+	pub fn vtimer_offset(&self) -> Result<u64, Error> {
+		let mut offset: u64 = 0;
+
+		match_error_code(unsafe { hv_vcpu_get_vtimer_offset(self.id, &mut offset) })?;
+
+		Ok(offset)
+	}
+
+	/// Sets the virtual timer offset of the VirtualCpu
+	pub fn set_vtimer_offset(&self, offset: u64) -> Result<(), Error> {
+		match_error_code(unsafe { hv_vcpu_set_vtimer_offset(self.id, offset) })
+	}
+
+	/// Reads the SIMD & FP registers Q0-Q31 of the VirtualCpu
+	///
+	/// Returns `Error::BadArg` if the buffer isn't `fpstate_size()` bytes long.
+	pub fn read_fpstate(&self, buffer: &mut [u8]) -> Result<(), Error> {
+		if buffer.len() != fpstate_size() {
+			return Err(Error::BadArg);
+		}
+
+		for (reg, chunk) in buffer.chunks_exact_mut(16).enumerate() {
+			let mut value = hv_simd_fp_uchar16_t::default();
+
+			match_error_code(unsafe {
+				hv_vcpu_get_simd_fp_reg(self.id, HV_SIMD_FP_REG_Q0 + reg as u32, &mut value)
+			})?;
+
+			chunk.copy_from_slice(&value.0);
+		}
+
+		Ok(())
+	}
+
+	/// Returns the SIMD & FP registers Q0-Q31 of the VirtualCpu
+	pub fn read_fpstate_vec(&self) -> Result<Vec<u8>, Error> {
+		let mut buffer = vec![0; fpstate_size()];
+
+		self.read_fpstate(&mut buffer)?;
+
+		Ok(buffer)
+	}
+}
+
+impl ModelRegisters for VirtualCpu<'_> {
+	/// System register.
+	type Id = SystemRegister;
+
+	fn read_model_register(&self, id: SystemRegister) -> Result<u64, Error> {
+		self.read_system_register(id)
+	}
+
+	fn write_model_register(&self, id: SystemRegister, value: u64) -> Result<(), Error> {
+		self.write_system_register(id, value)
+	}
+}
+
+impl fmt::Debug for VirtualCpu<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "VirtualCpu ID: {}", self.get_id())
 	}
 }
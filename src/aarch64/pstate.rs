@@ -0,0 +1,161 @@
+//! Decoding of the process state in CPSR.
+
+use crate::aarch64::{Register, SystemRegister, VirtualCpu};
+use crate::Error;
+
+const SP_SEL: u64 = 1 << 0;
+const EL_SHIFT: u64 = 2;
+const FIQ_MASK: u64 = 1 << 6;
+const IRQ_MASK: u64 = 1 << 7;
+const SERROR_MASK: u64 = 1 << 8;
+const DEBUG_MASK: u64 = 1 << 9;
+const V: u64 = 1 << 28;
+const C: u64 = 1 << 29;
+const Z: u64 = 1 << 30;
+const N: u64 = 1 << 31;
+
+/// Process state of a VirtualCpu in AArch64 state, as held in CPSR.
+///
+/// Only the exception level, the stack pointer selection, the DAIF masks and the
+/// condition flags are decoded. Other bits of CPSR are zero in `bits`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Pstate {
+	/// Exception level (0 or 1 for a guest).
+	pub el: u8,
+	/// Whether SP_ELx rather than SP_EL0 is used at EL1 (SPSel).
+	pub sp_sel: bool,
+	/// Whether watchpoint, breakpoint and software step exceptions are masked (D).
+	pub debug_masked: bool,
+	/// Whether SError interrupts are masked (A).
+	pub serror_masked: bool,
+	/// Whether IRQs are masked (I).
+	pub irq_masked: bool,
+	/// Whether FIQs are masked (F).
+	pub fiq_masked: bool,
+	/// Negative condition flag.
+	pub n: bool,
+	/// Zero condition flag.
+	pub z: bool,
+	/// Carry condition flag.
+	pub c: bool,
+	/// Overflow condition flag.
+	pub v: bool,
+}
+
+impl Pstate {
+	/// Returns the state of EL1 using SP_EL1 (EL1h) with all exceptions masked.
+	///
+	/// This is CPSR `0x3c5`, the usual state to enter a kernel.
+	pub fn el1h() -> Pstate {
+		Pstate {
+			el: 1,
+			sp_sel: true,
+			..Pstate::default().masked()
+		}
+	}
+
+	/// Returns the state of EL1 using SP_EL0 (EL1t) with all exceptions masked.
+	///
+	/// This is CPSR `0x3c4`.
+	pub fn el1t() -> Pstate {
+		Pstate {
+			el: 1,
+			..Pstate::default().masked()
+		}
+	}
+
+	/// Returns the state of EL0 with no exceptions masked.
+	///
+	/// This is CPSR `0x0`.
+	pub fn el0() -> Pstate {
+		Pstate::default()
+	}
+
+	/// Returns the state with the D, A, I and F exceptions masked.
+	pub fn masked(self) -> Pstate {
+		Pstate {
+			debug_masked: true,
+			serror_masked: true,
+			irq_masked: true,
+			fiq_masked: true,
+			..self
+		}
+	}
+
+	/// Returns the state with IRQs and FIQs unmasked.
+	pub fn with_interrupts(self) -> Pstate {
+		Pstate {
+			irq_masked: false,
+			fiq_masked: false,
+			..self
+		}
+	}
+
+	/// Returns the CPSR value of the state.
+	pub fn bits(&self) -> u64 {
+		let flag = |set: bool, bit: u64| if set { bit } else { 0 };
+
+		(u64::from(self.el & 0x3) << EL_SHIFT)
+			| flag(self.sp_sel, SP_SEL)
+			| flag(self.fiq_masked, FIQ_MASK)
+			| flag(self.irq_masked, IRQ_MASK)
+			| flag(self.serror_masked, SERROR_MASK)
+			| flag(self.debug_masked, DEBUG_MASK)
+			| flag(self.v, V)
+			| flag(self.c, C)
+			| flag(self.z, Z)
+			| flag(self.n, N)
+	}
+}
+
+impl From<u64> for Pstate {
+	fn from(cpsr: u64) -> Pstate {
+		Pstate {
+			el: ((cpsr >> EL_SHIFT) & 0x3) as u8,
+			sp_sel: cpsr & SP_SEL != 0,
+			debug_masked: cpsr & DEBUG_MASK != 0,
+			serror_masked: cpsr & SERROR_MASK != 0,
+			irq_masked: cpsr & IRQ_MASK != 0,
+			fiq_masked: cpsr & FIQ_MASK != 0,
+			n: cpsr & N != 0,
+			z: cpsr & Z != 0,
+			c: cpsr & C != 0,
+			v: cpsr & V != 0,
+		}
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the decoded process state of the VirtualCpu.
+	pub fn read_pstate(&self) -> Result<Pstate, Error> {
+		Ok(Pstate::from(self.read_register(Register::CPSR)?))
+	}
+
+	/// Sets the process state of the VirtualCpu.
+	///
+	/// CPSR bits that `Pstate` doesn't decode are cleared.
+	pub fn write_pstate(&self, pstate: Pstate) -> Result<(), Error> {
+		self.write_register(Register::CPSR, pstate.bits())
+	}
+
+	/// Returns the exception return address and saved process state at EL1.
+	///
+	/// These are ELR_EL1 and SPSR_EL1, which the processor sets when it takes an
+	/// exception to EL1 and which `eret` restores.
+	pub fn read_exception_context(&self) -> Result<(u64, Pstate), Error> {
+		let spsr = self.read_system_register(SystemRegister::SPSR_EL1)?;
+		let elr = self.read_system_register(SystemRegister::ELR_EL1)?;
+
+		Ok((elr, Pstate::from(spsr)))
+	}
+
+	/// Sets the state that the next `eret` at EL1 returns to.
+	///
+	/// `eret` jumps to `elr` with the process state `spsr`. Both registers are
+	/// written before the VirtualCpu runs again, since it belongs to the current
+	/// thread, so the guest never observes only one of them.
+	pub fn prepare_exception_return(&self, elr: u64, spsr: Pstate) -> Result<(), Error> {
+		self.write_system_register(SystemRegister::SPSR_EL1, spsr.bits())?;
+		self.write_system_register(SystemRegister::ELR_EL1, elr)
+	}
+}
@@ -0,0 +1,139 @@
+//! Snapshot of the general purpose registers.
+
+use crate::aarch64::{Register, SystemRegister, VirtualCpu};
+use crate::Error;
+
+/// General purpose registers of a VirtualCpu, including SP, PC and CPSR.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct GpRegs {
+	/// X0 to X30.
+	pub x: [u64; 31],
+	/// Stack pointer selected by CPSR, i.e. SP_EL0 or SP_EL1.
+	pub sp: u64,
+	/// Program counter.
+	pub pc: u64,
+	/// Current program status register.
+	pub cpsr: u64,
+}
+
+const X: [Register; 31] = [
+	Register::X0,
+	Register::X1,
+	Register::X2,
+	Register::X3,
+	Register::X4,
+	Register::X5,
+	Register::X6,
+	Register::X7,
+	Register::X8,
+	Register::X9,
+	Register::X10,
+	Register::X11,
+	Register::X12,
+	Register::X13,
+	Register::X14,
+	Register::X15,
+	Register::X16,
+	Register::X17,
+	Register::X18,
+	Register::X19,
+	Register::X20,
+	Register::X21,
+	Register::X22,
+	Register::X23,
+	Register::X24,
+	Register::X25,
+	Register::X26,
+	Register::X27,
+	Register::X28,
+	Register::X29,
+	Register::X30,
+];
+
+// Returns the stack pointer register selected by the mode bits of CPSR
+fn stack_pointer_register(cpsr: u64) -> SystemRegister {
+	let el = (cpsr >> 2) & 0x3;
+	let sp_sel = cpsr & 0x1;
+
+	if el == 0 || sp_sel == 0 {
+		SystemRegister::SP_EL0
+	} else {
+		SystemRegister::SP_EL1
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the general purpose registers of the VirtualCpu.
+	pub fn read_gp_regs(&self) -> Result<GpRegs, Error> {
+		let mut regs = GpRegs {
+			cpsr: self.read_register(Register::CPSR)?,
+			pc: self.read_register(Register::PC)?,
+			..Default::default()
+		};
+
+		for (value, reg) in regs.x.iter_mut().zip(X.iter()) {
+			*value = self.read_register(*reg)?;
+		}
+		regs.sp = self.read_system_register(stack_pointer_register(regs.cpsr))?;
+
+		Ok(regs)
+	}
+
+	/// Sets the general purpose registers of the VirtualCpu.
+	///
+	/// `sp` is written to the stack pointer selected by the new CPSR.
+	pub fn write_gp_regs(&self, regs: &GpRegs) -> Result<(), Error> {
+		for (value, reg) in regs.x.iter().zip(X.iter()) {
+			self.write_register(*reg, *value)?;
+		}
+		self.write_register(Register::CPSR, regs.cpsr)?;
+		self.write_register(Register::PC, regs.pc)?;
+		self.write_system_register(stack_pointer_register(regs.cpsr), regs.sp)
+	}
+
+	/// Returns the stack pointer of the current exception level.
+	///
+	/// At EL0, and at EL1 with SPSel cleared (EL1t), the VirtualCpu uses SP_EL0. At
+	/// EL1 with SPSel set (EL1h), it uses SP_EL1. The exception level is taken from
+	/// CPSR bits 3:2 and SPSel from bit 0.
+	pub fn stack_pointer(&self) -> Result<u64, Error> {
+		let cpsr = self.read_register(Register::CPSR)?;
+		self.read_system_register(stack_pointer_register(cpsr))
+	}
+
+	/// Sets the stack pointer of the current exception level.
+	///
+	/// The register is selected like in `stack_pointer`, so CPSR must be set first.
+	pub fn set_stack_pointer(&self, value: u64) -> Result<(), Error> {
+		let cpsr = self.read_register(Register::CPSR)?;
+		self.write_system_register(stack_pointer_register(cpsr), value)
+	}
+
+	/// Writes several registers in order and rolls them back if one write fails.
+	///
+	/// On failure, returns the index of the failing pair in `pairs` together with
+	/// the error, after restoring the previous values of the registers written so
+	/// far in reverse order. The rollback is best effort: errors while restoring are
+	/// ignored, since the original error is the one that matters.
+	pub fn write_registers_checked(&self, pairs: &[(Register, u64)]) -> Result<(), (usize, Error)> {
+		let mut previous = Vec::with_capacity(pairs.len());
+
+		for (index, (reg, value)) in pairs.iter().enumerate() {
+			let result = self
+				.read_register(*reg)
+				.and_then(|old| self.write_register(*reg, *value).map(|()| old));
+
+			match result {
+				Ok(old) => previous.push((*reg, old)),
+				Err(err) => {
+					for (reg, old) in previous.iter().rev() {
+						let _ = self.write_register(*reg, *old);
+					}
+					return Err((index, err));
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,207 @@
+//! Cancellation of a running VirtualCpu through a signal.
+
+use crate::aarch64::ffi::{hv_vcpu_t, hv_vcpus_exit};
+use crate::aarch64::VirtualCpu;
+use crate::Error;
+use libc::*;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex};
+
+/// Signal used to cancel a running VirtualCpu, unless configured otherwise.
+///
+/// `SIGUSR2` is left to the application by the runtime and by most libraries.
+pub const DEFAULT_CANCEL_SIGNAL: c_int = SIGUSR2;
+
+/// Number of signals on macOS.
+const NSIG: c_int = 32;
+
+/// Cancel signal and whether its handler has been installed.
+static CANCEL_SIGNAL: Mutex<(c_int, bool)> = Mutex::new((DEFAULT_CANCEL_SIGNAL, false));
+
+thread_local! {
+	/// VirtualCpu of the current thread that is protected by a SignalGuard.
+	static GUARDED_VCPU: Cell<Option<hv_vcpu_t>> = const { Cell::new(None) };
+}
+
+/// Sets the signal used to cancel a running VirtualCpu.
+///
+/// The signal can only be changed before the first SignalGuard is created. Returns
+/// `Error::BadArg` for signals that can't be caught or once the handler is installed.
+pub fn set_cancel_signal(signal: c_int) -> Result<(), Error> {
+	if signal <= 0 || signal >= NSIG || signal == SIGKILL || signal == SIGSTOP {
+		return Err(Error::BadArg);
+	}
+
+	let mut cancel = CANCEL_SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+	if cancel.1 && cancel.0 != signal {
+		return Err(Error::BadArg.context("the cancel signal handler is already installed"));
+	}
+	cancel.0 = signal;
+
+	Ok(())
+}
+
+/// Returns the signal used to cancel a running VirtualCpu.
+pub fn cancel_signal() -> c_int {
+	CANCEL_SIGNAL.lock().unwrap_or_else(|e| e.into_inner()).0
+}
+
+/// Keeps the cancel signal blocked on the thread of a VirtualCpu, except while it runs.
+///
+/// A signal that arrives outside of `hv_vcpu_run` stays pending until the next call
+/// to `VirtualCpu::run` unblocks it. Its handler then requests the exit before the
+/// VirtualCpu enters the guest, so that the run returns `Cancelled` right away
+/// instead of losing the cancellation. The previous signal mask is restored when
+/// the guard is dropped, which also invalidates its CancelHandles. The guard
+/// borrows the VirtualCpu, so it can't outlive it.
+pub struct SignalGuard<'a> {
+	/// Thread of the VirtualCpu.
+	thread: pthread_t,
+	/// Cancel signal.
+	signal: c_int,
+	/// Signal mask of the thread before the guard was created.
+	old_mask: sigset_t,
+	/// Whether the guard is alive, shared with the CancelHandles.
+	alive: Arc<Mutex<bool>>,
+	/// The guard belongs to the VirtualCpu and manipulates the signal mask of the
+	/// current thread.
+	_vcpu: PhantomData<&'a VirtualCpu<'a>>,
+}
+
+impl<'a> SignalGuard<'a> {
+	/// Installs the cancel signal handler and blocks the signal on the current thread.
+	///
+	/// `vcpu` must belong to the current thread. Returns `Error::BadArg` if the
+	/// thread is already protected by another guard.
+	pub fn new(vcpu: &'a VirtualCpu<'a>) -> Result<SignalGuard<'a>, Error> {
+		if GUARDED_VCPU.with(Cell::get).is_some() {
+			return Err(Error::BadArg.context("the thread already has a signal guard"));
+		}
+
+		let signal = install_handler()?;
+		let old_mask = set_blocked(signal, SIG_BLOCK)?;
+		GUARDED_VCPU.with(|guarded| guarded.set(Some(vcpu.get_id())));
+
+		Ok(SignalGuard {
+			thread: unsafe { pthread_self() },
+			signal,
+			old_mask,
+			alive: Arc::new(Mutex::new(true)),
+			_vcpu: PhantomData,
+		})
+	}
+
+	/// Returns a handle that cancels the VirtualCpu from any thread.
+	pub fn cancel_handle(&self) -> CancelHandle {
+		CancelHandle {
+			thread: self.thread,
+			signal: self.signal,
+			alive: self.alive.clone(),
+		}
+	}
+}
+
+impl Drop for SignalGuard<'_> {
+	fn drop(&mut self) {
+		*self.alive.lock().unwrap_or_else(|e| e.into_inner()) = false;
+		GUARDED_VCPU.with(|guarded| guarded.set(None));
+		unsafe {
+			pthread_sigmask(SIG_SETMASK, &self.old_mask, std::ptr::null_mut());
+		}
+	}
+}
+
+/// Cancels the VirtualCpu of a SignalGuard by sending it the cancel signal.
+#[derive(Clone, Debug)]
+pub struct CancelHandle {
+	/// Thread of the VirtualCpu.
+	thread: pthread_t,
+	/// Cancel signal.
+	signal: c_int,
+	/// Whether the SignalGuard is alive.
+	alive: Arc<Mutex<bool>>,
+}
+
+// pthread_kill may be called from any thread.
+unsafe impl Send for CancelHandle {}
+unsafe impl Sync for CancelHandle {}
+
+impl CancelHandle {
+	/// Makes the current or next run of the VirtualCpu return `Cancelled`.
+	///
+	/// Returns `Error::BadArg` once the SignalGuard has been dropped, since the
+	/// thread may have exited and its ID may have been reused by then.
+	pub fn cancel(&self) -> Result<(), Error> {
+		// The guard, and thus the thread, stays alive while the flag is locked.
+		let alive = self.alive.lock().unwrap_or_else(|e| e.into_inner());
+		if !*alive {
+			return Err(Error::BadArg.context("the SignalGuard has been dropped"));
+		}
+
+		match unsafe { pthread_kill(self.thread, self.signal) } {
+			0 => Ok(()),
+			_ => Err(Error::BadArg),
+		}
+	}
+}
+
+/// Runs `f` with the cancel signal unblocked, if the thread has a SignalGuard.
+pub(crate) fn unblocked<F: FnOnce() -> T, T>(f: F) -> Result<T, Error> {
+	if GUARDED_VCPU.with(Cell::get).is_none() {
+		return Ok(f());
+	}
+
+	let signal = cancel_signal();
+	set_blocked(signal, SIG_UNBLOCK)?;
+	let result = f();
+	set_blocked(signal, SIG_BLOCK)?;
+
+	Ok(result)
+}
+
+// Installs the handler of the cancel signal once and returns the signal
+fn install_handler() -> Result<c_int, Error> {
+	let mut cancel = CANCEL_SIGNAL.lock().unwrap_or_else(|e| e.into_inner());
+	if cancel.1 {
+		return Ok(cancel.0);
+	}
+
+	unsafe {
+		let mut action: sigaction = std::mem::zeroed();
+		action.sa_sigaction = handle_cancel as extern "C" fn(c_int) as sighandler_t;
+		sigemptyset(&mut action.sa_mask);
+		if sigaction(cancel.0, &action, std::ptr::null_mut()) != 0 {
+			return Err(Error::Error.context("failed to install the cancel signal handler"));
+		}
+	}
+	cancel.1 = true;
+
+	Ok(cancel.0)
+}
+
+// Blocks or unblocks `signal` on the current thread and returns the previous mask
+fn set_blocked(signal: c_int, how: c_int) -> Result<sigset_t, Error> {
+	let mut old_mask = MaybeUninit::<sigset_t>::uninit();
+
+	unsafe {
+		let mut mask = MaybeUninit::<sigset_t>::uninit();
+		sigemptyset(mask.as_mut_ptr());
+		sigaddset(mask.as_mut_ptr(), signal);
+		if pthread_sigmask(how, mask.as_ptr(), old_mask.as_mut_ptr()) != 0 {
+			return Err(Error::Error);
+		}
+
+		Ok(old_mask.assume_init())
+	}
+}
+
+// Requests the exit of the VirtualCpu of the interrupted thread
+extern "C" fn handle_cancel(_signal: c_int) {
+	if let Ok(Some(vcpu)) = GUARDED_VCPU.try_with(Cell::get) {
+		unsafe {
+			hv_vcpus_exit(&vcpu, 1);
+		}
+	}
+}
@@ -0,0 +1,194 @@
+//! Copy of the architectural state of a VirtualCpu.
+
+use crate::aarch64::{GpRegs, InterruptType, Register, SystemRegister, VirtualCpu};
+use crate::Error;
+
+/// Architectural state of a VirtualCpu that can be restored onto another VirtualCpu.
+///
+/// The state covers the general purpose registers, FPCR and FPSR, the system
+/// registers, the virtual timer and the pending interrupts. The SIMD & FP registers
+/// Q0-Q31 aren't included, since the bindings can't set them.
+#[derive(Clone, Debug)]
+pub struct VcpuState {
+	/// General purpose registers.
+	regs: GpRegs,
+	/// FPCR and FPSR.
+	fp_control: [u64; 2],
+	/// Values of the system registers that could be read.
+	system: Vec<(SystemRegister, u64)>,
+	/// Mask and offset of the virtual timer.
+	vtimer: (bool, u64),
+	/// Pending IRQ and FIQ.
+	pending: [bool; 2],
+}
+
+const SYSTEM_REGISTERS: [SystemRegister; 112] = [
+	SystemRegister::DBGBVR0_EL1,
+	SystemRegister::DBGBCR0_EL1,
+	SystemRegister::DBGWVR0_EL1,
+	SystemRegister::DBGWCR0_EL1,
+	SystemRegister::DBGBVR1_EL1,
+	SystemRegister::DBGBCR1_EL1,
+	SystemRegister::DBGWVR1_EL1,
+	SystemRegister::DBGWCR1_EL1,
+	SystemRegister::MDCCINT_EL1,
+	SystemRegister::MDSCR_EL1,
+	SystemRegister::DBGBVR2_EL1,
+	SystemRegister::DBGBCR2_EL1,
+	SystemRegister::DBGWVR2_EL1,
+	SystemRegister::DBGWCR2_EL1,
+	SystemRegister::DBGBVR3_EL1,
+	SystemRegister::DBGBCR3_EL1,
+	SystemRegister::DBGWVR3_EL1,
+	SystemRegister::DBGWCR3_EL1,
+	SystemRegister::DBGBVR4_EL1,
+	SystemRegister::DBGBCR4_EL1,
+	SystemRegister::DBGWVR4_EL1,
+	SystemRegister::DBGWCR4_EL1,
+	SystemRegister::DBGBVR5_EL1,
+	SystemRegister::DBGBCR5_EL1,
+	SystemRegister::DBGWVR5_EL1,
+	SystemRegister::DBGWCR5_EL1,
+	SystemRegister::DBGBVR6_EL1,
+	SystemRegister::DBGBCR6_EL1,
+	SystemRegister::DBGWVR6_EL1,
+	SystemRegister::DBGWCR6_EL1,
+	SystemRegister::DBGBVR7_EL1,
+	SystemRegister::DBGBCR7_EL1,
+	SystemRegister::DBGWVR7_EL1,
+	SystemRegister::DBGWCR7_EL1,
+	SystemRegister::DBGBVR8_EL1,
+	SystemRegister::DBGBCR8_EL1,
+	SystemRegister::DBGWVR8_EL1,
+	SystemRegister::DBGWCR8_EL1,
+	SystemRegister::DBGBVR9_EL1,
+	SystemRegister::DBGBCR9_EL1,
+	SystemRegister::DBGWVR9_EL1,
+	SystemRegister::DBGWCR9_EL1,
+	SystemRegister::DBGBVR10_EL1,
+	SystemRegister::DBGBCR10_EL1,
+	SystemRegister::DBGWVR10_EL1,
+	SystemRegister::DBGWCR10_EL1,
+	SystemRegister::DBGBVR11_EL1,
+	SystemRegister::DBGBCR11_EL1,
+	SystemRegister::DBGWVR11_EL1,
+	SystemRegister::DBGWCR11_EL1,
+	SystemRegister::DBGBVR12_EL1,
+	SystemRegister::DBGBCR12_EL1,
+	SystemRegister::DBGWVR12_EL1,
+	SystemRegister::DBGWCR12_EL1,
+	SystemRegister::DBGBVR13_EL1,
+	SystemRegister::DBGBCR13_EL1,
+	SystemRegister::DBGWVR13_EL1,
+	SystemRegister::DBGWCR13_EL1,
+	SystemRegister::DBGBVR14_EL1,
+	SystemRegister::DBGBCR14_EL1,
+	SystemRegister::DBGWVR14_EL1,
+	SystemRegister::DBGWCR14_EL1,
+	SystemRegister::DBGBVR15_EL1,
+	SystemRegister::DBGBCR15_EL1,
+	SystemRegister::DBGWVR15_EL1,
+	SystemRegister::DBGWCR15_EL1,
+	SystemRegister::MIDR_EL1,
+	SystemRegister::MPIDR_EL1,
+	SystemRegister::ID_AA64PFR0_EL1,
+	SystemRegister::ID_AA64PFR1_EL1,
+	SystemRegister::ID_AA64DFR0_EL1,
+	SystemRegister::ID_AA64DFR1_EL1,
+	SystemRegister::ID_AA64ISAR0_EL1,
+	SystemRegister::ID_AA64ISAR1_EL1,
+	SystemRegister::ID_AA64MMFR0_EL1,
+	SystemRegister::ID_AA64MMFR1_EL1,
+	SystemRegister::ID_AA64MMFR2_EL1,
+	SystemRegister::SCTLR_EL1,
+	SystemRegister::CPACR_EL1,
+	SystemRegister::TTBR0_EL1,
+	SystemRegister::TTBR1_EL1,
+	SystemRegister::TCR_EL1,
+	SystemRegister::APIAKEYLO_EL1,
+	SystemRegister::APIAKEYHI_EL1,
+	SystemRegister::APIBKEYLO_EL1,
+	SystemRegister::APIBKEYHI_EL1,
+	SystemRegister::APDAKEYLO_EL1,
+	SystemRegister::APDAKEYHI_EL1,
+	SystemRegister::APDBKEYLO_EL1,
+	SystemRegister::APDBKEYHI_EL1,
+	SystemRegister::APGAKEYLO_EL1,
+	SystemRegister::APGAKEYHI_EL1,
+	SystemRegister::SPSR_EL1,
+	SystemRegister::ELR_EL1,
+	SystemRegister::SP_EL0,
+	SystemRegister::AFSR0_EL1,
+	SystemRegister::AFSR1_EL1,
+	SystemRegister::ESR_EL1,
+	SystemRegister::FAR_EL1,
+	SystemRegister::PAR_EL1,
+	SystemRegister::MAIR_EL1,
+	SystemRegister::AMAIR_EL1,
+	SystemRegister::VBAR_EL1,
+	SystemRegister::CONTEXTIDR_EL1,
+	SystemRegister::TPIDR_EL1,
+	SystemRegister::CNTKCTL_EL1,
+	SystemRegister::CSSELR_EL1,
+	SystemRegister::TPIDR_EL0,
+	SystemRegister::TPIDRRO_EL0,
+	SystemRegister::CNTV_CTL_EL0,
+	SystemRegister::CNTV_CVAL_EL0,
+	SystemRegister::SP_EL1,
+];
+
+impl VirtualCpu<'_> {
+	/// Returns the architectural state of the VirtualCpu.
+	///
+	/// System registers that can't be read are skipped.
+	pub fn save_state(&self) -> Result<VcpuState, Error> {
+		let system = SYSTEM_REGISTERS
+			.iter()
+			.filter_map(|reg| Some((*reg, self.read_system_register(*reg).ok()?)))
+			.collect();
+
+		Ok(VcpuState {
+			regs: self.read_gp_regs()?,
+			fp_control: [
+				self.read_register(Register::FPCR)?,
+				self.read_register(Register::FPSR)?,
+			],
+			system,
+			vtimer: (self.vtimer_mask()?, self.vtimer_offset()?),
+			pending: [
+				self.pending_interrupt(InterruptType::IRQ)?,
+				self.pending_interrupt(InterruptType::FIQ)?,
+			],
+		})
+	}
+
+	/// Restores a state returned by `save_state`, possibly of another VirtualCpu.
+	///
+	/// The system registers are written before the general purpose registers, so that
+	/// the stack pointer selected by CPSR ends up with the saved value. System
+	/// registers that already hold the saved value aren't written, which skips the
+	/// identification registers.
+	pub fn restore_state(&self, state: &VcpuState) -> Result<(), Error> {
+		for (reg, value) in state.system.iter() {
+			if self.read_system_register(*reg).ok() != Some(*value) {
+				self.write_system_register(*reg, *value)?;
+			}
+		}
+
+		self.write_gp_regs(&state.regs)?;
+		self.write_register(Register::FPCR, state.fp_control[0])?;
+		self.write_register(Register::FPSR, state.fp_control[1])?;
+		self.set_vtimer_mask(state.vtimer.0)?;
+		self.set_vtimer_offset(state.vtimer.1)?;
+		self.set_pending_interrupt(InterruptType::IRQ, state.pending[0])?;
+		self.set_pending_interrupt(InterruptType::FIQ, state.pending[1])
+	}
+
+	/// Copies the architectural state of `src` onto the VirtualCpu.
+	///
+	/// Both VirtualCpus must belong to the current thread. Use `save_state` and
+	/// `restore_state` to copy the state between threads.
+	pub fn copy_state_from(&self, src: &VirtualCpu) -> Result<(), Error> {
+		self.restore_state(&src.save_state()?)
+	}
+}
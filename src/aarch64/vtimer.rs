@@ -0,0 +1,68 @@
+//! Virtual timer handshake
+
+use crate::aarch64::{InterruptType, SystemRegister, VirtualCpu};
+use crate::Error;
+
+/// CNTV_CTL_EL0.ENABLE
+const CNTV_CTL_ENABLE: u64 = 1 << 0;
+/// CNTV_CTL_EL0.IMASK
+const CNTV_CTL_IMASK: u64 = 1 << 1;
+/// CNTV_CTL_EL0.ISTATUS
+const CNTV_CTL_ISTATUS: u64 = 1 << 2;
+
+/// Tracks the virtual timer interrupt of a VirtualCpu
+///
+/// When the virtual timer fires, `run` returns `VirtualCpuExitReason::VTimerActivated`
+/// and the framework masks the timer, so that it doesn't fire again until the host
+/// unmasks it. The expected handshake is:
+///
+/// 1. On a `VTimerActivated` exit call `on_activated`, which raises the IRQ line
+///    of the VirtualCpu.
+/// 2. Before every subsequent `run` call `service`. As long as the guest hasn't
+///    acknowledged the timer (CNTV_CTL_EL0 still reports a pending, unmasked and
+///    enabled timer), the IRQ is raised again. Once the guest has acknowledged it,
+///    the timer is unmasked so that it can fire again.
+#[derive(Debug, Default)]
+pub struct VTimer {
+	/// The timer fired and hasn't been acknowledged by the guest yet
+	pending: bool,
+}
+
+impl VTimer {
+	/// Creates the state for a VirtualCpu whose virtual timer is unmasked
+	pub fn new() -> VTimer {
+		VTimer { pending: false }
+	}
+
+	/// Returns `true` if the timer fired and the guest hasn't acknowledged it yet
+	pub fn is_pending(&self) -> bool {
+		self.pending
+	}
+
+	/// Handles a `VTimerActivated` exit
+	pub fn on_activated(&mut self, vcpu: &VirtualCpu) -> Result<(), Error> {
+		vcpu.set_vtimer_mask(true)?;
+		vcpu.set_pending_interrupt(InterruptType::IRQ, true)?;
+		self.pending = true;
+
+		Ok(())
+	}
+
+	/// Updates the timer state before the VirtualCpu is run again
+	pub fn service(&mut self, vcpu: &VirtualCpu) -> Result<(), Error> {
+		if !self.pending {
+			return Ok(());
+		}
+
+		let ctl = vcpu.read_system_register(SystemRegister::CNTV_CTL_EL0)?;
+		if ctl & (CNTV_CTL_ENABLE | CNTV_CTL_IMASK | CNTV_CTL_ISTATUS)
+			== CNTV_CTL_ENABLE | CNTV_CTL_ISTATUS
+		{
+			// the pending interrupt is consumed by every run
+			vcpu.set_pending_interrupt(InterruptType::IRQ, true)
+		} else {
+			self.pending = false;
+			vcpu.set_vtimer_mask(false)
+		}
+	}
+}
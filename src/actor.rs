@@ -0,0 +1,144 @@
+//! VirtualCpu running on a dedicated thread, controlled through a channel
+
+use crate::vm::VcpuId;
+use crate::{interrupt_vcpus, Error, Register, VirtualCpu};
+use std::sync::mpsc::{self, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Command executed by the thread of a VcpuActor
+type Command = Box<dyn FnOnce(&VirtualCpu) + Send>;
+
+/// Handle to a VirtualCpu that lives on its own thread
+///
+/// VirtualCpus are bound to the thread that creates them. A VcpuActor spawns a
+/// thread, creates the VirtualCpu there and forwards every call over a channel,
+/// so that the handle can be shared between threads. The calls block until the
+/// thread has executed them. The VirtualCpu is destroyed by `stop` or when the
+/// handle is dropped.
+pub struct VcpuActor {
+	/// ID of the VirtualCpu
+	id: VcpuId,
+	/// Channel to the thread of the VirtualCpu
+	commands: Option<Sender<Command>>,
+	/// Thread of the VirtualCpu
+	thread: Option<JoinHandle<Result<(), Error>>>,
+}
+
+impl VcpuActor {
+	/// Spawns a thread and creates a VirtualCpu on it
+	///
+	/// # Safety
+	///
+	/// The VirtualCpu isn't bound to a `Vm`, so the requirements of
+	/// `VirtualCpu::new_unbound` apply: the VM instance must exist until the actor is
+	/// stopped or dropped.
+	pub unsafe fn spawn() -> Result<VcpuActor, Error> {
+		let (commands, receiver) = mpsc::channel::<Command>();
+		let (created, creation) = mpsc::channel();
+
+		let thread = thread::spawn(move || {
+			let vcpu = match unsafe { VirtualCpu::new_unbound() } {
+				Ok(vcpu) => vcpu,
+				Err(err) => {
+					let _ = created.send(Err(err));
+					return Ok(());
+				}
+			};
+			let _ = created.send(Ok(vcpu.get_id()));
+
+			for command in receiver {
+				command(&vcpu);
+			}
+
+			vcpu.destroy()
+		});
+
+		let id = creation.recv().map_err(|_| terminated())??;
+
+		Ok(VcpuActor {
+			id,
+			commands: Some(commands),
+			thread: Some(thread),
+		})
+	}
+
+	/// Returns the ID of the VirtualCpu
+	pub fn get_id(&self) -> VcpuId {
+		self.id
+	}
+
+	/// Executes `f` with the VirtualCpu on its thread and returns the result
+	pub fn call<T, F>(&self, f: F) -> Result<T, Error>
+	where
+		T: Send + 'static,
+		F: FnOnce(&VirtualCpu) -> T + Send + 'static,
+	{
+		let (result, receiver) = mpsc::channel();
+		let command: Command = Box::new(move |vcpu| {
+			let _ = result.send(f(vcpu));
+		});
+
+		self.commands
+			.as_ref()
+			.ok_or_else(terminated)?
+			.send(command)
+			.map_err(|_| terminated())?;
+
+		receiver.recv().map_err(|_| terminated())
+	}
+
+	/// Executes the VirtualCpu until the next exit
+	pub fn run(&self) -> Result<(), Error> {
+		self.call(|vcpu| vcpu.run())?
+	}
+
+	/// Returns the current value of a register of the VirtualCpu
+	pub fn read_register(&self, reg: Register) -> Result<u64, Error> {
+		#[cfg(target_arch = "x86_64")]
+		return self.call(move |vcpu| vcpu.read_register(&reg))?;
+		#[cfg(target_arch = "aarch64")]
+		return self.call(move |vcpu| vcpu.read_register(reg))?;
+	}
+
+	/// Sets the value of a register of the VirtualCpu
+	pub fn write_register(&self, reg: Register, value: u64) -> Result<(), Error> {
+		#[cfg(target_arch = "x86_64")]
+		return self.call(move |vcpu| vcpu.write_register(&reg, value))?;
+		#[cfg(target_arch = "aarch64")]
+		return self.call(move |vcpu| vcpu.write_register(reg, value))?;
+	}
+
+	/// Forces an immediate exit of the VirtualCpu
+	///
+	/// Unlike the other calls, this doesn't wait for the thread, so it also
+	/// interrupts a running `run`.
+	pub fn interrupt(&self) -> Result<(), Error> {
+		interrupt_vcpus(&[self.id])
+	}
+
+	/// Destroys the VirtualCpu and waits for its thread to finish
+	pub fn stop(mut self) -> Result<(), Error> {
+		self.shutdown()
+	}
+
+	// Closes the channel and joins the thread, which destroys the VirtualCpu
+	fn shutdown(&mut self) -> Result<(), Error> {
+		self.commands.take();
+
+		match self.thread.take() {
+			Some(thread) => thread.join().map_err(|_| terminated())?,
+			None => Ok(()),
+		}
+	}
+}
+
+impl Drop for VcpuActor {
+	fn drop(&mut self) {
+		let _ = self.shutdown();
+	}
+}
+
+// Returns the error for a VirtualCpu whose thread isn't running anymore
+fn terminated() -> Error {
+	Error::Error.context("the thread of the VirtualCpu has terminated")
+}
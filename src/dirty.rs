@@ -0,0 +1,142 @@
+//! Tracking of the guest pages written by the guest
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::VirtualCpuExitReason;
+use crate::vm::{lock, protect_no_flush};
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::ExitDetails;
+use crate::{page_size, protect_mem, Error, MemPerm, VirtualCpu};
+use std::mem;
+use std::sync::Mutex;
+
+/// Log of the pages written by the guest in a range of the guest physical address space
+///
+/// The range is write-protected while it is tracked. The first write of the guest
+/// to a page faults, the VMM passes the fault to `handle_exit` or `record_write`,
+/// which marks the page as dirty and makes it writable again. Dropping the log
+/// restores the permissions of the mapping.
+pub struct DirtyLog {
+	/// Guest physical address of the tracked range
+	gpa: u64,
+	/// Size of the tracked range in bytes
+	size: usize,
+	/// Size of the tracked pages in bytes
+	page_size: usize,
+	/// Permissions of the mapping that contains the range
+	perm: MemPerm,
+	/// One bit per page of the range, set for the pages written since the last reset
+	bitmap: Mutex<Vec<u64>>,
+}
+
+impl DirtyLog {
+	// Write-protects the range and starts tracking it
+	pub(crate) fn new(gpa: u64, size: usize, perm: MemPerm) -> Result<DirtyLog, Error> {
+		let page_size = page_size();
+		if size == 0 || !gpa.is_multiple_of(page_size as u64) || !size.is_multiple_of(page_size) {
+			return Err(Error::BadArg);
+		}
+
+		protect_mem(gpa, size, read_only(perm))?;
+
+		let pages = size / page_size;
+		Ok(DirtyLog {
+			gpa,
+			size,
+			page_size,
+			perm,
+			bitmap: Mutex::new(vec![0; pages.div_ceil(64)]),
+		})
+	}
+
+	/// Returns the guest physical address of the tracked range
+	pub fn gpa(&self) -> u64 {
+		self.gpa
+	}
+
+	/// Returns the size of the tracked range in bytes
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// Records a write of the guest to `gpa` and makes the page writable again
+	///
+	/// Restoring the write access leaves the TLBs alone, a stale read-only translation
+	/// only leads to another fault that is recorded again. Returns `false` if `gpa` lies outside the tracked range, i.e. the fault has
+	/// to be handled by the VMM.
+	pub fn record_write(&self, gpa: u64) -> Result<bool, Error> {
+		if gpa < self.gpa || gpa - self.gpa >= self.size as u64 {
+			return Ok(false);
+		}
+
+		let page = ((gpa - self.gpa) / self.page_size as u64) as usize;
+		let mut bitmap = lock(&self.bitmap);
+		protect_no_flush(self.page_gpa(page), self.page_size, self.perm)?;
+		bitmap[page / 64] |= 1 << (page % 64);
+
+		Ok(true)
+	}
+
+	/// Records the write fault that caused the last VM exit of the VirtualCpu
+	///
+	/// Returns `false` if the exit wasn't an EPT violation caused by a write to the
+	/// tracked range.
+	#[cfg(target_arch = "x86_64")]
+	pub fn handle_exit(&self, vcpu: &VirtualCpu) -> Result<bool, Error> {
+		match vcpu.exit_details()? {
+			ExitDetails::EptViolation(ept) if ept.write => self.record_write(ept.gpa),
+			_ => Ok(false),
+		}
+	}
+
+	/// Records the write fault that caused the last exit of the VirtualCpu.
+	///
+	/// Returns `false` if the exit wasn't a data abort caused by a write to the
+	/// tracked range.
+	#[cfg(target_arch = "aarch64")]
+	pub fn handle_exit(&self, vcpu: &VirtualCpu) -> Result<bool, Error> {
+		match vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception })
+				if exception.syndrome().is_write() == Some(true) =>
+			{
+				self.record_write(exception.fault_ipa())
+			}
+			_ => Ok(false),
+		}
+	}
+
+	/// Returns the dirty bitmap and write-protects the range again
+	///
+	/// Bit `i % 64` of word `i / 64` is set if page `i` of the range has been written
+	/// since the last reset. The whole range is protected at once, so the TLBs of
+	/// the VirtualCpus are invalidated a single time.
+	pub fn take_and_reset(&self) -> Result<Vec<u64>, Error> {
+		let mut bitmap = lock(&self.bitmap);
+
+		if bitmap.iter().any(|&word| word != 0) {
+			protect_mem(self.gpa, self.size, read_only(self.perm))?;
+		}
+
+		let empty = vec![0; bitmap.len()];
+		Ok(mem::replace(&mut *bitmap, empty))
+	}
+
+	// Returns the guest physical address of a page of the range
+	fn page_gpa(&self, page: usize) -> u64 {
+		self.gpa + (page * self.page_size) as u64
+	}
+}
+
+impl Drop for DirtyLog {
+	fn drop(&mut self) {
+		let _ = protect_mem(self.gpa, self.size, self.perm);
+	}
+}
+
+// Returns the permissions without write access
+fn read_only(perm: MemPerm) -> MemPerm {
+	match perm {
+		MemPerm::Write => MemPerm::Read,
+		MemPerm::ExecAndWrite => MemPerm::ExecAndRead,
+		perm => perm,
+	}
+}
@@ -0,0 +1,421 @@
+//! GDB remote serial protocol server on top of the gdbstub crate
+//!
+//! `GdbTarget` exposes a VirtualCpu and the memory slots of its `Vm` to GDB or
+//! LLDB. The session runs on the thread that owns the VirtualCpu:
+//!
+//! ```no_run
+//! # fn main() -> Result<(), xhypervisor::Error> {
+//! use std::net::TcpListener;
+//! use xhypervisor::{GdbTarget, Vm};
+//!
+//! let vm = Vm::new()?;
+//! let vcpu = vm.create_vcpu()?;
+//! // map the guest memory with `Vm::add_slot` and set up the registers ...
+//!
+//! let listener = TcpListener::bind("127.0.0.1:1234")?;
+//! let (stream, _) = listener.accept()?;
+//! GdbTarget::new(&vcpu, &vm).serve(stream)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! and `target remote 127.0.0.1:1234` in GDB. Addresses of memory accesses are
+//! guest physical addresses, so they only match the addresses of the guest code
+//! under an identity mapping. Ctrl-C is handled between two runs of the
+//! VirtualCpu, a running guest can be stopped with `Vm::interrupt_vcpus` from
+//! another thread.
+
+use crate::{Error, VirtualCpu, Vm};
+use gdbstub::common::Signal;
+use gdbstub::conn::ConnectionExt;
+use gdbstub::stub::run_blocking::{BlockingEventLoop, Event, WaitForStopReasonError};
+use gdbstub::stub::{DisconnectReason, GdbStub, SingleThreadStopReason};
+use gdbstub::target::ext::base::singlethread::{
+	SingleThreadBase, SingleThreadResume, SingleThreadResumeOps,
+};
+use gdbstub::target::ext::base::BaseOps;
+use gdbstub::target::{Target, TargetError, TargetResult};
+use std::fmt::Display;
+use std::io;
+use std::marker::PhantomData;
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::{GpRegs, Register, VirtualCpuExitReason};
+#[cfg(target_arch = "aarch64")]
+use gdbstub_arch::aarch64::{reg::AArch64CoreRegs as CoreRegs, AArch64 as GdbArch};
+
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::consts::vmcs::{VMCS_CTRL_CPU_BASED, VMCS_CTRL_EXC_BITMAP};
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::consts::vmx_cap::CPU_BASED_MTF;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::consts::vmx_exit::{VMX_REASON_IRQ, VMX_REASON_MTF};
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::ffi::{HV_MEMORY_EXEC, HV_MEMORY_READ, HV_MEMORY_WRITE};
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::{
+	BreakKind, BreakLen, EptViolationExit, ExitDetails, GpRegs, Register, HW_BREAKPOINTS,
+};
+#[cfg(target_arch = "x86_64")]
+use crate::{match_MemPerm, MemPerm};
+#[cfg(target_arch = "x86_64")]
+use gdbstub::target::ext::base::singlethread::{SingleThreadSingleStep, SingleThreadSingleStepOps};
+#[cfg(target_arch = "x86_64")]
+use gdbstub::target::ext::breakpoints::{
+	Breakpoints, BreakpointsOps, HwBreakpoint, HwBreakpointOps,
+};
+#[cfg(target_arch = "x86_64")]
+use gdbstub_arch::x86::{reg::X86_64CoreRegs as CoreRegs, X86_64_SSE as GdbArch};
+
+/// Resume flag of RFLAGS, suppresses instruction breakpoints for one instruction
+#[cfg(target_arch = "x86_64")]
+const RFLAGS_RF: u64 = 1 << 16;
+
+/// Vector of the debug exception (#DB)
+#[cfg(target_arch = "x86_64")]
+const DB_VECTOR: u8 = 1;
+
+/// Debugging target that gives GDB access to a VirtualCpu and the memory of a `Vm`
+///
+/// Supported are reading and writing the registers and the memory in the slots of
+/// the VM, continuing and, on x86_64, single-stepping with the monitor trap flag
+/// and hardware breakpoints in the debug registers. The transferred registers are
+/// the general purpose registers with the program counter and the flags, the
+/// segment selectors on x86_64 are read-only and the vector registers read as zero.
+pub struct GdbTarget<'a> {
+	vcpu: &'a VirtualCpu<'a>,
+	vm: &'a Vm,
+	#[cfg(target_arch = "x86_64")]
+	breakpoints: [Option<u64>; HW_BREAKPOINTS as usize],
+}
+
+impl<'a> GdbTarget<'a> {
+	/// Creates a target for the VirtualCpu and the VM it belongs to
+	pub fn new(vcpu: &'a VirtualCpu<'a>, vm: &'a Vm) -> GdbTarget<'a> {
+		GdbTarget {
+			vcpu,
+			vm,
+			#[cfg(target_arch = "x86_64")]
+			breakpoints: [None; HW_BREAKPOINTS as usize],
+		}
+	}
+
+	/// Runs a GDB session over the connection until the debugger detaches or kills
+	/// the target
+	///
+	/// Errors of the VirtualCpu are returned as they are, errors of the connection
+	/// or the protocol as `Error::Io`.
+	pub fn serve<C>(&mut self, conn: C) -> Result<DisconnectReason, Error>
+	where
+		C: ConnectionExt,
+		C::Error: Display,
+	{
+		GdbStub::new(conn)
+			.run_blocking::<GdbEventLoop<'a, C>>(self)
+			.map_err(|err| {
+				if err.is_target_error() {
+					err.into_target_error().unwrap()
+				} else {
+					Error::Io(io::Error::other(format!("GDB session failed: {}", err)))
+				}
+			})
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+impl GdbTarget<'_> {
+	// Selects whether the next run stops after one instruction and lets it pass an
+	// instruction breakpoint on the current instruction
+	fn prepare_run(&self, single_step: bool) -> Result<(), Error> {
+		let controls = self.vcpu.read_vmcs(VMCS_CTRL_CPU_BASED)?;
+		let controls = if single_step {
+			controls | CPU_BASED_MTF
+		} else {
+			controls & !CPU_BASED_MTF
+		};
+		self.vcpu.write_vmcs(VMCS_CTRL_CPU_BASED, controls)?;
+
+		let rflags = self.vcpu.read_register(&Register::RFLAGS)?;
+		self.vcpu
+			.write_register(&Register::RFLAGS, rflags | RFLAGS_RF)
+	}
+
+	// Runs the VirtualCpu until an exit that concerns the debugger
+	fn run_until_stop(&mut self) -> Result<SingleThreadStopReason<u64>, Error> {
+		loop {
+			self.vcpu.run()?;
+
+			match self.vcpu.exit_details()? {
+				ExitDetails::Other { reason, .. } if reason.0 == VMX_REASON_IRQ => {}
+				ExitDetails::EptViolation(ept)
+					if self
+						.vm
+						.permissions_at(ept.gpa)
+						.is_some_and(|perm| permits(perm, &ept)) => {}
+				ExitDetails::EptViolation(_) => {
+					return Ok(SingleThreadStopReason::Signal(Signal::SIGSEGV))
+				}
+				ExitDetails::Other { reason, .. } if reason.0 == VMX_REASON_MTF => {
+					return Ok(SingleThreadStopReason::DoneStep)
+				}
+				ExitDetails::Exception(exception) if exception.vector == DB_VECTOR => {
+					return Ok(SingleThreadStopReason::HwBreak(()))
+				}
+				_ => return Ok(SingleThreadStopReason::Signal(Signal::SIGTRAP)),
+			}
+		}
+	}
+}
+
+// Returns whether the permissions allow the access of an EPT violation, i.e. the
+// fault only came from a stale translation and the access succeeds when retried
+#[cfg(target_arch = "x86_64")]
+fn permits(perm: MemPerm, ept: &EptViolationExit) -> bool {
+	let rights = match_MemPerm(perm);
+
+	(!ept.read || rights & HV_MEMORY_READ != 0)
+		&& (!ept.write || rights & HV_MEMORY_WRITE != 0)
+		&& (!ept.exec || rights & HV_MEMORY_EXEC != 0)
+}
+
+#[cfg(target_arch = "aarch64")]
+impl GdbTarget<'_> {
+	// Runs the VirtualCpu until its next exit
+	fn run_until_stop(&mut self) -> Result<SingleThreadStopReason<u64>, Error> {
+		self.vcpu.run()?;
+
+		match self.vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Cancelled) => {
+				Ok(SingleThreadStopReason::Signal(Signal::SIGINT))
+			}
+			_ => Ok(SingleThreadStopReason::Signal(Signal::SIGTRAP)),
+		}
+	}
+}
+
+impl Target for GdbTarget<'_> {
+	type Arch = GdbArch;
+	type Error = Error;
+
+	fn base_ops(&mut self) -> BaseOps<'_, Self::Arch, Self::Error> {
+		BaseOps::SingleThread(self)
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn support_breakpoints(&mut self) -> Option<BreakpointsOps<'_, Self>> {
+		Some(self)
+	}
+}
+
+impl SingleThreadBase for GdbTarget<'_> {
+	#[cfg(target_arch = "x86_64")]
+	fn read_registers(&mut self, regs: &mut CoreRegs) -> TargetResult<(), Self> {
+		let gp = self.vcpu.read_gp_regs().map_err(TargetError::Fatal)?;
+
+		regs.regs = [
+			gp.rax, gp.rbx, gp.rcx, gp.rdx, gp.rsi, gp.rdi, gp.rbp, gp.rsp, gp.r8, gp.r9, gp.r10,
+			gp.r11, gp.r12, gp.r13, gp.r14, gp.r15,
+		];
+		regs.rip = gp.rip;
+		regs.eflags = gp.rflags as u32;
+
+		let selector = |reg| self.vcpu.read_register(&reg).map(|value| value as u32);
+		regs.segments.cs = selector(Register::CS).map_err(TargetError::Fatal)?;
+		regs.segments.ss = selector(Register::SS).map_err(TargetError::Fatal)?;
+		regs.segments.ds = selector(Register::DS).map_err(TargetError::Fatal)?;
+		regs.segments.es = selector(Register::ES).map_err(TargetError::Fatal)?;
+		regs.segments.fs = selector(Register::FS).map_err(TargetError::Fatal)?;
+		regs.segments.gs = selector(Register::GS).map_err(TargetError::Fatal)?;
+
+		Ok(())
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn write_registers(&mut self, regs: &CoreRegs) -> TargetResult<(), Self> {
+		let [rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp, r8, r9, r10, r11, r12, r13, r14, r15] =
+			regs.regs;
+		let gp = GpRegs {
+			rax,
+			rbx,
+			rcx,
+			rdx,
+			rsi,
+			rdi,
+			rsp,
+			rbp,
+			r8,
+			r9,
+			r10,
+			r11,
+			r12,
+			r13,
+			r14,
+			r15,
+			rip: regs.rip,
+			rflags: u64::from(regs.eflags),
+		};
+
+		self.vcpu.write_gp_regs(&gp).map_err(TargetError::Fatal)
+	}
+
+	#[cfg(target_arch = "aarch64")]
+	fn read_registers(&mut self, regs: &mut CoreRegs) -> TargetResult<(), Self> {
+		let gp = self.vcpu.read_gp_regs().map_err(TargetError::Fatal)?;
+
+		regs.x = gp.x;
+		regs.sp = gp.sp;
+		regs.pc = gp.pc;
+		regs.cpsr = gp.cpsr as u32;
+		regs.fpcr = self
+			.vcpu
+			.read_register(Register::FPCR)
+			.map_err(TargetError::Fatal)? as u32;
+		regs.fpsr = self
+			.vcpu
+			.read_register(Register::FPSR)
+			.map_err(TargetError::Fatal)? as u32;
+
+		Ok(())
+	}
+
+	#[cfg(target_arch = "aarch64")]
+	fn write_registers(&mut self, regs: &CoreRegs) -> TargetResult<(), Self> {
+		let gp = GpRegs {
+			x: regs.x,
+			sp: regs.sp,
+			pc: regs.pc,
+			cpsr: u64::from(regs.cpsr),
+		};
+
+		self.vcpu.write_gp_regs(&gp).map_err(TargetError::Fatal)?;
+		self.vcpu
+			.write_register(Register::FPCR, u64::from(regs.fpcr))
+			.map_err(TargetError::Fatal)?;
+		self.vcpu
+			.write_register(Register::FPSR, u64::from(regs.fpsr))
+			.map_err(TargetError::Fatal)
+	}
+
+	fn read_addrs(&mut self, start_addr: u64, data: &mut [u8]) -> TargetResult<usize, Self> {
+		self.vm
+			.guest_read(start_addr, data)
+			.map(|()| data.len())
+			.map_err(|_| TargetError::NonFatal)
+	}
+
+	fn write_addrs(&mut self, start_addr: u64, data: &[u8]) -> TargetResult<(), Self> {
+		self.vm
+			.guest_write(start_addr, data)
+			.map_err(|_| TargetError::NonFatal)
+	}
+
+	fn support_resume(&mut self) -> Option<SingleThreadResumeOps<'_, Self>> {
+		Some(self)
+	}
+}
+
+impl SingleThreadResume for GdbTarget<'_> {
+	// Signals have no meaning for a VirtualCpu and are ignored
+	fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Error> {
+		#[cfg(target_arch = "x86_64")]
+		self.prepare_run(false)?;
+
+		Ok(())
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+		Some(self)
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+impl SingleThreadSingleStep for GdbTarget<'_> {
+	fn step(&mut self, _signal: Option<Signal>) -> Result<(), Error> {
+		self.prepare_run(true)
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+impl Breakpoints for GdbTarget<'_> {
+	fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<'_, Self>> {
+		Some(self)
+	}
+}
+
+#[cfg(target_arch = "x86_64")]
+impl HwBreakpoint for GdbTarget<'_> {
+	fn add_hw_breakpoint(&mut self, addr: u64, _kind: usize) -> TargetResult<bool, Self> {
+		if self.breakpoints.contains(&Some(addr)) {
+			return Ok(true);
+		}
+		let Some(slot) = self.breakpoints.iter().position(Option::is_none) else {
+			return Ok(false);
+		};
+
+		// let the debug exception of the breakpoint exit to the host
+		let bitmap = self
+			.vcpu
+			.read_vmcs(VMCS_CTRL_EXC_BITMAP)
+			.map_err(TargetError::Fatal)?;
+		self.vcpu
+			.write_vmcs(VMCS_CTRL_EXC_BITMAP, bitmap | (1 << DB_VECTOR))
+			.map_err(TargetError::Fatal)?;
+		self.vcpu
+			.set_hw_breakpoint(slot as u8, addr, BreakKind::Execute, BreakLen::One)
+			.map_err(TargetError::Fatal)?;
+		self.breakpoints[slot] = Some(addr);
+
+		Ok(true)
+	}
+
+	fn remove_hw_breakpoint(&mut self, addr: u64, _kind: usize) -> TargetResult<bool, Self> {
+		let Some(slot) = self.breakpoints.iter().position(|bp| *bp == Some(addr)) else {
+			return Ok(false);
+		};
+
+		self.vcpu
+			.clear_hw_breakpoint(slot as u8)
+			.map_err(TargetError::Fatal)?;
+		self.breakpoints[slot] = None;
+
+		Ok(true)
+	}
+}
+
+/// Blocking event loop of a GDB session with a `GdbTarget`
+///
+/// `GdbTarget::serve` runs the session with this loop, it is public to start a
+/// session from a customized `GdbStub`, e.g. with a larger packet buffer:
+/// `stub.run_blocking::<GdbEventLoop<_>>(&mut target)`.
+pub struct GdbEventLoop<'a, C>(PhantomData<(&'a (), C)>);
+
+impl<'a, C: ConnectionExt> BlockingEventLoop for GdbEventLoop<'a, C> {
+	type Target = GdbTarget<'a>;
+	type Connection = C;
+	type StopReason = SingleThreadStopReason<u64>;
+
+	fn wait_for_stop_reason(
+		target: &mut GdbTarget<'a>,
+		conn: &mut C,
+	) -> Result<Event<Self::StopReason>, WaitForStopReasonError<Error, C::Error>> {
+		// data from the debugger, e.g. a Ctrl-C, takes priority over the next run
+		if conn
+			.peek()
+			.map_err(WaitForStopReasonError::Connection)?
+			.is_some()
+		{
+			let byte = conn.read().map_err(WaitForStopReasonError::Connection)?;
+			return Ok(Event::IncomingData(byte));
+		}
+
+		target
+			.run_until_stop()
+			.map(Event::TargetStopped)
+			.map_err(WaitForStopReasonError::Target)
+	}
+
+	fn on_interrupt(_target: &mut GdbTarget<'a>) -> Result<Option<Self::StopReason>, Error> {
+		Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+	}
+}
@@ -45,16 +45,53 @@ in your Terminal:
 
 extern crate core;
 extern crate libc;
+#[cfg(feature = "serde")]
+extern crate serde;
 extern crate thiserror;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[macro_use]
+mod trace;
 
 #[cfg(target_arch = "aarch64")]
 #[allow(non_camel_case_types)]
 pub mod aarch64;
+mod actor;
+mod dirty;
+#[cfg(feature = "gdbstub")]
+mod gdb;
+/// Type definitions of the aarch64 bindings, available on every host with the `doc` feature
+#[cfg(all(not(target_arch = "aarch64"), any(doc, feature = "doc")))]
+#[allow(non_camel_case_types)]
+pub mod aarch64 {
+	pub mod ffi;
+}
+mod memory;
+pub mod prelude;
+mod vm;
 #[cfg(target_arch = "x86_64")]
 #[allow(non_camel_case_types)]
 pub mod x86_64;
+/// Type definitions of the x86_64 bindings, available on every host with the `doc` feature
+#[cfg(all(not(target_arch = "x86_64"), any(doc, feature = "doc")))]
+#[allow(non_camel_case_types)]
+pub mod x86_64 {
+	pub mod consts;
+	pub mod ffi;
+}
 
-use core::fmt;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::mem;
+use std::ops::BitOr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 use thiserror::Error;
 
 #[cfg(target_arch = "x86_64")]
@@ -63,11 +100,22 @@ use self::x86_64::ffi::*;
 use aarch64::ffi::*;
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::*;
+pub use actor::VcpuActor;
+pub use dirty::DirtyLog;
+#[cfg(feature = "gdbstub")]
+pub use gdb::{GdbEventLoop, GdbTarget};
+pub use memory::{GuestMemory, GuestSlice};
+pub use vm::{Mapping, MemRegion, SlotId, Vm};
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::*;
 
 /// Error returned after every call
+///
+/// Errors compare equal if they are the same variant. `Error::Io` additionally
+/// compares the kind and OS error code of the I/O error, `Error::Context` the
+/// message and the underlying error.
 #[derive(Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
 	#[error("success")]
 	Success,
@@ -81,8 +129,107 @@ pub enum Error {
 	NoRes,
 	#[error("no device")]
 	NoDev,
+	#[error("denied (is the binary signed with the com.apple.security.hypervisor entitlement?)")]
+	Denied,
 	#[error("unsupported")]
 	Unsupp,
+	#[error("I/O error: {0}")]
+	Io(#[from] io::Error),
+	#[error("{message}: {source}")]
+	Context {
+		/// Description of the cause of the error
+		message: String,
+		/// Underlying error
+		source: Box<Error>,
+	},
+}
+
+impl Error {
+	/// Attaches a description of the cause to the error
+	pub fn context<S: Into<String>>(self, message: S) -> Error {
+		Error::Context {
+			message: message.into(),
+			source: Box::new(self),
+		}
+	}
+
+	/// Returns the underlying error without any context
+	pub fn root_cause(&self) -> &Error {
+		match self {
+			Error::Context { source, .. } => source.root_cause(),
+			err => err,
+		}
+	}
+
+	/// Returns `true` if retrying the failed operation may succeed
+	///
+	/// Only `Error::Busy` is transient, e.g. while a previous VM is still being
+	/// torn down. Context is ignored.
+	pub fn is_transient(&self) -> bool {
+		matches!(self.root_cause(), Error::Busy)
+	}
+
+	/// Returns `true` if retrying the failed operation won't help
+	pub fn is_fatal(&self) -> bool {
+		!self.is_transient()
+	}
+
+	/// Explains an `Error::NoDev` of `create_vm` in terms of the host's virtualization support
+	///
+	/// `hv_support` is the result of the `hv_support` probe. Other errors are returned
+	/// unchanged.
+	pub fn with_hv_support_hint(self, hv_support: Option<bool>) -> Error {
+		if !matches!(self, Error::NoDev) {
+			return self;
+		}
+
+		match hv_support {
+			Some(false) => self.context(
+				"the host doesn't support Hypervisor.framework (sysctl kern.hv_support is 0); \
+				 check that the CPU supports virtualization and that it isn't disabled",
+			),
+			_ => self.context(
+				"no virtualization support found; verify that `sysctl kern.hv_support` reports 1",
+			),
+		}
+	}
+}
+
+impl PartialEq for Error {
+	fn eq(&self, other: &Error) -> bool {
+		match (self, other) {
+			(Error::Io(a), Error::Io(b)) => {
+				a.kind() == b.kind() && a.raw_os_error() == b.raw_os_error()
+			}
+			(
+				Error::Context { message, source },
+				Error::Context {
+					message: other_message,
+					source: other_source,
+				},
+			) => message == other_message && source == other_source,
+			_ => mem::discriminant(self) == mem::discriminant(other),
+		}
+	}
+}
+
+impl Eq for Error {}
+
+impl Hash for Error {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		mem::discriminant(self).hash(state);
+		match self {
+			Error::Io(err) => {
+				err.kind().hash(state);
+				err.raw_os_error().hash(state);
+			}
+			Error::Context { message, source } => {
+				message.hash(state);
+				source.hash(state);
+			}
+			_ => {}
+		}
+	}
 }
 
 // Returns an Error for a hv_return_t
@@ -93,18 +240,131 @@ fn match_error_code(code: hv_return_t) -> Result<(), Error> {
 		HV_BAD_ARGUMENT => Err(Error::BadArg),
 		HV_NO_RESOURCES => Err(Error::NoRes),
 		HV_NO_DEVICE => Err(Error::NoDev),
+		HV_DENIED => Err(Error::Denied),
 		HV_UNSUPPORTED => Err(Error::Unsupp),
 		_ => Err(Error::Error),
 	}
 }
 
+/// Returns the page size of the host in bytes
+///
+/// Guest memory is mapped with the granularity of host pages, which are 16 KiB on
+/// Apple silicon and 4 KiB on Intel Macs. Mappings, `GuestMemory` and `DirtyLog`
+/// ranges are aligned to this size.
+pub fn page_size() -> usize {
+	unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Returns whether the host supports Hypervisor.framework, as reported by `sysctl kern.hv_support`
+///
+/// Returns `None` if the sysctl can't be read, e.g. on other operating systems.
+pub fn hv_support() -> Option<bool> {
+	#[cfg(target_os = "macos")]
+	{
+		let mut value: libc::c_int = 0;
+		let mut size = std::mem::size_of::<libc::c_int>();
+		let ret = unsafe {
+			libc::sysctlbyname(
+				c"kern.hv_support".as_ptr(),
+				&mut value as *mut libc::c_int as *mut libc::c_void,
+				&mut size,
+				std::ptr::null_mut(),
+				0,
+			)
+		};
+
+		(ret == 0).then_some(value != 0)
+	}
+	#[cfg(not(target_os = "macos"))]
+	None
+}
+
+/// Maximum number of VirtualCpu IDs accepted by `interrupt_vcpus`
+pub const MAX_INTERRUPT_VCPUS: usize = 256;
+
+// Validates the IDs passed to `interrupt_vcpus` and removes duplicates
+fn dedup_vcpu_ids<T: Copy + Ord>(vcpu_ids: &[T]) -> Result<Vec<T>, Error> {
+	if vcpu_ids.is_empty() || vcpu_ids.len() > MAX_INTERRUPT_VCPUS {
+		return Err(Error::BadArg);
+	}
+
+	let mut ids = vcpu_ids.to_vec();
+	ids.sort_unstable();
+	ids.dedup();
+
+	Ok(ids)
+}
+
+/// Calls `f` until it succeeds or fails with an error that isn't transient
+///
+/// `f` is called at most `attempts` times, but at least once. The current thread
+/// sleeps for `backoff` between attempts. Returns the result of the last call.
+pub fn retry_busy<T, F>(attempts: usize, backoff: Duration, mut f: F) -> Result<T, Error>
+where
+	F: FnMut() -> Result<T, Error>,
+{
+	let mut attempt = 1;
+
+	loop {
+		match f() {
+			Err(err) if err.is_transient() && attempt < attempts => {
+				thread::sleep(backoff);
+				attempt += 1;
+			}
+			result => return result,
+		}
+	}
+}
+
+/// Whether a VM instance exists for the current Mach task
+static VM_EXISTS: AtomicBool = AtomicBool::new(false);
+
+// Marks the VM instance of the task as existing before it's created
+//
+// The framework reports a second VM as `Busy` without saying why. Unlike a busy
+// framework, the existing VM doesn't go away by retrying, so it's a bad argument.
+fn claim_vm() -> Result<(), Error> {
+	if VM_EXISTS.swap(true, Ordering::AcqRel) {
+		return Err(
+			Error::BadArg.context("a VM already exists for this Mach task; destroy it first")
+		);
+	}
+
+	Ok(())
+}
+
+// Creates the VM instance with `create` once it has been claimed
+//
+// `Error::NoDev` usually means that virtualization is unavailable, which is
+// explained by the hint of `Error::with_hv_support_hint`.
+fn create_claimed_vm<F: FnOnce() -> Result<(), Error>>(create: F) -> Result<(), Error> {
+	claim_vm()?;
+
+	let result = create();
+	if result.is_err() {
+		VM_EXISTS.store(false, Ordering::Release);
+	}
+
+	match result {
+		Err(Error::NoDev) => Err(Error::NoDev.with_hv_support_hint(hv_support())),
+		result => result,
+	}
+}
+
 /// Destroys the VM instance associated with the current Mach task
 pub fn destroy_vm() -> Result<(), Error> {
-	match_error_code(unsafe { hv_vm_destroy() })
+	traced!(
+		debug,
+		match_error_code(unsafe { hv_vm_destroy() }),
+		"destroy_vm"
+	)?;
+	VM_EXISTS.store(false, Ordering::Release);
+
+	Ok(())
 }
 
 /// Guest physical memory region permissions
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum MemPerm {
 	/// Read
 	Read,
@@ -118,6 +378,113 @@ pub enum MemPerm {
 	ExecAndRead,
 }
 
+impl fmt::Display for MemPerm {
+	/// Formats the permissions as an `rwx` triplet, e.g. `r-x` for `ExecAndRead`
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match self {
+			MemPerm::Read => "r--",
+			MemPerm::Write => "rw-",
+			MemPerm::Exec => "--x",
+			MemPerm::ExecAndWrite => "rwx",
+			MemPerm::ExecAndRead => "r-x",
+		})
+	}
+}
+
+impl FromStr for MemPerm {
+	type Err = Error;
+
+	/// Parses the `rwx` triplet written by `Display`
+	///
+	/// Write permission without read permission has no `MemPerm` and returns
+	/// `Error::BadArg`, like any other string.
+	fn from_str(s: &str) -> Result<MemPerm, Error> {
+		match s {
+			"r--" => Ok(MemPerm::Read),
+			"rw-" => Ok(MemPerm::Write),
+			"--x" => Ok(MemPerm::Exec),
+			"rwx" => Ok(MemPerm::ExecAndWrite),
+			"r-x" => Ok(MemPerm::ExecAndRead),
+			_ => Err(Error::BadArg.context(format!("invalid memory permissions {:?}", s))),
+		}
+	}
+}
+
+/// Serialized as the `rwx` triplet of `Display`
+#[cfg(feature = "serde")]
+impl serde::Serialize for MemPerm {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.collect_str(self)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MemPerm {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<MemPerm, D::Error> {
+		let s = String::deserialize(deserializer)?;
+		s.parse().map_err(serde::de::Error::custom)
+	}
+}
+
+/// Flags of a guest physical memory region beyond the `MemPerm` permissions
+///
+/// The flags are passed to Hypervisor.framework as they are, so that extensions of
+/// newer framework versions can be used. As of macOS 14, the framework defines no
+/// flags besides read, write and execute on either architecture and rejects any
+/// other flag, which is reported as `Error::Unsupp`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MemFlags(u64);
+
+impl MemFlags {
+	/// No additional flags
+	pub const NONE: MemFlags = MemFlags(0);
+
+	/// Returns the flags for the raw `hv_memory_flags_t` bits
+	///
+	/// Returns `None` if the bits contain read, write or execute permissions,
+	/// which are expressed by `MemPerm`.
+	pub const fn from_bits(bits: u64) -> Option<MemFlags> {
+		if bits & (HV_MEMORY_READ | HV_MEMORY_WRITE | HV_MEMORY_EXEC) != 0 {
+			None
+		} else {
+			Some(MemFlags(bits))
+		}
+	}
+
+	/// Returns the raw `hv_memory_flags_t` bits
+	pub const fn bits(&self) -> u64 {
+		self.0
+	}
+
+	/// Returns `true` if no flag is set
+	pub const fn is_empty(&self) -> bool {
+		self.0 == 0
+	}
+}
+
+impl BitOr for MemFlags {
+	type Output = MemFlags;
+
+	fn bitor(self, rhs: MemFlags) -> MemFlags {
+		MemFlags(self.0 | rhs.0)
+	}
+}
+
+// Returns the hv_memory_flags_t for the permissions and flags
+fn memory_flags(mem_perm: MemPerm, flags: MemFlags) -> u64 {
+	match_MemPerm(mem_perm) | flags.bits()
+}
+
+// Reports the rejection of memory flags as Error::Unsupp
+fn match_flags_error(result: Result<(), Error>, flags: MemFlags) -> Result<(), Error> {
+	match result {
+		Err(Error::BadArg) if !flags.is_empty() => {
+			Err(Error::Unsupp.context(format!("memory flags {:#x}", flags.bits())))
+		}
+		result => result,
+	}
+}
+
 #[allow(non_snake_case)]
 #[inline(always)]
 fn match_MemPerm(mem_perm: MemPerm) -> u64 {
@@ -130,20 +497,158 @@ fn match_MemPerm(mem_perm: MemPerm) -> u64 {
 	}
 }
 
-impl VirtualCpu {
+/// Access to the model-specific registers of a VirtualCpu
+///
+/// The identifiers depend on the architecture:
+///
+/// * x86_64: the `u32` index of an MSR, as used by `RDMSR`/`WRMSR`
+/// * aarch64: a `SystemRegister`
+pub trait ModelRegisters {
+	/// Identifier of a model-specific register
+	type Id;
+
+	/// Returns the current value of a model-specific register
+	fn read_model_register(&self, id: Self::Id) -> Result<u64, Error>;
+
+	/// Sets the value of a model-specific register
+	fn write_model_register(&self, id: Self::Id, value: u64) -> Result<(), Error>;
+}
+
+impl VirtualCpu<'_> {
 	/// Destroys the VirtualCpu instance associated with the current thread
 	pub fn destroy(&self) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_destroy(self.get_id()) })
+		#[cfg(target_arch = "x86_64")]
+		self.assert_owner();
+		traced!(
+			debug,
+			match_error_code(unsafe { hv_vcpu_destroy(self.get_id()) }),
+			vcpu = self.get_id(),
+			"destroy"
+		)?;
+
+		if let Some(vcpus) = self.registry.upgrade() {
+			vm::lock(&vcpus).remove(&self.get_id());
+		}
+		#[cfg(target_arch = "x86_64")]
+		if let Some(requests) = self.requests.upgrade() {
+			vm::lock(&requests).remove(&self.get_id());
+		}
+
+		Ok(())
 	}
 
 	/// Executes the VirtualCpu
+	///
+	/// On aarch64, the cancel signal of a `SignalGuard` is unblocked for the duration of
+	/// the run
+	///
+	/// On x86_64, a failed VM entry is returned with a context that holds the decoded
+	/// VM-instruction error, e.g. for invalid control fields. Interrupts queued by
+	/// `Vm::inject_to_all` are injected and TLB flushes requested by
+	/// `Vm::protect_range` or `protect_mem` are done before the entry.
 	pub fn run(&self) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_run(self.get_id()) })
+		#[cfg(target_arch = "x86_64")]
+		{
+			self.assert_owner();
+			self.service_requests()?;
+		}
+		self.forget_instruction_pointer();
+		#[cfg(target_arch = "aarch64")]
+		let result = {
+			let result = aarch64::unblocked(|| unsafe { hv_vcpu_run(self.get_id()) })?;
+			if result == HV_SUCCESS {
+				self.exited.set(true);
+			}
+			match_error_code(result)
+		};
+		#[cfg(target_arch = "x86_64")]
+		let result = match_error_code(unsafe { hv_vcpu_run(self.get_id()) })
+			.map(|()| self.dirty.store(false, Ordering::Relaxed))
+			.map_err(|err| self.entry_error(err));
+
+		traced!(
+			debug,
+			result,
+			vcpu = self.get_id(),
+			exit = ?self.traced_exit_reason(),
+			"run"
+		)
 	}
-}
 
-impl fmt::Debug for VirtualCpu {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "VirtualCpu ID: {}", (*self).get_id())
+	/// Executes the VirtualCpu until it exits or `timeout` has passed
+	///
+	/// A helper thread interrupts the VirtualCpu when the timeout expires, it is
+	/// joined before this returns. On x86_64, exits for host interrupts re-enter the
+	/// guest, since they can't be told apart from the interrupt of the timeout. If
+	/// the timeout expires just as the VirtualCpu exits on its own, `Exited` is
+	/// returned and the next run may return right away.
+	pub fn run_with_timeout(&self, timeout: Duration) -> Result<RunOutcome, Error> {
+		let id = self.get_id();
+		let timer = &Mutex::new(Timer::Armed);
+		let (disarm, disarmed) = mpsc::channel::<()>();
+
+		thread::scope(|s| {
+			s.spawn(move || {
+				if let Err(RecvTimeoutError::Timeout) = disarmed.recv_timeout(timeout) {
+					let mut timer = vm::lock(timer);
+					if *timer == Timer::Armed {
+						*timer = Timer::Fired;
+						let _ = interrupt_vcpus(&[id]);
+					}
+				}
+			});
+
+			let result = self.run_until_timeout(timer);
+			*vm::lock(timer) = Timer::Disarmed;
+			drop(disarm);
+
+			result
+		})
+	}
+
+	// Runs the VirtualCpu until it exits for another reason than a host interrupt
+	// or the timer has interrupted it
+	fn run_until_timeout(&self, timer: &Mutex<Timer>) -> Result<RunOutcome, Error> {
+		loop {
+			self.run()?;
+
+			let interrupted = self.was_interrupted()?;
+			match *vm::lock(timer) {
+				Timer::Fired if interrupted => return Ok(RunOutcome::TimedOut),
+				Timer::Armed if interrupted && cfg!(target_arch = "x86_64") => {}
+				_ => return Ok(RunOutcome::Exited),
+			}
+		}
 	}
+
+	// Returns whether the last exit may have been caused by `interrupt_vcpus`
+	fn was_interrupted(&self) -> Result<bool, Error> {
+		#[cfg(target_arch = "x86_64")]
+		return Ok(u64::from(self.exit_reason_raw()?) == x86_64::consts::vmx_exit::VMX_REASON_IRQ);
+		#[cfg(target_arch = "aarch64")]
+		return Ok(matches!(
+			self.exit_reason(),
+			Some(VirtualCpuExitReason::Cancelled)
+		));
+	}
+}
+
+/// Result of `VirtualCpu::run_with_timeout`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RunOutcome {
+	/// The VirtualCpu exited before the timeout, the exit is available like after `run`
+	Exited,
+	/// The VirtualCpu was interrupted when the timeout expired
+	TimedOut,
+}
+
+/// State of the helper thread of `VirtualCpu::run_with_timeout`
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Timer {
+	/// The timeout hasn't expired yet
+	Armed,
+	/// The timeout has expired and the VirtualCpu was interrupted
+	Fired,
+	/// The run has ended, the VirtualCpu must not be interrupted anymore
+	Disarmed,
 }
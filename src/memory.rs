@@ -0,0 +1,250 @@
+//! Host memory backing the guest physical address space
+
+use crate::{page_size, Error};
+use libc::*;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+use std::sync::Arc;
+
+/// Page-aligned, zero-initialized host memory that can back guest physical memory
+///
+/// Clones share the same memory, which is released when the last clone is dropped.
+/// The guest may modify the memory at any time while it is mapped, so it is only
+/// accessible by copying through `read` and `write` or through the raw pointer.
+///
+/// The copies use volatile byte accesses, so they are well-defined while the guest
+/// or other threads access the same bytes, but they aren't atomic: a copy that
+/// races with a write may observe a mix of old and new bytes, and no ordering is
+/// implied between the bytes. Protocols with the guest, e.g. ring buffers, have to
+/// publish data through their own fences and indices, like on real hardware.
+#[derive(Clone)]
+pub struct GuestMemory {
+	/// Shared host mapping
+	inner: Arc<Allocation>,
+}
+
+/// Anonymous host mapping
+struct Allocation {
+	/// Start of the host mapping
+	host: *mut u8,
+	/// Size of the mapping in bytes
+	size: usize,
+}
+
+// The memory is only accessed through raw pointers, like the guest does
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+/// Range of `GuestMemory`, e.g. a guest buffer of an emulated device
+///
+/// The guest and other threads may access the range concurrently, so it never
+/// hands out a Rust reference to the bytes. They are copied through `read` and
+/// `write` like with `GuestMemory`, or accessed through the raw pointer, with the
+/// caller being responsible for the synchronization. The slice keeps the memory
+/// alive, even if its slot is removed from the VM in the meantime.
+#[derive(Clone)]
+pub struct GuestSlice {
+	/// Memory that contains the range
+	memory: GuestMemory,
+	/// Offset of the range in the memory
+	offset: usize,
+	/// Length of the range in bytes
+	len: usize,
+}
+
+impl GuestSlice {
+	/// Returns the length of the range in bytes
+	pub fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns `true` if the range is empty
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Returns a pointer to the start of the range
+	pub fn as_ptr(&self) -> *mut u8 {
+		self.memory.as_ptr().wrapping_add(self.offset)
+	}
+
+	/// Copies the range at `offset` into `buf`
+	///
+	/// Returns `Error::BadArg` if the copy exceeds the range.
+	pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+		self.check_range(offset, buf.len())?;
+		self.memory.read(self.offset + offset, buf)
+	}
+
+	/// Copies `data` into the range at `offset`
+	///
+	/// Returns `Error::BadArg` if the copy exceeds the range.
+	pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+		self.check_range(offset, data.len())?;
+		self.memory.write(self.offset + offset, data)
+	}
+
+	// Checks that the copy lies within the range
+	fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+		match offset.checked_add(len) {
+			Some(end) if end <= self.len => Ok(()),
+			_ => Err(Error::BadArg),
+		}
+	}
+}
+
+impl Drop for Allocation {
+	fn drop(&mut self) {
+		unsafe {
+			munmap(self.host as *mut c_void, self.size);
+		}
+	}
+}
+
+impl GuestMemory {
+	/// Allocates `size` bytes of zeroed host memory, rounded up to the host page size
+	///
+	/// Returns `Error::BadArg` if `size` is zero and `Error::NoRes` if the memory
+	/// can't be allocated.
+	pub fn new(size: usize) -> Result<GuestMemory, Error> {
+		if size == 0 {
+			return Err(Error::BadArg);
+		}
+
+		let page_size = page_size();
+		let size = (size + page_size - 1) & !(page_size - 1);
+		let host = unsafe {
+			mmap(
+				ptr::null_mut(),
+				size,
+				PROT_READ | PROT_WRITE,
+				MAP_PRIVATE | MAP_ANON,
+				-1,
+				0,
+			)
+		};
+		if host == MAP_FAILED {
+			return Err(Error::NoRes);
+		}
+
+		Ok(GuestMemory {
+			inner: Arc::new(Allocation {
+				host: host as *mut u8,
+				size,
+			}),
+		})
+	}
+
+	/// Returns the size of the memory in bytes
+	pub fn size(&self) -> usize {
+		self.inner.size
+	}
+
+	/// Returns a pointer to the start of the memory
+	pub fn as_ptr(&self) -> *mut u8 {
+		self.inner.host
+	}
+
+	/// Copies the memory at `offset` into `buf`
+	///
+	/// Returns `Error::BadArg` if the range exceeds the memory.
+	pub fn read(&self, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+		self.check_range(offset, buf.len())?;
+
+		unsafe {
+			self.copy_from(offset, buf.as_mut_ptr(), buf.len());
+		}
+
+		Ok(())
+	}
+
+	/// Copies `data` into the memory at `offset`
+	///
+	/// Returns `Error::BadArg` if the range exceeds the memory.
+	pub fn write(&self, offset: usize, data: &[u8]) -> Result<(), Error> {
+		self.check_range(offset, data.len())?;
+
+		unsafe {
+			self.copy_to(offset, data.as_ptr(), data.len());
+		}
+
+		Ok(())
+	}
+
+	/// Reads a `T` from the memory at `offset`
+	///
+	/// The value is copied without alignment requirements, e.g. for descriptors
+	/// and ring entries of emulated devices. Returns `Error::BadArg` if the
+	/// `size_of::<T>()` bytes at `offset` exceed the memory.
+	///
+	/// # Safety
+	///
+	/// Every bit pattern must be a valid `T`, since the guest controls the memory.
+	/// Types with references, `bool`, `char` or enums don't qualify, plain
+	/// `#[repr(C)]` structs of integers do.
+	pub unsafe fn read_struct<T: Copy>(&self, offset: usize) -> Result<T, Error> {
+		self.check_range(offset, mem::size_of::<T>())?;
+
+		let mut value = MaybeUninit::<T>::uninit();
+		self.copy_from(offset, value.as_mut_ptr() as *mut u8, mem::size_of::<T>());
+
+		Ok(value.assume_init())
+	}
+
+	/// Writes `value` to the memory at `offset`
+	///
+	/// The value is copied without alignment requirements. Returns `Error::BadArg`
+	/// if the `size_of::<T>()` bytes at `offset` exceed the memory.
+	///
+	/// # Safety
+	///
+	/// `T` must not contain padding, otherwise uninitialized bytes end up in the
+	/// memory, where `read` and the guest may observe them.
+	pub unsafe fn write_struct<T: Copy>(&self, offset: usize, value: T) -> Result<(), Error> {
+		self.check_range(offset, mem::size_of::<T>())?;
+		self.copy_to(offset, &value as *const T as *const u8, mem::size_of::<T>());
+
+		Ok(())
+	}
+
+	/// Returns the `len` bytes at `offset` as a `GuestSlice`
+	///
+	/// Returns `None` if the range exceeds the memory.
+	pub fn slice(&self, offset: usize, len: usize) -> Option<GuestSlice> {
+		self.check_range(offset, len).ok()?;
+
+		Some(GuestSlice {
+			memory: self.clone(),
+			offset,
+			len,
+		})
+	}
+
+	// Copies `len` bytes of the memory at `offset` to `dst` with volatile reads
+	//
+	// The range must lie within the memory.
+	unsafe fn copy_from(&self, offset: usize, dst: *mut u8, len: usize) {
+		let src = self.as_ptr().add(offset);
+		for i in 0..len {
+			dst.add(i).write(src.add(i).read_volatile());
+		}
+	}
+
+	// Copies `len` bytes from `src` to the memory at `offset` with volatile writes
+	//
+	// The range must lie within the memory.
+	unsafe fn copy_to(&self, offset: usize, src: *const u8, len: usize) {
+		let dst = self.as_ptr().add(offset);
+		for i in 0..len {
+			dst.add(i).write_volatile(src.add(i).read());
+		}
+	}
+
+	// Checks that the range lies within the memory
+	fn check_range(&self, offset: usize, len: usize) -> Result<(), Error> {
+		match offset.checked_add(len) {
+			Some(end) if end <= self.size() => Ok(()),
+			_ => Err(Error::BadArg),
+		}
+	}
+}
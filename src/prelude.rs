@@ -0,0 +1,26 @@
+//! Commonly used items of the safe interface
+//!
+//! `use xhypervisor::prelude::*` imports the VM and VirtualCpu types, the memory
+//! and error types and the exit reasons of the host architecture. On x86_64, it
+//! also imports the VMCS field IDs, VMX capability bits and basic exit reasons,
+//! which are needed to set up and run a VirtualCpu. The raw bindings of `ffi`
+//! are left out on purpose.
+
+pub use crate::{
+	create_vm, destroy_vm, map_mem, page_size, protect_mem, unmap_mem, Error, GuestMemory, Mapping,
+	MemFlags, MemPerm, MemRegion, RunOutcome, SlotId, VcpuActor, VirtualCpu, Vm,
+};
+
+#[cfg(target_arch = "aarch64")]
+pub use crate::aarch64::{
+	Esr, ExceptionClass, GpRegs, GuestException, InterruptType, MmioExit, Pstate, Register,
+	SystemRegister, VirtualCpuExitReason,
+};
+
+#[cfg(target_arch = "x86_64")]
+pub use crate::x86_64::consts::{vmcs::*, vmx_cap::*, vmx_exit::*};
+#[cfg(target_arch = "x86_64")]
+pub use crate::x86_64::{
+	cap2ctrl, read_vmx_cap, CpuidExit, EptViolationExit, ExceptionExit, ExitDetails, ExitReason,
+	GpRegs, IoExit, MsrExit, Register, VMXCap, VmcsField,
+};
@@ -0,0 +1,65 @@
+//! Optional instrumentation of the FFI wrappers with `tracing`
+//!
+//! Without the `tracing` feature the macros expand to their bare result, so
+//! neither the fields nor the exit reasons are evaluated.
+
+/// Enters a span at debug level that lasts until the returned guard is dropped
+#[cfg(feature = "tracing")]
+macro_rules! debug_span {
+	($($arg:tt)*) => {
+		::tracing::debug_span!($($arg)*).entered()
+	};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug_span {
+	($($arg:tt)*) => {
+		()
+	};
+}
+
+/// Evaluates to `$result` after emitting it with the fields at the given level
+#[cfg(feature = "tracing")]
+macro_rules! traced {
+	($level:ident, $result:expr, $($field:tt)+) => {{
+		let result = $result;
+		::tracing::$level!(result = ?result, $($field)+);
+		result
+	}};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! traced {
+	($level:ident, $result:expr, $($field:tt)+) => {
+		$result
+	};
+}
+
+#[cfg(all(feature = "tracing", target_arch = "x86_64"))]
+impl crate::VirtualCpu<'_> {
+	// Returns the reason of the last VM exit for the trace of `run`
+	pub(crate) fn traced_exit_reason(&self) -> Option<crate::x86_64::ExitReason> {
+		use crate::x86_64::consts::vmcs::VMCS_RO_EXIT_REASON;
+
+		let mut reason: u64 = 0;
+		let ret = unsafe {
+			crate::x86_64::ffi::hv_vmx_vcpu_read_vmcs(
+				self.get_id(),
+				VMCS_RO_EXIT_REASON,
+				&mut reason,
+			)
+		};
+
+		crate::match_error_code(ret)
+			.ok()
+			.map(|()| crate::x86_64::ExitReason(reason & 0xffff))
+	}
+}
+
+#[cfg(all(feature = "tracing", target_arch = "aarch64"))]
+impl crate::VirtualCpu<'_> {
+	// Returns the reason of the last exit for the trace of `run`
+	pub(crate) fn traced_exit_reason(&self) -> Option<crate::aarch64::VirtualCpuExitReason> {
+		self.exit_reason()
+	}
+}
@@ -0,0 +1,936 @@
+//! Owned handle to the VM instance of the current Mach task
+
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::ffi::hv_vcpu_destroy;
+#[cfg(target_arch = "aarch64")]
+pub(crate) use crate::aarch64::ffi::hv_vcpu_t as VcpuId;
+#[cfg(target_arch = "aarch64")]
+use crate::aarch64::VcpuConfig;
+#[cfg(target_arch = "aarch64")]
+use crate::protect_mem;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::ffi::hv_vcpu_destroy;
+#[cfg(target_arch = "x86_64")]
+pub(crate) use crate::x86_64::ffi::hv_vcpuid_t as VcpuId;
+#[cfg(target_arch = "x86_64")]
+use crate::x86_64::protect_mem_no_flush;
+use crate::{
+	create_vm, destroy_vm, hv_vcpu_get_exec_time, interrupt_vcpus, map_mem, map_mem_raw,
+	match_error_code, page_size, unmap_mem, DirtyLog, Error, GuestMemory, GuestSlice, MemPerm,
+	VirtualCpu,
+};
+use libc::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+/// Mappings of a Vm, keyed by their guest physical address
+pub(crate) type Mappings = Mutex<BTreeMap<u64, Mapping>>;
+
+/// VirtualCpus created through a Vm that haven't been destroyed yet
+pub(crate) type Vcpus = Mutex<BTreeSet<VcpuId>>;
+
+/// Work the Vm left for a VirtualCpu, done by its next `run`
+#[cfg(target_arch = "x86_64")]
+#[derive(Default)]
+pub(crate) struct VcpuRequests {
+	/// Interrupts queued by `Vm::inject_to_all`
+	pub(crate) interrupts: crate::x86_64::InterruptQueue,
+	/// Whether permissions were changed through the Vm since the last TLB flush
+	pub(crate) invalidate_tlb: bool,
+}
+
+/// Requests for the VirtualCpus of a Vm, keyed by their IDs
+#[cfg(target_arch = "x86_64")]
+pub(crate) type Requests = Mutex<BTreeMap<VcpuId, VcpuRequests>>;
+
+/// State shared with the Vm of the current task, which is the only one
+#[cfg(target_arch = "x86_64")]
+struct Active {
+	/// Mappings of the Vm
+	mappings: Weak<Mappings>,
+	/// Requests for the VirtualCpus of the Vm
+	requests: Weak<Requests>,
+}
+
+/// State of the Vm of the current task, for the free functions
+#[cfg(target_arch = "x86_64")]
+static ACTIVE: Mutex<Active> = Mutex::new(Active {
+	mappings: Weak::new(),
+	requests: Weak::new(),
+});
+
+// Locks the mutex, ignoring a panic of another thread that held the lock
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Returns the guest physical addresses of all mappings overlapping the range
+pub(crate) fn overlapping(mappings: &BTreeMap<u64, Mapping>, gpa: u64, size: usize) -> Vec<u64> {
+	let end = gpa + size as u64;
+
+	mappings
+		.range(..end)
+		.rev()
+		.take_while(|(_, mapping)| mapping.end() > gpa)
+		.map(|(start, _)| *start)
+		.collect()
+}
+
+// Records new permissions for a range, splitting the mappings that only partially
+// overlap it
+fn record_protection(mappings: &mut BTreeMap<u64, Mapping>, gpa: u64, size: usize, perm: MemPerm) {
+	let end = gpa + size as u64;
+
+	for start in overlapping(mappings, gpa, size) {
+		let mapping = mappings.remove(&start).unwrap();
+		let pieces = [
+			(mapping.gpa, gpa.max(mapping.gpa), mapping.perm),
+			(gpa.max(mapping.gpa), end.min(mapping.end()), perm),
+			(end.min(mapping.end()), mapping.end(), mapping.perm),
+		];
+
+		for (from, to, perm) in pieces {
+			if from < to {
+				mappings.insert(
+					from,
+					Mapping {
+						gpa: from,
+						size: (to - from) as usize,
+						perm,
+					},
+				);
+			}
+		}
+	}
+}
+
+// Updates the requests of all VirtualCpus and interrupts them to pick them up
+//
+// If some VirtualCpus can't be interrupted, the error of the first one is
+// returned with the IDs of all that failed.
+#[cfg(target_arch = "x86_64")]
+fn request_all<F: FnMut(&mut VcpuRequests)>(requests: &Requests, mut f: F) -> Result<(), Error> {
+	let ids: Vec<VcpuId> = {
+		let mut requests = lock(requests);
+		requests.values_mut().for_each(&mut f);
+		requests.keys().copied().collect()
+	};
+
+	let mut failed = Vec::new();
+	let mut first_error = None;
+	for id in ids {
+		if let Err(err) = interrupt_vcpus(&[id]) {
+			failed.push(id);
+			first_error.get_or_insert(err);
+		}
+	}
+
+	match first_error {
+		None => Ok(()),
+		Some(err) => Err(err.context(format!("failed to interrupt VirtualCpus {:?}", failed))),
+	}
+}
+
+// Modifies the guest permissions of a range without invalidating any TLB
+pub(crate) fn protect_no_flush(gpa: u64, size: usize, perm: MemPerm) -> Result<(), Error> {
+	#[cfg(target_arch = "x86_64")]
+	return protect_mem_no_flush(gpa, size, perm);
+	#[cfg(target_arch = "aarch64")]
+	return protect_mem(gpa, size, perm);
+}
+
+// Returns whether `perm` takes a right away from a part of the range
+//
+// Parts of the range without a recorded mapping count as accessible with every right.
+#[cfg(target_arch = "x86_64")]
+fn narrows(mappings: &BTreeMap<u64, Mapping>, gpa: u64, size: usize, perm: MemPerm) -> bool {
+	let rights = crate::match_MemPerm(perm);
+	let end = gpa + size as u64;
+	let mut covered = 0;
+
+	for start in overlapping(mappings, gpa, size) {
+		let mapping = &mappings[&start];
+		if crate::match_MemPerm(mapping.perm) & !rights != 0 {
+			return true;
+		}
+		covered += end.min(mapping.end()) - gpa.max(mapping.gpa);
+	}
+
+	covered < size as u64 && perm != MemPerm::ExecAndWrite
+}
+
+// Returns whether `perm` takes a right away from a part of the range, judged by
+// the mappings of the Vm of the current task
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn narrows_active(gpa: u64, size: usize, perm: MemPerm) -> bool {
+	let mappings = lock(&ACTIVE).mappings.upgrade();
+
+	mappings.is_some_and(|mappings| narrows(&lock(&mappings), gpa, size, perm))
+}
+
+// Lets every VirtualCpu of the Vm of the current task invalidate its TLB at the
+// start of its next `run`
+//
+// Every narrowing of the guest permissions on x86_64 ends up here, unless the
+// caller asked to skip the flush.
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn request_tlb_flush() -> Result<(), Error> {
+	let requests = match lock(&ACTIVE).requests.upgrade() {
+		Some(requests) => requests,
+		None => return Ok(()),
+	};
+
+	request_all(&requests, |requests| requests.invalidate_tlb = true)
+		.map_err(|err| err.context("failed to request a TLB flush"))
+}
+
+/// Region of the guest physical address space mapped through a Vm
+///
+/// With the `serde` feature, a memory layout can be loaded from a configuration file
+/// as a list of mappings and applied with `Vm::add_slot`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Mapping {
+	/// Guest physical address of the mapping
+	pub gpa: u64,
+	/// Size of the mapping in bytes
+	pub size: usize,
+	/// Permissions of the mapping
+	pub perm: MemPerm,
+}
+
+/// Stable identifier of a memory slot of a Vm
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SlotId(u64);
+
+/// Guest physical memory region backed by memory that the Vm keeps alive
+struct Slot {
+	/// Guest physical address of the slot
+	gpa: u64,
+	/// Size of the slot in bytes
+	size: usize,
+	/// Host memory backing the slot
+	backing: GuestMemory,
+}
+
+impl Mapping {
+	// Returns the first guest physical address behind the mapping
+	fn end(&self) -> u64 {
+		self.gpa + self.size as u64
+	}
+}
+
+/// VM instance of the current Mach task
+///
+/// The VM is created by `Vm::new` and destroyed when the handle is dropped or by
+/// `Vm::destroy`. The framework only destroys a VM without VirtualCpus, so the
+/// VirtualCpus that are still registered are destroyed first.
+pub struct Vm {
+	/// Thread that is allowed to create VirtualCpus, if the VM is thread-bound
+	owner: Option<ThreadId>,
+	/// Mappings established through the VM
+	mappings: Arc<Mappings>,
+	/// VirtualCpus created through the VM
+	vcpus: Arc<Vcpus>,
+	/// Work queued for the VirtualCpus of the VM
+	#[cfg(target_arch = "x86_64")]
+	requests: Arc<Requests>,
+	/// Memory slots of the VM
+	slots: Mutex<BTreeMap<SlotId, Slot>>,
+	/// Identifier of the next memory slot
+	next_slot: AtomicU64,
+	/// Whether the VM instance has been destroyed
+	destroyed: bool,
+}
+
+impl Vm {
+	/// Creates the VM instance for the current Mach task
+	pub fn new() -> Result<Vm, Error> {
+		Vm::create(None)
+	}
+
+	// Creates the VM instance, bound to the thread `owner` if there is one
+	fn create(owner: Option<ThreadId>) -> Result<Vm, Error> {
+		create_vm()?;
+
+		let vm = Vm {
+			owner,
+			mappings: Default::default(),
+			vcpus: Default::default(),
+			#[cfg(target_arch = "x86_64")]
+			requests: Default::default(),
+			slots: Default::default(),
+			next_slot: AtomicU64::new(0),
+			destroyed: false,
+		};
+		#[cfg(target_arch = "x86_64")]
+		{
+			*lock(&ACTIVE) = Active {
+				mappings: Arc::downgrade(&vm.mappings),
+				requests: Arc::downgrade(&vm.requests),
+			};
+		}
+
+		Ok(vm)
+	}
+
+	/// Creates the VM instance with `size` bytes of RWX guest RAM mapped at gpa 0
+	///
+	/// The size is rounded up to the host page size. The RAM is mapped as a memory
+	/// slot that keeps a clone of the returned `GuestMemory`:
+	///
+	/// ```no_run
+	/// # fn main() -> Result<(), xhypervisor::Error> {
+	/// use xhypervisor::Vm;
+	///
+	/// let (vm, ram) = Vm::with_guest_ram(1 << 20)?;
+	/// ram.write(0x100, &[0xf4])?;
+	/// let vcpu = vm.create_vcpu()?;
+	/// # Ok(())
+	/// # }
+	/// ```
+	pub fn with_guest_ram(size: usize) -> Result<(Vm, GuestMemory), Error> {
+		let ram = GuestMemory::new(size)?;
+		let vm = Vm::new()?;
+		vm.add_slot(0, ram.size(), MemPerm::ExecAndWrite, ram.clone())?;
+
+		Ok((vm, ram))
+	}
+
+	/// Creates the VM instance for the current Mach task and binds it to the current thread
+	///
+	/// VirtualCpus are bound to the thread that creates them. A thread-bound VM
+	/// rejects `create_vcpu` calls from any other thread with `Error::BadArg`
+	/// instead of leaving the mistake to surface later in the framework.
+	pub fn new_with_guard() -> Result<Vm, Error> {
+		Vm::create(Some(thread::current().id()))
+	}
+
+	/// Destroys the VirtualCpus registered with the VM and then the VM instance
+	///
+	/// VirtualCpus that were dropped without `VirtualCpu::destroy` are still
+	/// registered and get destroyed here, which would otherwise make the framework
+	/// reject the destruction of the VM with `Error::Busy`. The framework only
+	/// destroys a VirtualCpu on the thread that created it, so the VirtualCpus of
+	/// other threads must be destroyed by their owning threads beforehand.
+	/// Otherwise they stay registered, the VM instance is kept and `Error::Busy` is
+	/// returned with their IDs. Dropping the VM does the same, but only reports the
+	/// errors with the `tracing` feature.
+	pub fn destroy(mut self) -> Result<(), Error> {
+		self.teardown()
+	}
+
+	// Destroys the registered VirtualCpus and the VM instance
+	//
+	// Every VirtualCpu is attempted. Those that can't be destroyed, e.g. because
+	// they belong to another thread, stay registered and the VM instance is kept.
+	fn teardown(&mut self) -> Result<(), Error> {
+		if self.destroyed {
+			return Ok(());
+		}
+
+		let mut failed = Vec::new();
+		for id in self.vcpu_ids() {
+			let result = traced!(
+				debug,
+				match_error_code(unsafe { hv_vcpu_destroy(id) }),
+				vcpu = id,
+				"destroy"
+			);
+			match result {
+				Ok(()) => {
+					lock(&self.vcpus).remove(&id);
+					#[cfg(target_arch = "x86_64")]
+					lock(&self.requests).remove(&id);
+				}
+				Err(_err) => {
+					#[cfg(feature = "tracing")]
+					tracing::warn!(vcpu = id, error = %_err, "failed to destroy a VirtualCpu of the Vm");
+					failed.push(id);
+				}
+			}
+		}
+		if !failed.is_empty() {
+			return Err(Error::Busy.context(format!(
+				"VirtualCpus {:?} must be destroyed by their owning threads",
+				failed
+			)));
+		}
+
+		destroy_vm()?;
+		self.destroyed = true;
+
+		Ok(())
+	}
+
+	/// Creates a VirtualCpu for the current thread and registers it with the VM
+	///
+	/// Returns `Error::BadArg` if the VM was created by `Vm::new_with_guard` on another thread.
+	/// The VirtualCpu stays registered until it is destroyed. It borrows the VM, so the VM
+	/// can't be dropped while the VirtualCpu is alive.
+	pub fn create_vcpu(&self) -> Result<VirtualCpu<'_>, Error> {
+		self.check_owner()?;
+
+		// the VirtualCpu borrows the VM, which outlives it
+		let vcpu = unsafe { VirtualCpu::new_unbound()? };
+
+		Ok(self.register(vcpu))
+	}
+
+	/// Creates a VirtualCpu with the options of `config` and registers it with the VM
+	///
+	/// See `create_vcpu`, which uses the default options.
+	#[cfg(target_arch = "aarch64")]
+	pub fn create_vcpu_with(&self, config: &VcpuConfig) -> Result<VirtualCpu<'_>, Error> {
+		self.check_owner()?;
+
+		// the VirtualCpu borrows the VM, which outlives it
+		let vcpu = unsafe { VirtualCpu::new_unbound_with(config)? };
+
+		Ok(self.register(vcpu))
+	}
+
+	// Returns `Error::BadArg` if the VM is bound to another thread
+	fn check_owner(&self) -> Result<(), Error> {
+		match self.owner {
+			Some(owner) if owner != thread::current().id() => Err(Error::BadArg),
+			_ => Ok(()),
+		}
+	}
+
+	// Registers a VirtualCpu created for the VM
+	fn register<'vm>(&'vm self, mut vcpu: VirtualCpu<'static>) -> VirtualCpu<'vm> {
+		lock(&self.vcpus).insert(vcpu.get_id());
+		vcpu.registry = Arc::downgrade(&self.vcpus);
+		#[cfg(target_arch = "x86_64")]
+		{
+			lock(&self.requests).insert(vcpu.get_id(), Default::default());
+			vcpu.requests = Arc::downgrade(&self.requests);
+			vcpu.mappings = Arc::downgrade(&self.mappings);
+		}
+
+		vcpu
+	}
+
+	/// Returns the IDs of the VirtualCpus registered with the VM in ascending order
+	pub fn vcpu_ids(&self) -> Vec<VcpuId> {
+		lock(&self.vcpus).iter().copied().collect()
+	}
+
+	/// Forces an immediate exit of all VirtualCpus registered with the VM
+	pub fn interrupt_vcpus(&self) -> Result<(), Error> {
+		let ids = self.vcpu_ids();
+		if ids.is_empty() {
+			return Ok(());
+		}
+
+		interrupt_vcpus(&ids)
+	}
+
+	/// Injects the external interrupt `vector` into all VirtualCpus registered with the VM
+	///
+	/// VirtualCpus are bound to their threads, so the vector is queued for every
+	/// VirtualCpu and injected by its next `run` as soon as the guest can take it,
+	/// like by `InterruptQueue::service`. The VirtualCpus are interrupted to pick it
+	/// up. If some of them can't be interrupted, the error of the first one is
+	/// returned with the IDs of all that failed, the vector stays queued for them.
+	#[cfg(target_arch = "x86_64")]
+	pub fn inject_to_all(&self, vector: u8) -> Result<(), Error> {
+		request_all(&self.requests, |requests| requests.interrupts.push(vector))
+			.map_err(|err| err.context(format!("failed to inject vector {:#x}", vector)))
+	}
+
+	/// Synchronizes the guest timestamp counters (TSC) of all VirtualCpus of the VM
+	///
+	/// Afterwards `VirtualCpu::read_tsc` returns about the same value on every VirtualCpu.
+	#[cfg(target_arch = "x86_64")]
+	pub fn sync_tsc(&self, tsc: u64) -> Result<(), Error> {
+		crate::x86_64::sync_tsc(tsc)
+	}
+
+	/// Returns the cumulative execution time of all VirtualCpus registered with the VM
+	///
+	/// VirtualCpus that have already been destroyed don't contribute.
+	pub fn total_exec_time(&self) -> Result<Duration, Error> {
+		let mut total: u64 = 0;
+
+		for id in lock(&self.vcpus).iter() {
+			let mut exec_time: u64 = 0;
+			match_error_code(unsafe { hv_vcpu_get_exec_time(*id, &mut exec_time) })?;
+			total += exec_time;
+		}
+
+		Ok(Duration::from_nanos(total))
+	}
+
+	/// Maps a region in the virtual address space of the current task into the guest
+	/// physical address space and records the mapping
+	///
+	/// Hypervisor.framework accepts writable and executable guest mappings on Intel
+	/// Macs, where they become EPT entries, as well as on Apple silicon, where they
+	/// become stage 2 translations. Should a configuration reject an `ExecAndWrite`
+	/// mapping, the error of the framework is kept and a context mentions
+	/// `map_mem_wxn` in case W^X is the reason. Returns `Error::BadArg` without mapping anything if
+	/// the region overlaps a mapping of the VM.
+	pub fn map_mem(&self, mem: &mut [u8], gpa: u64, perm: MemPerm) -> Result<(), Error> {
+		self.map_recorded(gpa, mem.len(), perm, || {
+			map_mem(mem, gpa, perm).map_err(|err| wx_error(err, mem, gpa, perm))
+		})
+	}
+
+	/// Maps a region for code that the host writes and the guest executes
+	///
+	/// The region is mapped writable first and then protected to `ExecAndRead`, so
+	/// the guest never sees it writable and executable at once. The host may still
+	/// update the code through `mem`. Returns `Error::BadArg` without mapping
+	/// anything if the region overlaps a mapping of the VM.
+	pub fn map_mem_wxn(&self, mem: &mut [u8], gpa: u64) -> Result<(), Error> {
+		let size = mem.len();
+		self.map_recorded(gpa, size, MemPerm::ExecAndRead, || {
+			map_mem(mem, gpa, MemPerm::Write)?;
+			protect_no_flush(gpa, size, MemPerm::ExecAndRead).inspect_err(|_| {
+				let _ = unmap_mem(gpa, size);
+			})
+		})?;
+
+		// a VirtualCpu may have cached the writable translation in the meantime
+		#[cfg(target_arch = "x86_64")]
+		request_tlb_flush()?;
+
+		Ok(())
+	}
+
+	/// Maps the first `size` bytes of `backing` at `gpa` as a new memory slot
+	///
+	/// Returns `Error::BadArg` without mapping anything if the range overlaps any
+	/// mapping of the VM or if `size` is zero or exceeds `backing`. The slot keeps
+	/// `backing` alive until it is removed by `remove_slot`.
+	pub fn add_slot(
+		&self,
+		gpa: u64,
+		size: usize,
+		perm: MemPerm,
+		backing: GuestMemory,
+	) -> Result<SlotId, Error> {
+		if size == 0 || size > backing.size() || gpa.checked_add(size as u64).is_none() {
+			return Err(Error::BadArg);
+		}
+
+		let mut mappings = lock(&self.mappings);
+		if !overlapping(&mappings, gpa, size).is_empty() {
+			return Err(Error::BadArg);
+		}
+		unsafe { map_mem_raw(backing.as_ptr(), size, gpa, perm)? };
+		mappings.insert(gpa, Mapping { gpa, size, perm });
+		drop(mappings);
+
+		let id = SlotId(self.next_slot.fetch_add(1, Ordering::Relaxed));
+		lock(&self.slots).insert(id, Slot { gpa, size, backing });
+
+		Ok(id)
+	}
+
+	/// Unmaps a memory slot and returns its backing memory
+	///
+	/// Returns `Error::BadArg` if the slot doesn't exist. If the slot can't be
+	/// unmapped, it is kept with its mappings, since the guest may still access the
+	/// backing memory.
+	pub fn remove_slot(&self, id: SlotId) -> Result<GuestMemory, Error> {
+		let mut mappings = lock(&self.mappings);
+		let mut slots = lock(&self.slots);
+		let slot = slots.get(&id).ok_or(Error::BadArg)?;
+
+		unmap_mem(slot.gpa, slot.size)?;
+		for start in overlapping(&mappings, slot.gpa, slot.size) {
+			mappings.remove(&start);
+		}
+
+		Ok(slots.remove(&id).unwrap().backing)
+	}
+
+	/// Returns the host address that backs the guest physical address
+	///
+	/// Only memory slots are considered, since the VM doesn't own the host memory
+	/// of other mappings. Returns `None` if no slot contains the address.
+	pub fn gpa_to_host(&self, gpa: u64) -> Option<*mut u8> {
+		lock(&self.slots)
+			.values()
+			.find(|slot| gpa >= slot.gpa && gpa < slot.gpa + slot.size as u64)
+			.map(|slot| unsafe { slot.backing.as_ptr().add((gpa - slot.gpa) as usize) })
+	}
+
+	/// Returns the `len` bytes of slot memory starting at the guest physical address
+	///
+	/// Returns `None` unless a single slot contains the whole range. Since running
+	/// VirtualCpus may access the memory at the same time, the `GuestSlice` only
+	/// copies from and into it. It keeps the host memory alive if the slot is
+	/// removed, but doesn't keep the range mapped into the guest.
+	pub fn gpa_to_slice(&self, gpa: u64, len: usize) -> Option<GuestSlice> {
+		let end = gpa.checked_add(len as u64)?;
+		let slots = lock(&self.slots);
+		let slot = slots
+			.values()
+			.find(|slot| gpa >= slot.gpa && end <= slot.gpa + slot.size as u64)?;
+
+		slot.backing.slice((gpa - slot.gpa) as usize, len)
+	}
+
+	/// Copies the slot memory starting at the guest physical address into `buf`
+	///
+	/// The range may span several adjacent slots. Returns `Error::BadArg` without
+	/// copying anything if any byte of the range isn't backed by a slot.
+	pub fn guest_read(&self, gpa: u64, buf: &mut [u8]) -> Result<(), Error> {
+		let mut pos = 0;
+		for (backing, offset, len) in self.slot_chunks(gpa, buf.len())? {
+			backing.read(offset, &mut buf[pos..pos + len])?;
+			pos += len;
+		}
+
+		Ok(())
+	}
+
+	/// Copies `data` into the slot memory starting at the guest physical address
+	///
+	/// The range may span several adjacent slots. Returns `Error::BadArg` without
+	/// copying anything if any byte of the range isn't backed by a slot.
+	pub fn guest_write(&self, gpa: u64, data: &[u8]) -> Result<(), Error> {
+		let mut pos = 0;
+		for (backing, offset, len) in self.slot_chunks(gpa, data.len())? {
+			backing.write(offset, &data[pos..pos + len])?;
+			pos += len;
+		}
+
+		Ok(())
+	}
+
+	// Splits the range at slot boundaries and returns the backing memory, the offset
+	// into it and the length of each piece
+	fn slot_chunks(&self, gpa: u64, len: usize) -> Result<Vec<(GuestMemory, usize, usize)>, Error> {
+		let end = gpa.checked_add(len as u64).ok_or(Error::BadArg)?;
+		let slots = lock(&self.slots);
+		let mut chunks = Vec::new();
+		let mut addr = gpa;
+
+		while addr < end {
+			let slot = slots
+				.values()
+				.find(|slot| addr >= slot.gpa && addr < slot.gpa + slot.size as u64)
+				.ok_or(Error::BadArg)?;
+			let chunk_end = end.min(slot.gpa + slot.size as u64);
+			chunks.push((
+				slot.backing.clone(),
+				(addr - slot.gpa) as usize,
+				(chunk_end - addr) as usize,
+			));
+			addr = chunk_end;
+		}
+
+		Ok(chunks)
+	}
+
+	/// Returns the mappings established through the VM, ordered by guest physical address
+	pub fn mappings(&self) -> Vec<Mapping> {
+		lock(&self.mappings).values().copied().collect()
+	}
+
+	/// Returns the permissions of the mapping that contains the guest physical address
+	pub fn permissions_at(&self, gpa: u64) -> Option<MemPerm> {
+		lock(&self.mappings)
+			.range(..=gpa)
+			.next_back()
+			.filter(|(_, mapping)| mapping.end() > gpa)
+			.map(|(_, mapping)| mapping.perm)
+	}
+
+	/// Starts tracking the pages of a range that are written by the guest
+	///
+	/// Returns `Error::BadArg` unless the range is aligned to the host page size and
+	/// lies within a single mapping of the VM. The recorded permissions of the
+	/// mapping are unaffected, the write protection only lasts until the returned
+	/// log is dropped.
+	pub fn enable_dirty_tracking(&self, gpa: u64, size: usize) -> Result<DirtyLog, Error> {
+		let end = gpa.checked_add(size as u64).ok_or(Error::BadArg)?;
+		let perm = lock(&self.mappings)
+			.range(..=gpa)
+			.next_back()
+			.filter(|(_, mapping)| end <= mapping.end())
+			.map(|(_, mapping)| mapping.perm)
+			.ok_or(Error::BadArg)?;
+
+		DirtyLog::new(gpa, size, perm)
+	}
+
+	/// Modifies the permissions of a range in the guest physical address space
+	///
+	/// Mappings that only partially overlap the range are split, so that
+	/// `mappings` and `permissions_at` reflect the new permissions exactly.
+	///
+	/// On x86_64, a VirtualCpu may keep using translations with the old permissions
+	/// from its TLB until the TLB is invalidated, so a page that was made read-only
+	/// might still be written. Since a TLB can only be invalidated on the thread of
+	/// its VirtualCpu, every VirtualCpu registered with the VM is interrupted and
+	/// invalidates its TLB at the start of its next `run` if the new permissions
+	/// take a right away. VirtualCpus created by `VirtualCpu::new_unbound` have to
+	/// call `invalidate_tlb` themselves.
+	pub fn protect_range(&self, gpa: u64, size: usize, perm: MemPerm) -> Result<(), Error> {
+		#[cfg(target_arch = "x86_64")]
+		let narrowed = narrows(&lock(&self.mappings), gpa, size, perm);
+		self.protect_range_no_flush(gpa, size, perm)?;
+
+		#[cfg(target_arch = "x86_64")]
+		if narrowed {
+			request_tlb_flush()?;
+		}
+
+		Ok(())
+	}
+
+	/// Modifies the permissions of a range like `protect_range` without invalidating
+	/// the TLBs of the VirtualCpus
+	///
+	/// For batches of changes, the caller has to call `VirtualCpu::invalidate_tlb`
+	/// on every VirtualCpu afterwards, before the guest relies on the new permissions.
+	pub fn protect_range_no_flush(
+		&self,
+		gpa: u64,
+		size: usize,
+		perm: MemPerm,
+	) -> Result<(), Error> {
+		protect_no_flush(gpa, size, perm)?;
+		record_protection(&mut lock(&self.mappings), gpa, size, perm);
+
+		Ok(())
+	}
+
+	/// Unmaps every mapping established through the VM
+	///
+	/// Continues after a failed unmap and returns the first error. Mappings that
+	/// couldn't be unmapped stay recorded. Memory slots without any mapping left
+	/// are removed.
+	pub fn unmap_all(&mut self) -> Result<(), Error> {
+		let mut mappings = lock(&self.mappings);
+		let mut result = Ok(());
+
+		mappings.retain(|_, mapping| match unmap_mem(mapping.gpa, mapping.size) {
+			Ok(()) => false,
+			Err(err) => {
+				if result.is_ok() {
+					result = Err(err);
+				}
+				true
+			}
+		});
+		lock(&self.slots).retain(|_, slot| !overlapping(&mappings, slot.gpa, slot.size).is_empty());
+
+		result
+	}
+
+	// Establishes a mapping through `map` and records it, unless the range overlaps
+	// a mapping of the VM
+	fn map_recorded<F: FnOnce() -> Result<(), Error>>(
+		&self,
+		gpa: u64,
+		size: usize,
+		perm: MemPerm,
+		map: F,
+	) -> Result<(), Error> {
+		if gpa.checked_add(size as u64).is_none() {
+			return Err(Error::BadArg);
+		}
+
+		let mut mappings = lock(&self.mappings);
+		if !overlapping(&mappings, gpa, size).is_empty() {
+			return Err(Error::BadArg);
+		}
+		map()?;
+		mappings.insert(gpa, Mapping { gpa, size, perm });
+
+		Ok(())
+	}
+
+	/// Maps the content of a host file into the guest physical address space
+	///
+	/// The file is mapped copy-on-write, i.e. writes of the guest never reach the
+	/// file on disk. If the file length isn't a multiple of the host page size, the
+	/// mapping is rounded up and the tail of the last page reads as zero. The host
+	/// mapping is readable and, if `perm` allows writes, writable. Execute permission
+	/// applies to the guest only. The mapping is recorded until the region is dropped.
+	/// Failures to open or map the file are returned as `Error::Io`. Returns
+	/// `Error::BadArg` without mapping anything if the range overlaps a mapping of
+	/// the VM.
+	pub fn map_file(&self, path: &Path, gpa: u64, perm: MemPerm) -> Result<MemRegion, Error> {
+		let file = File::open(path)?;
+		let len = file.metadata()?.len() as usize;
+		if len == 0 {
+			return Err(Error::BadArg);
+		}
+
+		let page_size = page_size();
+		let size = (len + page_size - 1) & !(page_size - 1);
+		let prot = match perm {
+			MemPerm::Write | MemPerm::ExecAndWrite => PROT_READ | PROT_WRITE,
+			MemPerm::Read | MemPerm::Exec | MemPerm::ExecAndRead => PROT_READ,
+		};
+
+		let host = unsafe {
+			mmap(
+				ptr::null_mut(),
+				size,
+				prot,
+				MAP_PRIVATE,
+				file.as_raw_fd(),
+				0,
+			)
+		};
+		if host == MAP_FAILED {
+			return Err(Error::Io(io::Error::last_os_error())
+				.context(format!("failed to map {} into the host", path.display())));
+		}
+
+		// the region unmaps the host memory if the guest mapping fails
+		let mut region = MemRegion {
+			host: host as *mut u8,
+			gpa,
+			size,
+			mappings: Weak::new(),
+		};
+		self.map_recorded(gpa, size, perm, || unsafe {
+			map_mem_raw(region.host, size, gpa, perm)
+		})?;
+		region.mappings = Arc::downgrade(&self.mappings);
+
+		Ok(region)
+	}
+}
+
+impl Drop for Vm {
+	fn drop(&mut self) {
+		// drop can't return the error, so it is only traced
+		if let Err(_err) = self.teardown() {
+			#[cfg(feature = "tracing")]
+			tracing::warn!(error = %_err, "failed to destroy the Vm");
+		}
+	}
+}
+
+/// Region of host memory mapped into the guest physical address space
+///
+/// The region is unmapped from the guest and released on the host when it is dropped.
+#[derive(Debug)]
+pub struct MemRegion {
+	/// Start of the host mapping
+	host: *mut u8,
+	/// Guest physical address of the region
+	gpa: u64,
+	/// Size of the region in bytes
+	size: usize,
+	/// Mappings of the VM that recorded the region
+	mappings: Weak<Mappings>,
+}
+
+impl MemRegion {
+	/// Returns the guest physical address of the region
+	pub fn gpa(&self) -> u64 {
+		self.gpa
+	}
+
+	/// Returns the size of the region in bytes
+	pub fn size(&self) -> usize {
+		self.size
+	}
+
+	/// Returns the content of the region as seen by the guest
+	pub fn as_slice(&self) -> &[u8] {
+		unsafe { slice::from_raw_parts(self.host, self.size) }
+	}
+
+	/// Changes the guest permissions of the region and the recorded mappings
+	///
+	/// Like `Vm::protect_range`, recorded mappings that only partially overlap the
+	/// region are split and the VirtualCpus of the VM invalidate their TLBs if the
+	/// new permissions take a right away. The permissions of the host mapping are
+	/// left unchanged. Returns `Error::BadArg` if the VM is gone.
+	pub fn protect(&self, perm: MemPerm) -> Result<(), Error> {
+		let mappings = self.mappings.upgrade().ok_or(Error::BadArg)?;
+		let mut mappings = lock(&mappings);
+
+		#[cfg(target_arch = "x86_64")]
+		let narrowed = narrows(&mappings, self.gpa, self.size, perm);
+		protect_no_flush(self.gpa, self.size, perm)?;
+		record_protection(&mut mappings, self.gpa, self.size, perm);
+		drop(mappings);
+
+		#[cfg(target_arch = "x86_64")]
+		if narrowed {
+			request_tlb_flush()?;
+		}
+
+		Ok(())
+	}
+
+	/// Unmaps the region from the guest and releases it on the host
+	///
+	/// Unlike dropping the region, this reports a failure to unmap it. The guest may
+	/// still access the region then, so it is handed back with the error instead of
+	/// being released.
+	pub fn unmap(self) -> Result<(), (MemRegion, Error)> {
+		if let Some(mappings) = self.mappings.upgrade() {
+			let mut mappings = lock(&mappings);
+			if !overlapping(&mappings, self.gpa, self.size).is_empty() {
+				if let Err(err) = unmap_mem(self.gpa, self.size) {
+					drop(mappings);
+					return Err((self, err));
+				}
+				for start in overlapping(&mappings, self.gpa, self.size) {
+					mappings.remove(&start);
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for MemRegion {
+	fn drop(&mut self) {
+		// the region may already be unmapped by Vm::unmap_all or by destroying the VM
+		if let Some(mappings) = self.mappings.upgrade() {
+			let mut mappings = lock(&mappings);
+			for start in overlapping(&mappings, self.gpa, self.size) {
+				let mapping = mappings.remove(&start).unwrap();
+				let _ = unmap_mem(mapping.gpa, mapping.size);
+			}
+		}
+		unsafe {
+			munmap(self.host as *mut c_void, self.size);
+		}
+	}
+}
+
+// Adds a hint to the rejection of a writable and executable mapping
+//
+// The framework doesn't tell why it rejected the mapping, so the hint only names
+// W^X as a possibility, and only if the range itself looks valid.
+fn wx_error(err: Error, mem: &[u8], gpa: u64, perm: MemPerm) -> Error {
+	let page_size = page_size();
+	let valid_range = !mem.is_empty()
+		&& (mem.as_ptr() as usize).is_multiple_of(page_size)
+		&& mem.len().is_multiple_of(page_size)
+		&& gpa.is_multiple_of(page_size as u64);
+
+	match (perm, err.root_cause()) {
+		(MemPerm::ExecAndWrite, Error::BadArg | Error::Unsupp) if valid_range => err.context(
+			"the mapping was writable and executable; if the host enforces W^X, use map_mem_wxn",
+		),
+		_ => err,
+	}
+}
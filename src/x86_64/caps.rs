@@ -0,0 +1,164 @@
+//! Runtime detection of VMX features of the host
+
+use crate::x86_64::consts::vmx_cap::*;
+use crate::x86_64::{read_vmx_cap, VMXCap};
+use crate::Error;
+use core::fmt;
+
+/// VMX feature that the host may or may not support
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Feature {
+	/// Virtual NMIs
+	VirtualNmi,
+	/// VMX-preemption timer
+	PreemptionTimer,
+	/// Posted interrupts
+	PostedInterrupts,
+	/// TPR shadow
+	TprShadow,
+	/// Monitor trap flag
+	MonitorTrapFlag,
+	/// I/O bitmaps
+	IoBitmaps,
+	/// MSR bitmaps
+	MsrBitmaps,
+	/// Virtualization of APIC accesses
+	VirtualApic,
+	/// Descriptor-table exiting
+	DescriptorTableExiting,
+	/// `RDTSCP` in the guest
+	Rdtscp,
+	/// Virtualization of x2APIC mode
+	X2Apic,
+	/// Virtual processor identifiers
+	Vpid,
+	/// Unrestricted guest
+	UnrestrictedGuest,
+	/// APIC-register virtualization
+	ApicRegisterVirtualization,
+	/// Virtual-interrupt delivery
+	VirtualInterruptDelivery,
+	/// PAUSE-loop exiting
+	PauseLoopExiting,
+	/// `INVPCID` in the guest
+	Invpcid,
+	/// `XSAVES`/`XRSTORS` in the guest
+	Xsaves,
+	/// IA-32e mode guests
+	GuestIa32e,
+	/// Loading of IA32_EFER on VM entry
+	LoadEfer,
+}
+
+impl Feature {
+	// Returns the capability and the control bit that enables the feature
+	fn control(&self) -> (VMXCap, u64) {
+		match self {
+			Feature::VirtualNmi => (VMXCap::PINBASED, PIN_BASED_VIRTUAL_NMI),
+			Feature::PreemptionTimer => (VMXCap::PINBASED, PIN_BASED_PREEMPTION_TIMER),
+			Feature::PostedInterrupts => (VMXCap::PINBASED, PIN_BASED_POSTED_INTR),
+			Feature::TprShadow => (VMXCap::PROCBASED, CPU_BASED_TPR_SHADOW),
+			Feature::MonitorTrapFlag => (VMXCap::PROCBASED, CPU_BASED_MTF),
+			Feature::IoBitmaps => (VMXCap::PROCBASED, CPU_BASED_IO_BITMAPS),
+			Feature::MsrBitmaps => (VMXCap::PROCBASED, CPU_BASED_MSR_BITMAPS),
+			Feature::VirtualApic => (VMXCap::PROCBASED2, CPU_BASED2_VIRTUAL_APIC),
+			Feature::DescriptorTableExiting => (VMXCap::PROCBASED2, CPU_BASED2_DESC_TABLE),
+			Feature::Rdtscp => (VMXCap::PROCBASED2, CPU_BASED2_RDTSCP),
+			Feature::X2Apic => (VMXCap::PROCBASED2, CPU_BASED2_X2APIC),
+			Feature::Vpid => (VMXCap::PROCBASED2, CPU_BASED2_VPID),
+			Feature::UnrestrictedGuest => (VMXCap::PROCBASED2, CPU_BASED2_UNRESTRICTED),
+			Feature::ApicRegisterVirtualization => (VMXCap::PROCBASED2, CPU_BASED2_APIC_REG_VIRT),
+			Feature::VirtualInterruptDelivery => {
+				(VMXCap::PROCBASED2, CPU_BASED2_VIRT_INTR_DELIVERY)
+			}
+			Feature::PauseLoopExiting => (VMXCap::PROCBASED2, CPU_BASED2_PAUSE_LOOP),
+			Feature::Invpcid => (VMXCap::PROCBASED2, CPU_BASED2_INVPCID),
+			Feature::Xsaves => (VMXCap::PROCBASED2, CPU_BASED2_XSAVES_XRSTORS),
+			Feature::GuestIa32e => (VMXCap::ENTRY, VMENTRY_GUEST_IA32E),
+			Feature::LoadEfer => (VMXCap::ENTRY, VMENTRY_LOAD_EFER),
+		}
+	}
+}
+
+impl fmt::Display for Feature {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let name = match self {
+			Feature::VirtualNmi => "virtual NMIs",
+			Feature::PreemptionTimer => "VMX-preemption timer",
+			Feature::PostedInterrupts => "posted interrupts",
+			Feature::TprShadow => "TPR shadow",
+			Feature::MonitorTrapFlag => "monitor trap flag",
+			Feature::IoBitmaps => "I/O bitmaps",
+			Feature::MsrBitmaps => "MSR bitmaps",
+			Feature::VirtualApic => "APIC access virtualization",
+			Feature::DescriptorTableExiting => "descriptor-table exiting",
+			Feature::Rdtscp => "RDTSCP",
+			Feature::X2Apic => "x2APIC virtualization",
+			Feature::Vpid => "VPID",
+			Feature::UnrestrictedGuest => "unrestricted guest",
+			Feature::ApicRegisterVirtualization => "APIC-register virtualization",
+			Feature::VirtualInterruptDelivery => "virtual-interrupt delivery",
+			Feature::PauseLoopExiting => "PAUSE-loop exiting",
+			Feature::Invpcid => "INVPCID",
+			Feature::Xsaves => "XSAVES/XRSTORS",
+			Feature::GuestIa32e => "IA-32e mode guest",
+			Feature::LoadEfer => "load IA32_EFER on VM entry",
+		};
+
+		f.write_str(name)
+	}
+}
+
+/// VMX capabilities of the host processor
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+	/// Pin-based VMX capabilities
+	pub pinbased: u64,
+	/// Primary proc-based VMX capabilities
+	pub procbased: u64,
+	/// Secondary proc-based VMX capabilities
+	pub procbased2: u64,
+	/// VM-entry VMX capabilities
+	pub entry: u64,
+	/// VM-exit VMX capabilities
+	pub exit: u64,
+}
+
+impl Capabilities {
+	/// Reads the VMX capabilities of the host processor
+	pub fn read() -> Result<Capabilities, Error> {
+		Ok(Capabilities {
+			pinbased: read_vmx_cap(VMXCap::PINBASED)?,
+			procbased: read_vmx_cap(VMXCap::PROCBASED)?,
+			procbased2: read_vmx_cap(VMXCap::PROCBASED2)?,
+			entry: read_vmx_cap(VMXCap::ENTRY)?,
+			exit: read_vmx_cap(VMXCap::EXIT)?,
+		})
+	}
+
+	/// Returns `true` if the host allows to enable the feature
+	pub fn supports(&self, feature: Feature) -> bool {
+		let (cap, bit) = feature.control();
+		let value = match cap {
+			VMXCap::PINBASED => self.pinbased,
+			VMXCap::PROCBASED => self.procbased,
+			VMXCap::PROCBASED2 => self.procbased2,
+			VMXCap::ENTRY => self.entry,
+			VMXCap::EXIT | VMXCap::PREEMPTION_TIMER => self.exit,
+		};
+
+		(value >> 32) & bit != 0
+	}
+
+	/// Checks that the host supports all features
+	///
+	/// Returns `Error::Unsupp` with the first missing feature as context otherwise.
+	pub fn require(&self, features: &[Feature]) -> Result<(), Error> {
+		match features.iter().find(|feature| !self.supports(**feature)) {
+			Some(feature) => {
+				Err(Error::Unsupp.context(format!("host doesn't support {}", feature)))
+			}
+			None => Ok(()),
+		}
+	}
+}
@@ -82,3 +82,72 @@ pub const VMX_REASON_VMFUNC: u64 = 59;
 pub const VMX_REASON_RDSEED: u64 = 61;
 pub const VMX_REASON_XSAVES: u64 = 63;
 pub const VMX_REASON_XRSTORS: u64 = 64;
+
+/// Returns a descriptive name of a basic VM exit reason
+///
+/// Unknown reasons are reported as `"unknown"`.
+pub fn vmx_reason_name(code: u16) -> &'static str {
+	match u64::from(code) {
+		VMX_REASON_EXC_NMI => "exception or NMI",
+		VMX_REASON_IRQ => "external interrupt",
+		VMX_REASON_TRIPLE_FAULT => "triple fault",
+		VMX_REASON_INIT => "INIT signal",
+		VMX_REASON_SIPI => "startup IPI",
+		VMX_REASON_IO_SMI => "I/O SMI",
+		VMX_REASON_OTHER_SMI => "other SMI",
+		VMX_REASON_IRQ_WND => "interrupt window",
+		VMX_REASON_VIRTUAL_NMI_WND => "NMI window",
+		VMX_REASON_TASK => "task switch",
+		VMX_REASON_CPUID => "CPUID",
+		VMX_REASON_GETSEC => "GETSEC",
+		VMX_REASON_HLT => "HLT",
+		VMX_REASON_INVD => "INVD",
+		VMX_REASON_INVLPG => "INVLPG",
+		VMX_REASON_RDPMC => "RDPMC",
+		VMX_REASON_RDTSC => "RDTSC",
+		VMX_REASON_RSM => "RSM",
+		VMX_REASON_VMCALL => "VMCALL",
+		VMX_REASON_VMCLEAR => "VMCLEAR",
+		VMX_REASON_VMLAUNCH => "VMLAUNCH",
+		VMX_REASON_VMPTRLD => "VMPTRLD",
+		VMX_REASON_VMPTRST => "VMPTRST",
+		VMX_REASON_VMREAD => "VMREAD",
+		VMX_REASON_VMRESUME => "VMRESUME",
+		VMX_REASON_VMWRITE => "VMWRITE",
+		VMX_REASON_VMOFF => "VMXOFF",
+		VMX_REASON_VMON => "VMXON",
+		VMX_REASON_MOV_CR => "control register access",
+		VMX_REASON_MOV_DR => "debug register access",
+		VMX_REASON_IO => "I/O instruction",
+		VMX_REASON_RDMSR => "RDMSR",
+		VMX_REASON_WRMSR => "WRMSR",
+		VMX_REASON_VMENTRY_GUEST => "VM-entry failure due to invalid guest state",
+		VMX_REASON_VMENTRY_MSR => "VM-entry failure due to MSR loading",
+		VMX_REASON_MWAIT => "MWAIT",
+		VMX_REASON_MTF => "monitor trap flag",
+		VMX_REASON_MONITOR => "MONITOR",
+		VMX_REASON_PAUSE => "PAUSE",
+		VMX_REASON_VMENTRY_MC => "VM-entry failure due to machine-check event",
+		VMX_REASON_TPR_THRESHOLD => "TPR below threshold",
+		VMX_REASON_APIC_ACCESS => "APIC access",
+		VMX_REASON_VIRTUALIZED_EOI => "virtualized EOI",
+		VMX_REASON_GDTR_IDTR => "GDTR or IDTR access",
+		VMX_REASON_LDTR_TR => "LDTR or TR access",
+		VMX_REASON_EPT_VIOLATION => "EPT violation",
+		VMX_REASON_EPT_MISCONFIG => "EPT misconfiguration",
+		VMX_REASON_EPT_INVEPT => "INVEPT",
+		VMX_REASON_RDTSCP => "RDTSCP",
+		VMX_REASON_VMX_TIMER_EXPIRED => "VMX-preemption timer expired",
+		VMX_REASON_INVVPID => "INVVPID",
+		VMX_REASON_WBINVD => "WBINVD",
+		VMX_REASON_XSETBV => "XSETBV",
+		VMX_REASON_APIC_WRITE => "APIC write",
+		VMX_REASON_RDRAND => "RDRAND",
+		VMX_REASON_INVPCID => "INVPCID",
+		VMX_REASON_VMFUNC => "VMFUNC",
+		VMX_REASON_RDSEED => "RDSEED",
+		VMX_REASON_XSAVES => "XSAVES",
+		VMX_REASON_XRSTORS => "XRSTORS",
+		_ => "unknown",
+	}
+}
@@ -0,0 +1,124 @@
+//! Hardware breakpoints in the debug registers
+
+use crate::x86_64::{Register, VirtualCpu};
+use crate::Error;
+
+/// Number of breakpoints, one per address register DR0 to DR3
+pub const HW_BREAKPOINTS: u8 = 4;
+
+/// Address registers of the breakpoints
+const ADDRESS_REGISTERS: [Register; HW_BREAKPOINTS as usize] =
+	[Register::DR0, Register::DR1, Register::DR2, Register::DR3];
+
+/// Access that triggers a hardware breakpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BreakKind {
+	/// Instruction fetch
+	Execute,
+	/// Data write
+	Write,
+	/// Data read or write
+	ReadWrite,
+}
+
+impl BreakKind {
+	// Returns the R/W field of DR7 for the access
+	fn bits(self) -> u64 {
+		match self {
+			BreakKind::Execute => 0b00,
+			BreakKind::Write => 0b01,
+			BreakKind::ReadWrite => 0b11,
+		}
+	}
+}
+
+/// Length of the memory range watched by a hardware breakpoint
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BreakLen {
+	/// One byte
+	One,
+	/// Two bytes
+	Two,
+	/// Four bytes
+	Four,
+	/// Eight bytes
+	Eight,
+}
+
+impl BreakLen {
+	/// Returns the length in bytes
+	pub fn bytes(self) -> u64 {
+		match self {
+			BreakLen::One => 1,
+			BreakLen::Two => 2,
+			BreakLen::Four => 4,
+			BreakLen::Eight => 8,
+		}
+	}
+
+	// Returns the LEN field of DR7 for the length
+	fn bits(self) -> u64 {
+		match self {
+			BreakLen::One => 0b00,
+			BreakLen::Two => 0b01,
+			BreakLen::Four => 0b11,
+			BreakLen::Eight => 0b10,
+		}
+	}
+}
+
+// Returns the DR7 bits that belong to `slot`: its local and global enable bits and
+// its R/W and LEN fields
+fn slot_mask(slot: u8) -> u64 {
+	(0b11 << (2 * slot)) | (0b1111 << (16 + 4 * slot))
+}
+
+impl VirtualCpu<'_> {
+	/// Arms hardware breakpoint `slot` at the linear address `addr`
+	///
+	/// The address is written to the DR0 to DR3 register of the slot and the
+	/// breakpoint is locally enabled in DR7. A hit raises a debug exception (#DB),
+	/// which exits to the host if bit 1 of the exception bitmap is set. Execute
+	/// breakpoints must have a length of one byte and data breakpoints must be
+	/// aligned to their length, otherwise `Error::BadArg` is returned.
+	pub fn set_hw_breakpoint(
+		&self,
+		slot: u8,
+		addr: u64,
+		kind: BreakKind,
+		len: BreakLen,
+	) -> Result<(), Error> {
+		if slot >= HW_BREAKPOINTS {
+			return Err(Error::BadArg);
+		}
+		if kind == BreakKind::Execute && len != BreakLen::One {
+			return Err(Error::BadArg.context("execute breakpoints must be one byte long"));
+		}
+		if !addr.is_multiple_of(len.bytes()) {
+			return Err(Error::BadArg.context("breakpoint address isn't aligned to its length"));
+		}
+
+		let dr7 = self.read_register(&Register::DR7)? & !slot_mask(slot);
+		let dr7 = dr7
+			| (1 << (2 * slot))
+			| (kind.bits() << (16 + 4 * slot))
+			| (len.bits() << (18 + 4 * slot));
+
+		self.write_register(&ADDRESS_REGISTERS[usize::from(slot)], addr)?;
+		self.write_register(&Register::DR7, dr7)
+	}
+
+	/// Disarms hardware breakpoint `slot`
+	///
+	/// Clears the enable bits and the fields of the slot in DR7 and its address
+	/// register.
+	pub fn clear_hw_breakpoint(&self, slot: u8) -> Result<(), Error> {
+		if slot >= HW_BREAKPOINTS {
+			return Err(Error::BadArg);
+		}
+
+		let dr7 = self.read_register(&Register::DR7)? & !slot_mask(slot);
+		self.write_register(&Register::DR7, dr7)?;
+		self.write_register(&ADDRESS_REGISTERS[usize::from(slot)], 0)
+	}
+}
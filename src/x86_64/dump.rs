@@ -0,0 +1,191 @@
+//! Textual dump of the state of a VirtualCpu
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::{Register, VirtualCpu, VmcsField};
+use crate::Error;
+use core::fmt::{self, Write};
+
+/// Radix of the values in a state dump
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Radix {
+	/// Zero-padded hexadecimal with `0x` prefix
+	Hex,
+	/// Decimal
+	Decimal,
+}
+
+/// Selects the sections and the format of `VirtualCpu::dump_state_with`
+///
+/// The default dumps every section in hexadecimal.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DumpOptions {
+	/// RIP, RFLAGS and the general purpose registers
+	pub gprs: bool,
+	/// Selector, base, limit and access rights of the segment registers
+	pub segments: bool,
+	/// Control registers and EFER
+	pub control: bool,
+	/// VM-execution, VM-entry and VM-exit controls
+	pub vmcs_controls: bool,
+	/// Radix of the values
+	pub radix: Radix,
+}
+
+impl Default for DumpOptions {
+	fn default() -> DumpOptions {
+		DumpOptions {
+			gprs: true,
+			segments: true,
+			control: true,
+			vmcs_controls: true,
+			radix: Radix::Hex,
+		}
+	}
+}
+
+const GPRS: [Register; 18] = [
+	Register::RIP,
+	Register::RFLAGS,
+	Register::RAX,
+	Register::RBX,
+	Register::RCX,
+	Register::RDX,
+	Register::RSI,
+	Register::RDI,
+	Register::RSP,
+	Register::RBP,
+	Register::R8,
+	Register::R9,
+	Register::R10,
+	Register::R11,
+	Register::R12,
+	Register::R13,
+	Register::R14,
+	Register::R15,
+];
+
+/// Segment registers as (name, selector field)
+///
+/// The limit, access rights and base fields follow the selector fields at fixed
+/// offsets in the VMCS encoding.
+const SEGMENTS: [(&str, u32); 8] = [
+	("cs", VMCS_GUEST_CS),
+	("ss", VMCS_GUEST_SS),
+	("ds", VMCS_GUEST_DS),
+	("es", VMCS_GUEST_ES),
+	("fs", VMCS_GUEST_FS),
+	("gs", VMCS_GUEST_GS),
+	("ldtr", VMCS_GUEST_LDTR),
+	("tr", VMCS_GUEST_TR),
+];
+
+const VMCS_CONTROLS: [(&str, u32); 6] = [
+	("pin_based", VMCS_CTRL_PIN_BASED),
+	("cpu_based", VMCS_CTRL_CPU_BASED),
+	("cpu_based2", VMCS_CTRL_CPU_BASED2),
+	("vmentry", VMCS_CTRL_VMENTRY_CONTROLS),
+	("vmexit", VMCS_CTRL_VMEXIT_CONTROLS),
+	("exc_bitmap", VMCS_CTRL_EXC_BITMAP),
+];
+
+/// Value formatted in the radix of a dump
+struct Value(u64, Radix);
+
+impl fmt::Display for Value {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self.1 {
+			Radix::Hex => write!(f, "{:#018x}", self.0),
+			Radix::Decimal => write!(f, "{}", self.0),
+		}
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the sections of the VirtualCpu state selected by `opts` as text
+	///
+	/// Every line names one register or field, so only the selected sections are
+	/// read from the VirtualCpu.
+	pub fn dump_state_with(&self, opts: DumpOptions) -> Result<String, Error> {
+		let mut dump = String::new();
+		let value = |v| Value(v, opts.radix);
+
+		if opts.gprs {
+			for reg in GPRS.iter() {
+				let v = self.read_register(reg)?;
+				let _ = writeln!(dump, "{:<10} {}", reg, value(v));
+			}
+		}
+
+		if opts.segments {
+			let fields: Vec<VmcsField> = SEGMENTS
+				.iter()
+				.flat_map(|(_, selector)| {
+					[
+						*selector,
+						selector_to_base(*selector),
+						selector_to_limit(*selector),
+						selector_to_ar(*selector),
+					]
+				})
+				.map(VmcsField)
+				.collect();
+			let values = self.read_vmcs_many(&fields)?;
+
+			for ((name, _), v) in SEGMENTS.iter().zip(values.chunks_exact(4)) {
+				let _ = writeln!(
+					dump,
+					"{:<10} selector={} base={} limit={} ar={}",
+					name,
+					value(v[0]),
+					value(v[1]),
+					value(v[2]),
+					value(v[3]),
+				);
+			}
+		}
+
+		if opts.control {
+			for reg in [Register::CR0, Register::CR2, Register::CR3, Register::CR4].iter() {
+				let v = self.read_register(reg)?;
+				let _ = writeln!(dump, "{:<10} {}", reg, value(v));
+			}
+			let efer = self.read_vmcs(VMCS_GUEST_IA32_EFER)?;
+			let _ = writeln!(dump, "{:<10} {}", "efer", value(efer));
+		}
+
+		if opts.vmcs_controls {
+			for (name, field) in VMCS_CONTROLS.iter() {
+				let v = self.read_vmcs(*field)?;
+				let _ = writeln!(dump, "{:<10} {}", name, value(v));
+			}
+		}
+
+		Ok(dump)
+	}
+}
+
+// Returns the guest base field of a guest selector field
+fn selector_to_base(selector: u32) -> u32 {
+	VMCS_GUEST_ES_BASE + (selector - VMCS_GUEST_ES)
+}
+
+// Returns the guest limit field of a guest selector field
+fn selector_to_limit(selector: u32) -> u32 {
+	VMCS_GUEST_ES_LIMIT + (selector - VMCS_GUEST_ES)
+}
+
+// Returns the guest access rights field of a guest selector field
+fn selector_to_ar(selector: u32) -> u32 {
+	VMCS_GUEST_ES_AR + (selector - VMCS_GUEST_ES)
+}
+
+impl fmt::Debug for VirtualCpu<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		writeln!(f, "VirtualCpu ID: {}", self.get_id())?;
+
+		match self.dump_state_with(DumpOptions::default()) {
+			Ok(dump) => f.write_str(&dump),
+			Err(err) => write!(f, "state unavailable: {}", err),
+		}
+	}
+}
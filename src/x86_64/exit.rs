@@ -0,0 +1,433 @@
+//! Decoding of VM exits
+
+use crate::x86_64::consts::irq::*;
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::consts::vmx_exit::*;
+use crate::x86_64::setup::CR0_PE;
+use crate::x86_64::{Register, VirtualCpu};
+use crate::Error;
+use core::fmt;
+use std::time::Duration;
+
+/// Vector of the general protection exception (#GP)
+const EXCEPTION_GP: u8 = 13;
+
+/// Vector of the page fault exception (#PF)
+const EXCEPTION_PF: u8 = 14;
+
+/// Bit of the exit reason that is set if the VM entry failed
+const EXIT_REASON_ENTRY_FAILURE: u64 = 1 << 31;
+
+/// Basic VM exit reason (`VMX_REASON_*`)
+///
+/// The `Debug` output contains the name of the reason next to the raw number.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ExitReason(pub u64);
+
+impl ExitReason {
+	/// Returns a descriptive name of the exit reason
+	pub fn name(&self) -> &'static str {
+		vmx_reason_name(self.0 as u16)
+	}
+}
+
+impl fmt::Debug for ExitReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "ExitReason({}, {:?})", self.0, self.name())
+	}
+}
+
+impl fmt::Display for ExitReason {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// Port I/O exit (`VMX_REASON_IO`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IoExit {
+	/// I/O port
+	pub port: u16,
+	/// Access size in bytes (1, 2 or 4)
+	pub size: u8,
+	/// `true` for `IN`, `false` for `OUT`
+	pub is_in: bool,
+	/// String instruction (`INS`/`OUTS`)
+	pub string: bool,
+	/// Instruction has a `REP` prefix
+	pub rep: bool,
+	/// Iterations left for a `REP` instruction, i.e. RCX truncated to the address size
+	pub count: Option<u64>,
+	/// Value written by a non-string `OUT`, i.e. RAX truncated to `size`
+	pub value: Option<u64>,
+	/// Length of the exiting instruction in bytes
+	pub instruction_length: u64,
+}
+
+/// `CPUID` exit (`VMX_REASON_CPUID`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuidExit {
+	/// Requested leaf (EAX)
+	pub leaf: u32,
+	/// Requested subleaf (ECX)
+	pub subleaf: u32,
+	/// Length of the exiting instruction in bytes
+	pub instruction_length: u64,
+}
+
+/// `RDMSR` or `WRMSR` exit (`VMX_REASON_RDMSR`/`VMX_REASON_WRMSR`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MsrExit {
+	/// Accessed MSR (ECX)
+	pub msr: u32,
+	/// Value written by `WRMSR` (EDX:EAX), `None` for `RDMSR`
+	pub value: Option<u64>,
+	/// Length of the exiting instruction in bytes
+	pub instruction_length: u64,
+}
+
+/// EPT violation (`VMX_REASON_EPT_VIOLATION`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EptViolationExit {
+	/// Guest physical address of the access
+	pub gpa: u64,
+	/// The access was a data read
+	pub read: bool,
+	/// The access was a data write
+	pub write: bool,
+	/// The access was an instruction fetch
+	pub exec: bool,
+	/// Raw exit qualification
+	pub qualification: u64,
+}
+
+/// Exception or NMI intercepted by the exception bitmap (`VMX_REASON_EXC_NMI`)
+///
+/// Decoded from `VMCS_RO_VMEXIT_IRQ_INFO` and `VMCS_RO_VMEXIT_IRQ_ERROR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExceptionExit {
+	/// Exception vector, e.g. 6 for #UD
+	pub vector: u8,
+	/// Error code pushed by the exception, if any
+	pub error_code: Option<u32>,
+	/// The interruption information is valid
+	pub valid: bool,
+	/// Raw interruption information
+	pub info: u32,
+}
+
+/// VM exit of a VirtualCpu, decoded from the VMCS and the guest registers
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExitDetails {
+	/// Port I/O
+	Io(IoExit),
+	/// `CPUID` instruction
+	Cpuid(CpuidExit),
+	/// `RDMSR` instruction
+	Rdmsr(MsrExit),
+	/// `WRMSR` instruction
+	Wrmsr(MsrExit),
+	/// Access to guest physical memory that isn't mapped with the required permissions
+	EptViolation(EptViolationExit),
+	/// Guest exception or NMI selected by `VMCS_CTRL_EXC_BITMAP`
+	Exception(ExceptionExit),
+	/// `HLT` instruction
+	Hlt,
+	/// The guest became interruptible after `request_interrupt_window`
+	IrqWindow,
+	/// Any other exit
+	Other {
+		/// Basic exit reason
+		reason: ExitReason,
+		/// Raw exit qualification
+		qualification: u64,
+	},
+}
+
+/// Returns the description of a VM-instruction error number (`VMCS_RO_INSTR_ERROR`)
+pub fn instruction_error_name(code: u64) -> &'static str {
+	match code {
+		1 => "VMCALL executed in VMX root operation",
+		2 => "VMCLEAR with invalid physical address",
+		3 => "VMCLEAR with VMXON pointer",
+		4 => "VMLAUNCH with non-clear VMCS",
+		5 => "VMRESUME with non-launched VMCS",
+		6 => "VMRESUME after VMXOFF",
+		7 => "VM entry with invalid control field(s)",
+		8 => "VM entry with invalid host-state field(s)",
+		9 => "VMPTRLD with invalid physical address",
+		10 => "VMPTRLD with VMXON pointer",
+		11 => "VMPTRLD with incorrect VMCS revision identifier",
+		12 => "VMREAD/VMWRITE from/to unsupported VMCS component",
+		13 => "VMWRITE to read-only VMCS component",
+		15 => "VMXON executed in VMX root operation",
+		16 => "VM entry with invalid executive-VMCS pointer",
+		17 => "VM entry with non-launched executive VMCS",
+		18 => "VM entry with executive-VMCS pointer not VMXON pointer",
+		19 => "VMCALL with non-clear VMCS",
+		20 => "VMCALL with invalid VM-exit control fields",
+		22 => "VMCALL with incorrect MSEG revision identifier",
+		23 => "VMXOFF under dual-monitor treatment of SMIs and SMM",
+		24 => "VMCALL with invalid SMM-monitor features",
+		25 => "VM entry with invalid VM-execution control fields in executive VMCS",
+		26 => "VM entry with events blocked by MOV SS",
+		28 => "invalid operand to INVEPT/INVVPID",
+		_ => "unknown VM-instruction error",
+	}
+}
+
+impl VirtualCpu<'_> {
+	// Adds the VM-instruction error of a failed VM entry to the error of `run`
+	//
+	// The instruction error keeps its value after a later successful entry, so it is
+	// only consulted if the exit reason reports a failed entry or `run` failed with
+	// `Error::Error`, which the framework returns for a failed entry. The error is
+	// returned unchanged otherwise or if the VMCS reports no instruction error.
+	pub(crate) fn entry_error(&self, err: Error) -> Error {
+		let entry_failed = matches!(err.root_cause(), Error::Error)
+			|| matches!(
+				self.read_vmcs(VMCS_RO_EXIT_REASON),
+				Ok(reason) if reason & EXIT_REASON_ENTRY_FAILURE != 0
+			);
+		if !entry_failed {
+			return err;
+		}
+
+		match self.read_vmcs(VMCS_RO_INSTR_ERROR) {
+			Ok(code) if code != 0 => err.context(format!(
+				"VM entry failed with VM-instruction error {} ({})",
+				code,
+				instruction_error_name(code)
+			)),
+			_ => err,
+		}
+	}
+
+	/// Returns the basic reason of the last VM exit (bits 15:0 of `VMCS_RO_EXIT_REASON`)
+	pub fn exit_reason_raw(&self) -> Result<u16, Error> {
+		Ok(self.read_vmcs(VMCS_RO_EXIT_REASON)? as u16)
+	}
+
+	/// Returns the exit qualification of the last VM exit
+	pub fn exit_qualification(&self) -> Result<u64, Error> {
+		self.read_vmcs(VMCS_RO_EXIT_QUALIFIC)
+	}
+
+	/// Returns the length of the instruction that caused the last VM exit in bytes
+	///
+	/// The value is only defined for exits caused by the execution of an instruction.
+	pub fn vmexit_instruction_length(&self) -> Result<u64, Error> {
+		self.read_vmcs(VMCS_RO_VMEXIT_INSTR_LEN)
+	}
+
+	/// Decodes the last VM exit of the VirtualCpu
+	///
+	/// Reads the exit reason, the exit qualification and the registers relevant
+	/// for the exit once and returns them as `ExitDetails`.
+	pub fn exit_details(&self) -> Result<ExitDetails, Error> {
+		let reason = u64::from(self.exit_reason_raw()?);
+
+		let details = match reason {
+			VMX_REASON_IO => {
+				let qual = self.exit_qualification()?;
+				let size = ((qual & 0x7) + 1) as u8;
+				let is_in = qual & (1 << 3) != 0;
+				let string = qual & (1 << 4) != 0;
+				let value = if !is_in && !string {
+					let mask = u64::MAX >> (64 - 8 * u32::from(size));
+					Some(self.read_register(&Register::RAX)? & mask)
+				} else {
+					None
+				};
+
+				let rep = qual & (1 << 5) != 0;
+				let count = if rep {
+					Some(self.read_register(&Register::RCX)? & self.address_size_mask()?)
+				} else {
+					None
+				};
+
+				ExitDetails::Io(IoExit {
+					port: (qual >> 16) as u16,
+					size,
+					is_in,
+					string,
+					rep,
+					count,
+					value,
+					instruction_length: self.vmexit_instruction_length()?,
+				})
+			}
+			VMX_REASON_CPUID => ExitDetails::Cpuid(CpuidExit {
+				leaf: self.read_register(&Register::RAX)? as u32,
+				subleaf: self.read_register(&Register::RCX)? as u32,
+				instruction_length: self.vmexit_instruction_length()?,
+			}),
+			VMX_REASON_RDMSR => ExitDetails::Rdmsr(MsrExit {
+				msr: self.read_register(&Register::RCX)? as u32,
+				value: None,
+				instruction_length: self.vmexit_instruction_length()?,
+			}),
+			VMX_REASON_WRMSR => {
+				let eax = self.read_register(&Register::RAX)? & 0xffffffff;
+				let edx = self.read_register(&Register::RDX)? & 0xffffffff;
+
+				ExitDetails::Wrmsr(MsrExit {
+					msr: self.read_register(&Register::RCX)? as u32,
+					value: Some((edx << 32) | eax),
+					instruction_length: self.vmexit_instruction_length()?,
+				})
+			}
+			VMX_REASON_EPT_VIOLATION => {
+				let qual = self.exit_qualification()?;
+
+				ExitDetails::EptViolation(EptViolationExit {
+					gpa: self.read_vmcs(VMCS_GUEST_PHYSICAL_ADDRESS)?,
+					read: qual & (1 << 0) != 0,
+					write: qual & (1 << 1) != 0,
+					exec: qual & (1 << 2) != 0,
+					qualification: qual,
+				})
+			}
+			VMX_REASON_EXC_NMI => {
+				let info = self.read_vmcs(VMCS_RO_VMEXIT_IRQ_INFO)? as u32;
+				let error_code = if info & IRQ_INFO_ERROR_VALID != 0 {
+					Some(self.read_vmcs(VMCS_RO_VMEXIT_IRQ_ERROR)? as u32)
+				} else {
+					None
+				};
+
+				ExitDetails::Exception(ExceptionExit {
+					vector: info as u8,
+					error_code,
+					valid: info & IRQ_INFO_VALID != 0,
+					info,
+				})
+			}
+			VMX_REASON_HLT => ExitDetails::Hlt,
+			VMX_REASON_IRQ_WND => ExitDetails::IrqWindow,
+			_ => ExitDetails::Other {
+				reason: ExitReason(reason),
+				qualification: self.exit_qualification()?,
+			},
+		};
+
+		Ok(details)
+	}
+
+	/// Executes the VirtualCpu and returns the decoded VM exit with the time spent in the guest
+	///
+	/// The time is the difference of `exec_time` around the run.
+	pub fn run_and_time(&self) -> Result<(ExitDetails, Duration), Error> {
+		let start = self.exec_time()?;
+		self.run()?;
+		let end = self.exec_time()?;
+
+		Ok((self.exit_details()?, Duration::from_nanos(end - start)))
+	}
+
+	/// Advances RIP past the instruction that caused the last VM exit
+	///
+	/// A `REP` string I/O instruction is only retired once its count reaches zero.
+	/// While RCX is nonzero after the VMM has serviced an element, RIP is left
+	/// unchanged so that the guest re-enters the instruction for the next element.
+	/// Returns `Error::BadArg` if the last exit wasn't caused by an instruction
+	/// with a known length.
+	pub fn skip_instruction(&self) -> Result<(), Error> {
+		let length = match self.exit_details()? {
+			ExitDetails::Io(io) => match io.count {
+				Some(count) if count != 0 => return Ok(()),
+				_ => io.instruction_length,
+			},
+			ExitDetails::Cpuid(cpuid) => cpuid.instruction_length,
+			ExitDetails::Rdmsr(msr) | ExitDetails::Wrmsr(msr) => msr.instruction_length,
+			ExitDetails::Hlt => self.vmexit_instruction_length()?,
+			_ => return Err(Error::BadArg),
+		};
+
+		let rip = self.read_register(&Register::RIP)?;
+		self.write_register(&Register::RIP, rip + length)
+	}
+
+	// Returns the mask of the address size of the exiting string I/O instruction
+	fn address_size_mask(&self) -> Result<u64, Error> {
+		match (self.read_vmcs(VMCS_RO_VMX_INSTR_INFO)? >> 7) & 0x7 {
+			0 => Ok(0xffff),
+			1 => Ok(0xffff_ffff),
+			_ => Ok(u64::MAX),
+		}
+	}
+
+	/// Injects a hardware exception into the guest on the next VM entry
+	///
+	/// The exception is delivered through the guest's IDT (or IVT in real mode)
+	/// before the first instruction is executed. `error_code` must be given for
+	/// exceptions that push an error code in the current guest mode.
+	pub fn inject_exception(&self, vector: u8, error_code: Option<u32>) -> Result<(), Error> {
+		let mut info = IRQ_INFO_VALID | IRQ_INFO_HARD_EXC | u32::from(vector);
+		if let Some(error_code) = error_code {
+			info |= IRQ_INFO_ERROR_VALID;
+			self.write_vmcs(VMCS_CTRL_VMENTRY_EXC_ERROR, u64::from(error_code))?;
+		}
+
+		self.write_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO, u64::from(info))
+	}
+
+	/// Delivers an intercepted exception to the guest on the next VM entry
+	///
+	/// The exception is re-injected with its original type and error code, so the
+	/// guest handles it as if the exception bitmap hadn't trapped it. Software
+	/// exceptions (`INT3`, `INTO`, `INT n`) also get the length of the exiting
+	/// instruction. Returns `Error::BadArg` if `exception` isn't valid.
+	pub fn reinject_exception(&self, exception: &ExceptionExit) -> Result<(), Error> {
+		if !exception.valid {
+			return Err(Error::BadArg);
+		}
+
+		let kind = exception.info & (0x7 << 8);
+		let mut info = IRQ_INFO_VALID | kind | u32::from(exception.vector);
+		if let Some(error_code) = exception.error_code {
+			info |= IRQ_INFO_ERROR_VALID;
+			self.write_vmcs(VMCS_CTRL_VMENTRY_EXC_ERROR, u64::from(error_code))?;
+		}
+		if kind == IRQ_INFO_SOFT_IRQ || kind == IRQ_INFO_PRIV_SOFT_EXC || kind == IRQ_INFO_SOFT_EXC
+		{
+			self.write_vmcs(
+				VMCS_CTRL_VMENTRY_INSTR_LEN,
+				self.vmexit_instruction_length()?,
+			)?;
+		}
+
+		self.write_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO, u64::from(info))
+	}
+
+	/// Completes a `RDMSR` or `WRMSR` exit by raising #GP(0) in the guest
+	///
+	/// This is how hardware handles accesses to MSRs that don't exist. RIP isn't
+	/// advanced, so the guest's fault handler sees the faulting instruction.
+	/// Decode the exit with `exit_details` and call this for unknown MSRs instead
+	/// of emulating the access. In real mode no error code is pushed.
+	pub fn complete_msr_with_gp(&self) -> Result<(), Error> {
+		let protected = self.read_register(&Register::CR0)? & CR0_PE != 0;
+
+		self.inject_exception(EXCEPTION_GP, if protected { Some(0) } else { None })
+	}
+
+	/// Injects a page fault at the linear address `fault_addr` into the guest
+	///
+	/// Unlike the processor, `inject_exception` leaves CR2 alone, so a #PF injected
+	/// with it would report a stale address to the guest's handler. This writes
+	/// `fault_addr` to CR2 and then injects vector 14 with `error_code`, which is
+	/// what demand paging emulation needs. The guest's previous CR2 is overwritten.
+	/// In real mode no error code is pushed.
+	pub fn inject_page_fault(&self, fault_addr: u64, error_code: u32) -> Result<(), Error> {
+		let protected = self.read_register(&Register::CR0)? & CR0_PE != 0;
+
+		self.write_register(&Register::CR2, fault_addr)?;
+		self.inject_exception(
+			EXCEPTION_PF,
+			if protected { Some(error_code) } else { None },
+		)
+	}
+}
@@ -34,13 +34,16 @@ pub const HV_BUSY: hv_return_t = 0xfae94002;
 pub const HV_BAD_ARGUMENT: hv_return_t = 0xfae94003;
 pub const HV_NO_RESOURCES: hv_return_t = 0xfae94005;
 pub const HV_NO_DEVICE: hv_return_t = 0xfae94006;
+pub const HV_DENIED: hv_return_t = 0xfae94007;
 pub const HV_UNSUPPORTED: hv_return_t = 0xfae9400f;
 
 /// Options for hv_vcpu_create()
 pub type hv_vm_options_t = u64;
 pub const HV_VM_DEFAULT: hv_vm_options_t = 0 << 0;
+pub const HV_VM_ACCEL_APIC: hv_vm_options_t = 1 << 5;
 
 // Creating and Destroying VM Instances
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Creates a VM instance for the current Mach task
 	pub fn hv_vm_create(flags: hv_vm_options_t) -> hv_return_t;
@@ -56,6 +59,7 @@ pub type hv_vcpuid_t = c_uint;
 pub const HV_VCPU_DEFAULT: u64 = 0;
 
 // Creating and Managing vCPU Instances
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Creates a vCPU instance for the current thread
 	pub fn hv_vcpu_create(vcpu: *mut hv_vcpuid_t, flags: hv_vm_options_t) -> hv_return_t;
@@ -80,6 +84,7 @@ extern "C" {
 }
 
 // Accessing Registers
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Returns the current value of an architectural x86 register
 	/// of a vCPU
@@ -98,6 +103,7 @@ extern "C" {
 }
 
 // Accessing Floating Point (FP) State
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Returns the current architectural x86 floating point and
 	/// SIMD state of a vCPU
@@ -117,6 +123,7 @@ extern "C" {
 }
 
 // Accessing Machine Specific Registers (MSRs)
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Enables an MSR to be used natively by the VM
 	pub fn hv_vcpu_enable_native_msr(vcpu: hv_vcpuid_t, msr: u32, enable: bool) -> hv_return_t;
@@ -129,6 +136,7 @@ extern "C" {
 }
 
 // Managing Timestamp-Counters (TSC)
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Synchronizes guest Timestamp-Counters (TSC) across all vCPUs
 	pub fn hv_vm_sync_tsc(tsc: u64) -> hv_return_t;
@@ -150,6 +158,7 @@ pub const HV_MEMORY_WRITE: hv_memory_flags_t = 1 << 1;
 pub const HV_MEMORY_EXEC: hv_memory_flags_t = 1 << 2;
 
 // Managing Memory Regions
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Maps a region in the virtual address space of the current
 	/// task into the guest physical address space of the VM
@@ -169,6 +178,7 @@ extern "C" {
 }
 
 // Managing Virtual Machine Control Structure (VMCS)
+#[cfg(target_arch = "x86_64")]
 extern "C" {
 	/// Returns the current value of a VMCS field of a vCPU
 	pub fn hv_vmx_vcpu_read_vmcs(vcpu: hv_vcpuid_t, field: u32, value: *mut u64) -> hv_return_t;
@@ -0,0 +1,127 @@
+//! Injection of external interrupts
+
+use crate::x86_64::consts::irq::*;
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::consts::vmx_cap::CPU_BASED_IRQ_WND;
+use crate::x86_64::{Register, VirtualCpu};
+use crate::Error;
+use std::collections::BTreeSet;
+
+/// Interrupt flag in RFLAGS
+const RFLAGS_IF: u64 = 1 << 9;
+/// Blocking by `STI` in the interruptibility state
+const BLOCKING_BY_STI: u64 = 1 << 0;
+/// Blocking by `MOV SS` or `POP SS` in the interruptibility state
+const BLOCKING_BY_MOV_SS: u64 = 1 << 1;
+
+impl VirtualCpu<'_> {
+	/// Returns whether an external interrupt can be injected on the next VM entry
+	///
+	/// The guest must have RFLAGS.IF set, must not be in the interrupt shadow of
+	/// `STI`, `MOV SS` or `POP SS` and no other event may be pending for injection.
+	pub fn can_inject_interrupt(&self) -> Result<bool, Error> {
+		let rflags = self.read_register(&Register::RFLAGS)?;
+		let interruptibility = self.read_vmcs(VMCS_GUEST_IGNORE_IRQ)?;
+		let pending = self.read_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO)?;
+
+		Ok(rflags & RFLAGS_IF != 0
+			&& interruptibility & (BLOCKING_BY_STI | BLOCKING_BY_MOV_SS) == 0
+			&& pending & u64::from(IRQ_INFO_VALID) == 0)
+	}
+
+	/// Injects an external interrupt into the guest on the next VM entry
+	///
+	/// Returns `Error::Busy` without injecting anything if `can_inject_interrupt`
+	/// returns `false`, since the processor would otherwise fail the VM entry or
+	/// deliver the interrupt while the guest can't take it. To inject it as soon as
+	/// possible, call `request_interrupt_window` and retry on the `IrqWindow` exit.
+	pub fn inject_interrupt(&self, vector: u8) -> Result<(), Error> {
+		if !self.can_inject_interrupt()? {
+			return Err(Error::Busy);
+		}
+
+		let info = IRQ_INFO_VALID | IRQ_INFO_EXT_IRQ | u32::from(vector);
+		self.write_vmcs(VMCS_CTRL_VMENTRY_IRQ_INFO, u64::from(info))
+	}
+
+	/// Enables or disables interrupt-window exiting
+	///
+	/// While enabled, the VirtualCpu exits with `ExitDetails::IrqWindow` before the
+	/// first instruction at which the guest has RFLAGS.IF set and isn't in an
+	/// interrupt shadow, i.e. as soon as an external interrupt can be injected.
+	/// The control stays set until it is disabled again.
+	pub fn request_interrupt_window(&self, enable: bool) -> Result<(), Error> {
+		let controls = self.read_vmcs(VMCS_CTRL_CPU_BASED)?;
+		let controls = if enable {
+			controls | CPU_BASED_IRQ_WND
+		} else {
+			controls & !CPU_BASED_IRQ_WND
+		};
+
+		self.write_vmcs(VMCS_CTRL_CPU_BASED, controls)
+	}
+}
+
+/// Set of pending external interrupts, delivered highest vector first
+///
+/// Like the IRR of a local APIC, the queue holds each vector at most once and a
+/// higher vector has a higher priority. `service` is meant to be called before
+/// every `run` of the VirtualCpu, including after `ExitDetails::IrqWindow` exits.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InterruptQueue {
+	pending: BTreeSet<u8>,
+	/// Whether `service` enabled interrupt-window exiting, which was disabled before
+	window: bool,
+}
+
+impl InterruptQueue {
+	/// Creates an empty queue
+	pub fn new() -> InterruptQueue {
+		InterruptQueue::default()
+	}
+
+	/// Marks `vector` as pending, does nothing if it is already pending
+	pub fn push(&mut self, vector: u8) {
+		self.pending.insert(vector);
+	}
+
+	/// Returns whether no interrupt is pending
+	pub fn is_empty(&self) -> bool {
+		self.pending.is_empty()
+	}
+
+	/// Returns the number of pending interrupts
+	pub fn len(&self) -> usize {
+		self.pending.len()
+	}
+
+	/// Injects the highest pending interrupt if the VirtualCpu can take it
+	///
+	/// Returns the injected vector, or `None` if the queue is empty or the guest
+	/// can't take an interrupt on the next VM entry. Interrupt-window exiting is
+	/// enabled as long as interrupts remain pending after this call. Once the queue
+	/// is empty, it is only disabled if the queue enabled it, so a window requested
+	/// by the caller stays open.
+	pub fn service(&mut self, vcpu: &VirtualCpu) -> Result<Option<u8>, Error> {
+		let injected = match self.pending.last() {
+			Some(&vector) if vcpu.can_inject_interrupt()? => {
+				vcpu.inject_interrupt(vector)?;
+				self.pending.remove(&vector);
+				Some(vector)
+			}
+			_ => None,
+		};
+
+		if !self.pending.is_empty() {
+			if !self.window && vcpu.read_vmcs(VMCS_CTRL_CPU_BASED)? & CPU_BASED_IRQ_WND == 0 {
+				vcpu.request_interrupt_window(true)?;
+				self.window = true;
+			}
+		} else if self.window {
+			vcpu.request_interrupt_window(false)?;
+			self.window = false;
+		}
+
+		Ok(injected)
+	}
+}
@@ -1,40 +1,253 @@
+mod caps;
 pub mod consts;
+mod debug;
+mod dump;
+mod exit;
 pub mod ffi;
+mod interrupt;
+mod msr;
+mod regs;
+mod segment;
+mod serial;
+mod setup;
+mod snapshot;
+mod state;
+mod validate;
+mod vmcs;
+mod xsave;
 
+pub use self::caps::{Capabilities, Feature};
+use self::consts::vmcs::*;
+use self::consts::vmx_cap::CPU_BASED_TSC_OFFSET;
+pub use self::debug::{BreakKind, BreakLen, HW_BREAKPOINTS};
+pub use self::dump::{DumpOptions, Radix};
+pub use self::exit::{
+	instruction_error_name, CpuidExit, EptViolationExit, ExceptionExit, ExitDetails, ExitReason,
+	IoExit, MsrExit,
+};
 use self::ffi::*;
-use crate::{match_MemPerm, match_error_code, Error, MemPerm};
+pub use self::interrupt::InterruptQueue;
+pub use self::msr::{ApicBase, Efer};
+pub use self::regs::GpRegs;
+pub use self::segment::{Segment, SegmentRegister};
+pub use self::serial::{SerialConsole, COM1};
+pub use self::setup::build_identity_page_tables;
+pub use self::snapshot::{VmcsCategory, VmcsSnapshot};
+pub use self::state::VcpuState;
+pub use self::vmcs::{VmcsField, VmcsFieldWidth};
+pub use self::xsave::{supported_xcr0, xsave_size, XsaveState, XCR0_AVX};
+use crate::vm::{
+	lock, narrows_active, overlapping, request_tlb_flush, Mappings, Requests, Vcpus, Vm,
+};
+use crate::{
+	create_claimed_vm, dedup_vcpu_ids, match_MemPerm, match_error_code, match_flags_error,
+	memory_flags, Error, MemFlags, MemPerm, ModelRegisters,
+};
 use core::fmt;
+use core::ops::BitOr;
 use libc::*;
+use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Weak};
+#[cfg(debug_assertions)]
+use std::thread::{self, ThreadId};
 
 /// Creates a VM instance for the current Mach task
+///
+/// Only one VM instance may exist per task. If it already exists, `Error::BadArg` is
+/// returned with a context that says so, without calling into the framework.
+/// `Error::NoDev` is returned with a hint to check `sysctl kern.hv_support`.
 pub fn create_vm() -> Result<(), Error> {
-	match_error_code(unsafe { hv_vm_create(HV_VM_DEFAULT) })
+	create_vm_with_flags(VmCreateFlags::DEFAULT)
+}
+
+/// Options of the VM instance, passed to `create_vm_with_flags`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct VmCreateFlags(hv_vm_options_t);
+
+impl VmCreateFlags {
+	/// Default options
+	pub const DEFAULT: VmCreateFlags = VmCreateFlags(HV_VM_DEFAULT);
+	/// Emulate the local APIC in the framework (macOS 12 and later)
+	pub const ACCEL_APIC: VmCreateFlags = VmCreateFlags(HV_VM_ACCEL_APIC);
+
+	/// Returns the flags for the raw `hv_vm_options_t` bits
+	pub const fn from_bits(bits: hv_vm_options_t) -> VmCreateFlags {
+		VmCreateFlags(bits)
+	}
+
+	/// Returns the raw `hv_vm_options_t` bits
+	pub const fn bits(&self) -> hv_vm_options_t {
+		self.0
+	}
+
+	/// Returns `true` if all flags of `other` are set
+	pub const fn contains(&self, other: VmCreateFlags) -> bool {
+		self.0 & other.0 == other.0
+	}
+}
+
+impl BitOr for VmCreateFlags {
+	type Output = VmCreateFlags;
+
+	fn bitor(self, rhs: VmCreateFlags) -> VmCreateFlags {
+		VmCreateFlags(self.0 | rhs.0)
+	}
+}
+
+/// Creates a VM instance for the current Mach task with the given options
+///
+/// Returns `Error::Unsupp` if the framework of the host doesn't support one of the
+/// flags. Like `create_vm`, returns `Error::BadArg` if the VM instance already exists.
+pub fn create_vm_with_flags(flags: VmCreateFlags) -> Result<(), Error> {
+	create_claimed_vm(|| {
+		let result = match match_error_code(unsafe { hv_vm_create(flags.bits()) }) {
+			Err(Error::BadArg) | Err(Error::Unsupp) if flags != VmCreateFlags::DEFAULT => {
+				Err(Error::Unsupp.context(format!("VM creation flags {:#x}", flags.bits())))
+			}
+			result => result,
+		};
+
+		traced!(debug, result, flags = flags.bits(), "create_vm")
+	})
 }
 
 /// Maps a region in the virtual address space of the current task into the guest physical
 /// address space of the virutal machine
-pub fn map_mem(mem: &[u8], gpa: u64, mem_perm: MemPerm) -> Result<(), Error> {
-	match_error_code(unsafe {
-		hv_vm_map(
-			mem.as_ptr() as *const c_void,
-			gpa as hv_gpaddr_t,
-			mem.len() as size_t,
-			match_MemPerm(mem_perm),
-		)
-	})
+///
+/// The guest may write to the region, so it is borrowed mutably.
+pub fn map_mem(mem: &mut [u8], gpa: u64, mem_perm: MemPerm) -> Result<(), Error> {
+	map_mem_with_flags(mem, gpa, mem_perm, MemFlags::NONE)
+}
+
+/// Maps a region like `map_mem` with additional memory flags
+///
+/// Returns `Error::Unsupp` if the framework rejects the flags.
+pub fn map_mem_with_flags(
+	mem: &mut [u8],
+	gpa: u64,
+	mem_perm: MemPerm,
+	flags: MemFlags,
+) -> Result<(), Error> {
+	let _span =
+		debug_span!("map_mem", gpa, size = mem.len(), perm = ?mem_perm, flags = flags.bits());
+
+	traced!(
+		debug,
+		match_flags_error(
+			match_error_code(unsafe {
+				hv_vm_map(
+					mem.as_mut_ptr() as *const c_void,
+					gpa as hv_gpaddr_t,
+					mem.len() as size_t,
+					memory_flags(mem_perm, flags),
+				)
+			}),
+			flags
+		),
+		"map_mem"
+	)
 }
 
 /// Modifies the permissions of a region in the guest physical address space of the virtual
 /// machine
+///
+/// If the new permissions take a right away from a part of the region, the
+/// VirtualCpus registered with the `Vm` of the task invalidate their TLBs at the
+/// start of their next `run`, like after `Vm::protect_range`. Parts that weren't
+/// mapped through the `Vm` count as accessible with every right. Relaxing the
+/// permissions doesn't disturb the VirtualCpus.
 pub fn protect_mem(gpa: u64, size: usize, mem_perm: MemPerm) -> Result<(), Error> {
-	match_error_code(unsafe {
-		hv_vm_protect(gpa as hv_gpaddr_t, size as size_t, match_MemPerm(mem_perm))
-	})
+	protect_mem_with_flags(gpa, size, mem_perm, MemFlags::NONE)
+}
+
+/// Modifies the permissions of a region like `protect_mem` with additional memory flags
+///
+/// Returns `Error::Unsupp` if the framework rejects the flags.
+pub fn protect_mem_with_flags(
+	gpa: u64,
+	size: usize,
+	mem_perm: MemPerm,
+	flags: MemFlags,
+) -> Result<(), Error> {
+	let narrowed = narrows_active(gpa, size, mem_perm);
+	vm_protect(gpa, size, mem_perm, flags)?;
+	if narrowed {
+		request_tlb_flush()?;
+	}
+
+	Ok(())
+}
+
+/// Modifies the permissions of a region like `protect_mem` without invalidating the
+/// TLBs of the VirtualCpus
+///
+/// A VirtualCpu may keep using translations with the old permissions from its TLB,
+/// so a page that was made read-only might still be written. After narrowing the
+/// permissions, the caller has to call `VirtualCpu::invalidate_tlb` on every
+/// VirtualCpu before the guest relies on the new permissions, e.g. once after a
+/// batch of changes.
+pub fn protect_mem_no_flush(gpa: u64, size: usize, mem_perm: MemPerm) -> Result<(), Error> {
+	vm_protect(gpa, size, mem_perm, MemFlags::NONE)
+}
+
+// Modifies the permissions of a region through the framework
+fn vm_protect(gpa: u64, size: usize, mem_perm: MemPerm, flags: MemFlags) -> Result<(), Error> {
+	let _span = debug_span!("protect_mem", gpa, size, perm = ?mem_perm, flags = flags.bits());
+
+	traced!(
+		debug,
+		match_flags_error(
+			match_error_code(unsafe {
+				hv_vm_protect(
+					gpa as hv_gpaddr_t,
+					size as size_t,
+					memory_flags(mem_perm, flags),
+				)
+			}),
+			flags
+		),
+		"protect_mem"
+	)
+}
+
+/// Maps a region of externally managed host memory into the guest physical address space
+/// of the virtual machine
+///
+/// # Safety
+///
+/// `ptr` must be page-aligned and valid for reads and, if `mem_perm` allows writes,
+/// for writes of `len` bytes until the region is unmapped or the VM is destroyed.
+pub unsafe fn map_mem_raw(
+	ptr: *mut u8,
+	len: usize,
+	gpa: u64,
+	mem_perm: MemPerm,
+) -> Result<(), Error> {
+	let _span = debug_span!("map_mem_raw", ?ptr, gpa, size = len, perm = ?mem_perm);
+
+	traced!(
+		debug,
+		match_error_code(hv_vm_map(
+			ptr as *mut c_void,
+			gpa as hv_gpaddr_t,
+			len as size_t,
+			match_MemPerm(mem_perm),
+		)),
+		"map_mem_raw"
+	)
 }
 
 /// Unmaps a region in the guest physical address space of the virutal machine
 pub fn unmap_mem(gpa: u64, size: usize) -> Result<(), Error> {
-	match_error_code(unsafe { hv_vm_unmap(gpa as hv_gpaddr_t, size as size_t) })
+	let _span = debug_span!("unmap_mem", gpa, size);
+
+	traced!(
+		debug,
+		match_error_code(unsafe { hv_vm_unmap(gpa as hv_gpaddr_t, size as size_t) }),
+		"unmap_mem"
+	)
 }
 
 /// Synchronizes the guest Timestamp-Counters (TSC) across all VirtualCpus
@@ -47,18 +260,80 @@ pub fn sync_tsc(tsc: u64) -> Result<(), Error> {
 /// Forces an immediate VMEXIT of a set of VirtualCpus
 ///
 /// * `VirtualCpu_ids` Array of VirtualCpu IDs
+///
+/// Duplicate IDs are removed. Returns `Error::BadArg` if the array is empty or holds
+/// more than `MAX_INTERRUPT_VCPUS` IDs.
 pub fn interrupt_vcpus(vcpu_ids: &[u32]) -> Result<(), Error> {
-	match_error_code(unsafe { hv_vcpu_interrupt(vcpu_ids.as_ptr(), vcpu_ids.len() as c_uint) })
+	let ids = dedup_vcpu_ids(vcpu_ids)?;
+	match_error_code(unsafe { hv_vcpu_interrupt(ids.as_ptr(), ids.len() as c_uint) })
+}
+
+/// Returns the maximum number of VirtualCpus that the host supports per VM
+///
+/// Hypervisor.framework exposes the limit only on Apple silicon, so this always
+/// returns `Error::Unsupp` on x86_64.
+pub fn max_vcpu_count() -> Result<u32, Error> {
+	Err(Error::Unsupp.context("hv_vm_get_max_vcpu_count is only available on Apple silicon"))
+}
+
+/// Returns the size of the floating point and SIMD state in bytes
+///
+/// The state uses the legacy `FXSAVE` layout of 512 bytes. `VirtualCpu::read_xsave`
+/// also covers the extended state, e.g. of AVX.
+pub fn fpstate_size() -> usize {
+	512
+}
+
+/// Returns the guest physical address of the local APIC after reset (0xfee00000)
+pub const fn default_apic_addr() -> u64 {
+	0xfee0_0000
 }
 
+/// Size of the APIC page in bytes
+const APIC_PAGE_SIZE: u64 = 0x1000;
+
+/// RFLAGS bit that always reads as 1
+pub(crate) const RFLAGS_RESERVED_ONE: u64 = 1 << 1;
+
+/// RFLAGS bits that aren't reserved as 0 (CF to ID, without bits 3, 5 and 15)
+pub(crate) const RFLAGS_DEFINED: u64 = 0x003f_7fd7;
+
 /// Virtual CPU
-pub struct VirtualCpu {
+///
+/// A VirtualCpu is bound to the thread that created it. In debug builds, methods
+/// that call into the framework panic when used on another thread. VirtualCpus
+/// created by `Vm::create_vcpu` borrow the `Vm` for `'vm`, so the VM can't be
+/// destroyed while they are alive.
+pub struct VirtualCpu<'vm> {
 	/// Virtual CPU handle
 	id: hv_vcpuid_t,
+	/// Registry of the Vm that created the VirtualCpu
+	pub(crate) registry: Weak<Vcpus>,
+	/// Work queued by the Vm that created the VirtualCpu
+	pub(crate) requests: Weak<Requests>,
+	/// Mappings of the Vm that created the VirtualCpu
+	pub(crate) mappings: Weak<Mappings>,
+	/// Whether registers or VMCS fields were written since the last run or flush
+	pub(crate) dirty: AtomicBool,
+	/// RIP read by `instruction_pointer` since the last run or write of RIP
+	cached_ip: Mutex<Option<u64>>,
+	/// Thread that created the VirtualCpu, checked in debug builds
+	#[cfg(debug_assertions)]
+	owner: ThreadId,
+	/// VM instance the VirtualCpu belongs to
+	vm: PhantomData<&'vm Vm>,
 }
 
 /// x86 architectural register
-#[derive(Clone)]
+///
+/// The general purpose, segment selector, control and debug registers as well as
+/// TPR and XCR0 are accessible through `read_register` and `write_register`. The
+/// hidden parts of the descriptor table and system segment registers
+/// (`IDT_BASE` to `TSS_AR`, except the `LDTR` and `TR` selectors) are only held in
+/// the VMCS, so they are rejected with `Error::BadArg` and have to be accessed
+/// through `read_vmcs` with the field of `Register::vmcs_field`. `read_segment`
+/// and `write_segment` access a whole segment register regardless of the split.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub enum Register {
 	RIP,
@@ -112,23 +387,181 @@ pub enum Register {
 	DR7,
 	TPR,
 	XCR0,
+	/// Number of registers, not a register itself
+	///
+	/// `read_register` and `write_register` reject it with `Error::BadArg`.
 	REGISTERS_MAX,
 }
 
-impl VirtualCpu {
-	/// Creates a VirtualCpu instance for the current thread
-	pub fn new() -> Result<VirtualCpu, Error> {
+impl Register {
+	/// Returns the guest VMCS field of a register that is only accessible through the VMCS
+	pub fn vmcs_field(&self) -> Option<u32> {
+		match self {
+			Register::IDT_BASE => Some(VMCS_GUEST_IDTR_BASE),
+			Register::IDT_LIMIT => Some(VMCS_GUEST_IDTR_LIMIT),
+			Register::GDT_BASE => Some(VMCS_GUEST_GDTR_BASE),
+			Register::GDT_LIMIT => Some(VMCS_GUEST_GDTR_LIMIT),
+			Register::LDT_BASE => Some(VMCS_GUEST_LDTR_BASE),
+			Register::LDT_LIMIT => Some(VMCS_GUEST_LDTR_LIMIT),
+			Register::LDT_AR => Some(VMCS_GUEST_LDTR_AR),
+			Register::TSS_BASE => Some(VMCS_GUEST_TR_BASE),
+			Register::TSS_LIMIT => Some(VMCS_GUEST_TR_LIMIT),
+			Register::TSS_AR => Some(VMCS_GUEST_TR_AR),
+			_ => None,
+		}
+	}
+
+	// Checks that the register is accessible through hv_vcpu_read_register and
+	// hv_vcpu_write_register
+	fn check_accessible(&self) -> Result<(), Error> {
+		if let Register::REGISTERS_MAX = self {
+			return Err(Error::BadArg);
+		}
+		if let Some(field) = self.vmcs_field() {
+			return Err(Error::BadArg.context(format!(
+				"{} is only accessible through the VMCS field {:#x}",
+				self, field
+			)));
+		}
+
+		Ok(())
+	}
+
+	// Returns the canonical lowercase name of the register
+	fn name(&self) -> &'static str {
+		match self {
+			Register::RIP => "rip",
+			Register::RFLAGS => "rflags",
+			Register::RAX => "rax",
+			Register::RCX => "rcx",
+			Register::RDX => "rdx",
+			Register::RBX => "rbx",
+			Register::RSI => "rsi",
+			Register::RDI => "rdi",
+			Register::RSP => "rsp",
+			Register::RBP => "rbp",
+			Register::R8 => "r8",
+			Register::R9 => "r9",
+			Register::R10 => "r10",
+			Register::R11 => "r11",
+			Register::R12 => "r12",
+			Register::R13 => "r13",
+			Register::R14 => "r14",
+			Register::R15 => "r15",
+			Register::CS => "cs",
+			Register::SS => "ss",
+			Register::DS => "ds",
+			Register::ES => "es",
+			Register::FS => "fs",
+			Register::GS => "gs",
+			Register::IDT_BASE => "idt_base",
+			Register::IDT_LIMIT => "idt_limit",
+			Register::GDT_BASE => "gdt_base",
+			Register::GDT_LIMIT => "gdt_limit",
+			Register::LDTR => "ldtr",
+			Register::LDT_BASE => "ldt_base",
+			Register::LDT_LIMIT => "ldt_limit",
+			Register::LDT_AR => "ldt_ar",
+			Register::TR => "tr",
+			Register::TSS_BASE => "tss_base",
+			Register::TSS_LIMIT => "tss_limit",
+			Register::TSS_AR => "tss_ar",
+			Register::CR0 => "cr0",
+			Register::CR1 => "cr1",
+			Register::CR2 => "cr2",
+			Register::CR3 => "cr3",
+			Register::CR4 => "cr4",
+			Register::DR0 => "dr0",
+			Register::DR1 => "dr1",
+			Register::DR2 => "dr2",
+			Register::DR3 => "dr3",
+			Register::DR4 => "dr4",
+			Register::DR5 => "dr5",
+			Register::DR6 => "dr6",
+			Register::DR7 => "dr7",
+			Register::TPR => "tpr",
+			Register::XCR0 => "xcr0",
+			Register::REGISTERS_MAX => "registers_max",
+		}
+	}
+}
+
+// Returns the register of control register CR`n`, except for CR8 which is part of
+// the TPR
+fn control_register(n: u8) -> Result<Register, Error> {
+	match n {
+		0 => Ok(Register::CR0),
+		2 => Ok(Register::CR2),
+		3 => Ok(Register::CR3),
+		4 => Ok(Register::CR4),
+		_ => Err(Error::BadArg.context(format!("CR{} isn't a control register", n))),
+	}
+}
+
+impl fmt::Display for Register {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+impl VirtualCpu<'static> {
+	/// Creates a VirtualCpu instance for the current thread without binding it to a `Vm`
+	///
+	/// This is the escape hatch for VMs created by `create_vm`, `Vm::create_vcpu` is
+	/// the safe way to create a VirtualCpu.
+	///
+	/// # Safety
+	///
+	/// The VM instance must exist until the VirtualCpu is destroyed, i.e. the caller
+	/// has to call `destroy` before `destroy_vm` and must not use the VirtualCpu
+	/// after the VM instance is gone.
+	pub unsafe fn new_unbound() -> Result<VirtualCpu<'static>, Error> {
 		let mut vcpuid: hv_vcpuid_t = 0;
 
-		match_error_code(unsafe { hv_vcpu_create(&mut vcpuid, HV_VCPU_DEFAULT) })?;
+		traced!(
+			debug,
+			match_error_code(unsafe { hv_vcpu_create(&mut vcpuid, HV_VCPU_DEFAULT) }),
+			vcpu = vcpuid,
+			"create_vcpu"
+		)?;
 
-		Ok(VirtualCpu { id: vcpuid })
+		Ok(VirtualCpu {
+			id: vcpuid,
+			registry: Weak::new(),
+			requests: Weak::new(),
+			mappings: Weak::new(),
+			dirty: AtomicBool::new(false),
+			cached_ip: Mutex::new(None),
+			#[cfg(debug_assertions)]
+			owner: thread::current().id(),
+			vm: PhantomData,
+		})
 	}
+}
 
+impl VirtualCpu<'_> {
 	pub fn get_id(&self) -> hv_vcpuid_t {
 		self.id
 	}
 
+	// Panics if the current thread isn't the one that created the VirtualCpu
+	//
+	// The framework rejects calls from other threads with errors that don't point
+	// at the cause, so debug builds check the thread on entry of every method that
+	// calls into the framework. `interrupt` and `exec_time` may be used from any
+	// thread and aren't checked.
+	#[inline(always)]
+	pub(crate) fn assert_owner(&self) {
+		#[cfg(debug_assertions)]
+		assert!(
+			thread::current().id() == self.owner,
+			"VirtualCpu {} used on {:?}, but it is bound to the thread that created it ({:?})",
+			self.id,
+			thread::current().id(),
+			self.owner
+		);
+	}
+
 	/// Forces an immediate VMEXIT of the VirtualCpu
 	pub fn interrupt(&self) -> Result<(), Error> {
 		match_error_code(unsafe { hv_vcpu_interrupt(&(self.id), 1 as c_uint) })
@@ -143,72 +576,345 @@ impl VirtualCpu {
 		Ok(exec_time)
 	}
 
+	/// Returns the current value of the guest's timestamp counter (TSC)
+	///
+	/// The value is derived from the host TSC and the TSC offset of the VirtualCpu,
+	/// i.e. it is what `RDTSC` would return in the guest right now.
+	pub fn read_tsc(&self) -> Result<u64, Error> {
+		let procbased = self.read_vmcs(VMCS_CTRL_CPU_BASED)?;
+		let offset = if procbased & CPU_BASED_TSC_OFFSET != 0 {
+			self.read_vmcs(VMCS_CTRL_TSC_OFFSET)?
+		} else {
+			0
+		};
+
+		Ok(unsafe { core::arch::x86_64::_rdtsc() }.wrapping_add(offset))
+	}
+
 	/// Forces flushing of cached VirtualCpu state
 	pub fn flush(&self) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_flush(self.id) })
+		self.assert_owner();
+		match_error_code(unsafe { hv_vcpu_flush(self.id) })?;
+		self.dirty.store(false, Ordering::Relaxed);
+
+		Ok(())
+	}
+
+	/// Returns whether registers or VMCS fields were written since the last run or flush
+	///
+	/// Only writes through `write_register` and `write_vmcs`, including the helpers
+	/// built on them, are tracked.
+	pub fn is_dirty(&self) -> bool {
+		self.dirty.load(Ordering::Relaxed)
+	}
+
+	/// Flushes the cached VirtualCpu state unless it is clean
+	///
+	/// Returns whether `flush` was called, so redundant flushes in tight loops can be
+	/// skipped without losing writes. See `is_dirty` for the tracked writes.
+	pub fn flush_if_dirty(&self) -> Result<bool, Error> {
+		if !self.is_dirty() {
+			return Ok(false);
+		}
+
+		self.flush()?;
+		Ok(true)
 	}
 
 	/// Invalidates the translation lookaside buffer (TLB) of the VirtualCpu
 	pub fn invalidate_tlb(&self) -> Result<(), Error> {
+		self.assert_owner();
 		match_error_code(unsafe { hv_vcpu_invalidate_tlb(self.id) })
 	}
 
+	// Does the work queued by the Vm before the next VM entry
+	//
+	// The queue only closes an interrupt window that it opened itself, so a window
+	// requested by the caller stays open.
+	pub(crate) fn service_requests(&self) -> Result<(), Error> {
+		let requests = match self.requests.upgrade() {
+			Some(requests) => requests,
+			None => return Ok(()),
+		};
+		let mut requests = lock(&requests);
+		let requests = match requests.get_mut(&self.id) {
+			Some(requests) => requests,
+			None => return Ok(()),
+		};
+
+		if requests.invalidate_tlb {
+			self.invalidate_tlb()?;
+			requests.invalidate_tlb = false;
+		}
+		requests
+			.interrupts
+			.service(self)
+			.map_err(|err| err.context("failed to inject a broadcast interrupt"))?;
+
+		Ok(())
+	}
+
 	/// Enables an MSR to be used natively by the VM
 	pub fn enable_native_msr(&self, msr: u32, enable: bool) -> Result<(), Error> {
+		self.assert_owner();
 		match_error_code(unsafe { hv_vcpu_enable_native_msr(self.id, msr, enable) })
 	}
 
 	/// Returns the current value of an MSR of the VirtualCpu
 	pub fn read_msr(&self, msr: u32) -> Result<u64, Error> {
+		self.assert_owner();
 		let mut value: u64 = 0;
 
-		let _error = match_error_code(unsafe { hv_vcpu_read_msr(self.id, msr, &mut value) })?;
-
-		Ok(value)
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_read_msr(self.id, msr, &mut value) }).map(|()| value),
+			vcpu = self.id,
+			msr,
+			"read_msr"
+		)
 	}
 
 	/// Set the value of an MSR of the VirtualCpu
 	pub fn write_msr(&self, msr: u32, value: u64) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_write_msr(self.id, msr, &(value)) })
+		self.assert_owner();
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_write_msr(self.id, msr, &(value)) }),
+			vcpu = self.id,
+			msr,
+			value,
+			"write_msr"
+		)
 	}
 
 	/// Returns the current value of an architectural x86 register
 	/// of the VirtualCpu
+	///
+	/// Returns `Error::BadArg` for the `Register::REGISTERS_MAX` sentinel and for the
+	/// registers that are only accessible through the VMCS (see `Register`).
 	pub fn read_register(&self, reg: &Register) -> Result<u64, Error> {
-		let mut value: u64 = 0;
+		self.assert_owner();
+		reg.check_accessible()?;
 
-		match_error_code(unsafe { hv_vcpu_read_register(self.id, (*reg).clone(), &mut value) })?;
+		let mut value: u64 = 0;
 
-		Ok(value)
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_read_register(self.id, *reg, &mut value) })
+				.map(|()| value),
+			vcpu = self.id,
+			%reg,
+			"read_register"
+		)
 	}
 
 	/// Sets the value of an architectural x86 register of the VirtualCpu
+	///
+	/// Returns `Error::BadArg` for the `Register::REGISTERS_MAX` sentinel and for the
+	/// registers that are only accessible through the VMCS (see `Register`). Values
+	/// written to `Register::RFLAGS` get the always-1 bit 1 set and the always-0
+	/// reserved bits cleared, since the VM entry would fail otherwise.
 	pub fn write_register(&self, reg: &Register, value: u64) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vcpu_write_register(self.id, (*reg).clone(), value) })
+		self.assert_owner();
+		reg.check_accessible()?;
+		let value = match reg {
+			Register::RFLAGS => (value & RFLAGS_DEFINED) | RFLAGS_RESERVED_ONE,
+			_ => value,
+		};
+		self.dirty.store(true, Ordering::Relaxed);
+		if let Register::RIP = reg {
+			self.forget_instruction_pointer();
+		}
+
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vcpu_write_register(self.id, *reg, value) }),
+			vcpu = self.id,
+			%reg,
+			value,
+			"write_register"
+		)
+	}
+
+	/// Returns the task priority register (TPR) of the VirtualCpu
+	///
+	/// The TPR is an 8-bit register, so only the low byte of `Register::TPR` is used.
+	pub fn read_tpr(&self) -> Result<u8, Error> {
+		Ok(self.read_register(&Register::TPR)? as u8)
+	}
+
+	/// Sets the task priority register (TPR) of the VirtualCpu
+	pub fn write_tpr(&self, value: u8) -> Result<(), Error> {
+		self.write_register(&Register::TPR, u64::from(value))
+	}
+
+	/// Returns control register CR`n` of the VirtualCpu, for `n` in 0, 2, 3, 4 and 8
+	///
+	/// The value is the one the processor uses while running the guest, not the
+	/// one the guest reads: bits of CR0 and CR4 that are set in the guest/host mask
+	/// (`VMCS_CTRL_CR0_MASK`, `VMCS_CTRL_CR4_MASK`) are owned by the host, and a
+	/// `mov` from CR0 or CR4 in the guest returns them from the read shadow
+	/// (`VMCS_CTRL_CR0_SHADOW`, `VMCS_CTRL_CR4_SHADOW`) instead. CR8 is bits 7:4 of
+	/// the TPR. Other values of `n` return `Error::BadArg`.
+	pub fn read_cr(&self, n: u8) -> Result<u64, Error> {
+		match n {
+			8 => Ok(u64::from(self.read_tpr()? >> 4)),
+			_ => self.read_register(&control_register(n)?),
+		}
+	}
+
+	/// Sets control register CR`n` of the VirtualCpu, for `n` in 0, 2, 3, 4 and 8
+	///
+	/// Sets the value the processor uses while running the guest and leaves the
+	/// read shadow alone, so a guest that reads CR0 or CR4 still sees the shadowed
+	/// value for the bits in the guest/host mask. Use `write_cr_with_shadow` to
+	/// keep both in sync. CR8 only takes values up to 15. Other values of `n`
+	/// return `Error::BadArg`.
+	pub fn write_cr(&self, n: u8, value: u64) -> Result<(), Error> {
+		match n {
+			8 if value > 0xf => Err(Error::BadArg.context("CR8 holds a 4-bit priority class")),
+			8 => self.write_tpr((value as u8) << 4),
+			_ => self.write_register(&control_register(n)?, value),
+		}
+	}
+
+	/// Sets CR0 or CR4 of the VirtualCpu together with its read shadow
+	///
+	/// Like `write_cr`, but the guest also reads back `value` for the bits in the
+	/// guest/host mask. Returns `Error::BadArg` for the other control registers,
+	/// which have no read shadow.
+	pub fn write_cr_with_shadow(&self, n: u8, value: u64) -> Result<(), Error> {
+		let shadow = match n {
+			0 => VMCS_CTRL_CR0_SHADOW,
+			4 => VMCS_CTRL_CR4_SHADOW,
+			_ => return Err(Error::BadArg.context(format!("CR{} has no read shadow", n))),
+		};
+
+		self.write_cr(n, value)?;
+		self.write_vmcs(shadow, value)
+	}
+
+	/// Returns the instruction pointer (RIP) of the VirtualCpu
+	///
+	/// The value is cached until the next run or write of RIP, so repeated calls
+	/// while handling one exit read the register only once.
+	pub fn instruction_pointer(&self) -> Result<u64, Error> {
+		let mut cached_ip = lock(&self.cached_ip);
+		if let Some(ip) = *cached_ip {
+			return Ok(ip);
+		}
+
+		let ip = self.read_register(&Register::RIP)?;
+		*cached_ip = Some(ip);
+
+		Ok(ip)
+	}
+
+	// Drops the RIP cached by `instruction_pointer`
+	pub(crate) fn forget_instruction_pointer(&self) {
+		*lock(&self.cached_ip) = None;
+	}
+
+	/// Sets the instruction pointer (RIP) of the VirtualCpu
+	pub fn set_instruction_pointer(&self, value: u64) -> Result<(), Error> {
+		self.write_register(&Register::RIP, value)
+	}
+
+	/// Returns the stack pointer (RSP) of the VirtualCpu
+	pub fn stack_pointer(&self) -> Result<u64, Error> {
+		self.read_register(&Register::RSP)
+	}
+
+	/// Sets the stack pointer (RSP) of the VirtualCpu
+	pub fn set_stack_pointer(&self, value: u64) -> Result<(), Error> {
+		self.write_register(&Register::RSP, value)
 	}
 
 	/// Returns the current value of a VMCS field of the VirtualCpu
-	pub fn read_vmcs(&self, field: u32) -> Result<u64, Error> {
+	///
+	/// The value is truncated to the width of the field.
+	pub fn read_vmcs<F: Into<VmcsField>>(&self, field: F) -> Result<u64, Error> {
+		self.assert_owner();
+		let field = field.into();
 		let mut value: u64 = 0;
 
-		match_error_code(unsafe { hv_vmx_vcpu_read_vmcs(self.get_id(), field, &mut value) })?;
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vmx_vcpu_read_vmcs(self.get_id(), field.0, &mut value) })
+				.map(|()| value & field.width().mask()),
+			vcpu = self.id,
+			field = field.0,
+			"read_vmcs"
+		)
+	}
 
-		Ok(value)
+	/// Reads several VMCS fields and returns their values in the order of `fields`
+	///
+	/// Stops at the first field that can't be read.
+	pub fn read_vmcs_many(&self, fields: &[VmcsField]) -> Result<Vec<u64>, Error> {
+		fields.iter().map(|field| self.read_vmcs(*field)).collect()
 	}
 
 	/// Sets the value of a VMCS field of the VirtualCpu
-	pub fn write_vmcs(&self, field: u32, value: u64) -> Result<(), Error> {
-		match_error_code(unsafe { hv_vmx_vcpu_write_vmcs(self.id, field, value) })
+	///
+	/// Returns `Error::BadArg` if `value` doesn't fit into the width of the field.
+	pub fn write_vmcs<F: Into<VmcsField>>(&self, field: F, value: u64) -> Result<(), Error> {
+		self.assert_owner();
+		let field = field.into();
+		if value & !field.width().mask() != 0 {
+			return Err(Error::BadArg);
+		}
+		self.dirty.store(true, Ordering::Relaxed);
+		if field.0 == VMCS_GUEST_RIP {
+			self.forget_instruction_pointer();
+		}
+
+		traced!(
+			trace,
+			match_error_code(unsafe { hv_vmx_vcpu_write_vmcs(self.id, field.0, value) }),
+			vcpu = self.id,
+			field = field.0,
+			value,
+			"write_vmcs"
+		)
 	}
 
 	/// Sets the address of the guest APIC for the VirtualCpu in the
 	/// guest physical address space of the VM
+	///
+	/// Returns `Error::BadArg` if `gpa` isn't aligned to 4 KiB or if the APIC page
+	/// overlaps a mapping of the Vm that created the VirtualCpu. VirtualCpus created
+	/// with `new_unbound` only get the alignment check.
 	pub fn set_apic_addr(&self, gpa: u64) -> Result<(), Error> {
+		self.assert_owner();
+		if !gpa.is_multiple_of(APIC_PAGE_SIZE) || gpa.checked_add(APIC_PAGE_SIZE).is_none() {
+			return Err(Error::BadArg);
+		}
+		if let Some(mappings) = self.mappings.upgrade() {
+			if !overlapping(&lock(&mappings), gpa, APIC_PAGE_SIZE as usize).is_empty() {
+				return Err(
+					Error::BadArg.context(format!("APIC page at {:#x} overlaps guest memory", gpa))
+				);
+			}
+		}
+
 		match_error_code(unsafe { hv_vmx_vcpu_set_apic_address(self.id, gpa) })
 	}
 
+	/// Places the guest APIC at its architectural default address `default_apic_addr()`
+	pub fn set_default_apic_addr(&self) -> Result<(), Error> {
+		self.set_apic_addr(default_apic_addr())
+	}
+
 	/// Reads the current architectural x86 floating point and SIMD state of the VirtualCpu
+	///
+	/// Returns `Error::BadArg` if the buffer isn't `fpstate_size()` bytes long.
 	pub fn read_fpstate(&self, buffer: &mut [u8]) -> Result<(), Error> {
+		self.assert_owner();
+		if buffer.len() != fpstate_size() {
+			return Err(Error::BadArg);
+		}
+
 		match_error_code(unsafe {
 			hv_vcpu_read_fpstate(
 				self.id,
@@ -218,8 +924,24 @@ impl VirtualCpu {
 		})
 	}
 
+	/// Returns the current architectural x86 floating point and SIMD state of the VirtualCpu
+	pub fn read_fpstate_vec(&self) -> Result<Vec<u8>, Error> {
+		let mut buffer = vec![0; fpstate_size()];
+
+		self.read_fpstate(&mut buffer)?;
+
+		Ok(buffer)
+	}
+
 	/// Sets the architectural x86 floating point and SIMD state of the VirtualCpu
+	///
+	/// Returns `Error::BadArg` if the buffer isn't `fpstate_size()` bytes long.
 	pub fn write_fpstate(&self, buffer: &[u8]) -> Result<(), Error> {
+		self.assert_owner();
+		if buffer.len() != fpstate_size() {
+			return Err(Error::BadArg);
+		}
+
 		match_error_code(unsafe {
 			hv_vcpu_write_fpstate(
 				self.id,
@@ -230,9 +952,22 @@ impl VirtualCpu {
 	}
 }
 
+impl ModelRegisters for VirtualCpu<'_> {
+	/// MSR index
+	type Id = u32;
+
+	fn read_model_register(&self, id: u32) -> Result<u64, Error> {
+		self.read_msr(id)
+	}
+
+	fn write_model_register(&self, id: u32, value: u64) -> Result<(), Error> {
+		self.write_msr(id, value)
+	}
+}
+
 /// VMX cabability
 #[allow(non_camel_case_types)]
-#[derive(Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(C)]
 pub enum VMXCap {
 	/// Pin-based VMX capabilities
@@ -250,14 +985,34 @@ pub enum VMXCap {
 }
 
 /// Reads a VMX capability of the host processor
-pub fn read_vmx_cap(vmx_cap: &VMXCap) -> Result<u64, Error> {
+///
+/// The capability is taken by value, but references are accepted as well, so that
+/// calls like `read_vmx_cap(&VMXCap::PINBASED)` keep compiling.
+pub fn read_vmx_cap(vmx_cap: impl Borrow<VMXCap>) -> Result<u64, Error> {
 	let mut value: u64 = 0;
 
-	match_error_code(unsafe { hv_vmx_read_capability((*vmx_cap).clone(), &mut value) })?;
+	match_error_code(unsafe { hv_vmx_read_capability(*vmx_cap.borrow(), &mut value) })?;
 
 	Ok(value)
 }
 
+/// Returns the control word `ctrl` constrained by the VMX capability `cap`
+///
+/// Bits that the capability requires to be set are added, bits that it doesn't
+/// allow to be set are cleared.
+pub fn cap2ctrl(cap: u64, ctrl: u64) -> u64 {
+	(ctrl | (cap & 0xffffffff)) & (cap >> 32)
+}
+
+/// Reads a VMX capability of the host processor
+#[deprecated(
+	since = "0.2.0",
+	note = "pass `VMXCap` by value to `read_vmx_cap` instead"
+)]
+pub fn read_vmx_cap_ref(vmx_cap: &VMXCap) -> Result<u64, Error> {
+	read_vmx_cap(vmx_cap)
+}
+
 impl fmt::Display for VMXCap {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
 		match *self {
@@ -270,3 +1025,28 @@ impl fmt::Display for VMXCap {
 		}
 	}
 }
+
+impl TryFrom<u32> for VMXCap {
+	type Error = Error;
+
+	/// Returns the capability with the framework value `value`
+	///
+	/// Returns `Error::BadArg` for values that don't name a capability.
+	fn try_from(value: u32) -> Result<VMXCap, Error> {
+		match value {
+			0 => Ok(VMXCap::PINBASED),
+			1 => Ok(VMXCap::PROCBASED),
+			2 => Ok(VMXCap::PROCBASED2),
+			3 => Ok(VMXCap::ENTRY),
+			4 => Ok(VMXCap::EXIT),
+			32 => Ok(VMXCap::PREEMPTION_TIMER),
+			_ => Err(Error::BadArg),
+		}
+	}
+}
+
+impl From<VMXCap> for u32 {
+	fn from(cap: VMXCap) -> u32 {
+		cap as u32
+	}
+}
@@ -0,0 +1,145 @@
+//! Typed access to EFER and IA32_APIC_BASE
+
+use crate::x86_64::consts::vmcs::VMCS_GUEST_IA32_EFER;
+use crate::x86_64::VirtualCpu;
+use crate::Error;
+use core::ops::BitOr;
+
+/// Index of the IA32_APIC_BASE MSR
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// IA32_APIC_BASE bit of the bootstrap processor
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+/// IA32_APIC_BASE bit of the x2APIC mode
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// IA32_APIC_BASE bit of the global enable
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// IA32_APIC_BASE bits of the page frame of the APIC registers
+const APIC_BASE_ADDR: u64 = 0x000f_ffff_ffff_f000;
+
+/// Extended feature enable register (IA32_EFER)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Efer(u64);
+
+impl Efer {
+	/// No flags
+	pub const NONE: Efer = Efer(0);
+	/// System call extensions (`SYSCALL`/`SYSRET`)
+	pub const SCE: Efer = Efer(1 << 0);
+	/// Long mode enable
+	pub const LME: Efer = Efer(1 << 8);
+	/// Long mode active, set by the processor when paging is enabled with LME
+	pub const LMA: Efer = Efer(1 << 10);
+	/// No-execute enable
+	pub const NXE: Efer = Efer(1 << 11);
+
+	/// Returns the flags for the raw register value
+	pub const fn from_bits(bits: u64) -> Efer {
+		Efer(bits)
+	}
+
+	/// Returns the raw register value
+	pub const fn bits(&self) -> u64 {
+		self.0
+	}
+
+	/// Returns `true` if all flags of `other` are set
+	pub const fn contains(&self, other: Efer) -> bool {
+		self.0 & other.0 == other.0
+	}
+
+	/// Sets the flags of `other`
+	pub fn insert(&mut self, other: Efer) {
+		self.0 |= other.0;
+	}
+
+	/// Clears the flags of `other`
+	pub fn remove(&mut self, other: Efer) {
+		self.0 &= !other.0;
+	}
+}
+
+impl BitOr for Efer {
+	type Output = Efer;
+
+	fn bitor(self, rhs: Efer) -> Efer {
+		Efer(self.0 | rhs.0)
+	}
+}
+
+/// Decoded IA32_APIC_BASE MSR
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct ApicBase {
+	/// The processor is the bootstrap processor
+	pub bsp: bool,
+	/// The APIC is in x2APIC mode
+	pub x2apic: bool,
+	/// The APIC is globally enabled
+	pub enabled: bool,
+	/// Physical address of the APIC registers, aligned to 4 KiB
+	pub base_addr: u64,
+}
+
+impl ApicBase {
+	/// Decodes the raw MSR value, ignoring the reserved bits
+	pub const fn from_bits(bits: u64) -> ApicBase {
+		ApicBase {
+			bsp: bits & APIC_BASE_BSP != 0,
+			x2apic: bits & APIC_BASE_X2APIC != 0,
+			enabled: bits & APIC_BASE_ENABLE != 0,
+			base_addr: bits & APIC_BASE_ADDR,
+		}
+	}
+
+	/// Returns the raw MSR value
+	///
+	/// Bits of `base_addr` below 4 KiB or above the 52-bit physical address space
+	/// are dropped.
+	pub const fn bits(&self) -> u64 {
+		let mut bits = self.base_addr & APIC_BASE_ADDR;
+		if self.bsp {
+			bits |= APIC_BASE_BSP;
+		}
+		if self.x2apic {
+			bits |= APIC_BASE_X2APIC;
+		}
+		if self.enabled {
+			bits |= APIC_BASE_ENABLE;
+		}
+
+		bits
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the EFER of the VirtualCpu
+	///
+	/// Hypervisor.framework keeps the guest's EFER in the VMCS rather than behind
+	/// `read_msr`, so this reads `VMCS_GUEST_IA32_EFER`.
+	pub fn read_efer(&self) -> Result<Efer, Error> {
+		Ok(Efer(self.read_vmcs(VMCS_GUEST_IA32_EFER)?))
+	}
+
+	/// Sets the EFER of the VirtualCpu
+	///
+	/// The value is written to `VMCS_GUEST_IA32_EFER`, which is only loaded on VM
+	/// entry with the `Feature::LoadEfer` entry control, as set by `setup_long_mode`.
+	/// LMA must match the IA-32e mode guest entry control, otherwise the VM entry
+	/// fails.
+	pub fn write_efer(&self, efer: Efer) -> Result<(), Error> {
+		self.write_vmcs(VMCS_GUEST_IA32_EFER, efer.0)
+	}
+
+	/// Returns the IA32_APIC_BASE MSR of the VirtualCpu
+	pub fn read_apic_base(&self) -> Result<ApicBase, Error> {
+		Ok(ApicBase::from_bits(self.read_msr(IA32_APIC_BASE)?))
+	}
+
+	/// Sets the IA32_APIC_BASE MSR of the VirtualCpu
+	pub fn write_apic_base(&self, apic_base: ApicBase) -> Result<(), Error> {
+		self.write_msr(IA32_APIC_BASE, apic_base.bits())
+	}
+}
@@ -0,0 +1,118 @@
+//! Snapshot of the general purpose registers
+
+use crate::x86_64::{Register, VirtualCpu};
+use crate::Error;
+
+/// General purpose registers of a VirtualCpu, including RIP and RFLAGS
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct GpRegs {
+	pub rax: u64,
+	pub rbx: u64,
+	pub rcx: u64,
+	pub rdx: u64,
+	pub rsi: u64,
+	pub rdi: u64,
+	pub rsp: u64,
+	pub rbp: u64,
+	pub r8: u64,
+	pub r9: u64,
+	pub r10: u64,
+	pub r11: u64,
+	pub r12: u64,
+	pub r13: u64,
+	pub r14: u64,
+	pub r15: u64,
+	pub rip: u64,
+	pub rflags: u64,
+}
+
+impl GpRegs {
+	// Returns the registers paired with their values
+	fn registers(&self) -> [(Register, u64); 18] {
+		[
+			(Register::RAX, self.rax),
+			(Register::RBX, self.rbx),
+			(Register::RCX, self.rcx),
+			(Register::RDX, self.rdx),
+			(Register::RSI, self.rsi),
+			(Register::RDI, self.rdi),
+			(Register::RSP, self.rsp),
+			(Register::RBP, self.rbp),
+			(Register::R8, self.r8),
+			(Register::R9, self.r9),
+			(Register::R10, self.r10),
+			(Register::R11, self.r11),
+			(Register::R12, self.r12),
+			(Register::R13, self.r13),
+			(Register::R14, self.r14),
+			(Register::R15, self.r15),
+			(Register::RIP, self.rip),
+			(Register::RFLAGS, self.rflags),
+		]
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the general purpose registers of the VirtualCpu
+	pub fn read_gp_regs(&self) -> Result<GpRegs, Error> {
+		Ok(GpRegs {
+			rax: self.read_register(&Register::RAX)?,
+			rbx: self.read_register(&Register::RBX)?,
+			rcx: self.read_register(&Register::RCX)?,
+			rdx: self.read_register(&Register::RDX)?,
+			rsi: self.read_register(&Register::RSI)?,
+			rdi: self.read_register(&Register::RDI)?,
+			rsp: self.read_register(&Register::RSP)?,
+			rbp: self.read_register(&Register::RBP)?,
+			r8: self.read_register(&Register::R8)?,
+			r9: self.read_register(&Register::R9)?,
+			r10: self.read_register(&Register::R10)?,
+			r11: self.read_register(&Register::R11)?,
+			r12: self.read_register(&Register::R12)?,
+			r13: self.read_register(&Register::R13)?,
+			r14: self.read_register(&Register::R14)?,
+			r15: self.read_register(&Register::R15)?,
+			rip: self.read_register(&Register::RIP)?,
+			rflags: self.read_register(&Register::RFLAGS)?,
+		})
+	}
+
+	/// Sets the general purpose registers of the VirtualCpu
+	///
+	/// RFLAGS is adjusted like by `write_register`.
+	pub fn write_gp_regs(&self, regs: &GpRegs) -> Result<(), Error> {
+		for (reg, value) in regs.registers().iter() {
+			self.write_register(reg, *value)?;
+		}
+
+		Ok(())
+	}
+
+	/// Writes several registers in order and rolls them back if one write fails
+	///
+	/// On failure, returns the index of the failing pair in `pairs` together with
+	/// the error, after restoring the previous values of the registers written so
+	/// far in reverse order. The rollback is best effort: errors while restoring are
+	/// ignored, since the original error is the one that matters.
+	pub fn write_registers_checked(&self, pairs: &[(Register, u64)]) -> Result<(), (usize, Error)> {
+		let mut previous = Vec::with_capacity(pairs.len());
+
+		for (index, (reg, value)) in pairs.iter().enumerate() {
+			let result = self
+				.read_register(reg)
+				.and_then(|old| self.write_register(reg, *value).map(|()| old));
+
+			match result {
+				Ok(old) => previous.push((*reg, old)),
+				Err(err) => {
+					for (reg, old) in previous.iter().rev() {
+						let _ = self.write_register(reg, *old);
+					}
+					return Err((index, err));
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
@@ -0,0 +1,93 @@
+//! Unified access to the visible and hidden parts of segment registers
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::{Register, VirtualCpu};
+use crate::Error;
+
+/// Segment register of the guest
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Segment {
+	CS,
+	SS,
+	DS,
+	ES,
+	FS,
+	GS,
+	LDTR,
+	TR,
+}
+
+impl Segment {
+	/// Returns the register of the selector
+	pub fn selector_register(&self) -> Register {
+		match self {
+			Segment::CS => Register::CS,
+			Segment::SS => Register::SS,
+			Segment::DS => Register::DS,
+			Segment::ES => Register::ES,
+			Segment::FS => Register::FS,
+			Segment::GS => Register::GS,
+			Segment::LDTR => Register::LDTR,
+			Segment::TR => Register::TR,
+		}
+	}
+
+	// Returns the guest VMCS fields of the hidden part as (base, limit, access rights)
+	fn vmcs_fields(&self) -> (u32, u32, u32) {
+		match self {
+			Segment::CS => (VMCS_GUEST_CS_BASE, VMCS_GUEST_CS_LIMIT, VMCS_GUEST_CS_AR),
+			Segment::SS => (VMCS_GUEST_SS_BASE, VMCS_GUEST_SS_LIMIT, VMCS_GUEST_SS_AR),
+			Segment::DS => (VMCS_GUEST_DS_BASE, VMCS_GUEST_DS_LIMIT, VMCS_GUEST_DS_AR),
+			Segment::ES => (VMCS_GUEST_ES_BASE, VMCS_GUEST_ES_LIMIT, VMCS_GUEST_ES_AR),
+			Segment::FS => (VMCS_GUEST_FS_BASE, VMCS_GUEST_FS_LIMIT, VMCS_GUEST_FS_AR),
+			Segment::GS => (VMCS_GUEST_GS_BASE, VMCS_GUEST_GS_LIMIT, VMCS_GUEST_GS_AR),
+			Segment::LDTR => (
+				VMCS_GUEST_LDTR_BASE,
+				VMCS_GUEST_LDTR_LIMIT,
+				VMCS_GUEST_LDTR_AR,
+			),
+			Segment::TR => (VMCS_GUEST_TR_BASE, VMCS_GUEST_TR_LIMIT, VMCS_GUEST_TR_AR),
+		}
+	}
+}
+
+/// Selector and hidden descriptor cache of a segment register
+///
+/// `access_rights` uses the VMCS format, i.e. bit 16 marks the segment as
+/// unusable.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SegmentRegister {
+	pub selector: u16,
+	pub base: u64,
+	pub limit: u32,
+	pub access_rights: u32,
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the selector, base, limit and access rights of a segment register
+	///
+	/// The selector is read with `read_register`, the hidden part from the guest
+	/// VMCS fields, so callers don't need to know which of them holds what.
+	pub fn read_segment(&self, segment: Segment) -> Result<SegmentRegister, Error> {
+		let (base, limit, ar) = segment.vmcs_fields();
+
+		Ok(SegmentRegister {
+			selector: self.read_register(&segment.selector_register())? as u16,
+			base: self.read_vmcs(base)?,
+			limit: self.read_vmcs(limit)? as u32,
+			access_rights: self.read_vmcs(ar)? as u32,
+		})
+	}
+
+	/// Sets the selector, base, limit and access rights of a segment register
+	///
+	/// No consistency checks are done, invalid combinations fail the next VM entry.
+	pub fn write_segment(&self, segment: Segment, value: &SegmentRegister) -> Result<(), Error> {
+		let (base, limit, ar) = segment.vmcs_fields();
+
+		self.write_register(&segment.selector_register(), u64::from(value.selector))?;
+		self.write_vmcs(base, value.base)?;
+		self.write_vmcs(limit, u64::from(value.limit))?;
+		self.write_vmcs(ar, u64::from(value.access_rights))
+	}
+}
@@ -0,0 +1,46 @@
+//! Output-only serial console on an I/O port
+
+use crate::x86_64::{IoExit, VirtualCpu};
+use crate::Error;
+use std::io::Write;
+
+/// Port of the first legacy serial port (COM1)
+pub const COM1: u16 = 0x3f8;
+
+/// Serial console that forwards the bytes written by the guest to a sink
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SerialConsole {
+	/// I/O port of the data register
+	pub port: u16,
+}
+
+impl SerialConsole {
+	/// Creates a console for the data register at `port`
+	pub fn new(port: u16) -> SerialConsole {
+		SerialConsole { port }
+	}
+
+	/// Handles an I/O exit that writes to the console
+	///
+	/// If the exit is a non-string `OUT` to the port, the low byte of the value is
+	/// written to `sink`, RIP is advanced past the instruction and `true` is returned.
+	/// Any other exit is left to the caller and `false` is returned. Write errors of
+	/// `sink` are returned as `Error::Io`.
+	pub fn handle_io(
+		&mut self,
+		vcpu: &VirtualCpu,
+		io: &IoExit,
+		sink: &mut dyn Write,
+	) -> Result<bool, Error> {
+		let value = match io.value {
+			Some(value) if io.port == self.port => value,
+			_ => return Ok(false),
+		};
+
+		sink.write_all(&[value as u8])?;
+		let rip = vcpu.instruction_pointer()?;
+		vcpu.set_instruction_pointer(rip + io.instruction_length)?;
+
+		Ok(true)
+	}
+}
@@ -0,0 +1,243 @@
+//! Baseline VMCS setup for real mode and long mode guests
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::consts::vmx_cap::*;
+use crate::x86_64::{cap2ctrl, read_vmx_cap, Efer, Register, VMXCap, VirtualCpu};
+use crate::Error;
+
+const PAGE_SIZE: usize = 0x1000;
+const HUGE_PAGE_SIZE: u64 = 0x200000;
+const PDPT_ENTRY_SIZE: u64 = 0x40000000;
+const ENTRIES_PER_TABLE: u64 = 512;
+
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_WRITABLE: u64 = 1 << 1;
+const PAGE_HUGE: u64 = 1 << 7;
+
+pub(crate) const CR0_PE: u64 = 1 << 0;
+const CR0_ET: u64 = 1 << 4;
+pub(crate) const CR0_NE: u64 = 1 << 5;
+pub(crate) const CR0_PG: u64 = 1 << 31;
+pub(crate) const CR4_PAE: u64 = 1 << 5;
+pub(crate) const CR4_VMXE: u64 = 1 << 13;
+pub(crate) const CR4_OSXSAVE: u64 = 1 << 18;
+
+/// Writes page tables that identity map the first `size` bytes of the guest
+/// physical address space with 2 MiB pages
+///
+/// `mem` is the host memory backing the guest physical address `gpa`, which
+/// becomes the address of the PML4. The PML4, the PDPT and one page directory
+/// per started GiB are written to consecutive 4 KiB pages. Returns `Error::BadArg`
+/// if `gpa` isn't page aligned, `size` exceeds 512 GiB or `mem` is too small.
+pub fn build_identity_page_tables(mem: &mut [u8], gpa: u64, size: u64) -> Result<(), Error> {
+	let directories = size.div_ceil(PDPT_ENTRY_SIZE);
+	if !gpa.is_multiple_of(PAGE_SIZE as u64)
+		|| directories > ENTRIES_PER_TABLE
+		|| mem.len() < (2 + directories as usize) * PAGE_SIZE
+	{
+		return Err(Error::BadArg);
+	}
+
+	let tables = &mut mem[..(2 + directories as usize) * PAGE_SIZE];
+	tables.fill(0);
+
+	let mut write_entry = |table: usize, index: u64, entry: u64| {
+		let offset = table * PAGE_SIZE + index as usize * 8;
+		tables[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+	};
+
+	write_entry(
+		0,
+		0,
+		(gpa + PAGE_SIZE as u64) | PAGE_PRESENT | PAGE_WRITABLE,
+	);
+	for directory in 0..directories {
+		let directory_gpa = gpa + (2 + directory) * PAGE_SIZE as u64;
+		write_entry(1, directory, directory_gpa | PAGE_PRESENT | PAGE_WRITABLE);
+	}
+
+	let pages = size.div_ceil(HUGE_PAGE_SIZE);
+	for page in 0..pages {
+		write_entry(
+			2 + (page / ENTRIES_PER_TABLE) as usize,
+			page % ENTRIES_PER_TABLE,
+			(page * HUGE_PAGE_SIZE) | PAGE_PRESENT | PAGE_WRITABLE | PAGE_HUGE,
+		);
+	}
+
+	Ok(())
+}
+
+impl VirtualCpu<'_> {
+	/// Prepares the VirtualCpu to execute 16-bit real mode code
+	///
+	/// The control words are constrained by the VMX capabilities of the host and
+	/// enable `HLT` exiting. All data and code segments are flat 64 KiB segments with
+	/// base 0, paging and protection are disabled and RFLAGS holds only the reserved
+	/// bit. GDTR and IDTR hold the reset values base 0 and limit 0xffff, so the guest
+	/// may place an interrupt vector table at address 0. RIP and RSP are left
+	/// untouched. Every value can be overridden afterwards.
+	pub fn setup_real_mode(&self) -> Result<(), Error> {
+		self.setup_controls(0)?;
+
+		self.setup_segments((0, 0xffff, 0x9b), (0, 0xffff, 0x93), 0x83, 0xffff)?;
+
+		self.write_vmcs(VMCS_GUEST_CR0, CR0_NE)?;
+		self.write_vmcs(VMCS_GUEST_CR3, 0x0)?;
+		self.write_vmcs(VMCS_GUEST_CR4, CR4_VMXE)?;
+
+		self.write_register(&Register::RFLAGS, 0x2)
+	}
+
+	/// Prepares the VirtualCpu to execute 64-bit long mode code
+	///
+	/// The caller has to place the page tables at `pml4_gpa`, e.g. by
+	/// `build_identity_page_tables`. The control words are set up like by
+	/// `setup_real_mode`, with the guest entering IA-32e mode. CS is a flat 64-bit
+	/// code segment with selector 0x8, the data segments use selector 0x10. Paging,
+	/// PAE, EFER.LME and EFER.LMA are enabled. RIP and RSP are left untouched.
+	pub fn setup_long_mode(&self, pml4_gpa: u64) -> Result<(), Error> {
+		self.setup_controls(VMENTRY_GUEST_IA32E | VMENTRY_LOAD_EFER)?;
+
+		self.setup_segments(
+			(0x8, 0xffffffff, 0xa09b),
+			(0x10, 0xffffffff, 0xc093),
+			0x8b,
+			0,
+		)?;
+
+		self.write_vmcs(VMCS_GUEST_CR0, CR0_PG | CR0_NE | CR0_ET | CR0_PE)?;
+		self.write_vmcs(VMCS_GUEST_CR3, pml4_gpa)?;
+		self.write_vmcs(VMCS_GUEST_CR4, CR4_VMXE | CR4_PAE)?;
+		self.write_efer(Efer::LME | Efer::LMA)?;
+
+		self.write_register(&Register::RFLAGS, 0x2)
+	}
+
+	/// Lets the VirtualCpu start executing at the guest physical address `addr`
+	///
+	/// The mode is taken from CR0 as established by `setup_real_mode` or
+	/// `setup_long_mode`. In real mode, addresses below 64 KiB keep CS at 0 and
+	/// become RIP, higher addresses load CS with the segment `addr >> 4` and base
+	/// `addr & !0xf` and leave the rest in RIP. Returns `Error::BadArg` for real mode
+	/// addresses beyond 1 MiB. In protected and long mode, CS is assumed to be the flat
+	/// segment of the setup and `addr` becomes RIP.
+	pub fn set_entry_point(&self, addr: u64) -> Result<(), Error> {
+		if self.read_vmcs(VMCS_GUEST_CR0)? & CR0_PE != 0 {
+			return self.write_register(&Register::RIP, addr);
+		}
+
+		if addr >= 0x100000 {
+			return Err(Error::BadArg);
+		}
+		let (segment, offset) = if addr < 0x10000 {
+			(0, addr)
+		} else {
+			(addr >> 4, addr & 0xf)
+		};
+
+		self.write_vmcs(VMCS_GUEST_CS, segment)?;
+		self.write_vmcs(VMCS_GUEST_CS_BASE, segment << 4)?;
+		self.write_register(&Register::RIP, offset)
+	}
+
+	// Writes the execution and entry control words
+	fn setup_controls(&self, entry_ctrl: u64) -> Result<(), Error> {
+		let pinbased = read_vmx_cap(VMXCap::PINBASED)?;
+		let procbased = read_vmx_cap(VMXCap::PROCBASED)?;
+		let procbased2 = read_vmx_cap(VMXCap::PROCBASED2)?;
+		let entry = read_vmx_cap(VMXCap::ENTRY)?;
+
+		self.write_vmcs(VMCS_CTRL_PIN_BASED, cap2ctrl(pinbased, 0))?;
+		self.write_vmcs(
+			VMCS_CTRL_CPU_BASED,
+			cap2ctrl(
+				procbased,
+				CPU_BASED_HLT | CPU_BASED_CR8_LOAD | CPU_BASED_CR8_STORE,
+			),
+		)?;
+		self.write_vmcs(VMCS_CTRL_CPU_BASED2, cap2ctrl(procbased2, 0))?;
+		self.write_vmcs(VMCS_CTRL_VMENTRY_CONTROLS, cap2ctrl(entry, entry_ctrl))?;
+		self.write_vmcs(VMCS_CTRL_EXC_BITMAP, 0xffffffff)?;
+		self.write_vmcs(VMCS_CTRL_CR0_MASK, 0x60000000)?;
+		self.write_vmcs(VMCS_CTRL_CR0_SHADOW, 0)?;
+		self.write_vmcs(VMCS_CTRL_CR4_MASK, 0)?;
+		self.write_vmcs(VMCS_CTRL_CR4_SHADOW, 0)
+	}
+
+	// Writes flat segments with base 0, given as (selector, limit, access rights),
+	// an unusable LDT, a busy TSS with the access rights `tr_ar` and GDTR and IDTR
+	// with base 0 and the limit `table_limit`
+	fn setup_segments(
+		&self,
+		code: (u64, u64, u64),
+		data: (u64, u64, u64),
+		tr_ar: u64,
+		table_limit: u64,
+	) -> Result<(), Error> {
+		for (selector, limit, ar, base, (value, limit_value, access)) in [
+			(
+				VMCS_GUEST_CS,
+				VMCS_GUEST_CS_LIMIT,
+				VMCS_GUEST_CS_AR,
+				VMCS_GUEST_CS_BASE,
+				code,
+			),
+			(
+				VMCS_GUEST_DS,
+				VMCS_GUEST_DS_LIMIT,
+				VMCS_GUEST_DS_AR,
+				VMCS_GUEST_DS_BASE,
+				data,
+			),
+			(
+				VMCS_GUEST_ES,
+				VMCS_GUEST_ES_LIMIT,
+				VMCS_GUEST_ES_AR,
+				VMCS_GUEST_ES_BASE,
+				data,
+			),
+			(
+				VMCS_GUEST_FS,
+				VMCS_GUEST_FS_LIMIT,
+				VMCS_GUEST_FS_AR,
+				VMCS_GUEST_FS_BASE,
+				data,
+			),
+			(
+				VMCS_GUEST_GS,
+				VMCS_GUEST_GS_LIMIT,
+				VMCS_GUEST_GS_AR,
+				VMCS_GUEST_GS_BASE,
+				data,
+			),
+			(
+				VMCS_GUEST_SS,
+				VMCS_GUEST_SS_LIMIT,
+				VMCS_GUEST_SS_AR,
+				VMCS_GUEST_SS_BASE,
+				data,
+			),
+		] {
+			self.write_vmcs(selector, value)?;
+			self.write_vmcs(limit, limit_value)?;
+			self.write_vmcs(ar, access)?;
+			self.write_vmcs(base, 0)?;
+		}
+
+		self.write_vmcs(VMCS_GUEST_LDTR, 0)?;
+		self.write_vmcs(VMCS_GUEST_LDTR_LIMIT, 0)?;
+		self.write_vmcs(VMCS_GUEST_LDTR_AR, 0x10000)?;
+		self.write_vmcs(VMCS_GUEST_LDTR_BASE, 0)?;
+
+		self.write_vmcs(VMCS_GUEST_TR, 0)?;
+		self.write_vmcs(VMCS_GUEST_TR_LIMIT, 0)?;
+		self.write_vmcs(VMCS_GUEST_TR_AR, tr_ar)?;
+		self.write_vmcs(VMCS_GUEST_TR_BASE, 0)?;
+
+		self.write_vmcs(VMCS_GUEST_GDTR_LIMIT, table_limit)?;
+		self.write_vmcs(VMCS_GUEST_GDTR_BASE, 0)?;
+		self.write_vmcs(VMCS_GUEST_IDTR_LIMIT, table_limit)?;
+		self.write_vmcs(VMCS_GUEST_IDTR_BASE, 0)
+	}
+}
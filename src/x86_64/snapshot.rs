@@ -0,0 +1,292 @@
+//! Snapshot of all VMCS fields for offline analysis
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::{VirtualCpu, VmcsField};
+use crate::Error;
+use core::fmt;
+use std::collections::BTreeMap;
+
+/// Known VMCS fields as (name, field ID)
+const FIELDS: [(&str, u32); 150] = [
+	("vpid", VMCS_VPID),
+	("ctrl_posted_int_n_vector", VMCS_CTRL_POSTED_INT_N_VECTOR),
+	("ctrl_eptp_index", VMCS_CTRL_EPTP_INDEX),
+	("guest_es", VMCS_GUEST_ES),
+	("guest_cs", VMCS_GUEST_CS),
+	("guest_ss", VMCS_GUEST_SS),
+	("guest_ds", VMCS_GUEST_DS),
+	("guest_fs", VMCS_GUEST_FS),
+	("guest_gs", VMCS_GUEST_GS),
+	("guest_ldtr", VMCS_GUEST_LDTR),
+	("guest_tr", VMCS_GUEST_TR),
+	("guest_int_status", VMCS_GUEST_INT_STATUS),
+	("host_es", VMCS_HOST_ES),
+	("host_cs", VMCS_HOST_CS),
+	("host_ss", VMCS_HOST_SS),
+	("host_ds", VMCS_HOST_DS),
+	("host_fs", VMCS_HOST_FS),
+	("host_gs", VMCS_HOST_GS),
+	("host_tr", VMCS_HOST_TR),
+	("ctrl_io_bitmap_a", VMCS_CTRL_IO_BITMAP_A),
+	("ctrl_io_bitmap_b", VMCS_CTRL_IO_BITMAP_B),
+	("ctrl_msr_bitmaps", VMCS_CTRL_MSR_BITMAPS),
+	(
+		"ctrl_vmexit_msr_store_addr",
+		VMCS_CTRL_VMEXIT_MSR_STORE_ADDR,
+	),
+	("ctrl_vmexit_msr_load_addr", VMCS_CTRL_VMEXIT_MSR_LOAD_ADDR),
+	(
+		"ctrl_vmentry_msr_load_addr",
+		VMCS_CTRL_VMENTRY_MSR_LOAD_ADDR,
+	),
+	("ctrl_executive_vmcs_ptr", VMCS_CTRL_EXECUTIVE_VMCS_PTR),
+	("ctrl_tsc_offset", VMCS_CTRL_TSC_OFFSET),
+	("ctrl_virtual_apic", VMCS_CTRL_VIRTUAL_APIC),
+	("ctrl_apic_access", VMCS_CTRL_APIC_ACCESS),
+	("ctrl_posted_int_desc_addr", VMCS_CTRL_POSTED_INT_DESC_ADDR),
+	("ctrl_vmfunc_ctrl", VMCS_CTRL_VMFUNC_CTRL),
+	("ctrl_eptp", VMCS_CTRL_EPTP),
+	("ctrl_eoi_exit_bitmap_0", VMCS_CTRL_EOI_EXIT_BITMAP_0),
+	("ctrl_eoi_exit_bitmap_1", VMCS_CTRL_EOI_EXIT_BITMAP_1),
+	("ctrl_eoi_exit_bitmap_2", VMCS_CTRL_EOI_EXIT_BITMAP_2),
+	("ctrl_eoi_exit_bitmap_3", VMCS_CTRL_EOI_EXIT_BITMAP_3),
+	("ctrl_eptp_list_addr", VMCS_CTRL_EPTP_LIST_ADDR),
+	("ctrl_vmread_bitmap_addr", VMCS_CTRL_VMREAD_BITMAP_ADDR),
+	("ctrl_vmwrite_bitmap_addr", VMCS_CTRL_VMWRITE_BITMAP_ADDR),
+	("ctrl_virt_exc_info_addr", VMCS_CTRL_VIRT_EXC_INFO_ADDR),
+	("ctrl_xss_exiting_bitmap", VMCS_CTRL_XSS_EXITING_BITMAP),
+	("guest_physical_address", VMCS_GUEST_PHYSICAL_ADDRESS),
+	("guest_link_pointer", VMCS_GUEST_LINK_POINTER),
+	("guest_ia32_debugctl", VMCS_GUEST_IA32_DEBUGCTL),
+	("guest_ia32_pat", VMCS_GUEST_IA32_PAT),
+	("guest_ia32_efer", VMCS_GUEST_IA32_EFER),
+	(
+		"guest_ia32_perf_global_ctrl",
+		VMCS_GUEST_IA32_PERF_GLOBAL_CTRL,
+	),
+	("guest_pdpte0", VMCS_GUEST_PDPTE0),
+	("guest_pdpte1", VMCS_GUEST_PDPTE1),
+	("guest_pdpte2", VMCS_GUEST_PDPTE2),
+	("guest_pdpte3", VMCS_GUEST_PDPTE3),
+	("host_ia32_pat", VMCS_HOST_IA32_PAT),
+	("host_ia32_efer", VMCS_HOST_IA32_EFER),
+	(
+		"host_ia32_perf_global_ctrl",
+		VMCS_HOST_IA32_PERF_GLOBAL_CTRL,
+	),
+	("ctrl_pin_based", VMCS_CTRL_PIN_BASED),
+	("ctrl_cpu_based", VMCS_CTRL_CPU_BASED),
+	("ctrl_exc_bitmap", VMCS_CTRL_EXC_BITMAP),
+	("ctrl_pf_error_mask", VMCS_CTRL_PF_ERROR_MASK),
+	("ctrl_pf_error_match", VMCS_CTRL_PF_ERROR_MATCH),
+	("ctrl_cr3_count", VMCS_CTRL_CR3_COUNT),
+	("ctrl_vmexit_controls", VMCS_CTRL_VMEXIT_CONTROLS),
+	(
+		"ctrl_vmexit_msr_store_count",
+		VMCS_CTRL_VMEXIT_MSR_STORE_COUNT,
+	),
+	(
+		"ctrl_vmexit_msr_load_count",
+		VMCS_CTRL_VMEXIT_MSR_LOAD_COUNT,
+	),
+	("ctrl_vmentry_controls", VMCS_CTRL_VMENTRY_CONTROLS),
+	(
+		"ctrl_vmentry_msr_load_count",
+		VMCS_CTRL_VMENTRY_MSR_LOAD_COUNT,
+	),
+	("ctrl_vmentry_irq_info", VMCS_CTRL_VMENTRY_IRQ_INFO),
+	("ctrl_vmentry_exc_error", VMCS_CTRL_VMENTRY_EXC_ERROR),
+	("ctrl_vmentry_instr_len", VMCS_CTRL_VMENTRY_INSTR_LEN),
+	("ctrl_tpr_threshold", VMCS_CTRL_TPR_THRESHOLD),
+	("ctrl_cpu_based2", VMCS_CTRL_CPU_BASED2),
+	("ctrl_ple_gap", VMCS_CTRL_PLE_GAP),
+	("ctrl_ple_window", VMCS_CTRL_PLE_WINDOW),
+	("ro_instr_error", VMCS_RO_INSTR_ERROR),
+	("ro_exit_reason", VMCS_RO_EXIT_REASON),
+	("ro_vmexit_irq_info", VMCS_RO_VMEXIT_IRQ_INFO),
+	("ro_vmexit_irq_error", VMCS_RO_VMEXIT_IRQ_ERROR),
+	("ro_idt_vector_info", VMCS_RO_IDT_VECTOR_INFO),
+	("ro_idt_vector_error", VMCS_RO_IDT_VECTOR_ERROR),
+	("ro_vmexit_instr_len", VMCS_RO_VMEXIT_INSTR_LEN),
+	("ro_vmx_instr_info", VMCS_RO_VMX_INSTR_INFO),
+	("guest_es_limit", VMCS_GUEST_ES_LIMIT),
+	("guest_cs_limit", VMCS_GUEST_CS_LIMIT),
+	("guest_ss_limit", VMCS_GUEST_SS_LIMIT),
+	("guest_ds_limit", VMCS_GUEST_DS_LIMIT),
+	("guest_fs_limit", VMCS_GUEST_FS_LIMIT),
+	("guest_gs_limit", VMCS_GUEST_GS_LIMIT),
+	("guest_ldtr_limit", VMCS_GUEST_LDTR_LIMIT),
+	("guest_tr_limit", VMCS_GUEST_TR_LIMIT),
+	("guest_gdtr_limit", VMCS_GUEST_GDTR_LIMIT),
+	("guest_idtr_limit", VMCS_GUEST_IDTR_LIMIT),
+	("guest_es_ar", VMCS_GUEST_ES_AR),
+	("guest_cs_ar", VMCS_GUEST_CS_AR),
+	("guest_ss_ar", VMCS_GUEST_SS_AR),
+	("guest_ds_ar", VMCS_GUEST_DS_AR),
+	("guest_fs_ar", VMCS_GUEST_FS_AR),
+	("guest_gs_ar", VMCS_GUEST_GS_AR),
+	("guest_ldtr_ar", VMCS_GUEST_LDTR_AR),
+	("guest_tr_ar", VMCS_GUEST_TR_AR),
+	("guest_ignore_irq", VMCS_GUEST_IGNORE_IRQ),
+	("guest_activity_state", VMCS_GUEST_ACTIVITY_STATE),
+	("guest_smbase", VMCS_GUEST_SMBASE),
+	("guest_ia32_sysenter_cs", VMCS_GUEST_IA32_SYSENTER_CS),
+	("guest_vmx_timer_value", VMCS_GUEST_VMX_TIMER_VALUE),
+	("host_ia32_sysenter_cs", VMCS_HOST_IA32_SYSENTER_CS),
+	("ctrl_cr0_mask", VMCS_CTRL_CR0_MASK),
+	("ctrl_cr4_mask", VMCS_CTRL_CR4_MASK),
+	("ctrl_cr0_shadow", VMCS_CTRL_CR0_SHADOW),
+	("ctrl_cr4_shadow", VMCS_CTRL_CR4_SHADOW),
+	("ctrl_cr3_value0", VMCS_CTRL_CR3_VALUE0),
+	("ctrl_cr3_value1", VMCS_CTRL_CR3_VALUE1),
+	("ctrl_cr3_value2", VMCS_CTRL_CR3_VALUE2),
+	("ctrl_cr3_value3", VMCS_CTRL_CR3_VALUE3),
+	("ro_exit_qualific", VMCS_RO_EXIT_QUALIFIC),
+	("ro_io_rcx", VMCS_RO_IO_RCX),
+	("ro_io_rsi", VMCS_RO_IO_RSI),
+	("ro_io_rdi", VMCS_RO_IO_RDI),
+	("ro_io_rip", VMCS_RO_IO_RIP),
+	("ro_guest_lin_addr", VMCS_RO_GUEST_LIN_ADDR),
+	("guest_cr0", VMCS_GUEST_CR0),
+	("guest_cr3", VMCS_GUEST_CR3),
+	("guest_cr4", VMCS_GUEST_CR4),
+	("guest_es_base", VMCS_GUEST_ES_BASE),
+	("guest_cs_base", VMCS_GUEST_CS_BASE),
+	("guest_ss_base", VMCS_GUEST_SS_BASE),
+	("guest_ds_base", VMCS_GUEST_DS_BASE),
+	("guest_fs_base", VMCS_GUEST_FS_BASE),
+	("guest_gs_base", VMCS_GUEST_GS_BASE),
+	("guest_ldtr_base", VMCS_GUEST_LDTR_BASE),
+	("guest_tr_base", VMCS_GUEST_TR_BASE),
+	("guest_gdtr_base", VMCS_GUEST_GDTR_BASE),
+	("guest_idtr_base", VMCS_GUEST_IDTR_BASE),
+	("guest_dr7", VMCS_GUEST_DR7),
+	("guest_rsp", VMCS_GUEST_RSP),
+	("guest_rip", VMCS_GUEST_RIP),
+	("guest_rflags", VMCS_GUEST_RFLAGS),
+	("guest_debug_exc", VMCS_GUEST_DEBUG_EXC),
+	("guest_sysenter_esp", VMCS_GUEST_SYSENTER_ESP),
+	("guest_sysenter_eip", VMCS_GUEST_SYSENTER_EIP),
+	("host_cr0", VMCS_HOST_CR0),
+	("host_cr3", VMCS_HOST_CR3),
+	("host_cr4", VMCS_HOST_CR4),
+	("host_fs_base", VMCS_HOST_FS_BASE),
+	("host_gs_base", VMCS_HOST_GS_BASE),
+	("host_tr_base", VMCS_HOST_TR_BASE),
+	("host_gdtr_base", VMCS_HOST_GDTR_BASE),
+	("host_idtr_base", VMCS_HOST_IDTR_BASE),
+	("host_ia32_sysenter_esp", VMCS_HOST_IA32_SYSENTER_ESP),
+	("host_ia32_sysenter_eip", VMCS_HOST_IA32_SYSENTER_EIP),
+	("host_rsp", VMCS_HOST_RSP),
+	("host_rip", VMCS_HOST_RIP),
+];
+
+/// Category of a VMCS field, encoded in bits 11:10 of its ID
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum VmcsCategory {
+	/// VM-execution, VM-exit and VM-entry controls
+	Control,
+	/// VM-exit information, which is read-only
+	ReadOnly,
+	/// Guest state
+	Guest,
+	/// Host state
+	Host,
+}
+
+impl VmcsCategory {
+	// Returns the heading of the category in a snapshot
+	fn heading(self) -> &'static str {
+		match self {
+			VmcsCategory::Control => "controls",
+			VmcsCategory::Guest => "guest state",
+			VmcsCategory::Host => "host state",
+			VmcsCategory::ReadOnly => "read-only",
+		}
+	}
+}
+
+impl VmcsField {
+	/// Returns the category of the field as encoded in bits 10 and 11 of its ID
+	pub fn category(self) -> VmcsCategory {
+		match (self.0 >> 10) & 0x3 {
+			0 => VmcsCategory::Control,
+			1 => VmcsCategory::ReadOnly,
+			2 => VmcsCategory::Guest,
+			_ => VmcsCategory::Host,
+		}
+	}
+
+	/// Returns the name of a field of `consts::vmcs` without the `VMCS_` prefix
+	pub fn name(self) -> Option<&'static str> {
+		FIELDS
+			.iter()
+			.find(|(_, field)| *field == self.0)
+			.map(|(name, _)| *name)
+	}
+}
+
+/// Values of all VMCS fields of a VirtualCpu that could be read
+///
+/// The `Display` implementation lists the fields grouped by category.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VmcsSnapshot(BTreeMap<VmcsField, u64>);
+
+impl VmcsSnapshot {
+	/// Returns the value of a field, if it could be read
+	pub fn get<F: Into<VmcsField>>(&self, field: F) -> Option<u64> {
+		self.0.get(&field.into()).copied()
+	}
+
+	/// Returns the values of the fields, ordered by field ID
+	pub fn fields(&self) -> &BTreeMap<VmcsField, u64> {
+		&self.0
+	}
+
+	/// Returns the values of the fields, ordered by field ID
+	pub fn into_fields(self) -> BTreeMap<VmcsField, u64> {
+		self.0
+	}
+}
+
+impl fmt::Display for VmcsSnapshot {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for category in [
+			VmcsCategory::Control,
+			VmcsCategory::Guest,
+			VmcsCategory::Host,
+			VmcsCategory::ReadOnly,
+		] {
+			writeln!(f, "{}:", category.heading())?;
+			for (field, value) in self
+				.0
+				.iter()
+				.filter(|(field, _)| field.category() == category)
+			{
+				let name = field.name().unwrap_or("?");
+				writeln!(f, "  {:#06x} {:<28} {:#018x}", field.0, name, value)?;
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Reads every known VMCS field into a snapshot
+	///
+	/// Fields that can't be read, e.g. because the processor doesn't support them,
+	/// are left out instead of failing the snapshot. This is meant for diagnosing a
+	/// failed VM entry, after which the other accessors may report errors as well.
+	pub fn read_vmcs_snapshot(&self) -> Result<VmcsSnapshot, Error> {
+		let fields = FIELDS
+			.iter()
+			.filter_map(|(_, field)| {
+				let field = VmcsField(*field);
+				self.read_vmcs(field).ok().map(|value| (field, value))
+			})
+			.collect();
+
+		Ok(VmcsSnapshot(fields))
+	}
+}
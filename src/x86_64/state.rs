@@ -0,0 +1,171 @@
+//! Copy of the architectural state of a VirtualCpu
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::{GpRegs, Register, VirtualCpu, XsaveState};
+use crate::Error;
+
+/// VMCS control fields, written before the guest state so that the guest state is
+/// checked against the controls of the source
+const CONTROL_FIELDS: [u32; 16] = [
+	VMCS_CTRL_PIN_BASED,
+	VMCS_CTRL_CPU_BASED,
+	VMCS_CTRL_CPU_BASED2,
+	VMCS_CTRL_VMENTRY_CONTROLS,
+	VMCS_CTRL_VMEXIT_CONTROLS,
+	VMCS_CTRL_EXC_BITMAP,
+	VMCS_CTRL_PF_ERROR_MASK,
+	VMCS_CTRL_PF_ERROR_MATCH,
+	VMCS_CTRL_CR0_MASK,
+	VMCS_CTRL_CR0_SHADOW,
+	VMCS_CTRL_CR4_MASK,
+	VMCS_CTRL_CR4_SHADOW,
+	VMCS_CTRL_TPR_THRESHOLD,
+	VMCS_CTRL_TSC_OFFSET,
+	VMCS_CTRL_VMENTRY_EXC_ERROR,
+	VMCS_CTRL_VMENTRY_INSTR_LEN,
+];
+
+/// Guest state VMCS fields
+const GUEST_FIELDS: [u32; 58] = [
+	VMCS_GUEST_ES,
+	VMCS_GUEST_ES_LIMIT,
+	VMCS_GUEST_ES_AR,
+	VMCS_GUEST_ES_BASE,
+	VMCS_GUEST_CS,
+	VMCS_GUEST_CS_LIMIT,
+	VMCS_GUEST_CS_AR,
+	VMCS_GUEST_CS_BASE,
+	VMCS_GUEST_SS,
+	VMCS_GUEST_SS_LIMIT,
+	VMCS_GUEST_SS_AR,
+	VMCS_GUEST_SS_BASE,
+	VMCS_GUEST_DS,
+	VMCS_GUEST_DS_LIMIT,
+	VMCS_GUEST_DS_AR,
+	VMCS_GUEST_DS_BASE,
+	VMCS_GUEST_FS,
+	VMCS_GUEST_FS_LIMIT,
+	VMCS_GUEST_FS_AR,
+	VMCS_GUEST_FS_BASE,
+	VMCS_GUEST_GS,
+	VMCS_GUEST_GS_LIMIT,
+	VMCS_GUEST_GS_AR,
+	VMCS_GUEST_GS_BASE,
+	VMCS_GUEST_LDTR,
+	VMCS_GUEST_LDTR_LIMIT,
+	VMCS_GUEST_LDTR_AR,
+	VMCS_GUEST_LDTR_BASE,
+	VMCS_GUEST_TR,
+	VMCS_GUEST_TR_LIMIT,
+	VMCS_GUEST_TR_AR,
+	VMCS_GUEST_TR_BASE,
+	VMCS_GUEST_GDTR_LIMIT,
+	VMCS_GUEST_GDTR_BASE,
+	VMCS_GUEST_IDTR_LIMIT,
+	VMCS_GUEST_IDTR_BASE,
+	VMCS_GUEST_LINK_POINTER,
+	VMCS_GUEST_IA32_DEBUGCTL,
+	VMCS_GUEST_IA32_PAT,
+	VMCS_GUEST_IA32_EFER,
+	VMCS_GUEST_PDPTE0,
+	VMCS_GUEST_PDPTE1,
+	VMCS_GUEST_PDPTE2,
+	VMCS_GUEST_PDPTE3,
+	VMCS_GUEST_IGNORE_IRQ,
+	VMCS_GUEST_ACTIVITY_STATE,
+	VMCS_GUEST_IA32_SYSENTER_CS,
+	VMCS_GUEST_SYSENTER_ESP,
+	VMCS_GUEST_SYSENTER_EIP,
+	VMCS_GUEST_CR0,
+	VMCS_GUEST_CR3,
+	VMCS_GUEST_CR4,
+	VMCS_GUEST_DR7,
+	VMCS_GUEST_RSP,
+	VMCS_GUEST_RIP,
+	VMCS_GUEST_RFLAGS,
+	VMCS_GUEST_DEBUG_EXC,
+	VMCS_CTRL_VMENTRY_IRQ_INFO,
+];
+
+/// Registers that aren't part of the VMCS or the general purpose registers
+///
+/// XCR0 comes last, since it determines the size of the XSAVE area.
+const EXTRA_REGISTERS: [Register; 7] = [
+	Register::CR2,
+	Register::DR0,
+	Register::DR1,
+	Register::DR2,
+	Register::DR3,
+	Register::DR6,
+	Register::XCR0,
+];
+
+/// Architectural state of a VirtualCpu that can be restored onto another VirtualCpu
+///
+/// The state covers the VMCS controls and guest state, the general purpose, control
+/// and debug registers and the XSAVE area. MSRs that aren't part of the VMCS aren't
+/// included.
+#[derive(Clone, Debug)]
+pub struct VcpuState {
+	/// Values of `CONTROL_FIELDS`
+	controls: Vec<u64>,
+	/// Values of `GUEST_FIELDS`
+	guest: Vec<u64>,
+	/// General purpose registers
+	regs: GpRegs,
+	/// Values of `EXTRA_REGISTERS`
+	extra: Vec<u64>,
+	/// Floating point and SIMD state
+	xsave: XsaveState,
+}
+
+impl VirtualCpu<'_> {
+	/// Returns the architectural state of the VirtualCpu
+	pub fn save_state(&self) -> Result<VcpuState, Error> {
+		Ok(VcpuState {
+			controls: CONTROL_FIELDS
+				.iter()
+				.map(|field| self.read_vmcs(*field))
+				.collect::<Result<_, _>>()?,
+			guest: GUEST_FIELDS
+				.iter()
+				.map(|field| self.read_vmcs(*field))
+				.collect::<Result<_, _>>()?,
+			regs: self.read_gp_regs()?,
+			extra: EXTRA_REGISTERS
+				.iter()
+				.map(|reg| self.read_register(reg))
+				.collect::<Result<_, _>>()?,
+			xsave: self.read_xsave()?,
+		})
+	}
+
+	/// Restores a state returned by `save_state`, possibly of another VirtualCpu
+	///
+	/// The VMCS controls are written first, followed by the guest state, the
+	/// registers and XCR0. The XSAVE area is written last, once XCR0 selects the
+	/// components it was saved with.
+	pub fn restore_state(&self, state: &VcpuState) -> Result<(), Error> {
+		for (field, value) in CONTROL_FIELDS.iter().zip(state.controls.iter()) {
+			self.write_vmcs(*field, *value)?;
+		}
+		for (field, value) in GUEST_FIELDS.iter().zip(state.guest.iter()) {
+			self.write_vmcs(*field, *value)?;
+		}
+
+		self.write_gp_regs(&state.regs)?;
+		for (reg, value) in EXTRA_REGISTERS.iter().zip(state.extra.iter()) {
+			self.write_register(reg, *value)?;
+		}
+
+		self.write_xsave(&state.xsave)
+	}
+
+	/// Copies the architectural state of `src` onto the VirtualCpu
+	///
+	/// Both VirtualCpus must belong to the current thread. Use `save_state` and
+	/// `restore_state` to copy the state between threads.
+	pub fn copy_state_from(&self, src: &VirtualCpu) -> Result<(), Error> {
+		self.restore_state(&src.save_state()?)
+	}
+}
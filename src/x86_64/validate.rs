@@ -0,0 +1,157 @@
+//! Checks of the guest state and controls that VM entry would reject
+
+use crate::x86_64::consts::vmcs::*;
+use crate::x86_64::consts::vmx_cap::*;
+use crate::x86_64::setup::{CR0_NE, CR0_PE, CR0_PG, CR4_PAE, CR4_VMXE};
+use crate::x86_64::{read_vmx_cap, VMXCap, VirtualCpu, RFLAGS_DEFINED, RFLAGS_RESERVED_ONE};
+use crate::Error;
+
+/// Virtual-8086 mode flag of RFLAGS
+const RFLAGS_VM: u64 = 1 << 17;
+
+/// Control words and the capabilities that constrain them
+const CONTROLS: [(&str, u32, VMXCap); 5] = [
+	("pin-based controls", VMCS_CTRL_PIN_BASED, VMXCap::PINBASED),
+	(
+		"primary processor-based controls",
+		VMCS_CTRL_CPU_BASED,
+		VMXCap::PROCBASED,
+	),
+	(
+		"secondary processor-based controls",
+		VMCS_CTRL_CPU_BASED2,
+		VMXCap::PROCBASED2,
+	),
+	(
+		"VM-entry controls",
+		VMCS_CTRL_VMENTRY_CONTROLS,
+		VMXCap::ENTRY,
+	),
+	("VM-exit controls", VMCS_CTRL_VMEXIT_CONTROLS, VMXCap::EXIT),
+];
+
+impl VirtualCpu<'_> {
+	/// Checks the VMCS for the most common reasons of a failed VM entry
+	///
+	/// The control words are checked against the capabilities of the host, the
+	/// guest state for the reserved bits of RFLAGS, the bits of CR0 and CR4 that
+	/// are fixed in VMX operation and the consistency of the CS and SS privilege
+	/// levels. Hypervisor.framework doesn't report the `IA32_VMX_CR0_FIXED*` and
+	/// `IA32_VMX_CR4_FIXED*` MSRs, so the bits fixed on every VMX processor are
+	/// used, relaxed for an unrestricted guest. The guest isn't entered.
+	///
+	/// Returns a human-readable description of every problem that was found. This
+	/// is no complete implementation of the checks of the SDM, so VM entry might
+	/// still fail for a VMCS that passes.
+	pub fn validate_vmcs(&self) -> Result<(), Vec<String>> {
+		match self.vmcs_problems() {
+			Ok(problems) if problems.is_empty() => Ok(()),
+			Ok(problems) => Err(problems),
+			Err(err) => Err(vec![format!("failed to read the VMCS: {}", err)]),
+		}
+	}
+
+	// Collects the problems reported by `validate_vmcs`
+	fn vmcs_problems(&self) -> Result<Vec<String>, Error> {
+		let mut problems = Vec::new();
+
+		for (name, field, cap) in CONTROLS {
+			let value = self.read_vmcs(field)?;
+			let cap = read_vmx_cap(cap)?;
+			let required = cap & 0xffffffff & !value;
+			let unsupported = value & !(cap >> 32);
+			if required != 0 {
+				problems.push(format!(
+					"{} {:#x} lack the required bits {:#x}",
+					name, value, required
+				));
+			}
+			if unsupported != 0 {
+				problems.push(format!(
+					"{} {:#x} set the unsupported bits {:#x}",
+					name, value, unsupported
+				));
+			}
+		}
+
+		let cpu_based = self.read_vmcs(VMCS_CTRL_CPU_BASED)?;
+		let unrestricted = cpu_based & CPU_BASED_SECONDARY_CTLS != 0
+			&& self.read_vmcs(VMCS_CTRL_CPU_BASED2)? & CPU_BASED2_UNRESTRICTED != 0;
+		let ia32e = self.read_vmcs(VMCS_CTRL_VMENTRY_CONTROLS)? & VMENTRY_GUEST_IA32E != 0;
+
+		let rflags = self.read_vmcs(VMCS_GUEST_RFLAGS)?;
+		if rflags & !RFLAGS_DEFINED != 0 {
+			problems.push(format!(
+				"RFLAGS {:#x} sets the reserved bits {:#x}",
+				rflags,
+				rflags & !RFLAGS_DEFINED
+			));
+		}
+		if rflags & RFLAGS_RESERVED_ONE == 0 {
+			problems.push(format!("RFLAGS {:#x} clears the reserved bit 1", rflags));
+		}
+		if ia32e && rflags & RFLAGS_VM != 0 {
+			problems.push(String::from("RFLAGS.VM is set for an IA-32e mode guest"));
+		}
+
+		let cr0 = self.read_vmcs(VMCS_GUEST_CR0)?;
+		if cr0 >> 32 != 0 {
+			problems.push(format!("CR0 {:#x} sets bits above bit 31", cr0));
+		}
+		if cr0 & CR0_NE == 0 {
+			problems.push(format!(
+				"CR0 {:#x} clears CR0.NE, which is fixed to 1 in VMX operation",
+				cr0
+			));
+		}
+		if !unrestricted && cr0 & (CR0_PE | CR0_PG) != CR0_PE | CR0_PG {
+			problems.push(format!(
+				"CR0 {:#x} clears CR0.PE or CR0.PG without the unrestricted guest control",
+				cr0
+			));
+		}
+		if cr0 & CR0_PG != 0 && cr0 & CR0_PE == 0 {
+			problems.push(format!("CR0 {:#x} sets CR0.PG without CR0.PE", cr0));
+		}
+		if ia32e && cr0 & CR0_PG == 0 {
+			problems.push(format!(
+				"CR0 {:#x} clears CR0.PG for an IA-32e mode guest",
+				cr0
+			));
+		}
+
+		let cr4 = self.read_vmcs(VMCS_GUEST_CR4)?;
+		if cr4 & CR4_VMXE == 0 {
+			problems.push(format!(
+				"CR4 {:#x} clears CR4.VMXE, which is fixed to 1 in VMX operation",
+				cr4
+			));
+		}
+		if ia32e && cr4 & CR4_PAE == 0 {
+			problems.push(format!(
+				"CR4 {:#x} clears CR4.PAE for an IA-32e mode guest",
+				cr4
+			));
+		}
+
+		let cs_ar = self.read_vmcs(VMCS_GUEST_CS_AR)?;
+		let ss_ar = self.read_vmcs(VMCS_GUEST_SS_AR)?;
+		let cs_dpl = (cs_ar >> 5) & 0x3;
+		let ss_dpl = (ss_ar >> 5) & 0x3;
+		match cs_ar & 0xf {
+			/* non-conforming code */
+			9 | 11 if cs_dpl != ss_dpl => problems.push(format!(
+				"SS.DPL {} differs from the DPL {} of the non-conforming CS",
+				ss_dpl, cs_dpl
+			)),
+			/* read/write data, only allowed for an unrestricted guest */
+			3 if !unrestricted || cs_dpl != 0 => problems.push(format!(
+				"CS is a data segment with DPL {}, which needs the unrestricted guest control and DPL 0",
+				cs_dpl
+			)),
+			_ => {}
+		}
+
+		Ok(problems)
+	}
+}
@@ -0,0 +1,56 @@
+//! Typed VMCS field IDs
+
+/// Width of a VMCS field
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum VmcsFieldWidth {
+	/// 16-bit field
+	Bits16,
+	/// 32-bit field
+	Bits32,
+	/// 64-bit field
+	Bits64,
+	/// Natural-width field (64 bits on a 64-bit host)
+	Natural,
+}
+
+impl VmcsFieldWidth {
+	/// Returns the mask of the bits the field can hold
+	pub fn mask(self) -> u64 {
+		match self {
+			VmcsFieldWidth::Bits16 => 0xffff,
+			VmcsFieldWidth::Bits32 => 0xffff_ffff,
+			VmcsFieldWidth::Bits64 | VmcsFieldWidth::Natural => u64::MAX,
+		}
+	}
+}
+
+/// VMCS field ID (see `consts::vmcs`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VmcsField(pub u32);
+
+impl VmcsField {
+	/// Returns the width of the field as encoded in bits 13 and 14 of its ID
+	///
+	/// The high half of a 64-bit field (access type bit 0 set) is 32 bits wide.
+	pub fn width(self) -> VmcsFieldWidth {
+		match (self.0 >> 13) & 0x3 {
+			0 => VmcsFieldWidth::Bits16,
+			1 if self.0 & 1 != 0 => VmcsFieldWidth::Bits32,
+			1 => VmcsFieldWidth::Bits64,
+			2 => VmcsFieldWidth::Bits32,
+			_ => VmcsFieldWidth::Natural,
+		}
+	}
+}
+
+impl From<u32> for VmcsField {
+	fn from(field: u32) -> VmcsField {
+		VmcsField(field)
+	}
+}
+
+impl From<VmcsField> for u32 {
+	fn from(field: VmcsField) -> u32 {
+		field.0
+	}
+}
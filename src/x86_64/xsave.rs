@@ -0,0 +1,205 @@
+//! Extended processor state in the `XSAVE` layout
+
+use crate::x86_64::consts::vmcs::VMCS_GUEST_CR4;
+use crate::x86_64::ffi::{hv_vcpu_read_fpstate, hv_vcpu_write_fpstate};
+use crate::x86_64::setup::CR4_OSXSAVE;
+use crate::x86_64::{Register, VirtualCpu};
+use crate::{match_error_code, Error};
+use core::arch::x86_64::__cpuid_count;
+use libc::{c_void, size_t};
+
+/// Size of the legacy `FXSAVE` region at the start of the `XSAVE` area
+const LEGACY_SIZE: usize = 512;
+
+/// Size of the `XSAVE` header that follows the legacy region
+const HEADER_SIZE: usize = 64;
+
+/// Offset of the XSTATE_BV field, which marks the components present in the area
+const XSTATE_BV_OFFSET: usize = LEGACY_SIZE;
+
+/// Offset of XMM0 in the legacy region
+const XMM_OFFSET: usize = 160;
+
+/// XCR0 bit of the x87 state, which must always be set
+const XCR0_X87: u64 = 1 << 0;
+
+/// XCR0 bit of the SSE state (XMM0-XMM15 and MXCSR)
+const XCR0_SSE: u64 = 1 << 1;
+
+/// XCR0 bit of the AVX state (upper halves of YMM0-YMM15)
+pub const XCR0_AVX: u64 = 1 << 2;
+
+/// XCR0 bits of the AVX-512 state, which must be enabled together
+const XCR0_AVX512: u64 = 0b111 << 5;
+
+/// Standard (non-compacted) offset and size of the extended state components,
+/// indexed by their XCR0 bit
+const COMPONENTS: [(usize, usize); 8] = [
+	(0, 0),       // x87, part of the legacy region
+	(0, 0),       // SSE, part of the legacy region
+	(576, 256),   // AVX
+	(960, 64),    // MPX bound registers
+	(1024, 64),   // MPX configuration
+	(1088, 64),   // AVX-512 opmask registers
+	(1152, 512),  // AVX-512 upper halves of ZMM0-ZMM15
+	(1664, 1024), // AVX-512 ZMM16-ZMM31
+];
+
+/// Returns the size of the `XSAVE` area in bytes for the components enabled in `xcr0`
+///
+/// Components without a known layout don't contribute to the size.
+pub fn xsave_size(xcr0: u64) -> usize {
+	COMPONENTS
+		.iter()
+		.enumerate()
+		.filter(|(bit, _)| xcr0 & (1 << bit) != 0)
+		.map(|(_, (offset, size))| offset + size)
+		.fold(LEGACY_SIZE + HEADER_SIZE, usize::max)
+}
+
+/// Returns the XCR0 bits that the host supports, as reported by CPUID leaf 0xD
+pub fn supported_xcr0() -> u64 {
+	let leaf = __cpuid_count(0xd, 0);
+
+	(u64::from(leaf.edx) << 32) | u64::from(leaf.eax)
+}
+
+// Checks the dependencies between the state components enabled in `xcr0`
+fn valid_xcr0(xcr0: u64) -> bool {
+	let avx512 = xcr0 & XCR0_AVX512;
+
+	xcr0 & XCR0_X87 != 0
+		&& (xcr0 & XCR0_AVX == 0 || xcr0 & XCR0_SSE != 0)
+		&& (avx512 == 0 || (avx512 == XCR0_AVX512 && xcr0 & XCR0_AVX != 0))
+}
+
+/// Floating point and SIMD state of a VirtualCpu in the `XSAVE` layout
+///
+/// Unlike the `FXSAVE` layout of `read_fpstate`, the area covers the extended
+/// components that are enabled in the guest's XCR0, e.g. the upper halves of the
+/// YMM registers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XsaveState {
+	/// XCR0 the area was sized for
+	xcr0: u64,
+	/// Raw `XSAVE` area
+	area: Vec<u8>,
+}
+
+impl XsaveState {
+	/// Returns the XCR0 value the area was sized for
+	pub fn xcr0(&self) -> u64 {
+		self.xcr0
+	}
+
+	/// Returns the raw `XSAVE` area
+	pub fn as_bytes(&self) -> &[u8] {
+		&self.area
+	}
+
+	/// Returns the register XMM`n` (the lower half of YMM`n`)
+	pub fn xmm(&self, n: usize) -> Option<[u8; 16]> {
+		if n >= 16 {
+			return None;
+		}
+
+		let offset = XMM_OFFSET + 16 * n;
+		self.area[offset..offset + 16].try_into().ok()
+	}
+
+	/// Returns the upper half of the register YMM`n`
+	///
+	/// Returns `None` if AVX isn't enabled in XCR0 or `n` isn't below 16.
+	pub fn ymm_high(&self, n: usize) -> Option<[u8; 16]> {
+		let offset = self.ymm_high_offset(n)?;
+		self.area[offset..offset + 16].try_into().ok()
+	}
+
+	/// Sets the upper half of the register YMM`n`
+	///
+	/// Marks the AVX component as present, so that it is restored by `write_xsave`.
+	/// Returns `Error::BadArg` if AVX isn't enabled in XCR0 or `n` isn't below 16.
+	pub fn set_ymm_high(&mut self, n: usize, value: [u8; 16]) -> Result<(), Error> {
+		let offset = self.ymm_high_offset(n).ok_or(Error::BadArg)?;
+		self.area[offset..offset + 16].copy_from_slice(&value);
+		self.area[XSTATE_BV_OFFSET] |= XCR0_AVX as u8;
+
+		Ok(())
+	}
+
+	// Returns the offset of the upper half of YMM`n` in the area
+	fn ymm_high_offset(&self, n: usize) -> Option<usize> {
+		if self.xcr0 & XCR0_AVX == 0 || n >= 16 {
+			return None;
+		}
+
+		Some(COMPONENTS[2].0 + 16 * n)
+	}
+}
+
+impl VirtualCpu<'_> {
+	/// Enables the state components of `value` in the guest's XCR0
+	///
+	/// CR4.OSXSAVE is set first, since XCR0 can only be written with it set.
+	/// Returns `Error::BadArg` if `value` contains bits that `supported_xcr0` doesn't
+	/// report or violates the dependencies between the components, e.g. AVX without
+	/// SSE or x87 disabled.
+	pub fn set_xcr0(&self, value: u64) -> Result<(), Error> {
+		let unsupported = value & !supported_xcr0();
+		if unsupported != 0 {
+			return Err(Error::BadArg.context(format!(
+				"XCR0 bits {:#x} aren't supported by the host",
+				unsupported
+			)));
+		}
+		if !valid_xcr0(value) {
+			return Err(Error::BadArg.context(format!("invalid XCR0 {:#x}", value)));
+		}
+
+		let cr4 = self.read_vmcs(VMCS_GUEST_CR4)?;
+		if cr4 & CR4_OSXSAVE == 0 {
+			self.write_vmcs(VMCS_GUEST_CR4, cr4 | CR4_OSXSAVE)?;
+		}
+
+		self.write_register(&Register::XCR0, value)
+	}
+
+	/// Returns the floating point and SIMD state of the VirtualCpu in the `XSAVE` layout
+	///
+	/// The area is sized for the components enabled in the current XCR0 of the guest.
+	pub fn read_xsave(&self) -> Result<XsaveState, Error> {
+		self.assert_owner();
+		let xcr0 = self.read_register(&Register::XCR0)?;
+		let mut area = vec![0; xsave_size(xcr0)];
+
+		match_error_code(unsafe {
+			hv_vcpu_read_fpstate(
+				self.get_id(),
+				area.as_mut_ptr() as *mut c_void,
+				area.len() as size_t,
+			)
+		})?;
+
+		Ok(XsaveState { xcr0, area })
+	}
+
+	/// Sets the floating point and SIMD state of the VirtualCpu from an `XSAVE` area
+	///
+	/// Returns `Error::BadArg` if the area doesn't match the size required by the
+	/// current XCR0 of the guest.
+	pub fn write_xsave(&self, state: &XsaveState) -> Result<(), Error> {
+		self.assert_owner();
+		let xcr0 = self.read_register(&Register::XCR0)?;
+		if state.area.len() != xsave_size(xcr0) {
+			return Err(Error::BadArg);
+		}
+
+		match_error_code(unsafe {
+			hv_vcpu_write_fpstate(
+				self.get_id(),
+				state.area.as_ptr() as *const c_void,
+				state.area.len() as size_t,
+			)
+		})
+	}
+}
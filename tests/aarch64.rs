@@ -0,0 +1,682 @@
+#![cfg(target_arch = "aarch64")]
+
+extern crate xhypervisor;
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::slice;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use xhypervisor::*;
+
+/// Only one VM may exist per process, so the tests must not run concurrently
+static VM_LOCK: Mutex<()> = Mutex::new(());
+
+const PAYLOAD_ADDRESS: u64 = 0x20000;
+const MEM_SIZE: usize = 8 * 0x10000;
+
+/// Runs `f` with a VM that has `payload` mapped at `PAYLOAD_ADDRESS`
+fn with_payload<F: FnOnce(&mut [u8])>(payload: &[u8], f: F) {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	unsafe {
+		let layout = Layout::from_size_align(MEM_SIZE, 0x4000).unwrap();
+		let mem_raw = alloc_zeroed(layout);
+		let mem = slice::from_raw_parts_mut(mem_raw, MEM_SIZE);
+		mem[PAYLOAD_ADDRESS as usize..PAYLOAD_ADDRESS as usize + payload.len()]
+			.copy_from_slice(payload);
+
+		create_vm().unwrap();
+		map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
+
+		f(mem);
+
+		unmap_mem(0, MEM_SIZE).unwrap();
+		destroy_vm().unwrap();
+		dealloc(mem_raw, layout);
+	}
+}
+
+/// Creates a VirtualCpu at EL1 with interrupts masked, starting at `PAYLOAD_ADDRESS`
+fn el1_vcpu() -> VirtualCpu<'static> {
+	let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+	vcpu.write_pstate(Pstate::el1t()).unwrap();
+	vcpu.write_register(Register::PC, PAYLOAD_ADDRESS).unwrap();
+	vcpu
+}
+
+#[test]
+fn interrupt_vcpus_cancels_run() {
+	let payload = [
+		0x00, 0x00, 0x00, 0x14, // b .
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+
+		interrupt_vcpus(&[vcpu.get_id()]).unwrap();
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Cancelled)
+		));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn run_with_timeout_interrupts_guest() {
+	let payload = [
+		0x00, 0x00, 0x00, 0x14, // b .
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+
+		let start = Instant::now();
+		assert_eq!(
+			vcpu.run_with_timeout(Duration::from_millis(50)).unwrap(),
+			RunOutcome::TimedOut
+		);
+		assert!(start.elapsed() >= Duration::from_millis(50));
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Cancelled)
+		));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn max_vcpu_count_is_positive() {
+	/* hosts before macOS 15 can't tell */
+	match max_vcpu_count() {
+		Ok(count) => assert!(count >= 1),
+		Err(err) => assert!(matches!(err.root_cause(), Error::Unsupp)),
+	}
+}
+
+#[test]
+fn fpstate_size_is_checked() {
+	assert_eq!(fpstate_size(), 512);
+
+	with_payload(&[], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		let mut short = vec![0u8; 256];
+		assert!(matches!(vcpu.read_fpstate(&mut short), Err(Error::BadArg)));
+
+		let state = vcpu.read_fpstate_vec().unwrap();
+		assert_eq!(state.len(), fpstate_size());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn vtimer_handshake() {
+	let payload = [
+		0x00, 0x00, 0x00, 0x14, // b .
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+		let mut vtimer = VTimer::new();
+
+		// fire the timer immediately
+		vcpu.write_system_register(SystemRegister::CNTV_CVAL_EL0, 0)
+			.unwrap();
+		vcpu.write_system_register(SystemRegister::CNTV_CTL_EL0, 0b001)
+			.unwrap();
+
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::VTimerActivated)
+		));
+
+		vtimer.on_activated(&vcpu).unwrap();
+		assert!(vtimer.is_pending());
+		assert!(vcpu.vtimer_mask().unwrap());
+
+		// the guest hasn't acknowledged the timer yet
+		vtimer.service(&vcpu).unwrap();
+		assert!(vtimer.is_pending());
+		assert!(vcpu.vtimer_mask().unwrap());
+
+		// the guest masks the timer in its interrupt handler
+		vcpu.write_system_register(SystemRegister::CNTV_CTL_EL0, 0b011)
+			.unwrap();
+		vtimer.service(&vcpu).unwrap();
+		assert!(!vtimer.is_pending());
+		assert!(!vcpu.vtimer_mask().unwrap());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn vtimer_masked_at_creation() {
+	let payload = [
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+		0x00, 0x00, 0x00, 0x14, // b .
+	];
+
+	with_payload(&payload, |_| {
+		let config = VcpuConfig {
+			vtimer_masked: true,
+		};
+		let vcpu = unsafe { VirtualCpu::new_unbound_with(&config) }.unwrap();
+		vcpu.write_pstate(Pstate::el1t()).unwrap();
+		vcpu.write_register(Register::PC, PAYLOAD_ADDRESS).unwrap();
+		assert!(vcpu.vtimer_mask().unwrap());
+
+		// fire the timer immediately
+		vcpu.write_system_register(SystemRegister::CNTV_CVAL_EL0, 0)
+			.unwrap();
+		vcpu.write_system_register(SystemRegister::CNTV_CTL_EL0, 0b001)
+			.unwrap();
+
+		// the masked timer lets the guest run up to the HVC
+		vcpu.run().unwrap();
+		match vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => {
+				assert_eq!(exception.syndrome().exception_class(), ExceptionClass::Hvc);
+			}
+			reason => panic!("unexpected exit: {:?}", reason),
+		}
+
+		vcpu.set_vtimer_mask(false).unwrap();
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::VTimerActivated)
+		));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn register_display() {
+	assert_eq!(format!("{}", Register::X0), "x0");
+	assert_eq!(Register::CPSR.to_string(), "cpsr");
+	assert_eq!(SystemRegister::SCTLR_EL1.to_string(), "SCTLR_EL1");
+}
+
+#[test]
+fn esr_decoding() {
+	// hvc #0
+	let hvc = Esr(0x5a00_0000);
+	assert_eq!(hvc.exception_class(), ExceptionClass::Hvc);
+	assert_eq!(hvc.instruction_length(), 4);
+	assert_eq!(hvc.iss(), 0);
+	assert_eq!(hvc.fault_status(), None);
+	assert_eq!(hvc.is_write(), None);
+
+	// 32-bit write to a translation fault at level 3
+	let abort = Esr(0x9200_0047);
+	assert_eq!(abort.exception_class(), ExceptionClass::DataAbort);
+	assert_eq!(abort.fault_status(), Some(0x07));
+	assert_eq!(abort.is_write(), Some(true));
+
+	assert_eq!(
+		Esr(0x3f << 26).exception_class(),
+		ExceptionClass::Other(0x3f)
+	);
+	assert_eq!(u8::from(ExceptionClass::Brk), 0x3c);
+}
+
+#[test]
+fn mmio_read_on_unmapped_ipa() {
+	let payload = [
+		0x01, 0x00, 0x40, 0xb9, // ldr w1, [x0]
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+		vcpu.write_register(Register::X0, MEM_SIZE as u64).unwrap();
+
+		vcpu.run().unwrap();
+		assert_eq!(
+			vcpu.mmio_exit().unwrap(),
+			Some(MmioExit {
+				ipa: MEM_SIZE as u64,
+				is_write: false,
+				size: 4,
+				reg: 1,
+				data: None,
+				sign_extend: false,
+				sixty_four: false,
+			})
+		);
+		assert!(matches!(vcpu.complete_mmio_write(), Err(Error::BadArg)));
+		vcpu.complete_mmio_read(0x1234_5678_dead_beef).unwrap();
+
+		vcpu.run().unwrap();
+		match vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => {
+				assert_eq!(exception.syndrome().exception_class(), ExceptionClass::Hvc);
+			}
+			reason => panic!("unexpected exit: {:?}", reason),
+		}
+		assert_eq!(vcpu.read_register(Register::X1).unwrap(), 0xdead_beef);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn skip_instruction() {
+	let payload = [
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+		0x03, 0x00, 0x00, 0xd4, // smc #0
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+		assert!(matches!(vcpu.skip_instruction(), Err(Error::BadArg)));
+
+		vcpu.run().unwrap();
+		let pc = vcpu.read_register(Register::PC).unwrap();
+		vcpu.skip_instruction().unwrap();
+		assert_eq!(vcpu.read_register(Register::PC).unwrap(), pc);
+
+		vcpu.run().unwrap();
+		let pc = vcpu.read_register(Register::PC).unwrap();
+		assert_eq!(pc, PAYLOAD_ADDRESS + 4);
+		vcpu.skip_instruction().unwrap();
+		assert_eq!(vcpu.read_register(Register::PC).unwrap(), pc + 4);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn vcpu_actor_runs_payload() {
+	let payload = [
+		0x40, 0x05, 0x80, 0xd2, // mov x0, #42
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |_| {
+		let actor = unsafe { VcpuActor::spawn() }.unwrap();
+		actor
+			.write_register(Register::CPSR, Pstate::el1t().bits())
+			.unwrap();
+		actor.write_register(Register::PC, PAYLOAD_ADDRESS).unwrap();
+
+		actor.run().unwrap();
+		assert_eq!(actor.read_register(Register::X0).unwrap(), 42);
+
+		actor.stop().unwrap();
+	});
+}
+
+#[test]
+fn instruction_pointer() {
+	with_payload(&[], |_| {
+		let vcpu = el1_vcpu();
+		assert_eq!(vcpu.instruction_pointer().unwrap(), PAYLOAD_ADDRESS);
+
+		vcpu.set_instruction_pointer(PAYLOAD_ADDRESS + 4).unwrap();
+		assert_eq!(
+			vcpu.read_register(Register::PC).unwrap(),
+			PAYLOAD_ADDRESS + 4
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn model_registers() {
+	with_payload(&[], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.write_model_register(SystemRegister::TPIDR_EL1, 0x1234)
+			.unwrap();
+		assert_eq!(
+			vcpu.read_model_register(SystemRegister::TPIDR_EL1).unwrap(),
+			0x1234
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn guest_writes_reach_mapped_memory() {
+	let payload = [
+		0x41, 0x08, 0x80, 0xd2, // mov x1, #0x42
+		0x01, 0x00, 0x00, 0xf9, // str x1, [x0]
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |mem| {
+		let vcpu = el1_vcpu();
+		vcpu.write_register(Register::X0, 0x30000).unwrap();
+
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Exception { .. })
+		));
+
+		/* the mapping was created from a mutable borrow of `mem` */
+		assert_eq!(mem[0x30000..0x30008], 0x42u64.to_le_bytes());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn unknown_exit_reason_keeps_code() {
+	let exit = |reason| ffi::hv_vcpu_exit_t {
+		reason,
+		exception: ffi::hv_vcpu_exit_exception_t {
+			syndrome: 0,
+			virtual_address: 0,
+			physical_address: 0,
+		},
+	};
+
+	assert!(matches!(
+		VirtualCpuExitReason::from(exit(ffi::HV_EXIT_REASON_UNKNOWN)),
+		VirtualCpuExitReason::Unknown(3)
+	));
+	let reason = VirtualCpuExitReason::from(exit(42));
+	assert!(matches!(reason, VirtualCpuExitReason::Unknown(42)));
+	assert_eq!(format!("{:?}", reason), "Unknown(42)");
+	assert!(matches!(
+		VirtualCpuExitReason::from(exit(ffi::HV_EXIT_REASON_VTIMER_ACTIVATED)),
+		VirtualCpuExitReason::VTimerActivated
+	));
+}
+
+#[test]
+fn exit_reason_before_first_run() {
+	with_payload(&[0x02, 0x00, 0x00, 0xd4 /* hvc #0 */], |_| {
+		let vcpu = el1_vcpu();
+		assert!(vcpu.exit_reason().is_none());
+		assert!(matches!(vcpu.skip_instruction(), Err(Error::BadArg)));
+
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Exception { .. })
+		));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn set_entry_point_el1h() {
+	let payload = [
+		0x00, 0x00, 0x00, 0x14, // b .
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.set_entry_point(PAYLOAD_ADDRESS + 4).unwrap();
+		assert_eq!(vcpu.read_pstate().unwrap(), Pstate::el1h());
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Exception { .. })
+		));
+		assert_eq!(vcpu.instruction_pointer().unwrap(), PAYLOAD_ADDRESS + 8);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn guest_exception_decodes_hvc() {
+	with_payload(&[0xa2, 0x00, 0x00, 0xd4 /* hvc #5 */], |_| {
+		let vcpu = el1_vcpu();
+
+		vcpu.run().unwrap();
+		match vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => {
+				let esr = exception.syndrome();
+				assert_eq!(esr.exception_class(), ExceptionClass::Hvc);
+				assert_eq!(esr.iss() & 0xffff, 5);
+				assert_eq!(exception.raw().syndrome, esr.0);
+			}
+			reason => panic!("unexpected exit: {:?}", reason),
+		}
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exception_return_to_prepared_context() {
+	let payload = [
+		0x22, 0x00, 0x00, 0xd4, // hvc #1
+		0xe0, 0x03, 0x9f, 0xd6, // eret
+		0x42, 0x00, 0x00, 0xd4, // hvc #2
+		0x62, 0x00, 0x00, 0xd4, // hvc #3
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+		vcpu.prepare_exception_return(0x1234, Pstate::el1h())
+			.unwrap();
+
+		/* the HVC exits to the host and leaves the context of EL1 alone */
+		vcpu.run().unwrap();
+		assert!(matches!(
+			vcpu.exit_reason(),
+			Some(VirtualCpuExitReason::Exception { .. })
+		));
+		let (elr, spsr) = vcpu.read_exception_context().unwrap();
+		assert_eq!(elr, 0x1234);
+		assert_eq!(spsr, Pstate::el1h());
+
+		/* the ERET returns to `hvc #3`, skipping `hvc #2` right behind it */
+		vcpu.prepare_exception_return(PAYLOAD_ADDRESS + 12, spsr)
+			.unwrap();
+		vcpu.write_register(Register::PC, PAYLOAD_ADDRESS + 4)
+			.unwrap();
+		vcpu.run().unwrap();
+		match vcpu.exit_reason() {
+			Some(VirtualCpuExitReason::Exception { exception }) => {
+				assert_eq!(exception.syndrome().iss() & 0xffff, 3);
+			}
+			reason => panic!("unexpected exit: {:?}", reason),
+		}
+		assert_eq!(vcpu.read_pstate().unwrap(), Pstate::el1h());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn pstate_round_trip() {
+	assert_eq!(Pstate::el1h().bits(), 0x3c5);
+	assert_eq!(Pstate::el1t().bits(), 0x3c4);
+	assert_eq!(Pstate::el0().bits(), 0x0);
+	assert_eq!(Pstate::el1h().with_interrupts().bits(), 0x305);
+
+	let pstate = Pstate {
+		z: true,
+		c: true,
+		..Pstate::el1h()
+	};
+	assert_eq!(Pstate::from(pstate.bits()), pstate);
+	assert_eq!(pstate.bits(), 0x6000_03c5);
+
+	with_payload(&[], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.write_pstate(pstate).unwrap();
+		assert_eq!(vcpu.read_pstate().unwrap(), pstate);
+		assert_eq!(vcpu.read_register(Register::CPSR).unwrap(), 0x6000_03c5);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn run_and_time() {
+	let payload = [
+		0x00, 0x00, 0x88, 0xd2, // mov x0, #0x4000
+		0x00, 0x04, 0x00, 0xf1, // subs x0, x0, #1
+		0xe1, 0xff, 0xff, 0x54, // b.ne .-4
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |_| {
+		let vcpu = el1_vcpu();
+
+		let (reason, elapsed) = vcpu.run_and_time().unwrap();
+		assert!(matches!(reason, VirtualCpuExitReason::Exception { .. }));
+		assert!(elapsed > Duration::ZERO);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn gp_regs_round_trip() {
+	with_payload(&[], |_| {
+		let vcpu = el1_vcpu();
+
+		let mut regs = vcpu.read_gp_regs().unwrap();
+		assert_eq!(regs.pc, PAYLOAD_ADDRESS);
+		assert_eq!(regs.cpsr, Pstate::el1t().bits());
+
+		regs.x[0] = 0x1111;
+		regs.x[30] = 0x2222;
+		regs.sp = 0x3000;
+		vcpu.write_gp_regs(&regs).unwrap();
+
+		assert_eq!(vcpu.read_gp_regs().unwrap(), regs);
+		/* EL1 with SPSel = 0 uses SP_EL0 */
+		assert_eq!(
+			vcpu.read_system_register(SystemRegister::SP_EL0).unwrap(),
+			0x3000
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn stack_pointer_follows_spsel() {
+	with_payload(&[], |_| {
+		let vcpu = el1_vcpu();
+
+		/* EL1h uses SP_EL1 */
+		vcpu.write_pstate(Pstate::el1h()).unwrap();
+		vcpu.set_stack_pointer(0x8000).unwrap();
+		assert_eq!(vcpu.stack_pointer().unwrap(), 0x8000);
+		assert_eq!(
+			vcpu.read_system_register(SystemRegister::SP_EL1).unwrap(),
+			0x8000
+		);
+
+		/* EL1t uses SP_EL0 */
+		vcpu.write_pstate(Pstate::el1t()).unwrap();
+		vcpu.set_stack_pointer(0x4000).unwrap();
+		assert_eq!(vcpu.stack_pointer().unwrap(), 0x4000);
+		assert_eq!(
+			vcpu.read_system_register(SystemRegister::SP_EL1).unwrap(),
+			0x8000
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn restore_state_forks_guest() {
+	let payload = [
+		0x20, 0x00, 0x80, 0xd2, // mov x0, #1
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+		0x00, 0x04, 0x00, 0x91, // add x0, x0, #1
+		0x02, 0x00, 0x00, 0xd4, // hvc #0
+	];
+
+	with_payload(&payload, |_| {
+		let parent = el1_vcpu();
+		parent.run().unwrap();
+		assert_eq!(parent.read_register(Register::X0).unwrap(), 1);
+
+		/* a thread owns a single VirtualCpu, so the child runs on its own thread */
+		let regs = parent.read_gp_regs().unwrap();
+		let state = parent.save_state().unwrap();
+		thread::scope(|s| {
+			s.spawn(|| {
+				let child = unsafe { VirtualCpu::new_unbound() }.unwrap();
+				child.restore_state(&state).unwrap();
+				assert_eq!(child.read_gp_regs().unwrap(), regs);
+				child.run().unwrap();
+				assert_eq!(child.read_register(Register::X0).unwrap(), 2);
+				child.destroy().unwrap();
+			});
+		});
+
+		parent.run().unwrap();
+		assert_eq!(parent.read_register(Register::X0).unwrap(), 2);
+
+		parent.destroy().unwrap();
+	});
+}
+
+#[test]
+fn signal_guard_never_misses_a_cancel() {
+	let payload = [
+		0x00, 0x00, 0x00, 0x14, // b .
+	];
+
+	with_payload(&payload, |_| {
+		let (handle, receiver) = mpsc::channel();
+		let (cancelled, acks) = mpsc::channel();
+
+		thread::scope(|s| {
+			s.spawn(move || {
+				let vcpu = el1_vcpu();
+				let guard = SignalGuard::new(&vcpu).unwrap();
+				handle.send(guard.cancel_handle()).unwrap();
+
+				for _ in 0..1000 {
+					loop {
+						vcpu.run().unwrap();
+						if let Some(VirtualCpuExitReason::Cancelled) = vcpu.exit_reason() {
+							break;
+						}
+					}
+					cancelled.send(()).unwrap();
+				}
+
+				drop(guard);
+				cancelled.send(()).unwrap();
+				vcpu.destroy().unwrap();
+			});
+
+			/* the signal lands both inside and outside of hv_vcpu_run */
+			let handle: CancelHandle = receiver.recv().unwrap();
+			for i in 0..1000 {
+				handle.cancel().unwrap();
+				acks.recv_timeout(Duration::from_secs(5))
+					.unwrap_or_else(|_| panic!("cancel {} was lost", i));
+			}
+
+			/* the handle is useless once the guard is gone */
+			acks.recv().unwrap();
+			assert!(matches!(
+				handle.cancel().unwrap_err().root_cause(),
+				Error::BadArg
+			));
+		});
+	});
+}
@@ -0,0 +1,8 @@
+extern crate trybuild;
+
+/// Programs that the borrow checker has to reject, e.g. a VirtualCpu outliving its Vm
+#[test]
+fn compile_fail() {
+	let cases = trybuild::TestCases::new();
+	cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,145 @@
+extern crate xhypervisor;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::time::Duration;
+use xhypervisor::{hv_support, retry_busy, Error};
+
+#[test]
+fn transient_errors() {
+	let cases = [
+		(Error::Success, false),
+		(Error::Error, false),
+		(Error::Busy, true),
+		(Error::BadArg, false),
+		(Error::NoRes, false),
+		(Error::NoDev, false),
+		(Error::Denied, false),
+		(Error::Unsupp, false),
+		(Error::Busy.context("creating the VM"), true),
+		(Error::BadArg.context("mapping memory"), false),
+	];
+
+	for (err, transient) in cases.iter() {
+		assert_eq!(err.is_transient(), *transient, "{}", err);
+		assert_eq!(err.is_fatal(), !*transient, "{}", err);
+	}
+}
+
+#[test]
+fn errors_compare_by_variant() {
+	assert_eq!(Error::BadArg, Error::BadArg);
+	assert_ne!(Error::BadArg, Error::Busy);
+	assert_ne!(Error::Success, Error::Error);
+
+	let result: Result<(), Error> = Err(Error::Denied);
+	assert_eq!(result, Err(Error::Denied));
+
+	assert_eq!(
+		Error::Busy.context("creating the VM"),
+		Error::Busy.context("creating the VM")
+	);
+	assert_ne!(
+		Error::Busy.context("creating the VM"),
+		Error::Busy.context("mapping memory")
+	);
+	assert_ne!(Error::Busy.context("creating the VM"), Error::Busy);
+
+	let not_found = || Error::from(io::Error::from(io::ErrorKind::NotFound));
+	assert_eq!(not_found(), not_found());
+	assert_ne!(
+		not_found(),
+		Error::from(io::Error::from(io::ErrorKind::PermissionDenied))
+	);
+
+	let errors: HashSet<Error> = [
+		Error::BadArg,
+		Error::BadArg,
+		Error::NoRes,
+		not_found(),
+		not_found(),
+		Error::NoRes.context("allocating memory"),
+	]
+	.into_iter()
+	.collect();
+	assert_eq!(errors.len(), 4);
+	assert!(errors.contains(&Error::NoRes));
+}
+
+#[test]
+fn retry_busy_succeeds_within_budget() {
+	let mut calls = 0;
+	let result = retry_busy(3, Duration::from_millis(1), || {
+		calls += 1;
+		if calls < 3 {
+			Err(Error::Busy)
+		} else {
+			Ok(calls)
+		}
+	});
+	assert_eq!(result.unwrap(), 3);
+}
+
+#[test]
+fn retry_busy_gives_up() {
+	let mut calls = 0;
+	let result: Result<(), Error> = retry_busy(2, Duration::from_millis(1), || {
+		calls += 1;
+		Err(Error::Busy)
+	});
+	assert!(matches!(result, Err(Error::Busy)));
+	assert_eq!(calls, 2);
+
+	/* fatal errors aren't retried */
+	calls = 0;
+	let result: Result<(), Error> = retry_busy(5, Duration::from_millis(1), || {
+		calls += 1;
+		Err(Error::BadArg)
+	});
+	assert!(matches!(result, Err(Error::BadArg)));
+	assert_eq!(calls, 1);
+}
+
+#[test]
+fn io_errors_convert() {
+	fn open(path: &str) -> Result<File, Error> {
+		Ok(File::open(path)?)
+	}
+
+	let err = open("/nonexistent/xhypervisor").unwrap_err();
+	match &err {
+		Error::Io(io) => assert_eq!(io.kind(), io::ErrorKind::NotFound),
+		err => panic!("unexpected error: {:?}", err),
+	}
+	assert!(err.to_string().starts_with("I/O error: "));
+	assert!(err.is_fatal());
+	assert!(std::error::Error::source(&err).is_some());
+}
+
+#[test]
+fn no_device_explains_hv_support() {
+	/* the probe found virtualization disabled */
+	let err = Error::NoDev.with_hv_support_hint(Some(false));
+	assert!(matches!(err.root_cause(), Error::NoDev));
+	assert!(err.to_string().contains("kern.hv_support is 0"), "{}", err);
+
+	/* the probe failed or found no reason */
+	for hv_support in [None, Some(true)] {
+		let err = Error::NoDev.with_hv_support_hint(hv_support);
+		assert!(
+			err.to_string().contains("sysctl kern.hv_support"),
+			"{}",
+			err
+		);
+	}
+
+	/* other errors aren't about virtualization support */
+	let err = Error::BadArg.with_hv_support_hint(Some(false));
+	assert_eq!(err.to_string(), "bad argument");
+
+	#[cfg(target_os = "macos")]
+	assert!(hv_support().is_some());
+	#[cfg(not(target_os = "macos"))]
+	assert_eq!(hv_support(), None);
+}
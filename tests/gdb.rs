@@ -0,0 +1,75 @@
+#![cfg(feature = "gdbstub")]
+
+extern crate xhypervisor;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use xhypervisor::*;
+
+/// Offset of the program counter in the reply to `g`, in hex digits
+#[cfg(target_arch = "x86_64")]
+const PC_OFFSET: usize = 16 * 16;
+#[cfg(target_arch = "aarch64")]
+const PC_OFFSET: usize = 32 * 16;
+
+/// Reads the next packet from the stub and returns its run-length decoded payload
+fn read_packet(stream: &mut TcpStream) -> String {
+	let mut bytes = stream.bytes().map(|b| b.unwrap());
+	bytes.by_ref().find(|b| *b == b'$').unwrap();
+
+	let mut payload = String::new();
+	let mut last = '0';
+	while let Some(b) = bytes.next() {
+		match b {
+			b'#' => break,
+			b'*' => {
+				let count = bytes.next().unwrap() - 29;
+				payload.extend(std::iter::repeat_n(last, usize::from(count)));
+			}
+			b => {
+				last = char::from(b);
+				payload.push(last);
+			}
+		}
+	}
+	bytes.next().unwrap();
+	bytes.next().unwrap();
+
+	payload
+}
+
+#[test]
+fn gdb_reads_program_counter() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = listener.local_addr().unwrap();
+
+	let client = thread::spawn(move || {
+		let mut stream = TcpStream::connect(addr).unwrap();
+
+		stream.write_all(b"$g#67").unwrap();
+		let regs = read_packet(&mut stream);
+		stream.write_all(b"+$D#44").unwrap();
+		assert_eq!(read_packet(&mut stream), "OK");
+		stream.write_all(b"+").unwrap();
+
+		let mut pc = [0u8; 8];
+		for (i, byte) in pc.iter_mut().enumerate() {
+			let digits = &regs[PC_OFFSET + 2 * i..PC_OFFSET + 2 * i + 2];
+			*byte = u8::from_str_radix(digits, 16).unwrap();
+		}
+		u64::from_le_bytes(pc)
+	});
+
+	let vm = Vm::new().unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+	#[cfg(target_arch = "x86_64")]
+	vcpu.write_register(&Register::RIP, 0x1234).unwrap();
+	#[cfg(target_arch = "aarch64")]
+	vcpu.write_register(Register::PC, 0x1234).unwrap();
+
+	let (stream, _) = listener.accept().unwrap();
+	GdbTarget::new(&vcpu, &vm).serve(stream).unwrap();
+
+	assert_eq!(client.join().unwrap(), 0x1234);
+}
@@ -31,7 +31,7 @@ fn vm_create() {
 		//map the vec at address 0
 		map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
 
-		let vcpu = VirtualCpu::new().unwrap();
+		let vcpu = VirtualCpu::new_unbound().unwrap();
 
 		vcpu.write_register(Register::CPSR, 0x3c4).unwrap();
 		vcpu.write_register(Register::PC, EL1_USER_PAYLOAD_ADDRESS)
@@ -39,20 +39,20 @@ fn vm_create() {
 
 		loop {
 			vcpu.run().unwrap();
-			let reason = vcpu.exit_reason();
+			let reason = vcpu.exit_reason().unwrap();
 
 			match reason {
 				VirtualCpuExitReason::Exception { exception } => {
-					let ec = (exception.syndrome >> 26) & 0x3f;
+					let ec = exception.syndrome().exception_class();
 
-					if ec == 0x16 {
+					if ec == ExceptionClass::Hvc {
 						println!(
 							"HVC executed! x0 is {}",
 							vcpu.read_register(Register::X0).unwrap()
 						);
 						break;
 					} else {
-						println!("Unknown exception class 0x{:x}", ec);
+						println!("Unknown exception class {:?}", ec);
 						break;
 					}
 				}
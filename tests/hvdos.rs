@@ -7,6 +7,8 @@ use std::alloc::{alloc, dealloc, Layout};
 use std::io::Write;
 use std::slice;
 #[cfg(target_arch = "x86_64")]
+use std::sync::Mutex;
+#[cfg(target_arch = "x86_64")]
 use xhypervisor::consts::vmcs::*;
 #[cfg(target_arch = "x86_64")]
 use xhypervisor::consts::vmx_cap::*;
@@ -15,6 +17,23 @@ use xhypervisor::consts::vmx_exit::*;
 use xhypervisor::ffi::*;
 use xhypervisor::*;
 
+/// Serializes the tests, the VM instance is global to the process
+#[cfg(target_arch = "x86_64")]
+static VM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Guest code of hvdos, prints the sum of AL and BL to COM1, loaded at 0x100
+#[cfg(target_arch = "x86_64")]
+const HVDOS_CODE: [u8; 27] = [
+	0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+	0x00, 0xd8, /* add %bl, %al */
+	0x04, b'0', /* add $'0', %al */
+	0xee, /* out %al, (%dx) */
+	0xb0, b'\n', /* mov $'\n', %al */
+	0xee,  /* out %al, (%dx) */
+	0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90,
+	0xf4, /* hlt */
+];
+
 /* desired control word constrained by hardware/hypervisor capabilities */
 #[cfg(target_arch = "x86_64")]
 fn cap2ctrl(cap: u64, ctrl: u64) -> u64 {
@@ -24,6 +43,8 @@ fn cap2ctrl(cap: u64, ctrl: u64) -> u64 {
 #[cfg(target_arch = "x86_64")]
 #[test]
 fn vm_create() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
 	unsafe {
 		create_vm().unwrap();
 
@@ -62,7 +83,7 @@ fn vm_create() {
 		let mem = slice::from_raw_parts_mut(mem_raw, capacity);
 		map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
 
-		let vcpu = VirtualCpu::new().unwrap();
+		let vcpu = VirtualCpu::new_unbound().unwrap();
 
 		/* set VMCS control fields */
 		vcpu.write_vmcs(VMCS_CTRL_PIN_BASED, cap2ctrl(vmx_cap_pinbased, 0))
@@ -135,18 +156,7 @@ fn vm_create() {
 		vcpu.write_vmcs(VMCS_GUEST_CR3, 0x0).unwrap();
 		vcpu.write_vmcs(VMCS_GUEST_CR4, 0x2000).unwrap();
 
-		let code: Vec<u8> = vec![
-			0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
-			0x00, 0xd8, /* add %bl, %al */
-			0x04, '0' as u8, /* add $'0', %al */
-			0xee,      /* out %al, (%dx) */
-			0xb0, '\n' as u8, /* mov $'\n', %al */
-			0xee,       /* out %al, (%dx) */
-			0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90, 0x90,
-			0x90, 0xf4, /* hlt */
-		];
-
-		let _ = (&mut mem[256..]).write(&code);
+		let _ = (&mut mem[256..]).write(&HVDOS_CODE);
 
 		/* set up GPRs, start at adress 0x100 */
 		vcpu.write_register(&Register::RIP, 0x100).unwrap();
@@ -158,11 +168,16 @@ fn vm_create() {
 		vcpu.write_register(&Register::RAX, 0x5).unwrap();
 		vcpu.write_register(&Register::RBX, 0x3).unwrap();
 
-		let mut chars = 0u8;
+		let mut console = SerialConsole::new(COM1);
+		let mut output = Vec::new();
 		loop {
 			vcpu.run().unwrap();
 			let exit_reason = vcpu.read_vmcs(VMCS_RO_EXIT_REASON).unwrap() & 0xffff;
-			println!("exit reason: {}", exit_reason);
+			println!(
+				"exit reason: {} ({})",
+				exit_reason,
+				vmx_reason_name(exit_reason as u16)
+			);
 
 			let rip = vcpu.read_register(&Register::RIP).unwrap();
 			println!("RIP at {}", rip);
@@ -177,28 +192,11 @@ fn vm_create() {
 			//break;
 			} else if exit_reason == VMX_REASON_IO as u64 {
 				println!("IO");
-				if chars > 2 {
-					panic!("the guest code should not return more than 2 chars on the serial port");
-				}
-				let qual = vcpu.read_vmcs(VMCS_RO_EXIT_QUALIFIC).unwrap();
-				if (qual >> 16) & 0xFFFF == 0x3F8 {
-					let rax = vcpu.read_register(&Register::RAX).unwrap();
-					println!("RAX == {}", rax);
-					println!("got char: {}", (rax as u8) as char);
-
-					if chars == 0 {
-						assert_eq!(rax, '8' as u64);
-					}
-					if chars == 1 {
-						assert_eq!(rax, '\n' as u64);
-					}
-					chars += 1;
-
-					let inst_length = vcpu.read_vmcs(VMCS_RO_VMEXIT_INSTR_LEN).unwrap();
-
-					vcpu.write_register(&Register::RIP, rip + inst_length)
-						.unwrap();
-				} else {
+				let io = match vcpu.exit_details().unwrap() {
+					ExitDetails::Io(io) => io,
+					details => panic!("unexpected exit: {:?}", details),
+				};
+				if !console.handle_io(&vcpu, &io, &mut output).unwrap() {
 					println!("unrecognized IO port, exit");
 					break;
 				}
@@ -219,9 +217,44 @@ fn vm_create() {
 			}
 		}
 
+		assert_eq!(output, b"8\n");
+
 		drop(vcpu);
 		unmap_mem(0, mem.len()).unwrap();
 
 		dealloc(mem_raw, layout);
 	}
 }
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn vm_with_guest_ram() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let (vm, ram) = Vm::with_guest_ram(4 * 1024).unwrap();
+	ram.write(0x100, &HVDOS_CODE).unwrap();
+
+	let vcpu = vm.create_vcpu().unwrap();
+	vcpu.setup_real_mode().unwrap();
+	vcpu.write_register(&Register::RIP, 0x100).unwrap();
+	vcpu.write_register(&Register::RSP, 0x0).unwrap();
+	vcpu.write_register(&Register::RAX, 0x5).unwrap();
+	vcpu.write_register(&Register::RBX, 0x3).unwrap();
+
+	let mut console = SerialConsole::new(COM1);
+	let mut output = Vec::new();
+	loop {
+		vcpu.run().unwrap();
+		match vcpu.exit_details().unwrap() {
+			ExitDetails::Io(io) => assert!(console.handle_io(&vcpu, &io, &mut output).unwrap()),
+			ExitDetails::Hlt => break,
+			ExitDetails::Other { reason, .. } if reason.0 == VMX_REASON_IRQ => {}
+			ExitDetails::EptViolation(_) => {}
+			details => panic!("unexpected exit: {:?}", details),
+		}
+	}
+
+	assert_eq!(output, b"8\n");
+
+	vcpu.destroy().unwrap();
+}
@@ -0,0 +1,39 @@
+extern crate xhypervisor;
+
+use xhypervisor::prelude::*;
+
+// Names the items that typical users of the prelude rely on
+fn uses_prelude(vcpu: &VirtualCpu) -> Result<(), Error> {
+	#[cfg(target_arch = "aarch64")]
+	{
+		vcpu.write_pstate(Pstate::el1h())?;
+		vcpu.write_register(Register::PC, 0x4000)?;
+		if let Some(VirtualCpuExitReason::Exception { exception }) = vcpu.exit_reason() {
+			let _: ExceptionClass = exception.syndrome().exception_class();
+		}
+		let _ = vcpu.read_system_register(SystemRegister::SCTLR_EL1)?;
+	}
+
+	#[cfg(target_arch = "x86_64")]
+	{
+		let procbased = read_vmx_cap(VMXCap::PROCBASED)?;
+		vcpu.write_vmcs(VMCS_CTRL_CPU_BASED, cap2ctrl(procbased, CPU_BASED_HLT))?;
+		vcpu.write_register(&Register::RIP, 0x100)?;
+		match vcpu.exit_details()? {
+			ExitDetails::Io(_) | ExitDetails::Hlt => {}
+			ExitDetails::Exception(ExceptionExit { vector: 6, .. }) => {}
+			ExitDetails::Other { reason, .. } if reason.0 == VMX_REASON_IRQ => {}
+			_ => {}
+		}
+	}
+
+	Ok(())
+}
+
+#[test]
+fn prelude_covers_common_types() {
+	let _: fn(&VirtualCpu) -> Result<(), Error> = uses_prelude;
+	assert_eq!(MemPerm::ExecAndWrite.to_string(), "rwx");
+	assert!(MemFlags::NONE.is_empty());
+	assert!(page_size().is_power_of_two());
+}
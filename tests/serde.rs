@@ -0,0 +1,44 @@
+#![cfg(feature = "serde")]
+
+extern crate serde_json;
+extern crate xhypervisor;
+
+use xhypervisor::*;
+
+#[test]
+fn mappings_round_trip_through_json() {
+	let json = r#"[
+		{ "gpa": 0, "size": 65536, "perm": "rwx" },
+		{ "gpa": 1048576, "size": 4096, "perm": "r-x" },
+		{ "gpa": 2097152, "size": 8192, "perm": "r--" }
+	]"#;
+
+	let mappings: Vec<Mapping> = serde_json::from_str(json).unwrap();
+	assert_eq!(
+		mappings[1],
+		Mapping {
+			gpa: 0x100000,
+			size: 0x1000,
+			perm: MemPerm::ExecAndRead,
+		}
+	);
+
+	let serialized = serde_json::to_string(&mappings).unwrap();
+	assert!(serialized.contains(r#""perm":"rwx""#));
+	assert_eq!(
+		serde_json::from_str::<Vec<Mapping>>(&serialized).unwrap(),
+		mappings
+	);
+}
+
+#[test]
+fn invalid_permissions_are_rejected() {
+	assert_eq!("r-x".parse::<MemPerm>().unwrap(), MemPerm::ExecAndRead);
+	assert!(matches!(
+		"-w-".parse::<MemPerm>(),
+		Err(Error::Context { .. })
+	));
+
+	let err = serde_json::from_str::<MemPerm>(r#""-w-""#).unwrap_err();
+	assert!(err.to_string().contains("invalid memory permissions"));
+}
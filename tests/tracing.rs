@@ -0,0 +1,137 @@
+#![cfg(feature = "tracing")]
+
+extern crate tracing;
+extern crate xhypervisor;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use xhypervisor::*;
+
+/// Serializes the tests, the VM instance is global to the process
+static VM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Subscriber that records the names of all created spans
+#[derive(Clone, Default)]
+struct SpanRecorder {
+	names: Arc<Mutex<Vec<&'static str>>>,
+	next_id: Arc<AtomicU64>,
+}
+
+impl Subscriber for SpanRecorder {
+	fn enabled(&self, _: &Metadata<'_>) -> bool {
+		true
+	}
+
+	fn new_span(&self, span: &Attributes<'_>) -> Id {
+		self.names.lock().unwrap().push(span.metadata().name());
+		Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+	}
+
+	fn record(&self, _: &Id, _: &Record<'_>) {}
+
+	fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+	fn event(&self, _: &Event<'_>) {}
+
+	fn enter(&self, _: &Id) {}
+
+	fn exit(&self, _: &Id) {}
+}
+
+/// Subscriber that records the messages of all events
+#[derive(Clone, Default)]
+struct EventRecorder {
+	messages: Arc<Mutex<Vec<String>>>,
+}
+
+/// Visitor that extracts the message of an event
+struct MessageVisitor(Option<String>);
+
+impl Visit for MessageVisitor {
+	fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+		if field.name() == "message" {
+			self.0 = Some(format!("{:?}", value));
+		}
+	}
+}
+
+impl Subscriber for EventRecorder {
+	fn enabled(&self, _: &Metadata<'_>) -> bool {
+		true
+	}
+
+	fn new_span(&self, _: &Attributes<'_>) -> Id {
+		Id::from_u64(1)
+	}
+
+	fn record(&self, _: &Id, _: &Record<'_>) {}
+
+	fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+	fn event(&self, event: &Event<'_>) {
+		let mut visitor = MessageVisitor(None);
+		event.record(&mut visitor);
+		if let Some(message) = visitor.0 {
+			self.messages.lock().unwrap().push(message);
+		}
+	}
+
+	fn enter(&self, _: &Id) {}
+
+	fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn map_mem_span() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let recorder = SpanRecorder::default();
+	let mem = GuestMemory::new(0x4000).unwrap();
+	let slice = unsafe { std::slice::from_raw_parts_mut(mem.as_ptr(), mem.size()) };
+
+	tracing::subscriber::with_default(recorder.clone(), || {
+		create_vm().unwrap();
+		map_mem(slice, 0x10000, MemPerm::Read).unwrap();
+		unmap_mem(0x10000, slice.len()).unwrap();
+		destroy_vm().unwrap();
+	});
+
+	let names = recorder.names.lock().unwrap();
+	assert_eq!(*names, ["map_mem", "unmap_mem"]);
+}
+
+#[test]
+fn instruction_pointer_is_read_once_per_exit() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let (vm, ram) = Vm::with_guest_ram(0x4000).unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+
+	#[cfg(target_arch = "x86_64")]
+	{
+		ram.write(0x100, &[0xf4 /* hlt */]).unwrap();
+		vcpu.setup_real_mode().unwrap();
+		vcpu.set_instruction_pointer(0x100).unwrap();
+	}
+	#[cfg(target_arch = "aarch64")]
+	{
+		ram.write(0, &0xd400_0002u32.to_le_bytes() /* hvc #0 */)
+			.unwrap();
+		vcpu.set_entry_point(0).unwrap();
+	}
+	vcpu.run().unwrap();
+
+	let recorder = EventRecorder::default();
+	let (first, second) = tracing::subscriber::with_default(recorder.clone(), || {
+		(
+			vcpu.instruction_pointer().unwrap(),
+			vcpu.instruction_pointer().unwrap(),
+		)
+	});
+
+	assert_eq!(first, second);
+	let messages = recorder.messages.lock().unwrap();
+	assert_eq!(*messages, ["read_register"]);
+}
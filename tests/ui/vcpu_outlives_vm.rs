@@ -0,0 +1,11 @@
+extern crate xhypervisor;
+
+use xhypervisor::Vm;
+
+fn main() {
+	let vm = Vm::new().unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+
+	drop(vm);
+	vcpu.run().unwrap();
+}
@@ -0,0 +1,625 @@
+extern crate xhypervisor;
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::fs;
+use std::slice;
+use std::sync::Mutex;
+use std::thread;
+use xhypervisor::*;
+
+/// Only one VM may exist per process, so the tests must not run concurrently
+static VM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Runs `f` with a VM and `size` bytes of zeroed, page-aligned host memory
+fn with_mem<F: FnOnce(&mut Vm, &mut [u8])>(size: usize, f: F) {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let layout = Layout::from_size_align(size, 0x4000).unwrap();
+
+	unsafe {
+		let mem_raw = alloc_zeroed(layout);
+		let mut vm = Vm::new().unwrap();
+
+		f(&mut vm, slice::from_raw_parts_mut(mem_raw, size));
+
+		drop(vm);
+		dealloc(mem_raw, layout);
+	}
+}
+
+#[test]
+fn map_file() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let path = std::env::temp_dir().join("xhypervisor-map-file.bin");
+	let content: Vec<u8> = (0..100u8).collect();
+	fs::write(&path, &content).unwrap();
+
+	let vm = Vm::new().unwrap();
+	let region = vm.map_file(&path, 0x10000, MemPerm::Read).unwrap();
+
+	assert_eq!(region.gpa(), 0x10000);
+	assert!(region.size() >= content.len());
+	assert_eq!(region.size() % 4096, 0);
+	assert_eq!(&region.as_slice()[..content.len()], &content[..]);
+	assert!(region.as_slice()[content.len()..].iter().all(|b| *b == 0));
+
+	/* overlapping ranges are rejected like for slots */
+	assert!(matches!(
+		vm.map_file(&path, 0x10000, MemPerm::Read),
+		Err(Error::BadArg)
+	));
+	/* a directory can be opened, but not mapped */
+	assert!(matches!(
+		vm.map_file(&std::env::temp_dir(), 0x20000, MemPerm::Read)
+			.unwrap_err()
+			.root_cause(),
+		Error::Io(_)
+	));
+	assert_eq!(vm.mappings().len(), 1);
+
+	drop(region);
+	drop(vm);
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mem_region_protect_and_unmap() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let path = std::env::temp_dir().join("xhypervisor-mem-region.bin");
+	fs::write(&path, [0xaa; 100]).unwrap();
+
+	let vm = Vm::new().unwrap();
+	let region = vm.map_file(&path, 0x10000, MemPerm::Write).unwrap();
+
+	region.protect(MemPerm::Read).unwrap();
+	assert_eq!(vm.permissions_at(0x10000), Some(MemPerm::Read));
+
+	region.unmap().unwrap();
+	assert_eq!(vm.permissions_at(0x10000), None);
+	assert!(vm.mappings().is_empty());
+
+	drop(vm);
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn mem_region_protect_joins_split_mappings() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let path = std::env::temp_dir().join("xhypervisor-mem-region-split.bin");
+	let page = page_size();
+	fs::write(&path, vec![0xaa; 2 * page]).unwrap();
+
+	let vm = Vm::new().unwrap();
+	let region = vm.map_file(&path, 0x10000, MemPerm::Write).unwrap();
+
+	/* splits the recorded mapping of the region in two */
+	vm.protect_range(0x10000 + page as u64, page, MemPerm::Read)
+		.unwrap();
+	assert_eq!(vm.mappings().len(), 2);
+
+	region.protect(MemPerm::Read).unwrap();
+	assert_eq!(vm.permissions_at(0x10000), Some(MemPerm::Read));
+	assert_eq!(
+		vm.permissions_at(0x10000 + page as u64),
+		Some(MemPerm::Read)
+	);
+
+	region.protect(MemPerm::Write).unwrap();
+	assert_eq!(
+		vm.permissions_at(0x10000 + page as u64),
+		Some(MemPerm::Write)
+	);
+
+	drop(region);
+	drop(vm);
+	fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn thread_bound_vm() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let vm = Vm::new_with_guard().unwrap();
+
+	thread::scope(|s| {
+		let result = s.spawn(|| vm.create_vcpu().map(|_| ())).join().unwrap();
+		assert!(matches!(result, Err(Error::BadArg)));
+	});
+
+	let vcpu = vm.create_vcpu().unwrap();
+	vcpu.destroy().unwrap();
+}
+
+#[test]
+fn vcpu_registry() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let vm = Vm::new().unwrap();
+	assert!(vm.vcpu_ids().is_empty());
+
+	let first = vm.create_vcpu().unwrap();
+	let second = vm.create_vcpu().unwrap();
+	let mut ids = vec![first.get_id(), second.get_id()];
+	ids.sort();
+	assert_eq!(vm.vcpu_ids(), ids);
+
+	/* a VirtualCpu created outside the VM isn't registered */
+	let unregistered = unsafe { VirtualCpu::new_unbound() }.unwrap();
+	assert_eq!(vm.vcpu_ids().len(), 2);
+	unregistered.destroy().unwrap();
+
+	vm.interrupt_vcpus().unwrap();
+
+	first.destroy().unwrap();
+	assert_eq!(vm.vcpu_ids(), [second.get_id()]);
+	second.destroy().unwrap();
+	assert!(vm.vcpu_ids().is_empty());
+}
+
+#[test]
+fn vm_destroys_remaining_vcpus() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	/* dropping the VirtualCpus leaves them registered */
+	let vm = Vm::new().unwrap();
+	drop(vm.create_vcpu().unwrap());
+	drop(vm.create_vcpu().unwrap());
+	assert_eq!(vm.vcpu_ids().len(), 2);
+	drop(vm);
+
+	/* the VM was destroyed instead of failing with Error::Busy */
+	let vm = Vm::new().unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+	drop(vcpu);
+	vm.destroy().unwrap();
+
+	Vm::new().unwrap().destroy().unwrap();
+}
+
+#[test]
+fn unmap_all() {
+	with_mem(4 * 0x4000, |vm, mem| {
+		vm.map_mem(&mut mem[..0x4000], 0x0, MemPerm::Read).unwrap();
+		vm.map_mem(&mut mem[0x4000..0x8000], 0x10000, MemPerm::Write)
+			.unwrap();
+		vm.map_mem(&mut mem[0x8000..], 0x20000, MemPerm::ExecAndRead)
+			.unwrap();
+		assert_eq!(vm.mappings().len(), 3);
+		assert_eq!(
+			vm.mappings()[1],
+			Mapping {
+				gpa: 0x10000,
+				size: 0x4000,
+				perm: MemPerm::Write,
+			}
+		);
+
+		vm.unmap_all().unwrap();
+		assert!(vm.mappings().is_empty());
+
+		/* the guest physical space is clear, so the same ranges can be mapped again */
+		vm.map_mem(&mut mem[..0x4000], 0x0, MemPerm::Read).unwrap();
+		vm.map_mem(&mut mem[0x8000..], 0x20000, MemPerm::Read)
+			.unwrap();
+		vm.unmap_all().unwrap();
+	});
+}
+
+/// Returns the (gpa, size, perm) triples of the mappings of the VM
+fn layout(vm: &Vm) -> Vec<(u64, usize, MemPerm)> {
+	vm.mappings()
+		.iter()
+		.map(|mapping| (mapping.gpa, mapping.size, mapping.perm))
+		.collect()
+}
+
+#[test]
+fn protect_range_middle() {
+	with_mem(3 * 0x4000, |vm, mem| {
+		vm.map_mem(mem, 0x10000, MemPerm::Write).unwrap();
+		vm.protect_range(0x14000, 0x4000, MemPerm::Read).unwrap();
+
+		assert_eq!(
+			layout(vm),
+			[
+				(0x10000, 0x4000, MemPerm::Write),
+				(0x14000, 0x4000, MemPerm::Read),
+				(0x18000, 0x4000, MemPerm::Write),
+			]
+		);
+		assert_eq!(vm.permissions_at(0x13fff), Some(MemPerm::Write));
+		assert_eq!(vm.permissions_at(0x14000), Some(MemPerm::Read));
+		assert_eq!(vm.permissions_at(0x18000), Some(MemPerm::Write));
+		assert_eq!(vm.permissions_at(0x1c000), None);
+
+		vm.unmap_all().unwrap();
+	});
+}
+
+#[test]
+fn protect_range_head() {
+	with_mem(2 * 0x4000, |vm, mem| {
+		vm.map_mem(mem, 0x10000, MemPerm::Write).unwrap();
+		vm.protect_range(0x10000, 0x4000, MemPerm::Read).unwrap();
+
+		assert_eq!(
+			layout(vm),
+			[
+				(0x10000, 0x4000, MemPerm::Read),
+				(0x14000, 0x4000, MemPerm::Write),
+			]
+		);
+
+		vm.unmap_all().unwrap();
+	});
+}
+
+#[test]
+fn protect_range_tail() {
+	with_mem(2 * 0x4000, |vm, mem| {
+		vm.map_mem(mem, 0x10000, MemPerm::Write).unwrap();
+		vm.protect_range(0x14000, 0x4000, MemPerm::Read).unwrap();
+
+		assert_eq!(
+			layout(vm),
+			[
+				(0x10000, 0x4000, MemPerm::Write),
+				(0x14000, 0x4000, MemPerm::Read),
+			]
+		);
+
+		vm.unmap_all().unwrap();
+	});
+}
+
+#[test]
+fn protect_range_across_adjacent_mappings() {
+	with_mem(4 * 0x4000, |vm, mem| {
+		vm.map_mem(&mut mem[..0x8000], 0x10000, MemPerm::Write)
+			.unwrap();
+		vm.map_mem(&mut mem[0x8000..], 0x18000, MemPerm::ExecAndWrite)
+			.unwrap();
+		vm.protect_range(0x14000, 0x8000, MemPerm::Read).unwrap();
+
+		assert_eq!(
+			layout(vm),
+			[
+				(0x10000, 0x4000, MemPerm::Write),
+				(0x14000, 0x4000, MemPerm::Read),
+				(0x18000, 0x4000, MemPerm::Read),
+				(0x1c000, 0x4000, MemPerm::ExecAndWrite),
+			]
+		);
+
+		vm.unmap_all().unwrap();
+	});
+}
+
+#[test]
+fn map_mem_raw() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let layout = Layout::from_size_align(0x4000, 0x4000).unwrap();
+
+	unsafe {
+		let mem_raw = alloc_zeroed(layout);
+		let vm = Vm::new().unwrap();
+
+		xhypervisor::map_mem_raw(mem_raw, layout.size(), 0x10000, MemPerm::Write).unwrap();
+		unmap_mem(0x10000, layout.size()).unwrap();
+
+		drop(vm);
+		dealloc(mem_raw, layout);
+	}
+}
+
+#[test]
+fn slots_reject_overlaps() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let mem = GuestMemory::new(0x8000).unwrap();
+
+	let slot = vm
+		.add_slot(0x10000, 0x8000, MemPerm::Write, mem.clone())
+		.unwrap();
+
+	/* overlapping the head or the tail */
+	assert!(matches!(
+		vm.add_slot(0xc000, 0x8000, MemPerm::Write, mem.clone()),
+		Err(Error::BadArg)
+	));
+	assert!(matches!(
+		vm.add_slot(0x14000, 0x8000, MemPerm::Write, mem.clone()),
+		Err(Error::BadArg)
+	));
+
+	/* contained in or containing the slot */
+	assert!(matches!(
+		vm.add_slot(0x14000, 0x4000, MemPerm::Write, mem.clone()),
+		Err(Error::BadArg)
+	));
+	let large = GuestMemory::new(0x10000).unwrap();
+	assert!(matches!(
+		vm.add_slot(0xc000, 0x10000, MemPerm::Write, large),
+		Err(Error::BadArg)
+	));
+
+	/* adjacent on both sides */
+	let below = vm
+		.add_slot(0x8000, 0x8000, MemPerm::Read, mem.clone())
+		.unwrap();
+	let above = vm
+		.add_slot(0x18000, 0x8000, MemPerm::Read, mem.clone())
+		.unwrap();
+	assert_eq!(vm.mappings().len(), 3);
+
+	assert_eq!(vm.remove_slot(slot).unwrap().size(), 0x8000);
+	assert!(matches!(vm.remove_slot(slot), Err(Error::BadArg)));
+	vm.add_slot(0x14000, 0x4000, MemPerm::Write, mem.clone())
+		.unwrap();
+
+	vm.remove_slot(below).unwrap();
+	vm.remove_slot(above).unwrap();
+}
+
+#[test]
+fn guest_memory_read_write() {
+	let mem = GuestMemory::new(100).unwrap();
+	assert_eq!(mem.size() % 4096, 0);
+
+	mem.write(10, &[1, 2, 3]).unwrap();
+	let mut buf = [0u8; 4];
+	mem.read(9, &mut buf).unwrap();
+	assert_eq!(buf, [0, 1, 2, 3]);
+
+	assert!(matches!(
+		mem.write(mem.size() - 1, &[1, 2]),
+		Err(Error::BadArg)
+	));
+	assert!(matches!(mem.read(usize::MAX, &mut buf), Err(Error::BadArg)));
+}
+
+#[test]
+fn guest_memory_structs() {
+	#[repr(C)]
+	#[derive(Copy, Clone, Debug, PartialEq)]
+	struct Descriptor {
+		addr: u64,
+		len: u32,
+		flags: u16,
+		next: u16,
+	}
+
+	let mem = GuestMemory::new(100).unwrap();
+	/* little-endian descriptor at an odd offset */
+	mem.write(
+		3,
+		&[
+			0x00, 0x10, 0, 0, 0, 0, 0, 0, /* addr */
+			0x00, 0x02, 0, 0, /* len */
+			0x01, 0x00, /* flags */
+			0x07, 0x00, /* next */
+		],
+	)
+	.unwrap();
+
+	let desc = unsafe { mem.read_struct::<Descriptor>(3) }.unwrap();
+	assert_eq!(
+		desc,
+		Descriptor {
+			addr: 0x1000,
+			len: 0x200,
+			flags: 1,
+			next: 7
+		}
+	);
+
+	unsafe { mem.write_struct(0x20, Descriptor { next: 8, ..desc }) }.unwrap();
+	let mut buf = [0u8; 2];
+	mem.read(0x20 + 14, &mut buf).unwrap();
+	assert_eq!(buf, [8, 0]);
+
+	assert!(matches!(
+		unsafe { mem.read_struct::<Descriptor>(mem.size() - 15) },
+		Err(Error::BadArg)
+	));
+	assert!(matches!(
+		unsafe { mem.write_struct(usize::MAX, 0u32) },
+		Err(Error::BadArg)
+	));
+}
+
+#[test]
+fn guest_access_spans_slots() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let low = GuestMemory::new(0x4000).unwrap();
+	let high = GuestMemory::new(0x4000).unwrap();
+
+	vm.add_slot(0x10000, 0x4000, MemPerm::Write, low.clone())
+		.unwrap();
+	vm.add_slot(0x14000, 0x4000, MemPerm::Write, high.clone())
+		.unwrap();
+
+	/* straddling the boundary between the slots */
+	vm.guest_write(0x13ffe, &[1, 2, 3, 4]).unwrap();
+	let mut buf = [0u8; 2];
+	low.read(0x3ffe, &mut buf).unwrap();
+	assert_eq!(buf, [1, 2]);
+	high.read(0, &mut buf).unwrap();
+	assert_eq!(buf, [3, 4]);
+
+	let mut buf = [0u8; 6];
+	vm.guest_read(0x13ffd, &mut buf).unwrap();
+	assert_eq!(buf, [0, 1, 2, 3, 4, 0]);
+
+	/* running past the end of the second slot */
+	assert!(matches!(
+		vm.guest_write(0x17fff, &[5, 5]),
+		Err(Error::BadArg)
+	));
+	high.read(0x3fff, &mut buf[..1]).unwrap();
+	assert_eq!(buf[0], 0);
+	assert!(matches!(
+		vm.guest_read(0xffff, &mut buf),
+		Err(Error::BadArg)
+	));
+}
+
+#[test]
+fn page_size_matches_host() {
+	#[cfg(target_arch = "aarch64")]
+	assert_eq!(page_size(), 0x4000);
+	#[cfg(target_arch = "x86_64")]
+	assert_eq!(page_size(), 0x1000);
+
+	/* allocations and dirty tracking ranges use the host page size */
+	assert_eq!(GuestMemory::new(1).unwrap().size(), page_size());
+
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let size = 2 * page_size();
+	vm.add_slot(
+		0x10000,
+		size,
+		MemPerm::Write,
+		GuestMemory::new(size).unwrap(),
+	)
+	.unwrap();
+
+	assert!(matches!(
+		vm.enable_dirty_tracking(0x10000 + page_size() as u64 / 2, page_size()),
+		Err(Error::BadArg)
+	));
+	let log = vm.enable_dirty_tracking(0x10000, size).unwrap();
+	assert_eq!(log.take_and_reset().unwrap(), [0]);
+}
+
+#[test]
+fn writable_and_executable_mappings() {
+	let mem = GuestMemory::new(2 * page_size()).unwrap();
+	let code = unsafe { slice::from_raw_parts_mut(mem.as_ptr(), page_size()) };
+	let data = unsafe { slice::from_raw_parts_mut(mem.as_ptr().add(page_size()), page_size()) };
+
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+
+	/* both Intel and Apple silicon allow it, other configurations get an explanation */
+	match vm.map_mem(data, 0x20000, MemPerm::ExecAndWrite) {
+		Ok(()) => assert_eq!(vm.permissions_at(0x20000), Some(MemPerm::ExecAndWrite)),
+		Err(err) => assert!(err.to_string().contains("W^X")),
+	}
+
+	vm.map_mem_wxn(code, 0x10000).unwrap();
+	assert_eq!(vm.permissions_at(0x10000), Some(MemPerm::ExecAndRead));
+}
+
+#[test]
+fn gpa_translation() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let mem = GuestMemory::new(0x8000).unwrap();
+	let slot = vm
+		.add_slot(0x10000, 0x8000, MemPerm::Write, mem.clone())
+		.unwrap();
+	/* device emulation translates while the VirtualCpus are alive */
+	let vcpu = vm.create_vcpu().unwrap();
+
+	/* start, middle and just past the end of the slot */
+	assert_eq!(vm.gpa_to_host(0x10000), Some(mem.as_ptr()));
+	assert_eq!(
+		vm.gpa_to_host(0x14000),
+		Some(mem.as_ptr().wrapping_add(0x4000))
+	);
+	assert_eq!(vm.gpa_to_host(0x18000), None);
+	assert_eq!(vm.gpa_to_host(0xffff), None);
+
+	let slice = vm.gpa_to_slice(0x14000, 4).unwrap();
+	assert_eq!(slice.as_ptr(), mem.as_ptr().wrapping_add(0x4000));
+	slice.write(0, &[1, 2, 3, 4]).unwrap();
+	assert!(matches!(slice.write(2, &[0; 3]), Err(Error::BadArg)));
+	let mut buf = [0u8; 4];
+	mem.read(0x4000, &mut buf).unwrap();
+	assert_eq!(buf, [1, 2, 3, 4]);
+	let mut buf = [0u8; 2];
+	slice.read(1, &mut buf).unwrap();
+	assert_eq!(buf, [2, 3]);
+
+	assert_eq!(vm.gpa_to_slice(0x10000, 0x8000).unwrap().len(), 0x8000);
+	assert!(vm.gpa_to_slice(0x17fff, 2).is_none());
+	assert!(vm.gpa_to_slice(0x18000, 1).is_none());
+	assert!(vm.gpa_to_slice(u64::MAX, 2).is_none());
+
+	vcpu.destroy().unwrap();
+	vm.remove_slot(slot).unwrap();
+	assert_eq!(vm.gpa_to_host(0x10000), None);
+}
+
+#[test]
+fn second_vm_is_rejected() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+
+	for err in [Vm::new().err().unwrap(), create_vm().unwrap_err()] {
+		assert!(matches!(err.root_cause(), Error::BadArg));
+		assert!(!err.is_transient());
+		assert!(err.to_string().contains("a VM already exists"));
+	}
+
+	drop(vm);
+	Vm::new().unwrap();
+}
+
+#[test]
+fn interrupt_vcpus_checks_ids() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+	let id = vcpu.get_id();
+
+	assert!(matches!(interrupt_vcpus(&[]), Err(Error::BadArg)));
+	assert!(matches!(
+		interrupt_vcpus(&vec![id; MAX_INTERRUPT_VCPUS + 1]),
+		Err(Error::BadArg)
+	));
+
+	/* the framework receives the ID only once */
+	interrupt_vcpus(&[id, id, id]).unwrap();
+	interrupt_vcpus(&vec![id; MAX_INTERRUPT_VCPUS]).unwrap();
+
+	vcpu.destroy().unwrap();
+}
+
+#[test]
+fn mem_perm_display() {
+	let cases = [
+		(MemPerm::Read, "r--", "Read"),
+		(MemPerm::Write, "rw-", "Write"),
+		(MemPerm::Exec, "--x", "Exec"),
+		(MemPerm::ExecAndWrite, "rwx", "ExecAndWrite"),
+		(MemPerm::ExecAndRead, "r-x", "ExecAndRead"),
+	];
+
+	for (perm, display, debug) in cases {
+		assert_eq!(perm.to_string(), display);
+		assert_eq!(format!("{:?}", perm), debug);
+	}
+}
+
+#[test]
+fn memory_flags() {
+	assert_eq!(MemFlags::from_bits(0x2), None);
+	let flag = MemFlags::from_bits(1 << 60).unwrap();
+	assert!(!(MemFlags::NONE | flag).is_empty());
+
+	with_mem(0x4000, |_, mem| {
+		/* the framework doesn't know the flag */
+		let err = map_mem_with_flags(mem, 0x10000, MemPerm::Read, flag).unwrap_err();
+		assert!(matches!(err.root_cause(), Error::Unsupp));
+
+		/* without flags the mapping works as before */
+		map_mem_with_flags(mem, 0x10000, MemPerm::Read, MemFlags::NONE).unwrap();
+		let err = protect_mem_with_flags(0x10000, 0x4000, MemPerm::Write, flag).unwrap_err();
+		assert!(matches!(err.root_cause(), Error::Unsupp));
+		protect_mem_with_flags(0x10000, 0x4000, MemPerm::Write, MemFlags::NONE).unwrap();
+		unmap_mem(0x10000, 0x4000).unwrap();
+	});
+}
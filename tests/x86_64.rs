@@ -0,0 +1,1718 @@
+#![cfg(target_arch = "x86_64")]
+
+extern crate xhypervisor;
+
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::slice;
+use std::sync::{Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use xhypervisor::consts::vmcs::*;
+use xhypervisor::consts::vmx_cap::*;
+use xhypervisor::*;
+
+/// Only one VM may exist per process, so the tests must not run concurrently
+static VM_LOCK: Mutex<()> = Mutex::new(());
+
+const CODE_ADDRESS: u64 = 0x100;
+const MEM_SIZE: usize = 16 * 1024;
+
+/// Runs `f` with a VM that has `code` mapped at `CODE_ADDRESS`
+fn with_code<F: FnOnce(&mut [u8])>(code: &[u8], f: F) {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	unsafe {
+		let layout = Layout::from_size_align(MEM_SIZE, 4096).unwrap();
+		let mem_raw = alloc_zeroed(layout);
+		let mem = slice::from_raw_parts_mut(mem_raw, MEM_SIZE);
+		mem[CODE_ADDRESS as usize..CODE_ADDRESS as usize + code.len()].copy_from_slice(code);
+
+		create_vm().unwrap();
+		map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
+
+		f(mem);
+
+		unmap_mem(0, MEM_SIZE).unwrap();
+		destroy_vm().unwrap();
+		dealloc(mem_raw, layout);
+	}
+}
+
+/// Creates a VirtualCpu in 16-bit real mode, starting at `CODE_ADDRESS`
+fn real_mode_vcpu() -> VirtualCpu<'static> {
+	let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+	vcpu.setup_real_mode().unwrap();
+
+	vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+	vcpu.write_register(&Register::RSP, 0x0).unwrap();
+
+	vcpu
+}
+
+/// Runs the VirtualCpu until the first exit that isn't caused by the host
+fn run_until_exit(vcpu: &VirtualCpu) -> ExitDetails {
+	loop {
+		vcpu.run().unwrap();
+		match vcpu.exit_details().unwrap() {
+			ExitDetails::Other { reason, .. } if reason.0 == consts::vmx_exit::VMX_REASON_IRQ => {}
+			ExitDetails::EptViolation(ept) if ept.gpa < MEM_SIZE as u64 => {}
+			details => return details,
+		}
+	}
+}
+
+#[test]
+fn exit_details_io() {
+	let code = [
+		0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+		0xb0, b'A', /* mov $'A', %al */
+		0xee, /* out %al, (%dx) */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+
+		assert_eq!(
+			run_until_exit(&vcpu),
+			ExitDetails::Io(IoExit {
+				port: 0x3f8,
+				size: 1,
+				is_in: false,
+				string: false,
+				rep: false,
+				count: None,
+				value: Some(b'A' as u64),
+				instruction_length: 1,
+			})
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_details_cpuid() {
+	let code = [0x0f, 0xa2 /* cpuid */];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RAX, 0xd).unwrap();
+		vcpu.write_register(&Register::RCX, 1).unwrap();
+
+		assert_eq!(
+			run_until_exit(&vcpu),
+			ExitDetails::Cpuid(CpuidExit {
+				leaf: 0xd,
+				subleaf: 1,
+				instruction_length: 2,
+			})
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_details_rdmsr() {
+	let code = [0x0f, 0x32 /* rdmsr */];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RCX, 0xc0000080).unwrap();
+
+		assert_eq!(
+			run_until_exit(&vcpu),
+			ExitDetails::Rdmsr(MsrExit {
+				msr: 0xc0000080,
+				value: None,
+				instruction_length: 2,
+			})
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_details_wrmsr() {
+	let code = [0x0f, 0x30 /* wrmsr */];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RCX, 0x10).unwrap();
+		vcpu.write_register(&Register::RDX, 0x1234).unwrap();
+		vcpu.write_register(&Register::RAX, 0x5678).unwrap();
+
+		assert_eq!(
+			run_until_exit(&vcpu),
+			ExitDetails::Wrmsr(MsrExit {
+				msr: 0x10,
+				value: Some(0x1234_0000_5678),
+				instruction_length: 2,
+			})
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_details_ept_violation() {
+	let code = [
+		0xbb, 0x00, 0x80, /* mov $0x8000, %bx */
+		0x88, 0x07, /* mov %al, (%bx) */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+
+		match run_until_exit(&vcpu) {
+			ExitDetails::EptViolation(ept) => {
+				assert_eq!(ept.gpa, 0x8000);
+				assert!(ept.write);
+				assert!(!ept.exec);
+			}
+			details => panic!("unexpected exit: {:?}", details),
+		}
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_details_hlt() {
+	let code = [0xf4 /* hlt */];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn vmcs_field_width() {
+	assert_eq!(VmcsField(VMCS_GUEST_CS).width(), VmcsFieldWidth::Bits16);
+	assert_eq!(
+		VmcsField(VMCS_CTRL_EXC_BITMAP).width(),
+		VmcsFieldWidth::Bits32
+	);
+	assert_eq!(
+		VmcsField(VMCS_GUEST_IA32_EFER).width(),
+		VmcsFieldWidth::Bits64
+	);
+	assert_eq!(
+		VmcsField(VMCS_GUEST_IA32_EFER + 1).width(),
+		VmcsFieldWidth::Bits32
+	);
+	assert_eq!(VmcsField(VMCS_GUEST_RIP).width(), VmcsFieldWidth::Natural);
+}
+
+#[test]
+fn write_vmcs_checks_width() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		assert!(matches!(
+			vcpu.write_vmcs(VMCS_CTRL_EXC_BITMAP, 0xff_ffff_ffff),
+			Err(Error::BadArg)
+		));
+		vcpu.write_vmcs(VMCS_CTRL_EXC_BITMAP, 0xffff_ffff).unwrap();
+		assert_eq!(vcpu.read_vmcs(VMCS_CTRL_EXC_BITMAP).unwrap(), 0xffff_ffff);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+#[cfg(debug_assertions)]
+fn vcpu_panics_on_foreign_thread() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		let result = thread::scope(|s| s.spawn(|| vcpu.read_register(&Register::RIP)).join());
+		let panic = result.unwrap_err();
+		let message = panic.downcast_ref::<String>().unwrap();
+		assert!(message.contains("bound to the thread that created it"));
+
+		vcpu.read_register(&Register::RIP).unwrap();
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn max_vcpu_count_is_unsupported() {
+	assert!(matches!(
+		max_vcpu_count().unwrap_err().root_cause(),
+		Error::Unsupp
+	));
+}
+
+#[test]
+fn fpstate_size_is_checked() {
+	assert_eq!(fpstate_size(), 512);
+
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		let mut short = vec![0u8; 256];
+		assert!(matches!(vcpu.read_fpstate(&mut short), Err(Error::BadArg)));
+		assert!(matches!(vcpu.write_fpstate(&short), Err(Error::BadArg)));
+
+		let state = vcpu.read_fpstate_vec().unwrap();
+		assert_eq!(state.len(), fpstate_size());
+		vcpu.write_fpstate(&state).unwrap();
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn registers_max_is_rejected() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		assert!(matches!(
+			vcpu.read_register(&Register::REGISTERS_MAX),
+			Err(Error::BadArg)
+		));
+		assert!(matches!(
+			vcpu.write_register(&Register::REGISTERS_MAX, 0),
+			Err(Error::BadArg)
+		));
+		vcpu.read_register(&Register::RIP).unwrap();
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn setup_real_mode_is_overridable() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.setup_real_mode().unwrap();
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_CR0).unwrap(), 0x20);
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_CS_LIMIT).unwrap(), 0xffff);
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_GDTR_LIMIT).unwrap(), 0xffff);
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_IDTR_LIMIT).unwrap(), 0xffff);
+
+		vcpu.write_vmcs(VMCS_GUEST_CS_BASE, 0xf0000).unwrap();
+		vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_CS_BASE).unwrap(), 0xf0000);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn setup_long_mode_executes_64bit_code() {
+	let code = [
+		0x48, 0xc7, 0xc0, 0x2a, 0x00, 0x00, 0x00, /* mov $42, %rax */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |mem| {
+		build_identity_page_tables(&mut mem[0x1000..], 0x1000, MEM_SIZE as u64).unwrap();
+
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+		vcpu.setup_long_mode(0x1000).unwrap();
+		vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+		vcpu.write_register(&Register::RAX, 0).unwrap();
+
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RAX).unwrap(), 42);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn set_xcr0_enables_avx() {
+	let code = [
+		0xc5, 0xfc, 0x58, 0xc0, /* vaddps %ymm0, %ymm0, %ymm0 */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |mem| {
+		build_identity_page_tables(&mut mem[0x1000..], 0x1000, MEM_SIZE as u64).unwrap();
+
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+		vcpu.setup_long_mode(0x1000).unwrap();
+		vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+
+		/* x87 can't be disabled and AVX needs SSE */
+		assert!(matches!(
+			vcpu.set_xcr0(0x2).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+		assert!(matches!(
+			vcpu.set_xcr0(0x1 | XCR0_AVX).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+		assert!(matches!(
+			vcpu.set_xcr0(0x3 | 1 << 62).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+
+		if supported_xcr0() & XCR0_AVX != 0 {
+			vcpu.set_xcr0(0x3 | XCR0_AVX).unwrap();
+			assert_ne!(vcpu.read_vmcs(VMCS_GUEST_CR4).unwrap() & (1 << 18), 0);
+			assert_eq!(vcpu.read_register(&Register::XCR0).unwrap(), 0x7);
+
+			/* the instruction would raise #UD without AVX in XCR0 */
+			assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+			assert_eq!(
+				vcpu.read_register(&Register::RIP).unwrap(),
+				CODE_ADDRESS + 5
+			);
+		}
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn build_identity_page_tables_checks_arguments() {
+	let mut tables = vec![0u8; 3 * 4096];
+
+	assert!(matches!(
+		build_identity_page_tables(&mut tables, 0x1001, 0x200000),
+		Err(Error::BadArg)
+	));
+	assert!(matches!(
+		build_identity_page_tables(&mut tables, 0x1000, 0x80000000),
+		Err(Error::BadArg)
+	));
+
+	build_identity_page_tables(&mut tables, 0x1000, 0x400000).unwrap();
+	assert_eq!(&tables[0..8], &0x2003u64.to_le_bytes());
+	assert_eq!(&tables[0x1000..0x1008], &0x3003u64.to_le_bytes());
+	assert_eq!(&tables[0x2000..0x2008], &0x83u64.to_le_bytes());
+	assert_eq!(&tables[0x2008..0x2010], &0x200083u64.to_le_bytes());
+}
+
+#[test]
+fn vmx_reason_names() {
+	use xhypervisor::consts::vmx_exit::*;
+
+	assert_eq!(vmx_reason_name(VMX_REASON_HLT as u16), "HLT");
+	assert_eq!(
+		vmx_reason_name(VMX_REASON_EPT_VIOLATION as u16),
+		"EPT violation"
+	);
+	assert_eq!(vmx_reason_name(35), "unknown");
+	assert_eq!(
+		format!("{:?}", ExitReason(VMX_REASON_TRIPLE_FAULT)),
+		"ExitReason(2, \"triple fault\")"
+	);
+}
+
+#[test]
+fn vmx_cap_round_trips_u32() {
+	let caps = [
+		(VMXCap::PINBASED, 0),
+		(VMXCap::PROCBASED, 1),
+		(VMXCap::PROCBASED2, 2),
+		(VMXCap::ENTRY, 3),
+		(VMXCap::EXIT, 4),
+		(VMXCap::PREEMPTION_TIMER, 32),
+	];
+
+	for (cap, value) in caps {
+		assert_eq!(u32::from(cap), value);
+		assert_eq!(VMXCap::try_from(value).unwrap(), cap);
+	}
+	assert!(matches!(VMXCap::try_from(5), Err(Error::BadArg)));
+	assert_eq!(
+		VMXCap::try_from(2).unwrap().to_string(),
+		"Secondary proc-based VMX capabilities"
+	);
+}
+
+#[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
+fn read_vmx_cap_accepts_references() {
+	/* callers from before the by-value signature still compile */
+	assert_eq!(
+		read_vmx_cap(&VMXCap::PINBASED).unwrap(),
+		read_vmx_cap(VMXCap::PINBASED).unwrap()
+	);
+}
+
+#[test]
+fn register_display() {
+	assert_eq!(format!("{}", Register::RAX), "rax");
+	assert_eq!(Register::RFLAGS.to_string(), "rflags");
+	assert_eq!(Register::IDT_BASE.to_string(), "idt_base");
+}
+
+#[test]
+fn total_exec_time_sums_vcpus() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let code = [
+		0xb9, 0xff, 0xff, /* mov $0xffff, %cx */
+		0xe2, 0xfe, /* loop . */
+		0xf4, /* hlt */
+	];
+
+	unsafe {
+		let layout = Layout::from_size_align(MEM_SIZE, 4096).unwrap();
+		let mem_raw = alloc_zeroed(layout);
+		let mem = slice::from_raw_parts_mut(mem_raw, MEM_SIZE);
+		mem[CODE_ADDRESS as usize..CODE_ADDRESS as usize + code.len()].copy_from_slice(&code);
+
+		let vm = Vm::new().unwrap();
+		vm.map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
+		let ran = Barrier::new(3);
+		let measured = Barrier::new(3);
+
+		thread::scope(|s| {
+			for _ in 0..2 {
+				s.spawn(|| {
+					let vcpu = vm.create_vcpu().unwrap();
+					vcpu.setup_real_mode().unwrap();
+					vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+					assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+
+					ran.wait();
+					measured.wait();
+					vcpu.destroy().unwrap();
+				});
+			}
+
+			ran.wait();
+			assert!(vm.total_exec_time().unwrap() > Duration::ZERO);
+			measured.wait();
+		});
+
+		/* destroyed VirtualCpus are skipped */
+		assert_eq!(vm.total_exec_time().unwrap(), Duration::ZERO);
+
+		drop(vm);
+		dealloc(mem_raw, layout);
+	}
+}
+
+#[test]
+fn write_register_fixes_rflags() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.write_register(&Register::RFLAGS, 0).unwrap();
+		assert_eq!(vcpu.read_register(&Register::RFLAGS).unwrap(), 0x2);
+
+		/* reserved bits 3, 5, 15 and 22+ are cleared, CF and IF are kept */
+		vcpu.write_register(&Register::RFLAGS, 0xffff_0000_0040_8229)
+			.unwrap();
+		assert_eq!(vcpu.read_register(&Register::RFLAGS).unwrap(), 0x203);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn capabilities_require() {
+	let caps = Capabilities {
+		pinbased: (PIN_BASED_PREEMPTION_TIMER | PIN_BASED_INTR) << 32,
+		procbased: CPU_BASED_HLT << 32,
+		procbased2: (CPU_BASED2_UNRESTRICTED | CPU_BASED2_EPT) << 32,
+		entry: 0,
+		exit: 0,
+	};
+
+	assert!(caps.supports(Feature::PreemptionTimer));
+	assert!(!caps.supports(Feature::Rdtscp));
+	caps.require(&[Feature::PreemptionTimer, Feature::UnrestrictedGuest])
+		.unwrap();
+
+	let err = caps
+		.require(&[Feature::UnrestrictedGuest, Feature::Rdtscp, Feature::Vpid])
+		.unwrap_err();
+	assert!(matches!(err.root_cause(), Error::Unsupp));
+	assert_eq!(err.to_string(), "host doesn't support RDTSCP: unsupported");
+}
+
+#[test]
+fn vcpu_actor_runs_payload() {
+	fn assert_send_sync<T: Send + Sync>() {}
+	assert_send_sync::<VcpuActor>();
+
+	let code = [
+		0xb8, 0x2a, 0x00, /* mov $42, %ax */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |_| {
+		let actor = unsafe { VcpuActor::spawn() }.unwrap();
+		actor.call(|vcpu| vcpu.setup_real_mode()).unwrap().unwrap();
+		actor.write_register(Register::RIP, CODE_ADDRESS).unwrap();
+		actor.write_register(Register::RAX, 0).unwrap();
+
+		loop {
+			actor.run().unwrap();
+			if actor.call(|vcpu| vcpu.exit_details()).unwrap().unwrap() == ExitDetails::Hlt {
+				break;
+			}
+		}
+		assert_eq!(actor.read_register(Register::RAX).unwrap(), 42);
+
+		actor.stop().unwrap();
+	});
+}
+
+#[test]
+fn instruction_pointer() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+		assert_eq!(vcpu.instruction_pointer().unwrap(), CODE_ADDRESS);
+
+		vcpu.set_instruction_pointer(CODE_ADDRESS + 1).unwrap();
+		assert_eq!(
+			vcpu.read_register(&Register::RIP).unwrap(),
+			CODE_ADDRESS + 1
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn model_registers() {
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		/* IA32_KERNEL_GS_BASE */
+		vcpu.enable_native_msr(0xc0000102, true).unwrap();
+		vcpu.write_model_register(0xc0000102, 0x1000).unwrap();
+		assert_eq!(vcpu.read_model_register(0xc0000102).unwrap(), 0x1000);
+		assert_eq!(vcpu.read_msr(0xc0000102).unwrap(), 0x1000);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn dump_state_with_selects_sections() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let opts = DumpOptions {
+			gprs: true,
+			segments: false,
+			control: false,
+			vmcs_controls: false,
+			radix: Radix::Decimal,
+		};
+		let dump = vcpu.dump_state_with(opts).unwrap();
+		assert!(dump.contains(&format!("rip        {}\n", CODE_ADDRESS)));
+		assert!(!dump.contains("selector="));
+		assert!(!dump.contains("cr0"));
+
+		let full = format!("{:?}", vcpu);
+		assert!(full.starts_with("VirtualCpu ID: "));
+		assert!(full.contains("selector="));
+		assert!(full.contains("cpu_based2"));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn read_vmcs_many_matches_single_reads() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let fields = [
+			VMCS_GUEST_CS,
+			VMCS_GUEST_CS_BASE,
+			VMCS_GUEST_CS_LIMIT,
+			VMCS_GUEST_CS_AR,
+			VMCS_GUEST_RIP,
+			VMCS_CTRL_CPU_BASED,
+		]
+		.map(VmcsField);
+		let single: Vec<u64> = fields.iter().map(|f| vcpu.read_vmcs(*f).unwrap()).collect();
+
+		assert_eq!(vcpu.read_vmcs_many(&fields).unwrap(), single);
+		assert_eq!(single[4], CODE_ADDRESS);
+		assert!(vcpu.read_vmcs_many(&[]).unwrap().is_empty());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn vmcs_snapshot_contains_controls() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+		let snapshot = vcpu.read_vmcs_snapshot().unwrap();
+
+		for field in [
+			VMCS_CTRL_PIN_BASED,
+			VMCS_CTRL_CPU_BASED,
+			VMCS_CTRL_CPU_BASED2,
+			VMCS_CTRL_VMENTRY_CONTROLS,
+			VMCS_CTRL_EXC_BITMAP,
+		] {
+			assert_eq!(snapshot.get(field), Some(vcpu.read_vmcs(field).unwrap()));
+			assert_eq!(VmcsField(field).category(), VmcsCategory::Control);
+		}
+		assert_eq!(snapshot.get(VMCS_GUEST_RIP), Some(CODE_ADDRESS));
+		assert_eq!(
+			VmcsField(VMCS_RO_EXIT_REASON).category(),
+			VmcsCategory::ReadOnly
+		);
+		assert_eq!(VmcsField(VMCS_GUEST_RIP).name(), Some("guest_rip"));
+
+		let text = snapshot.to_string();
+		let controls = text.find("controls:").unwrap();
+		let guest = text.find("guest state:").unwrap();
+		assert!(controls < text.find("ctrl_cpu_based").unwrap());
+		assert!(guest < text.find("guest_rip").unwrap());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn write_registers_checked_reports_and_rolls_back() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RAX, 1).unwrap();
+		vcpu.write_register(&Register::RBX, 2).unwrap();
+		vcpu.write_register(&Register::RCX, 3).unwrap();
+
+		vcpu.write_registers_checked(&[(Register::RAX, 10), (Register::RBX, 20)])
+			.unwrap();
+		assert_eq!(vcpu.read_register(&Register::RAX).unwrap(), 10);
+		assert_eq!(vcpu.read_register(&Register::RBX).unwrap(), 20);
+
+		let (index, err) = vcpu
+			.write_registers_checked(&[
+				(Register::RAX, 100),
+				(Register::RBX, 200),
+				(Register::REGISTERS_MAX, 0),
+				(Register::RCX, 300),
+			])
+			.unwrap_err();
+		assert_eq!(index, 2);
+		assert!(matches!(err, Error::BadArg));
+
+		/* the writes before the failing one are undone, the ones after never happen */
+		assert_eq!(vcpu.read_register(&Register::RAX).unwrap(), 10);
+		assert_eq!(vcpu.read_register(&Register::RBX).unwrap(), 20);
+		assert_eq!(vcpu.read_register(&Register::RCX).unwrap(), 3);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn failed_entry_reports_instruction_error() {
+	assert_eq!(
+		instruction_error_name(7),
+		"VM entry with invalid control field(s)"
+	);
+	assert_eq!(instruction_error_name(99), "unknown VM-instruction error");
+
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		/* bits 1, 2 and 4 of the pin-based controls must be set */
+		vcpu.write_vmcs(VMCS_CTRL_PIN_BASED, 0).unwrap();
+
+		let err = vcpu.run().unwrap_err();
+		assert!(
+			err.to_string().contains("VM-instruction error 7"),
+			"unexpected error: {}",
+			err
+		);
+		assert_eq!(vcpu.read_vmcs(VMCS_RO_INSTR_ERROR).unwrap(), 7);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn register_access_checks_vmcs_only_registers() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		vcpu.write_tpr(0).unwrap();
+		assert_eq!(vcpu.read_tpr().unwrap(), 0);
+		assert_eq!(vcpu.read_register(&Register::TPR).unwrap(), 0);
+
+		/* the hidden parts of GDTR and TR live in the VMCS */
+		assert_eq!(Register::GDT_BASE.vmcs_field(), Some(VMCS_GUEST_GDTR_BASE));
+		assert_eq!(Register::TR.vmcs_field(), None);
+		let err = vcpu.read_register(&Register::GDT_BASE).unwrap_err();
+		assert!(matches!(err.root_cause(), Error::BadArg));
+		assert!(err.to_string().contains("gdt_base"));
+		assert!(matches!(
+			vcpu.write_register(&Register::TSS_AR, 0x8b)
+				.unwrap_err()
+				.root_cause(),
+			Error::BadArg
+		));
+		vcpu.read_vmcs(VMCS_GUEST_GDTR_BASE).unwrap();
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn efer_and_apic_base_are_typed() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.write_efer(Efer::NONE).unwrap();
+
+		let mut efer = vcpu.read_efer().unwrap();
+		assert!(!efer.contains(Efer::LME));
+
+		efer.insert(Efer::LME | Efer::NXE);
+		vcpu.write_efer(efer).unwrap();
+		let efer = vcpu.read_efer().unwrap();
+		assert!(efer.contains(Efer::LME));
+		assert!(efer.contains(Efer::NXE));
+		assert!(!efer.contains(Efer::LMA));
+		assert_eq!(
+			vcpu.read_vmcs(VMCS_GUEST_IA32_EFER).unwrap(),
+			(1 << 8) | (1 << 11)
+		);
+
+		let mut efer = efer;
+		efer.remove(Efer::LME);
+		vcpu.write_efer(efer).unwrap();
+		assert_eq!(vcpu.read_efer().unwrap(), Efer::NXE);
+
+		let apic_base = ApicBase::from_bits(0xfee0_0900);
+		assert_eq!(
+			apic_base,
+			ApicBase {
+				bsp: true,
+				x2apic: false,
+				enabled: true,
+				base_addr: 0xfee0_0000,
+			}
+		);
+		assert_eq!(apic_base.bits(), 0xfee0_0900);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn apic_base_round_trips_through_the_msr() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let mut apic_base = vcpu.read_apic_base().unwrap();
+		assert_eq!(apic_base.bits(), vcpu.read_msr(0x1b).unwrap());
+
+		apic_base.bsp = !apic_base.bsp;
+		vcpu.write_apic_base(apic_base).unwrap();
+		assert_eq!(vcpu.read_apic_base().unwrap(), apic_base);
+		assert_eq!(vcpu.read_msr(0x1b).unwrap(), apic_base.bits());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn flush_if_dirty_skips_clean_state() {
+	with_code(&[0x90, 0xf4 /* nop; hlt */], |_| {
+		let vcpu = real_mode_vcpu();
+		assert!(vcpu.is_dirty());
+
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert!(!vcpu.is_dirty());
+		assert!(!vcpu.flush_if_dirty().unwrap());
+
+		vcpu.write_register(&Register::RAX, 1).unwrap();
+		assert!(vcpu.is_dirty());
+		assert!(vcpu.flush_if_dirty().unwrap());
+		assert!(!vcpu.is_dirty());
+		assert!(!vcpu.flush_if_dirty().unwrap());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn control_registers_and_shadows() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		/* real mode sets CR0.NE and CR4.VMXE and clears the CR4 read shadow */
+		assert_ne!(vcpu.read_cr(0).unwrap() & (1 << 5), 0);
+		assert_ne!(vcpu.read_cr(4).unwrap() & (1 << 13), 0);
+		assert_eq!(
+			vcpu.read_cr(0).unwrap(),
+			vcpu.read_vmcs(VMCS_GUEST_CR0).unwrap()
+		);
+		assert_eq!(vcpu.read_vmcs(VMCS_CTRL_CR4_SHADOW).unwrap(), 0);
+
+		let cr4 = vcpu.read_cr(4).unwrap();
+		vcpu.write_cr_with_shadow(4, cr4 | (1 << 9)).unwrap();
+		assert_eq!(vcpu.read_cr(4).unwrap(), cr4 | (1 << 9));
+		assert_eq!(
+			vcpu.read_vmcs(VMCS_CTRL_CR4_SHADOW).unwrap(),
+			cr4 | (1 << 9)
+		);
+
+		vcpu.write_cr(8, 0x3).unwrap();
+		assert_eq!(vcpu.read_cr(8).unwrap(), 0x3);
+		assert_eq!(vcpu.read_tpr().unwrap(), 0x30);
+
+		assert!(matches!(
+			vcpu.read_cr(1).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+		assert!(matches!(
+			vcpu.write_cr(8, 0x10).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+		assert!(matches!(
+			vcpu.write_cr_with_shadow(3, 0).unwrap_err().root_cause(),
+			Error::BadArg
+		));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn set_entry_point_real_mode() {
+	with_code(&[0xf4 /* hlt */], |mem| {
+		mem[0x3000] = 0xf4; /* hlt */
+		let vcpu = real_mode_vcpu();
+
+		vcpu.set_entry_point(0x3000).unwrap();
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x3001);
+
+		/* beyond 64 KiB the address is split into CS and RIP */
+		vcpu.set_entry_point(0x12345).unwrap();
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_CS).unwrap(), 0x1234);
+		assert_eq!(vcpu.read_vmcs(VMCS_GUEST_CS_BASE).unwrap(), 0x12340);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x5);
+		assert!(matches!(vcpu.set_entry_point(0x100000), Err(Error::BadArg)));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn inject_interrupt_checks_window() {
+	with_code(&[0xf4 /* hlt */], |mem| {
+		/* handler of vector 0x20 at 0000:0200 */
+		mem[0x20 * 4..0x20 * 4 + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+		mem[0x200] = 0xf4; /* hlt */
+
+		let vcpu = real_mode_vcpu();
+
+		/* RFLAGS.IF is clear */
+		assert!(!vcpu.can_inject_interrupt().unwrap());
+		assert!(matches!(vcpu.inject_interrupt(0x20), Err(Error::Busy)));
+
+		vcpu.write_register(&Register::RFLAGS, 0x202).unwrap();
+		assert!(vcpu.can_inject_interrupt().unwrap());
+		vcpu.inject_interrupt(0x20).unwrap();
+		assert!(!vcpu.can_inject_interrupt().unwrap());
+
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x201);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn inject_page_fault_sets_cr2() {
+	with_code(&[0xf4 /* hlt */], |mem| {
+		/* #PF handler at 0000:0200 */
+		mem[14 * 4..14 * 4 + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+		mem[0x200..0x204].copy_from_slice(&[
+			0x0f, 0x20, 0xd0, /* mov %cr2, %eax */
+			0xf4, /* hlt */
+		]);
+
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::CR2, 0x1234).unwrap();
+		vcpu.write_register(&Register::RAX, 0).unwrap();
+
+		vcpu.inject_page_fault(0xdead_b000, 0x2).unwrap();
+		assert_eq!(vcpu.read_register(&Register::CR2).unwrap(), 0xdead_b000);
+
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x204);
+		assert_eq!(vcpu.read_register(&Register::RAX).unwrap(), 0xdead_b000);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exception_exit_decodes_invalid_opcode() {
+	with_code(&[0x0f, 0x0b /* ud2 */], |mem| {
+		/* #UD handler at 0000:0200 */
+		mem[6 * 4..6 * 4 + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+		mem[0x200] = 0xf4; /* hlt */
+
+		let vcpu = real_mode_vcpu();
+		vcpu.write_vmcs(VMCS_CTRL_EXC_BITMAP, 0xffffffff).unwrap();
+
+		let exception = match run_until_exit(&vcpu) {
+			ExitDetails::Exception(exception) => exception,
+			details => panic!("unexpected exit {:?}", details),
+		};
+		assert_eq!(exception.vector, 6);
+		assert_eq!(exception.error_code, None);
+		assert!(exception.valid);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), CODE_ADDRESS);
+
+		/* the guest handles the re-injected #UD itself */
+		vcpu.reinject_exception(&exception).unwrap();
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x201);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn set_apic_addr_checks_placement() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let (vm, _ram) = Vm::with_guest_ram(MEM_SIZE).unwrap();
+	let vcpu = vm.create_vcpu().unwrap();
+
+	assert!(matches!(
+		vcpu.set_apic_addr(0xfee0_0800),
+		Err(Error::BadArg)
+	));
+	/* the APIC page must not overlap the guest RAM */
+	let err = vcpu.set_apic_addr(0x1000).unwrap_err();
+	assert!(matches!(err.root_cause(), Error::BadArg));
+	vcpu.set_default_apic_addr().unwrap();
+	assert_eq!(default_apic_addr(), 0xfee0_0000);
+
+	vcpu.destroy().unwrap();
+}
+
+#[test]
+fn validate_vmcs_reports_invalid_cr0() {
+	with_code(&[0xf4 /* hlt */], |_| {
+		let vcpu = real_mode_vcpu();
+		assert_eq!(vcpu.validate_vmcs(), Ok(()));
+
+		vcpu.write_vmcs(VMCS_GUEST_CR0, 0x0).unwrap();
+		assert_eq!(
+			vcpu.validate_vmcs(),
+			Err(vec![String::from(
+				"CR0 0x0 clears CR0.NE, which is fixed to 1 in VMX operation"
+			)])
+		);
+
+		/* paging without protection */
+		vcpu.write_vmcs(VMCS_GUEST_CR0, 0x8000_0020).unwrap();
+		vcpu.write_vmcs(VMCS_GUEST_RFLAGS, 0x0).unwrap();
+		let problems = vcpu.validate_vmcs().unwrap_err();
+		assert!(problems.contains(&String::from("CR0 0x80000020 sets CR0.PG without CR0.PE")));
+		assert!(problems.contains(&String::from("RFLAGS 0x0 clears the reserved bit 1")));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn interrupt_window_exit() {
+	let code = [
+		0xfb, /* sti */
+		0x90, /* nop */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |mem| {
+		/* handler of vector 0x20 at 0000:0200 */
+		mem[0x20 * 4..0x20 * 4 + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+		mem[0x200] = 0xf4; /* hlt */
+
+		let vcpu = real_mode_vcpu();
+		vcpu.request_interrupt_window(true).unwrap();
+
+		/* the window opens once the STI shadow has passed */
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::IrqWindow);
+		assert_eq!(
+			vcpu.read_register(&Register::RIP).unwrap(),
+			CODE_ADDRESS + 2
+		);
+
+		vcpu.request_interrupt_window(false).unwrap();
+		vcpu.inject_interrupt(0x20).unwrap();
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x201);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn interrupt_queue_keeps_requested_window() {
+	let code = [
+		0xfb, /* sti */
+		0x90, /* nop */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		vcpu.request_interrupt_window(true).unwrap();
+
+		/* an empty queue leaves the window of the caller open */
+		let mut queue = InterruptQueue::new();
+		assert_eq!(queue.service(&vcpu).unwrap(), None);
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::IrqWindow);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn interrupt_queue_delivers_highest_vector_first() {
+	let code = [
+		0xfb, /* sti */
+		0x90, /* nop */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |mem| {
+		/* handlers at 0000:0200 and 0000:0300 log their vector at (%bx) */
+		for (vector, handler) in [(0x20usize, 0x200usize), (0x30, 0x300)] {
+			mem[vector * 4..vector * 4 + 4].copy_from_slice(&[
+				0x00,
+				(handler >> 8) as u8,
+				0x00,
+				0x00,
+			]);
+			mem[handler..handler + 5].copy_from_slice(&[
+				0xc6,
+				0x07,
+				vector as u8, /* movb $vector, (%bx) */
+				0x43,         /* inc %bx */
+				0xcf,         /* iret */
+			]);
+		}
+
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RBX, 0x400).unwrap();
+
+		let mut queue = InterruptQueue::new();
+		queue.push(0x20);
+		queue.push(0x30);
+		assert_eq!(queue.len(), 2);
+
+		/* RFLAGS.IF is clear, so the queue waits for the window */
+		assert_eq!(queue.service(&vcpu).unwrap(), None);
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::IrqWindow);
+
+		assert_eq!(queue.service(&vcpu).unwrap(), Some(0x30));
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::IrqWindow);
+
+		assert_eq!(queue.service(&vcpu).unwrap(), Some(0x20));
+		assert!(queue.is_empty());
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+
+		assert_eq!(&mem[0x400..0x402], &[0x30, 0x20]);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn inject_to_all_reaches_every_vcpu() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let (vm, ram) = Vm::with_guest_ram(MEM_SIZE).unwrap();
+	/* handler of vector 0x20 at 0000:0200 */
+	ram.write(0x20 * 4, &[0x00, 0x02, 0x00, 0x00]).unwrap();
+	ram.write(0x200, &[0xf4 /* hlt */]).unwrap();
+	ram.write(CODE_ADDRESS as usize, &[0xf4 /* hlt */]).unwrap();
+
+	let vcpus = [vm.create_vcpu().unwrap(), vm.create_vcpu().unwrap()];
+	for vcpu in vcpus.iter() {
+		vcpu.setup_real_mode().unwrap();
+		vcpu.set_entry_point(CODE_ADDRESS).unwrap();
+		vcpu.write_register(&Register::RSP, 0x0).unwrap();
+		vcpu.write_register(&Register::RFLAGS, 0x202).unwrap();
+	}
+
+	vm.inject_to_all(0x20).unwrap();
+
+	for vcpu in vcpus.iter() {
+		assert_eq!(run_until_exit(vcpu), ExitDetails::Hlt);
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x201);
+		vcpu.destroy().unwrap();
+	}
+}
+
+#[test]
+fn run_with_timeout_interrupts_guest() {
+	with_code(&[0xeb, 0xfe /* jmp . */], |_| {
+		let vcpu = real_mode_vcpu();
+
+		/* the first fetch of the code may exit for the EPT */
+		let mut start = Instant::now();
+		while vcpu.run_with_timeout(Duration::from_millis(50)).unwrap() == RunOutcome::Exited {
+			match vcpu.exit_details().unwrap() {
+				ExitDetails::EptViolation(ept) if ept.gpa < MEM_SIZE as u64 => {}
+				details => panic!("unexpected exit: {:?}", details),
+			}
+			start = Instant::now();
+		}
+		assert!(start.elapsed() >= Duration::from_millis(50));
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), CODE_ADDRESS);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn run_with_timeout_returns_exits() {
+	with_code(&[0xf4 /* hlt */], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let start = Instant::now();
+		loop {
+			assert_eq!(
+				vcpu.run_with_timeout(Duration::from_secs(10)).unwrap(),
+				RunOutcome::Exited
+			);
+			match vcpu.exit_details().unwrap() {
+				ExitDetails::Hlt => break,
+				ExitDetails::EptViolation(ept) if ept.gpa < MEM_SIZE as u64 => {}
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		}
+		assert!(start.elapsed() < Duration::from_secs(10));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+/// Checks that `protect` revokes the write access the VirtualCpu has cached in its TLB
+fn check_revokes_cached_write_access<F: FnOnce(&Vm)>(protect: F) {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	let code = [
+		0xa2, 0x00, 0x20, /* mov %al, 0x2000 */
+		0xf4, /* hlt */
+	];
+	let (vm, ram) = Vm::with_guest_ram(MEM_SIZE).unwrap();
+	ram.write(CODE_ADDRESS as usize, &code).unwrap();
+
+	let vcpu = vm.create_vcpu().unwrap();
+	vcpu.setup_real_mode().unwrap();
+	vcpu.set_entry_point(CODE_ADDRESS).unwrap();
+	vcpu.write_register(&Register::RAX, 0x42).unwrap();
+
+	/* the first write goes through and leaves the translation in the TLB */
+	assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+	let mut data = [0u8];
+	ram.read(0x2000, &mut data).unwrap();
+	assert_eq!(data, [0x42]);
+
+	protect(&vm);
+	vcpu.set_entry_point(CODE_ADDRESS).unwrap();
+	vcpu.write_register(&Register::RAX, 0x43).unwrap();
+
+	let ept = loop {
+		vcpu.run().unwrap();
+		match vcpu.exit_details().unwrap() {
+			ExitDetails::Other { reason, .. } if reason.0 == consts::vmx_exit::VMX_REASON_IRQ => {}
+			ExitDetails::EptViolation(ept) => break ept,
+			details => panic!("unexpected exit: {:?}", details),
+		}
+	};
+	assert_eq!(ept.gpa, 0x2000);
+	assert!(ept.write);
+
+	ram.read(0x2000, &mut data).unwrap();
+	assert_eq!(data, [0x42]);
+
+	vcpu.destroy().unwrap();
+}
+
+#[test]
+fn protect_range_revokes_cached_write_access() {
+	check_revokes_cached_write_access(|vm| {
+		vm.protect_range(0x2000, 0x1000, MemPerm::Read).unwrap()
+	});
+}
+
+#[test]
+fn protect_mem_revokes_cached_write_access() {
+	check_revokes_cached_write_access(|_| protect_mem(0x2000, 0x1000, MemPerm::Read).unwrap());
+}
+
+#[test]
+fn complete_msr_with_gp() {
+	let code = [0x0f, 0x32 /* rdmsr */];
+
+	with_code(&code, |mem| {
+		/* #GP handler at 0000:0200 */
+		mem[13 * 4..13 * 4 + 4].copy_from_slice(&[0x00, 0x02, 0x00, 0x00]);
+		mem[0x200] = 0xf4; /* hlt */
+
+		let vcpu = real_mode_vcpu();
+		vcpu.write_register(&Register::RSP, 0x1000).unwrap();
+		vcpu.write_register(&Register::RCX, 0xdead).unwrap();
+
+		assert!(matches!(run_until_exit(&vcpu), ExitDetails::Rdmsr(_)));
+		vcpu.complete_msr_with_gp().unwrap();
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+
+		/* the handler returns to the faulting instruction */
+		assert_eq!(vcpu.read_register(&Register::RIP).unwrap(), 0x200);
+		assert_eq!(&mem[0xffa..0xffc], &(CODE_ADDRESS as u16).to_le_bytes());
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn xsave_round_trips_ymm() {
+	assert_eq!(xsave_size(0x3), 576);
+	assert_eq!(xsave_size(0x7), 832);
+	assert_eq!(xsave_size(0xe7), 2688);
+
+	with_code(&[0xf4], |_| {
+		let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+
+		vcpu.write_register(&Register::XCR0, 0x3).unwrap();
+		let mut state = vcpu.read_xsave().unwrap();
+		assert_eq!(state.as_bytes().len(), 576);
+		assert_eq!(state.ymm_high(0), None);
+		assert!(matches!(state.set_ymm_high(0, [0; 16]), Err(Error::BadArg)));
+
+		/* the area no longer matches once AVX is enabled */
+		vcpu.write_register(&Register::XCR0, 0x3 | XCR0_AVX)
+			.unwrap();
+		assert!(matches!(vcpu.write_xsave(&state), Err(Error::BadArg)));
+
+		state = vcpu.read_xsave().unwrap();
+		assert_eq!(state.xcr0(), 0x7);
+		let high = [0xa5; 16];
+		state.set_ymm_high(3, high).unwrap();
+		vcpu.write_xsave(&state).unwrap();
+
+		let restored = vcpu.read_xsave().unwrap();
+		assert_eq!(restored.ymm_high(3), Some(high));
+		assert_eq!(restored.xmm(3), state.xmm(3));
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn skip_instruction_rep_outsb() {
+	let code = [
+		0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+		0xbe, 0x00, 0x02, /* mov $0x200, %si */
+		0xb9, 0x03, 0x00, /* mov $3, %cx */
+		0xf3, 0x6e, /* rep outsb */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |mem| {
+		mem[0x200..0x203].copy_from_slice(b"abc");
+		let vcpu = real_mode_vcpu();
+
+		let mut output = Vec::new();
+		loop {
+			match run_until_exit(&vcpu) {
+				ExitDetails::Io(io) => {
+					assert!(io.rep && io.string);
+					assert_eq!(io.count, Some(3 - output.len() as u64));
+					assert_eq!(
+						vcpu.read_register(&Register::RIP).unwrap(),
+						CODE_ADDRESS + 9
+					);
+
+					/* service one element like the processor would */
+					let rsi = vcpu.read_register(&Register::RSI).unwrap();
+					let rcx = vcpu.read_register(&Register::RCX).unwrap();
+					output.push(mem[rsi as usize]);
+					vcpu.write_register(&Register::RSI, rsi + 1).unwrap();
+					vcpu.write_register(&Register::RCX, rcx - 1).unwrap();
+
+					vcpu.skip_instruction().unwrap();
+				}
+				ExitDetails::Hlt => break,
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		}
+
+		assert_eq!(output, b"abc");
+		assert_eq!(
+			vcpu.read_register(&Register::RIP).unwrap(),
+			CODE_ADDRESS + 11
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn exit_field_accessors() {
+	let code = [
+		0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+		0xec, /* in (%dx), %al */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		assert!(matches!(run_until_exit(&vcpu), ExitDetails::Io(_)));
+
+		let reason = vcpu.read_vmcs(VMCS_RO_EXIT_REASON).unwrap();
+		assert_eq!(u64::from(vcpu.exit_reason_raw().unwrap()), reason & 0xffff);
+		assert_eq!(
+			u64::from(vcpu.exit_reason_raw().unwrap()),
+			consts::vmx_exit::VMX_REASON_IO
+		);
+		assert_eq!(
+			vcpu.exit_qualification().unwrap(),
+			vcpu.read_vmcs(VMCS_RO_EXIT_QUALIFIC).unwrap()
+		);
+		assert_eq!(vcpu.exit_qualification().unwrap() >> 16, 0x3f8);
+		assert_eq!(vcpu.vmexit_instruction_length().unwrap(), 1);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn run_and_time() {
+	let code = [
+		0xb9, 0xff, 0xff, /* mov $0xffff, %cx */
+		0xe2, 0xfe, /* loop . */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+
+		let mut total = Duration::ZERO;
+		loop {
+			let (details, elapsed) = vcpu.run_and_time().unwrap();
+			total += elapsed;
+			match details {
+				ExitDetails::Hlt => break,
+				ExitDetails::Other { .. } | ExitDetails::EptViolation(_) => {}
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		}
+		assert!(total > Duration::ZERO);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn dirty_log_records_written_pages() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let code = [
+		0xa2, 0x00, 0x10, /* mov %al, (0x1000) */
+		0xa2, 0x00, 0x30, /* mov %al, (0x3000) */
+		0xf4, /* hlt */
+		0xa2, 0x00, 0x30, /* mov %al, (0x3000) */
+		0xf4, /* hlt */
+	];
+
+	unsafe {
+		let layout = Layout::from_size_align(MEM_SIZE, 4096).unwrap();
+		let mem_raw = alloc_zeroed(layout);
+		let mem = slice::from_raw_parts_mut(mem_raw, MEM_SIZE);
+		mem[CODE_ADDRESS as usize..CODE_ADDRESS as usize + code.len()].copy_from_slice(&code);
+
+		let vm = Vm::new().unwrap();
+		vm.map_mem(mem, 0, MemPerm::ExecAndWrite).unwrap();
+		assert!(matches!(
+			vm.enable_dirty_tracking(0x1000, MEM_SIZE),
+			Err(Error::BadArg)
+		));
+		let log = vm.enable_dirty_tracking(0x1000, 0x3000).unwrap();
+
+		let vcpu = vm.create_vcpu().unwrap();
+		vcpu.setup_real_mode().unwrap();
+		vcpu.write_register(&Register::RIP, CODE_ADDRESS).unwrap();
+
+		let run = || loop {
+			vcpu.run().unwrap();
+			if log.handle_exit(&vcpu).unwrap() {
+				continue;
+			}
+			match vcpu.exit_details().unwrap() {
+				ExitDetails::Hlt => break,
+				ExitDetails::Other { reason, .. }
+					if reason.0 == consts::vmx_exit::VMX_REASON_IRQ => {}
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		};
+
+		run();
+		assert_eq!(log.take_and_reset().unwrap(), [0b101]);
+		assert_eq!(log.take_and_reset().unwrap(), [0]);
+
+		/* written pages are protected again after the reset */
+		vcpu.skip_instruction().unwrap();
+		run();
+		assert_eq!(log.take_and_reset().unwrap(), [0b100]);
+		assert_eq!(vm.permissions_at(0x1000), Some(MemPerm::ExecAndWrite));
+
+		vcpu.destroy().unwrap();
+		drop(log);
+		drop(vm);
+		dealloc(mem_raw, layout);
+	}
+}
+
+#[test]
+fn gp_regs_round_trip() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let mut regs = vcpu.read_gp_regs().unwrap();
+		assert_eq!(regs.rip, CODE_ADDRESS);
+		assert_eq!(regs.rflags, 0x2);
+
+		regs.rax = 0x1111;
+		regs.rsi = 0x2222;
+		regs.r15 = 0x3333;
+		regs.rflags = 0x202;
+		vcpu.write_gp_regs(&regs).unwrap();
+
+		assert_eq!(vcpu.read_gp_regs().unwrap(), regs);
+		assert_eq!(vcpu.read_register(&Register::RSI).unwrap(), 0x2222);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn segment_round_trip() {
+	with_code(&[0xf4], |_| {
+		let vcpu = real_mode_vcpu();
+
+		let cs = vcpu.read_segment(Segment::CS).unwrap();
+		assert_eq!(cs.selector, 0);
+		assert_eq!(cs.base, 0);
+
+		let new_cs = SegmentRegister {
+			selector: 0x1000,
+			base: 0x10000,
+			limit: 0xffff,
+			access_rights: cs.access_rights,
+		};
+		vcpu.write_segment(Segment::CS, &new_cs).unwrap();
+
+		assert_eq!(vcpu.read_segment(Segment::CS).unwrap(), new_cs);
+		assert_eq!(vcpu.read_register(&Register::CS).unwrap(), 0x1000);
+		assert_eq!(
+			vcpu.read_vmcs(consts::vmcs::VMCS_GUEST_CS_BASE).unwrap(),
+			0x10000
+		);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn serial_console() {
+	let code = [
+		0xba, 0xf8, 0x03, /* mov $0x3f8, %dx */
+		0xb0, b'8', /* mov $'8', %al */
+		0xee, /* out %al, (%dx) */
+		0xb0, b'\n', /* mov $'\n', %al */
+		0xee,  /* out %al, (%dx) */
+		0x42,  /* inc %dx */
+		0xee,  /* out %al, (%dx) */
+		0xf4,  /* hlt */
+	];
+
+	with_code(&code, |_| {
+		let vcpu = real_mode_vcpu();
+		let mut console = SerialConsole::new(COM1);
+		let mut output = Vec::new();
+
+		loop {
+			match run_until_exit(&vcpu) {
+				ExitDetails::Io(io) if io.port == COM1 => {
+					assert!(console.handle_io(&vcpu, &io, &mut output).unwrap());
+				}
+				ExitDetails::Io(io) => {
+					/* other ports are left to the caller */
+					assert!(!console.handle_io(&vcpu, &io, &mut output).unwrap());
+					vcpu.skip_instruction().unwrap();
+				}
+				ExitDetails::Hlt => break,
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		}
+
+		assert_eq!(output, b"8\n");
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn sync_tsc_aligns_vcpus() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+	let vm = Vm::new().unwrap();
+	let created = Barrier::new(3);
+	let synced = Barrier::new(3);
+	let tscs = Mutex::new(Vec::new());
+
+	thread::scope(|s| {
+		for _ in 0..2 {
+			s.spawn(|| {
+				let vcpu = vm.create_vcpu().unwrap();
+				created.wait();
+				synced.wait();
+				tscs.lock().unwrap().push(vcpu.read_tsc().unwrap());
+				vcpu.destroy().unwrap();
+			});
+		}
+
+		created.wait();
+		vm.sync_tsc(0).unwrap();
+		synced.wait();
+	});
+
+	/* both counters restarted at 0 less than a few seconds ago */
+	let tscs = tscs.into_inner().unwrap();
+	assert_eq!(tscs.len(), 2);
+	assert!(tscs.iter().all(|tsc| *tsc < 10_000_000_000));
+	assert!(tscs[0].abs_diff(tscs[1]) < 1_000_000_000);
+}
+
+#[test]
+fn copy_state_from_forks_guest() {
+	let code = [
+		0xb0, 0x01, /* mov $1, %al */
+		0xe6, 0x10, /* out %al, $0x10 */
+		0xfe, 0xc0, /* inc %al */
+		0xe6, 0x10, /* out %al, $0x10 */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |_| {
+		let parent = real_mode_vcpu();
+		let length = match run_until_exit(&parent) {
+			ExitDetails::Io(io) if io.value == Some(1) => io.instruction_length,
+			details => panic!("unexpected exit: {:?}", details),
+		};
+
+		let child = unsafe { VirtualCpu::new_unbound() }.unwrap();
+		child.copy_state_from(&parent).unwrap();
+		assert_eq!(
+			child.read_gp_regs().unwrap(),
+			parent.read_gp_regs().unwrap()
+		);
+
+		for vcpu in [&parent, &child] {
+			/* the read-only exit fields aren't copied, so step over the OUT by hand */
+			let rip = vcpu.read_register(&Register::RIP).unwrap();
+			vcpu.write_register(&Register::RIP, rip + length).unwrap();
+			match run_until_exit(vcpu) {
+				ExitDetails::Io(io) => {
+					assert_eq!(io.port, 0x10);
+					assert_eq!(io.value, Some(2));
+				}
+				details => panic!("unexpected exit: {:?}", details),
+			}
+		}
+
+		child.destroy().unwrap();
+		parent.destroy().unwrap();
+	});
+}
+
+#[test]
+fn hw_breakpoint_raises_debug_exception() {
+	let code = [
+		0x90, /* nop */
+		0x90, /* nop */
+		0xf4, /* hlt */
+	];
+
+	with_code(&code, |_mem| {
+		let vcpu = real_mode_vcpu();
+
+		assert!(matches!(
+			vcpu.set_hw_breakpoint(4, 0, BreakKind::Execute, BreakLen::One),
+			Err(Error::BadArg)
+		));
+		assert!(matches!(
+			vcpu.set_hw_breakpoint(0, 0, BreakKind::Execute, BreakLen::Four),
+			Err(Error::BadArg)
+		));
+		assert!(matches!(vcpu.clear_hw_breakpoint(4), Err(Error::BadArg)));
+
+		vcpu.set_hw_breakpoint(1, CODE_ADDRESS + 1, BreakKind::Execute, BreakLen::One)
+			.unwrap();
+		assert_eq!(
+			vcpu.read_register(&Register::DR1).unwrap(),
+			CODE_ADDRESS + 1
+		);
+		assert_eq!(vcpu.read_register(&Register::DR7).unwrap() & 0xf00fc, 0x4);
+
+		match run_until_exit(&vcpu) {
+			/* vector 1 (#DB) */
+			ExitDetails::Exception(exception) => assert_eq!(exception.vector, 1),
+			details => panic!("unexpected exit {:?}", details),
+		}
+		assert_eq!(
+			vcpu.read_register(&Register::RIP).unwrap(),
+			CODE_ADDRESS + 1
+		);
+
+		vcpu.clear_hw_breakpoint(1).unwrap();
+		assert_eq!(vcpu.read_register(&Register::DR7).unwrap() & 0xf00fc, 0);
+		assert_eq!(run_until_exit(&vcpu), ExitDetails::Hlt);
+
+		vcpu.destroy().unwrap();
+	});
+}
+
+#[test]
+fn create_vm_with_apic_acceleration() {
+	let _guard = VM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+	assert!(!VmCreateFlags::DEFAULT.contains(VmCreateFlags::ACCEL_APIC));
+	assert!(
+		(VmCreateFlags::DEFAULT | VmCreateFlags::ACCEL_APIC).contains(VmCreateFlags::ACCEL_APIC)
+	);
+
+	create_vm_with_flags(VmCreateFlags::DEFAULT).unwrap();
+	destroy_vm().unwrap();
+
+	match create_vm_with_flags(VmCreateFlags::ACCEL_APIC) {
+		Ok(()) => {
+			let vcpu = unsafe { VirtualCpu::new_unbound() }.unwrap();
+			vcpu.destroy().unwrap();
+			destroy_vm().unwrap();
+		}
+		/* older frameworks don't emulate the APIC */
+		Err(err) => assert!(matches!(err.root_cause(), Error::Unsupp)),
+	}
+
+	/* a failed creation doesn't leave the VM claimed */
+	create_vm().unwrap();
+	destroy_vm().unwrap();
+}